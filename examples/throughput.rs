@@ -0,0 +1,150 @@
+use clap::Parser;
+use rand::rngs::SmallRng;
+use rand::{Rng, SeedableRng};
+use std::error::Error;
+use std::net::SocketAddr;
+use std::thread;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+use veifka::test_util::{Reply, TestClient};
+use veifka::{serve, DataStore, Databases, GroupCommit, ProtoLimits, ServerContext};
+
+type BoxError = Box<dyn Error + Send + Sync>;
+
+// Keys the load is spread over, all written before the run so reads hit
+const KEYSPACE: usize = 10_000;
+
+#[derive(Parser, Debug)]
+#[command(author, version, about, long_about = None)]
+struct Args {
+    /// Number of concurrent client connections
+    #[arg(long, default_value_t = 8)]
+    clients: usize,
+
+    /// Total number of requests, spread evenly over the clients
+    #[arg(long, default_value_t = 100_000)]
+    requests: usize,
+
+    /// Size of the values written by SET, in bytes
+    #[arg(long, default_value_t = 64)]
+    value_size: usize,
+
+    /// Fraction of requests that are GETs, the rest being SETs
+    #[arg(long, default_value_t = 0.8)]
+    read_ratio: f64,
+
+    /// Group commit window of SETs in microseconds, 0 to commit each alone
+    #[arg(long, default_value_t = 0)]
+    group_commit_window_us: u64,
+}
+
+fn main() -> Result<(), BoxError> {
+    let args = Args::parse();
+    if !(0.0..=1.0).contains(&args.read_ratio) {
+        return Err("--read-ratio must be between 0 and 1".into());
+    }
+    let clients = args.clients.max(1);
+
+    // Dropped last, removing the datastore once the server is gone
+    let temp_dir = TempDir::new()?;
+    let datastore = DataStore::new(temp_dir.path().to_str().unwrap())?;
+    let databases = Databases::open(&datastore, 1)?;
+    let partition = databases.get(0).expect("at least one database");
+    let value = vec![b'x'; args.value_size];
+    for key in 0..KEYSPACE {
+        partition.set(format!("key:{}", key).as_bytes(), &value)?;
+    }
+    datastore.set_group_commit(GroupCommit {
+        window: Duration::from_micros(args.group_commit_window_us),
+        ..GroupCommit::default()
+    });
+
+    let runtime = tokio::runtime::Runtime::new()?;
+    let listener = runtime.block_on(tokio::net::TcpListener::bind("127.0.0.1:0"))?;
+    let addr = listener.local_addr()?;
+    let context = ServerContext::new(databases, ProtoLimits::default());
+    runtime.spawn(serve(listener, context));
+
+    let start = Instant::now();
+    let workers = (0..clients)
+        .map(|client| {
+            // The first clients take the remainder
+            let requests = args.requests / clients + usize::from(client < args.requests % clients);
+            let (value_size, read_ratio) = (args.value_size, args.read_ratio);
+            thread::spawn(move || run_client(addr, client as u64, requests, value_size, read_ratio))
+        })
+        .collect::<Vec<_>>();
+    let mut latencies = Vec::with_capacity(args.requests);
+    for worker in workers {
+        latencies.extend(worker.join().expect("client thread panicked")?);
+    }
+    let elapsed = start.elapsed();
+
+    latencies.sort_unstable();
+    let ops_per_sec = latencies.len() as f64 / elapsed.as_secs_f64();
+    // One line of key=value pairs, easy to diff or parse in CI
+    println!(
+        "clients={} requests={} value_size={} read_ratio={} group_commit_window_us={} elapsed_ms={} ops_per_sec={:.0} p50_us={} p90_us={} p99_us={} p999_us={} max_us={}",
+        clients,
+        latencies.len(),
+        args.value_size,
+        args.read_ratio,
+        args.group_commit_window_us,
+        elapsed.as_millis(),
+        ops_per_sec,
+        percentile(&latencies, 0.5),
+        percentile(&latencies, 0.9),
+        percentile(&latencies, 0.99),
+        percentile(&latencies, 0.999),
+        latencies.last().copied().unwrap_or_default(),
+    );
+
+    drop(runtime);
+    drop(partition);
+    drop(datastore);
+    temp_dir.close()?;
+    Ok(())
+}
+
+// Sends `requests` GETs and SETs over one connection, returning the latency
+// of each in microseconds
+fn run_client(
+    addr: SocketAddr,
+    seed: u64,
+    requests: usize,
+    value_size: usize,
+    read_ratio: f64,
+) -> Result<Vec<u64>, BoxError> {
+    let mut client = TestClient::connect(addr)?;
+    let mut rng = SmallRng::seed_from_u64(seed);
+    let value = vec![b'y'; value_size];
+    let mut latencies = Vec::with_capacity(requests);
+    for _ in 0..requests {
+        let key = format!("key:{}", rng.gen_range(0..KEYSPACE));
+        let start = Instant::now();
+        let reply = if rng.gen_bool(read_ratio) {
+            client.command(&[b"GET", key.as_bytes()])?
+        } else {
+            client.command(&[b"SET", key.as_bytes(), &value])?
+        };
+        latencies.push(duration_us(start.elapsed()));
+        if let Reply::Error(e) = reply {
+            return Err(e.into());
+        }
+    }
+    Ok(latencies)
+}
+
+fn duration_us(duration: Duration) -> u64 {
+    duration.as_micros().try_into().unwrap_or(u64::MAX)
+}
+
+// Nearest-rank percentile of sorted latencies
+fn percentile(sorted: &[u64], fraction: f64) -> u64 {
+    if sorted.is_empty() {
+        return 0;
+    }
+    let rank = (fraction * sorted.len() as f64).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
@@ -1,10 +1,10 @@
-use clap::Parser;
-use fjall::PartitionCreateOptions;
+use clap::{Parser, ValueEnum};
+use fjall::CompressionType;
 use indicatif::{ProgressBar, ProgressStyle};
 use rand::SeedableRng;
 use rand::{distributions::Alphanumeric, Rng};
 use std::error::Error;
-use std::path::{Path, PathBuf};
+use std::path::PathBuf;
 
 use veifka::{DataStore, DataStorePartition};
 
@@ -27,12 +27,46 @@ struct Args {
     #[arg(short, long, default_value = "./data")]
     db_path: PathBuf,
 
-    /// Run all test combinations
+    /// Block compression of the test partition
+    #[arg(long, value_enum, default_value_t = Compression::Lz4)]
+    compression: Compression,
+
+    /// Run all test combinations, for every compression
     #[arg(short, long)]
     run_all: bool,
 }
 
+// The block compressions fjall supports. It has no zstd; miniz (deflate) is
+// the stronger, slower alternative to LZ4.
+#[derive(ValueEnum, Debug, Clone, Copy)]
+enum Compression {
+    None,
+    Lz4,
+    Miniz,
+}
+
+impl Compression {
+    const ALL: [Compression; 3] = [Compression::None, Compression::Lz4, Compression::Miniz];
+
+    fn name(self) -> &'static str {
+        match self {
+            Compression::None => "none",
+            Compression::Lz4 => "lz4",
+            Compression::Miniz => "miniz",
+        }
+    }
+
+    fn fjall(self) -> CompressionType {
+        match self {
+            Compression::None => CompressionType::None,
+            Compression::Lz4 => CompressionType::Lz4,
+            Compression::Miniz => CompressionType::Miniz(6),
+        }
+    }
+}
+
 struct TestResult {
+    compression: Compression,
     key_size: usize,
     value_size: usize,
     count: usize,
@@ -61,7 +95,7 @@ fn main() -> Result<(), Box<dyn Error>> {
             .ok_or("value_size is required for single test")?;
         let count = args.count.ok_or("count is required for single test")?;
 
-        run_single_test(&data_store, key_size, value_size, count)?;
+        run_single_test(&data_store, args.compression, key_size, value_size, count)?;
     }
 
     Ok(())
@@ -69,16 +103,27 @@ fn main() -> Result<(), Box<dyn Error>> {
 
 fn run_single_test(
     data_store: &DataStore,
+    compression: Compression,
     key_size: usize,
     value_size: usize,
     count: usize,
 ) -> Result<(), Box<dyn Error>> {
-    let partition_name = format!("test_partition_k{}_v{}_c{}", key_size, value_size, count);
-    let partition_handle = data_store.create_partition(&partition_name)?;
-    let partition = DataStorePartition::new(partition_handle);
+    let partition_name = format!(
+        "test_partition_{}_k{}_v{}_c{}",
+        compression.name(),
+        key_size,
+        value_size,
+        count
+    );
+    let partition = data_store
+        .partition_builder(&partition_name)
+        .compression(compression.fjall())
+        .open()?;
 
     let total_written = generate_and_write_kv_pairs(&partition, key_size, value_size, count)?;
+    flush_to_segments(&partition)?;
 
+    println!("Compression: {}", compression.name());
     println!("Total data written: {} bytes", total_written);
     println!(
         "Total data written in MB: {:.3} MB",
@@ -88,17 +133,14 @@ fn run_single_test(
         "Disk space usage from keyspace: {}",
         data_store.keyspace().disk_space()
     );
-    // Get disk usage for this partition
-    let partition_handle = data_store
-        .keyspace()
-        .open_partition(&partition_name, PartitionCreateOptions::default())?;
-    let disk_usage = partition_handle.disk_space();
-    println!("Disk space usage from partition: {}", disk_usage);
+    println!(
+        "Disk space usage from partition: {}",
+        partition.disk_space()
+    );
 
     Ok(())
 }
 
-// fn run_all_combinations(data_store: &DataStore) -> Result<(), Box<dyn Error>> {
 fn run_all_combinations() -> Result<(), Box<dyn Error>> {
     let key_sizes = [16, 32, 64, 128];
     let value_sizes = [16, 32, 64, 128, 256];
@@ -106,78 +148,63 @@ fn run_all_combinations() -> Result<(), Box<dyn Error>> {
 
     let mut results = Vec::new();
 
-    for key_size in key_sizes.iter() {
-        for value_size in value_sizes.iter() {
-            for count in counts.iter() {
-                println!(
-                    "\nRunning test: key_size={}, value_size={}, count={}",
-                    key_size, value_size, count
-                );
-
-                let data_store_name = format!("datastore_k{}_v{}_c{}", key_size, value_size, count);
-                // Create a new datastore each time
-                let data_store = DataStore::new(&data_store_name)?;
-
-                // Create a unique partition for each test
-                let partition_name =
-                    format!("test_partition_k{}_v{}_c{}", key_size, value_size, count);
-                let partition_handle = data_store.create_partition(&partition_name)?;
-                let partition_data_store = DataStorePartition::new(partition_handle);
-
-                let total_written = generate_and_write_kv_pairs(
-                    &partition_data_store,
-                    *key_size,
-                    *value_size,
-                    *count,
-                )?;
-                // Flush active journal
-                // data_store.keyspace().persist(fjall::PersistMode::SyncAll)?;
-
-                // Add a small delay to ensure all disk operations are complete
-                // std::thread::sleep(std::time::Duration::from_millis(100));
-
-                // Get disk usage for this partition
-                // let partition_handle = data_store
-                //     .keyspace()
-                //     .open_partition(&partition_name, PartitionCreateOptions::default())?;
-                // let disk_usage = partition_handle.disk_space();
-                let disk_usage_keyspace = data_store.keyspace().disk_space();
-                let write_amp = disk_usage_keyspace as f64 / total_written as f64;
-
-                results.push(TestResult {
-                    key_size: *key_size,
-                    value_size: *value_size,
-                    count: *count,
-                    total_written,
-                    disk_usage: disk_usage_keyspace,
-                    write_amp,
-                });
-                println!(
-                    "Key Size: {}, Value Size: {}, Count: {}",
-                    key_size, value_size, count
-                );
-                println!("Total data: {} bytes", total_written);
-                println!("Keyspace disk usage: {}", disk_usage_keyspace);
-
-                // println!(
-                //     "Total data written (in MB): {:.3} MB",
-                //     total_written as f64 / (1024.0 * 1024.0)
-                // );
-                // println!("Partition disk Usage: {} bytes", disk_usage);
-                println!("Write Amplification: {:.2}", write_amp);
-                println!("----------------------------------------");
-
-                std::fs::remove_dir_all(&data_store_name)?;
+    for compression in Compression::ALL {
+        for key_size in key_sizes.iter() {
+            for value_size in value_sizes.iter() {
+                for count in counts.iter() {
+                    println!(
+                        "\nRunning test: compression={}, key_size={}, value_size={}, count={}",
+                        compression.name(),
+                        key_size,
+                        value_size,
+                        count
+                    );
+
+                    // A fresh datastore each time, removed with the directory
+                    // once the result is in
+                    let dir = tempfile::TempDir::new()?;
+                    let data_store = DataStore::new(dir.path().to_str().unwrap())?;
+                    let partition = data_store
+                        .partition_builder("test_partition")
+                        .compression(compression.fjall())
+                        .open()?;
+
+                    let total_written =
+                        generate_and_write_kv_pairs(&partition, *key_size, *value_size, *count)?;
+                    flush_to_segments(&partition)?;
+
+                    let disk_usage = partition.disk_space();
+                    let write_amp = disk_usage as f64 / total_written as f64;
+
+                    results.push(TestResult {
+                        compression,
+                        key_size: *key_size,
+                        value_size: *value_size,
+                        count: *count,
+                        total_written,
+                        disk_usage,
+                        write_amp,
+                    });
+                    println!("Total data: {} bytes", total_written);
+                    println!("Partition disk usage: {}", disk_usage);
+                    println!("Write Amplification: {:.2}", write_amp);
+                    println!("----------------------------------------");
+                }
             }
         }
     }
 
     println!("\nSummary of all tests:");
-    println!("Key Size | Value Size | Count | Total Written | Disk Usage | Write Amp");
-    println!("---------+------------+-------+---------------+------------+-----------");
+    println!(
+        "Compression | Key Size | Value Size | Count | Total Written | Disk Usage | Write Amp"
+    );
+    println!(
+        "------------+----------+------------+-------+---------------+------------+-----------"
+    );
     for result in results {
         println!(
-            "{:8} | {:10} | {:6} | {:13} | {:10} | {:.2}",
+            "{:11} | {:8} | {:10} | {:6} | {:13} | {:10} | {:.2}",
+            result.compression.name(),
             result.key_size,
             result.value_size,
             result.count,
@@ -190,6 +217,20 @@ fn run_all_combinations() -> Result<(), Box<dyn Error>> {
     Ok(())
 }
 
+// Writes the memtables out as segments, so that the partition's disk usage
+// reflects its compression rather than sitting in the journal
+fn flush_to_segments(partition: &DataStorePartition) -> Result<(), Box<dyn Error>> {
+    let segments = partition.segment_count();
+    partition.reload()?;
+    for _ in 0..1000 {
+        if partition.segment_count() > segments {
+            return Ok(());
+        }
+        std::thread::sleep(std::time::Duration::from_millis(10));
+    }
+    Err("memtable was not flushed".into())
+}
+
 fn generate_and_write_kv_pairs(
     partition: &DataStorePartition,
     key_size: usize,
@@ -0,0 +1,232 @@
+use std::fmt;
+use std::str::FromStr;
+
+// Command categories, one bit each, after Redis' @read, @write and @admin
+const READ: u8 = 1 << 0;
+const WRITE: u8 = 1 << 1;
+const ADMIN: u8 = 1 << 2;
+const ALL: u8 = READ | WRITE | ADMIN;
+
+const CATEGORIES: [(&str, u8); 4] = [
+    ("read", READ),
+    ("write", WRITE),
+    ("admin", ADMIN),
+    ("all", ALL),
+];
+
+// Commands any authenticated user can run: connection state, pub/sub and
+// commands that don't touch the dataset
+const UNRESTRICTED_COMMANDS: &[&str] = &[
+    "AUTH",
+    "HELLO",
+    "QUIT",
+    "PING",
+    "SELECT",
+    "ACL",
+    "SUBSCRIBE",
+    "UNSUBSCRIBE",
+    "PUBLISH",
+    "CLUSTER",
+    "WAIT",
+];
+
+const READ_COMMANDS: &[&str] = &[
+    "GET",
+    "MGET",
+    "EXISTS",
+    "TYPE",
+    "DUMP",
+    "TTL",
+    "PTTL",
+    "GETWITHTTL",
+    "EXPIRETIME",
+    "PEXPIRETIME",
+    "OBJECT",
+    "MEMORY",
+    "LLEN",
+    "LRANGE",
+    "LPOS",
+    "SISMEMBER",
+    "SMISMEMBER",
+    "SMEMBERS",
+    "SSCAN",
+    "SCARD",
+    "SUNION",
+    "SINTER",
+    "SDIFF",
+    "SINTERCARD",
+    "HGET",
+    "HMGET",
+    "HSCAN",
+    "LCS",
+    "DBSIZE",
+    "KEYS",
+    "SCAN",
+];
+
+const WRITE_COMMANDS: &[&str] = &[
+    "SET",
+    "MSET",
+    "MSETNX",
+    "DEL",
+    "INCR",
+    "DECR",
+    "INCRBY",
+    "DECRBY",
+    "EXPIRE",
+    "PEXPIRE",
+    "EXPIREAT",
+    "PEXPIREAT",
+    "PERSIST",
+    "RESTORE",
+    "SWAP",
+    "LPUSH",
+    "RPUSH",
+    "LPOP",
+    "RPOP",
+    "LMPOP",
+    "BLPOP",
+    "BRPOP",
+    "SADD",
+    "SREM",
+    "SUNIONSTORE",
+    "SINTERSTORE",
+    "SDIFFSTORE",
+    "HSET",
+    "HMSET",
+    "HDEL",
+    "HINCRBY",
+    "HINCRBYFLOAT",
+];
+
+// Category a command (upper case) belongs to, 0 for unrestricted commands.
+// Any command not listed above is @admin, so that new commands are only
+// open to every user once classified.
+fn command_category(cmd: &str) -> u8 {
+    if UNRESTRICTED_COMMANDS.contains(&cmd) {
+        0
+    } else if READ_COMMANDS.contains(&cmd) {
+        READ
+    } else if WRITE_COMMANDS.contains(&cmd) {
+        WRITE
+    } else {
+        ADMIN
+    }
+}
+
+// The command categories a user may run, parsed from rules such as
+// `+@read,+@write` or `+@all,-@admin`, applied left to right
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Permissions(u8);
+
+impl Permissions {
+    pub fn all() -> Self {
+        Permissions(ALL)
+    }
+
+    pub fn none() -> Self {
+        Permissions(0)
+    }
+
+    pub(crate) fn allows(self, cmd: &str) -> bool {
+        let category = command_category(cmd);
+        self.0 & category == category
+    }
+}
+
+impl FromStr for Permissions {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut granted = 0;
+        for rule in s.split(',').map(str::trim).filter(|rule| !rule.is_empty()) {
+            let bits = rule
+                .get(1..)
+                .and_then(|category| category.strip_prefix('@'))
+                .and_then(|category| {
+                    CATEGORIES
+                        .iter()
+                        .find(|(name, _)| category.eq_ignore_ascii_case(name))
+                })
+                .map(|(_, bits)| *bits)
+                .ok_or_else(|| format!("invalid ACL rule '{}'", rule))?;
+            match rule.as_bytes()[0] {
+                b'+' => granted |= bits,
+                b'-' => granted &= !bits,
+                _ => return Err(format!("invalid ACL rule '{}'", rule)),
+            }
+        }
+        Ok(Permissions(granted))
+    }
+}
+
+// A user other than "default", defined as `name:password:rules`. The
+// password can't contain ':'.
+#[derive(Clone, PartialEq, Eq)]
+pub struct AclUser {
+    pub name: String,
+    pub password: String,
+    pub permissions: Permissions,
+}
+
+impl FromStr for AclUser {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.splitn(3, ':');
+        let (Some(name), Some(password), Some(rules)) = (parts.next(), parts.next(), parts.next())
+        else {
+            return Err(format!(
+                "invalid user '{}', expected name:password:rules",
+                s
+            ));
+        };
+        if name.is_empty() || name == "default" {
+            return Err(format!("invalid user name '{}'", name));
+        }
+        Ok(AclUser {
+            name: name.to_string(),
+            password: password.to_string(),
+            permissions: rules.parse()?,
+        })
+    }
+}
+
+// Leaves the password out
+impl fmt::Debug for AclUser {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AclUser")
+            .field("name", &self.name)
+            .field("permissions", &self.permissions)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_user() {
+        let user: AclUser = "reader:s3cret:+@read".parse().unwrap();
+        assert_eq!(user.name, "reader");
+        assert_eq!(user.password, "s3cret");
+        assert!(user.permissions.allows("GET"));
+        assert!(user.permissions.allows("PING"));
+        assert!(!user.permissions.allows("SET"));
+        assert!(!user.permissions.allows("CONFIG"));
+
+        let user: AclUser = "app:pw:+@all,-@admin".parse().unwrap();
+        assert!(user.permissions.allows("SET"));
+        assert!(!user.permissions.allows("DEBUG"));
+        assert_eq!(
+            "x:y:".parse::<AclUser>().unwrap().permissions,
+            Permissions::none()
+        );
+
+        assert!("nopassword".parse::<AclUser>().is_err());
+        assert!("default:pw:+@all".parse::<AclUser>().is_err());
+        assert!("bob:pw:+@nothing".parse::<AclUser>().is_err());
+        assert!("bob:pw:@read".parse::<AclUser>().is_err());
+    }
+}
@@ -0,0 +1,346 @@
+use crate::datastore::KeyValue;
+#[cfg(feature = "mem-backend")]
+use crate::{now_millis, ValueKind};
+use crate::{DataStoreError, DataStorePartition, Expiry};
+#[cfg(feature = "mem-backend")]
+use std::collections::BTreeMap;
+#[cfg(feature = "mem-backend")]
+use std::sync::{Arc, Mutex};
+
+// One write of a `KvBackend::batch`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BatchOp {
+    Set(Vec<u8>, Vec<u8>),
+    Delete(Vec<u8>),
+}
+
+impl BatchOp {
+    pub fn key(&self) -> &[u8] {
+        match self {
+            BatchOp::Set(key, _) | BatchOp::Delete(key) => key,
+        }
+    }
+}
+
+// The string key-value operations the command dispatcher needs from storage,
+// so that the plain key commands can be served from another store than a
+// fjall partition. Values are strings: `get` fails with `WrongType` on a key
+// holding a container, as GET does. Keys past their expiry are gone.
+pub trait KvBackend: Clone + Send + Sync + 'static {
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, DataStoreError>;
+
+    fn set(&self, key: &[u8], value: &[u8]) -> Result<(), DataStoreError>;
+
+    // Writes a string value expiring at `deadline_ms` (unix ms), or never
+    // with None
+    fn set_expiring(
+        &self,
+        key: &[u8],
+        value: &[u8],
+        deadline_ms: Option<u64>,
+    ) -> Result<(), DataStoreError>;
+
+    // Whether the key existed
+    fn delete(&self, key: &[u8]) -> Result<bool, DataStoreError>;
+
+    fn exists(&self, key: &[u8]) -> Result<bool, DataStoreError>;
+
+    fn expire_time(&self, key: &[u8]) -> Result<Expiry, DataStoreError>;
+
+    // Live string entries under `prefix`, in key order
+    fn scan_prefix(&self, prefix: &[u8]) -> Result<Vec<KeyValue>, DataStoreError>;
+
+    // Applies the writes atomically, a later write to a key winning over an
+    // earlier one
+    fn batch(&self, ops: &[BatchOp]) -> Result<(), DataStoreError>;
+
+    // The partition serving the commands beyond these operations, such as
+    // those on lists, sets and hashes. Backends without one only serve the
+    // plain key commands.
+    fn partition(&self) -> Option<&DataStorePartition> {
+        None
+    }
+}
+
+impl KvBackend for DataStorePartition {
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, DataStoreError> {
+        self.get_string(key)
+    }
+
+    fn set(&self, key: &[u8], value: &[u8]) -> Result<(), DataStoreError> {
+        Ok(DataStorePartition::set(self, key, value)?)
+    }
+
+    fn set_expiring(
+        &self,
+        key: &[u8],
+        value: &[u8],
+        deadline_ms: Option<u64>,
+    ) -> Result<(), DataStoreError> {
+        Ok(DataStorePartition::set_expiring(
+            self,
+            key,
+            value,
+            deadline_ms,
+        )?)
+    }
+
+    fn delete(&self, key: &[u8]) -> Result<bool, DataStoreError> {
+        Ok(DataStorePartition::delete(self, key)?)
+    }
+
+    fn exists(&self, key: &[u8]) -> Result<bool, DataStoreError> {
+        Ok(DataStorePartition::exists(self, key)?)
+    }
+
+    fn expire_time(&self, key: &[u8]) -> Result<Expiry, DataStoreError> {
+        Ok(DataStorePartition::expire_time(self, key)?)
+    }
+
+    fn scan_prefix(&self, prefix: &[u8]) -> Result<Vec<KeyValue>, DataStoreError> {
+        Ok(DataStorePartition::scan_prefix(self, prefix)?)
+    }
+
+    fn batch(&self, ops: &[BatchOp]) -> Result<(), DataStoreError> {
+        Ok(self.write_batch(ops)?)
+    }
+
+    fn partition(&self) -> Option<&DataStorePartition> {
+        Some(self)
+    }
+}
+
+// A `KvBackend` over a `BTreeMap`, for tests of the command handlers that
+// don't need the disk. Clones share the map. Like a partition, it removes
+// keys past their expiry as they are read, and keys can hold other kinds of
+// values than strings, though only their kind is kept.
+#[cfg(feature = "mem-backend")]
+#[derive(Debug, Clone, Default)]
+pub struct MemBackend {
+    entries: Arc<Mutex<BTreeMap<Vec<u8>, MemEntry>>>,
+}
+
+#[cfg(feature = "mem-backend")]
+#[derive(Debug, Clone)]
+struct MemEntry {
+    kind: ValueKind,
+    // Unix time in milliseconds
+    expires_at: Option<u64>,
+    // Empty for other kinds than strings
+    value: Vec<u8>,
+}
+
+#[cfg(feature = "mem-backend")]
+impl MemBackend {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Makes `key` hold a value of `kind`, for tests of the commands' replies
+    // to keys of another kind than string
+    pub fn insert_kind(&self, key: &[u8], kind: ValueKind) {
+        let entry = MemEntry {
+            kind,
+            expires_at: None,
+            value: Vec::new(),
+        };
+        self.entries().insert(key.to_vec(), entry);
+    }
+
+    fn entries(&self) -> std::sync::MutexGuard<'_, BTreeMap<Vec<u8>, MemEntry>> {
+        // Every write is a single map operation, so a poisoned map is intact
+        self.entries
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    // The live entry of a key, removing it if its expiry has passed
+    fn live(&self, key: &[u8]) -> Option<MemEntry> {
+        let mut entries = self.entries();
+        match entries.get(key) {
+            Some(entry) if entry.expires_at.is_some_and(|at| at <= now_millis()) => {
+                entries.remove(key);
+                None
+            }
+            other => other.cloned(),
+        }
+    }
+}
+
+#[cfg(feature = "mem-backend")]
+impl KvBackend for MemBackend {
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, DataStoreError> {
+        match self.live(key) {
+            Some(entry) if entry.kind != ValueKind::String => Err(DataStoreError::WrongType),
+            entry => Ok(entry.map(|entry| entry.value)),
+        }
+    }
+
+    fn set(&self, key: &[u8], value: &[u8]) -> Result<(), DataStoreError> {
+        self.set_expiring(key, value, None)
+    }
+
+    fn set_expiring(
+        &self,
+        key: &[u8],
+        value: &[u8],
+        deadline_ms: Option<u64>,
+    ) -> Result<(), DataStoreError> {
+        let entry = MemEntry {
+            kind: ValueKind::String,
+            expires_at: deadline_ms,
+            value: value.to_vec(),
+        };
+        self.entries().insert(key.to_vec(), entry);
+        Ok(())
+    }
+
+    fn delete(&self, key: &[u8]) -> Result<bool, DataStoreError> {
+        let existed = self.live(key).is_some();
+        self.entries().remove(key);
+        Ok(existed)
+    }
+
+    fn exists(&self, key: &[u8]) -> Result<bool, DataStoreError> {
+        Ok(self.live(key).is_some())
+    }
+
+    fn expire_time(&self, key: &[u8]) -> Result<Expiry, DataStoreError> {
+        Ok(match self.live(key) {
+            None => Expiry::Missing,
+            Some(entry) => entry.expires_at.map_or(Expiry::Persistent, Expiry::At),
+        })
+    }
+
+    fn scan_prefix(&self, prefix: &[u8]) -> Result<Vec<KeyValue>, DataStoreError> {
+        let now = now_millis();
+        Ok(self
+            .entries()
+            .range(prefix.to_vec()..)
+            .take_while(|(key, _)| key.starts_with(prefix))
+            .filter(|(_, entry)| {
+                entry.kind == ValueKind::String && entry.expires_at.is_none_or(|at| at > now)
+            })
+            .map(|(key, entry)| (key.clone(), entry.value.clone()))
+            .collect())
+    }
+
+    fn batch(&self, ops: &[BatchOp]) -> Result<(), DataStoreError> {
+        let mut entries = self.entries();
+        for op in ops {
+            match op {
+                BatchOp::Set(key, value) => {
+                    let entry = MemEntry {
+                        kind: ValueKind::String,
+                        expires_at: None,
+                        value: value.clone(),
+                    };
+                    entries.insert(key.clone(), entry)
+                }
+                BatchOp::Delete(key) => entries.remove(key),
+            };
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{now_millis, DataStore};
+    use tempfile::TempDir;
+
+    // The same operations give the same results on every backend
+    fn exercise(backend: &impl KvBackend) {
+        backend.set(b"user:1", b"alice").unwrap();
+        backend.set(b"user:2", b"bob").unwrap();
+        backend.set(b"other", b"x").unwrap();
+        assert_eq!(backend.get(b"user:1").unwrap(), Some(b"alice".to_vec()));
+        assert!(backend.exists(b"other").unwrap());
+        assert!(backend.delete(b"other").unwrap());
+        assert!(!backend.delete(b"other").unwrap());
+        assert_eq!(backend.get(b"other").unwrap(), None);
+
+        backend
+            .batch(&[
+                BatchOp::Set(b"user:3".to_vec(), b"carol".to_vec()),
+                BatchOp::Delete(b"user:1".to_vec()),
+                BatchOp::Set(b"user:2".to_vec(), b"bobby".to_vec()),
+                BatchOp::Set(b"user:2".to_vec(), b"robert".to_vec()),
+            ])
+            .unwrap();
+        assert_eq!(
+            backend.scan_prefix(b"user:").unwrap(),
+            vec![
+                (b"user:2".to_vec(), b"robert".to_vec()),
+                (b"user:3".to_vec(), b"carol".to_vec()),
+            ]
+        );
+
+        let deadline = now_millis() + 60_000;
+        backend
+            .set_expiring(b"user:2", b"robert", Some(deadline))
+            .unwrap();
+        assert_eq!(
+            backend.expire_time(b"user:2").unwrap(),
+            Expiry::At(deadline)
+        );
+        assert_eq!(backend.expire_time(b"user:3").unwrap(), Expiry::Persistent);
+        assert_eq!(backend.expire_time(b"missing").unwrap(), Expiry::Missing);
+        // Past their expiry keys are gone from every operation
+        backend
+            .set_expiring(b"user:3", b"carol", Some(now_millis() - 1))
+            .unwrap();
+        assert_eq!(backend.get(b"user:3").unwrap(), None);
+        assert!(!backend.exists(b"user:3").unwrap());
+        assert_eq!(backend.expire_time(b"user:3").unwrap(), Expiry::Missing);
+        assert!(!backend.delete(b"user:3").unwrap());
+        assert_eq!(
+            backend.scan_prefix(b"user:").unwrap(),
+            vec![(b"user:2".to_vec(), b"robert".to_vec())]
+        );
+    }
+
+    // A key holding a set reads as the wrong type, but exists and can be
+    // deleted like any other
+    fn exercise_wrong_type(backend: &impl KvBackend) {
+        assert!(matches!(
+            backend.get(b"set"),
+            Err(DataStoreError::WrongType)
+        ));
+        assert!(backend.exists(b"set").unwrap());
+        assert_eq!(backend.scan_prefix(b"set").unwrap(), Vec::new());
+        assert!(backend.delete(b"set").unwrap());
+        assert!(!backend.exists(b"set").unwrap());
+    }
+
+    #[test]
+    fn test_partition_backend() {
+        let temp_dir = TempDir::new().expect("Failed to create temp dir");
+        let data_store = DataStore::new(temp_dir.path().to_str().unwrap()).unwrap();
+        let partition = data_store.partition("test").unwrap();
+        exercise(&partition);
+
+        partition.set_add(b"set", &[b"a"]).unwrap();
+        exercise_wrong_type(&partition);
+        partition.set_add(b"set", &[b"a"]).unwrap();
+        // Overwriting a container drops its members
+        partition
+            .write_batch(&[BatchOp::Set(b"set".to_vec(), b"now a string".to_vec())])
+            .unwrap();
+        assert_eq!(
+            partition.get_string(b"set").unwrap(),
+            Some(b"now a string".to_vec())
+        );
+        assert!(partition.members_handle().is_empty().unwrap());
+    }
+
+    #[cfg(feature = "mem-backend")]
+    #[test]
+    fn test_mem_backend() {
+        let backend = MemBackend::new();
+        exercise(&backend);
+        backend.insert_kind(b"set", ValueKind::Set);
+        exercise_wrong_type(&backend);
+    }
+}
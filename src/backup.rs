@@ -0,0 +1,268 @@
+use crate::datastore::member_prefix;
+use crate::value::{now_millis, StoredValue};
+use crate::{DataStore, DataStoreError, DataStorePartition};
+use fjall::{Instant, PersistMode, Snapshot};
+use std::io::{self, Read, Write};
+use std::path::Path;
+
+// Entries written to the backup per batch, bounding the memory a backup of a
+// large partition takes
+const BACKUP_BATCH_SIZE: usize = 1024;
+
+// A read-only view of every partition of a keyspace as of one instant.
+// Batches commit atomically, so the view holds either all or none of the
+// writes of each update, across partitions too. Holding a snapshot keeps
+// compaction from dropping the versions it reads, so drop it once done.
+pub struct KeyspaceSnapshot {
+    instant: Instant,
+    // The fjall partitions, companion members partitions included
+    partitions: Vec<(String, Snapshot)>,
+}
+
+impl DataStore {
+    // Captures the current contents of all partitions
+    pub fn snapshot(&self) -> Result<KeyspaceSnapshot, DataStoreError> {
+        let instant = self.keyspace().instant();
+        let partitions = self
+            .keyspace()
+            .list_partitions()
+            .into_iter()
+            .map(|name| {
+                let name = name.to_string();
+                let handle = self.create_partition(&name)?;
+                Ok((name, handle.snapshot_at(instant)))
+            })
+            .collect::<Result<_, DataStoreError>>()?;
+        Ok(KeyspaceSnapshot {
+            instant,
+            partitions,
+        })
+    }
+}
+
+impl KeyspaceSnapshot {
+    // The keyspace sequence number the snapshot reads at
+    pub fn instant(&self) -> Instant {
+        self.instant
+    }
+
+    pub fn partition_names(&self) -> impl Iterator<Item = &str> {
+        self.partitions.iter().map(|(name, _)| name.as_str())
+    }
+
+    // Writes the snapshot to a new keyspace at `path`, which can be opened
+    // as a `DataStore` to restore it. The backup is synced to disk before
+    // this returns, independently of the fsync policy of the source; keys
+    // keep their absolute expiry deadlines. Returns the number of entries
+    // written.
+    pub fn backup_to(&self, path: &Path) -> Result<u64, DataStoreError> {
+        if path.exists() {
+            return Err(DataStoreError::KeyspaceError(format!(
+                "backup path '{}' already exists",
+                path.display()
+            )));
+        }
+        let backup = DataStore::new(&path.to_string_lossy())?;
+        let mut entries = 0;
+        for (name, snapshot) in &self.partitions {
+            let handle = backup.create_partition(name)?;
+            let mut batch = backup.keyspace().batch();
+            let mut batched = 0;
+            for entry in snapshot.iter() {
+                // Snapshots yield lsm-tree's errors rather than fjall's
+                let (key, value) = entry.map_err(fjall::Error::from)?;
+                batch.insert(&handle, key, value);
+                entries += 1;
+                batched += 1;
+                if batched == BACKUP_BATCH_SIZE {
+                    batch.commit()?;
+                    batch = backup.keyspace().batch();
+                    batched = 0;
+                }
+            }
+            batch.commit()?;
+        }
+        backup.keyspace().persist(PersistMode::SyncAll)?;
+        Ok(entries)
+    }
+}
+
+// Stream written by `DataStorePartition::export`, one record per live key:
+//
+//   [key_len: u32 BE][key][expires_at: u64 BE, 0 = no expiry][dump_len: u32 BE][dump]
+//
+// where dump is the key's value as serialized by DUMP, members included.
+impl DataStorePartition {
+    // Streams every key of the partition as of `snapshot` to `w`, however
+    // the partition changes meanwhile, so an export taken under writes is
+    // consistent. Keys already expired at the snapshot are left out. Returns
+    // the number of keys written.
+    pub fn export<W: Write>(
+        &self,
+        snapshot: &KeyspaceSnapshot,
+        w: &mut W,
+    ) -> Result<u64, DataStoreError> {
+        let now = now_millis();
+        let keys = self.partition_handle().snapshot_at(snapshot.instant);
+        let members = self.members_handle().snapshot_at(snapshot.instant);
+        let mut exported = 0;
+        for entry in keys.iter() {
+            let (key, bytes) = entry.map_err(fjall::Error::from)?;
+            let stored = StoredValue::decode(&bytes)?;
+            if stored.is_expired(now) {
+                continue;
+            }
+            let mut key_members = Vec::new();
+            if stored.kind.has_members() {
+                let prefix = member_prefix(&key);
+                for entry in members.prefix(&prefix) {
+                    let (member, value) = entry.map_err(fjall::Error::from)?;
+                    key_members.push((member[prefix.len()..].to_vec(), value.to_vec()));
+                }
+            }
+            let dump = stored.dump(&key_members);
+            write_record(w, &key, stored.expires_at, &dump).map_err(export_error)?;
+            exported += 1;
+        }
+        w.flush().map_err(export_error)?;
+        Ok(exported)
+    }
+
+    // Restores the keys of an `export` stream, replacing existing ones only
+    // with `replace`. Returns the number of keys read.
+    pub fn import<R: Read>(&self, r: &mut R, replace: bool) -> Result<u64, DataStoreError> {
+        let mut imported = 0;
+        while let Some(key) = read_chunk(r, true).map_err(export_error)? {
+            let mut deadline = [0u8; 8];
+            r.read_exact(&mut deadline).map_err(export_error)?;
+            let expires_at = Some(u64::from_be_bytes(deadline)).filter(|&deadline| deadline != 0);
+            let dump = read_chunk(r, false)
+                .map_err(export_error)?
+                .unwrap_or_default();
+            self.restore(&key, &dump, expires_at, replace)?;
+            imported += 1;
+        }
+        Ok(imported)
+    }
+}
+
+fn write_record<W: Write>(
+    w: &mut W,
+    key: &[u8],
+    expires_at: Option<u64>,
+    dump: &[u8],
+) -> io::Result<()> {
+    w.write_all(&(key.len() as u32).to_be_bytes())?;
+    w.write_all(key)?;
+    w.write_all(&expires_at.unwrap_or(0).to_be_bytes())?;
+    w.write_all(&(dump.len() as u32).to_be_bytes())?;
+    w.write_all(dump)
+}
+
+// Reads a u32 BE length and that many bytes. A clean end of stream before the
+// length yields None where `eof_ok`.
+fn read_chunk<R: Read>(r: &mut R, eof_ok: bool) -> io::Result<Option<Vec<u8>>> {
+    let mut len = [0u8; 4];
+    match r.read_exact(&mut len) {
+        Err(e) if eof_ok && e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        result => result?,
+    }
+    let mut chunk = vec![0u8; u32::from_be_bytes(len) as usize];
+    r.read_exact(&mut chunk)?;
+    Ok(Some(chunk))
+}
+
+fn export_error(e: io::Error) -> DataStoreError {
+    DataStoreError::DataError(format!("export stream: {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_backup_reflects_snapshot() {
+        let temp_dir = TempDir::new().expect("Failed to create temp dir");
+        let data_store = DataStore::new(temp_dir.path().join("live").to_str().unwrap()).unwrap();
+        let users = data_store.partition("users").unwrap();
+        let orders = data_store.partition("orders").unwrap();
+        users.set(b"alice", b"1").unwrap();
+        users.set_add(b"admins", &[b"alice"]).unwrap();
+        orders.set(b"order", b"pending").unwrap();
+
+        let snapshot = data_store.snapshot().unwrap();
+        assert!(snapshot.partition_names().any(|name| name == "users"));
+        users.set(b"alice", b"2").unwrap();
+        users.set_add(b"admins", &[b"bob"]).unwrap();
+        orders.delete(b"order").unwrap();
+        orders.set(b"new", b"x").unwrap();
+
+        let backup_path = temp_dir.path().join("backup");
+        snapshot.backup_to(&backup_path).unwrap();
+        assert!(snapshot.backup_to(&backup_path).is_err());
+        drop(snapshot);
+
+        let restored = DataStore::new(backup_path.to_str().unwrap()).unwrap();
+        let users = restored.partition("users").unwrap();
+        let orders = restored.partition("orders").unwrap();
+        assert_eq!(users.get(b"alice").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(
+            users.set_members(b"admins").unwrap(),
+            vec![b"alice".to_vec()]
+        );
+        assert_eq!(orders.get(b"order").unwrap(), Some(b"pending".to_vec()));
+        assert_eq!(orders.get(b"new").unwrap(), None);
+    }
+
+    #[test]
+    fn test_export_reflects_snapshot() {
+        let temp_dir = TempDir::new().expect("Failed to create temp dir");
+        let data_store = DataStore::new(temp_dir.path().to_str().unwrap()).unwrap();
+        let source = data_store.partition("source").unwrap();
+        for i in 0..100u32 {
+            source.set(format!("key:{}", i).as_bytes(), b"old").unwrap();
+        }
+        source.hash_set(b"hash", &[(b"a", b"1")]).unwrap();
+        source.expire_at(b"key:0", now_millis() + 60_000).unwrap();
+        let snapshot = data_store.snapshot().unwrap();
+
+        // A writer that mutates the partition midway through the export
+        struct Interfering<'a> {
+            out: Vec<u8>,
+            partition: &'a DataStorePartition,
+        }
+        impl Write for Interfering<'_> {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                if self.out.len() < 1024 && self.out.len() + buf.len() >= 1024 {
+                    self.partition.set(b"key:99", b"new").unwrap();
+                    self.partition.set(b"late", b"x").unwrap();
+                    self.partition.hash_set(b"hash", &[(b"b", b"2")]).unwrap();
+                }
+                self.out.extend_from_slice(buf);
+                Ok(buf.len())
+            }
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+        let mut writer = Interfering {
+            out: Vec::new(),
+            partition: &source,
+        };
+        assert_eq!(source.export(&snapshot, &mut writer).unwrap(), 101);
+        drop(snapshot);
+        assert_eq!(source.get(b"late").unwrap(), Some(b"x".to_vec()));
+
+        let target = data_store.partition("target").unwrap();
+        assert_eq!(target.import(&mut &writer.out[..], false).unwrap(), 101);
+        assert_eq!(target.get(b"key:99").unwrap(), Some(b"old".to_vec()));
+        assert_eq!(target.get(b"late").unwrap(), None);
+        assert_eq!(target.hash_len(b"hash").unwrap(), 1);
+        assert!(matches!(
+            target.expire_time(b"key:0").unwrap(),
+            crate::Expiry::At(_)
+        ));
+        assert!(target.import(&mut &writer.out[..5], true).is_err());
+    }
+}
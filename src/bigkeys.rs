@@ -0,0 +1,117 @@
+use crate::datastore::DEFAULT_MEMORY_USAGE_SAMPLES;
+use crate::value::{now_millis, StoredValue};
+use crate::{DataStoreError, DataStorePartition, ValueKind};
+
+// Keys DEBUG BIGKEYS reads when not given a COUNT
+pub const DEFAULT_BIGKEYS_SCAN_LIMIT: usize = 100_000;
+
+// Key count, size and biggest key of one kind of value, sizes being as
+// reported by MEMORY USAGE
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KindSizes {
+    pub kind: ValueKind,
+    pub keys: u64,
+    pub total_bytes: u64,
+    pub biggest_key: Vec<u8>,
+    pub biggest_bytes: u64,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BigKeysReport {
+    pub scanned: u64,
+    // Whether the scan stopped at its limit before the end of the partition
+    pub truncated: bool,
+    // In the order the kinds were first met
+    pub kinds: Vec<KindSizes>,
+}
+
+impl DataStorePartition {
+    // Finds the biggest key of every kind among the first `limit` live keys,
+    // in key order, as redis-cli --bigkeys does. Containers are sized by
+    // sampling their members like MEMORY USAGE. The limit bounds the time
+    // the scan takes on a large partition; the report tells if it was hit.
+    pub fn big_keys(&self, limit: usize) -> Result<BigKeysReport, DataStoreError> {
+        let mut report = BigKeysReport::default();
+        for entry in self.partition_handle().iter() {
+            let (key, bytes) = entry?;
+            if report.scanned == limit as u64 {
+                report.truncated = true;
+                break;
+            }
+            let stored = StoredValue::decode(&bytes)?;
+            if stored.is_expired(now_millis()) {
+                continue;
+            }
+            // None if the key went away since the iterator read it
+            let Some(usage) = self.memory_usage(&key, DEFAULT_MEMORY_USAGE_SAMPLES)? else {
+                continue;
+            };
+            report.scanned += 1;
+            let kind = stored.kind;
+            let index = match report.kinds.iter().position(|sizes| sizes.kind == kind) {
+                Some(index) => index,
+                None => {
+                    report.kinds.push(KindSizes {
+                        kind,
+                        keys: 0,
+                        total_bytes: 0,
+                        biggest_key: Vec::new(),
+                        biggest_bytes: 0,
+                    });
+                    report.kinds.len() - 1
+                }
+            };
+            let sizes = &mut report.kinds[index];
+            sizes.keys += 1;
+            sizes.total_bytes += usage;
+            if usage > sizes.biggest_bytes {
+                sizes.biggest_key = key.to_vec();
+                sizes.biggest_bytes = usage;
+            }
+        }
+        Ok(report)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::DataStore;
+    use crate::ValueKind;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_big_keys() {
+        let temp_dir = TempDir::new().expect("Failed to create temp dir");
+        let data_store = DataStore::new(temp_dir.path().to_str().unwrap()).unwrap();
+        let partition = data_store.partition("test").unwrap();
+        partition.set(b"small", b"x").unwrap();
+        partition.set(b"large", &[b'x'; 4096]).unwrap();
+        partition.set(b"medium", &[b'x'; 64]).unwrap();
+        let members: Vec<String> = (0..100).map(|i| format!("member:{}", i)).collect();
+        let members: Vec<&[u8]> = members.iter().map(|member| member.as_bytes()).collect();
+        partition.set_add(b"set", &members).unwrap();
+        partition.set_add(b"tiny_set", &[b"a"]).unwrap();
+
+        let report = partition.big_keys(100).unwrap();
+        assert_eq!(report.scanned, 5);
+        assert!(!report.truncated);
+        let strings = report
+            .kinds
+            .iter()
+            .find(|sizes| sizes.kind == ValueKind::String)
+            .unwrap();
+        assert_eq!(strings.keys, 3);
+        assert_eq!(strings.biggest_key, b"large");
+        assert!(strings.biggest_bytes > 4096);
+        let sets = report
+            .kinds
+            .iter()
+            .find(|sizes| sizes.kind == ValueKind::Set)
+            .unwrap();
+        assert_eq!((sets.keys, sets.biggest_key.as_slice()), (2, &b"set"[..]));
+
+        let report = partition.big_keys(2).unwrap();
+        assert_eq!(report.scanned, 2);
+        assert!(report.truncated);
+    }
+}
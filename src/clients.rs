@@ -0,0 +1,165 @@
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+// The connections currently open, for CLIENT LIST
+#[derive(Default)]
+pub(crate) struct Clients {
+    next_id: AtomicU64,
+    connected: Mutex<BTreeMap<u64, Arc<ClientStats>>>,
+}
+
+impl Clients {
+    // Registers a new connection, until the returned handle is dropped
+    pub(crate) fn register(self: &Arc<Self>, addr: Option<SocketAddr>) -> ClientHandle {
+        let stats = Arc::new(ClientStats {
+            id: self.next_id.fetch_add(1, Ordering::Relaxed) + 1,
+            addr,
+            connected_at: Instant::now(),
+            commands: AtomicU64::new(0),
+            last_command_ms: AtomicU64::new(0),
+            last_command: Mutex::new(String::new()),
+            request_id: Mutex::new(String::new()),
+        });
+        self.lock().insert(stats.id, stats.clone());
+        ClientHandle {
+            clients: self.clone(),
+            stats,
+        }
+    }
+
+    // One line per connection, oldest first
+    pub(crate) fn list(&self) -> String {
+        self.lock()
+            .values()
+            .map(|stats| stats.info_line() + "\n")
+            .collect()
+    }
+
+    // Number of connections currently open
+    pub(crate) fn len(&self) -> usize {
+        self.lock().len()
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, BTreeMap<u64, Arc<ClientStats>>> {
+        self.connected.lock().unwrap_or_else(|p| p.into_inner())
+    }
+}
+
+// Per-connection counters, updated by the connection itself on every command
+pub(crate) struct ClientStats {
+    id: u64,
+    addr: Option<SocketAddr>,
+    connected_at: Instant,
+    commands: AtomicU64,
+    // Since `connected_at`
+    last_command_ms: AtomicU64,
+    last_command: Mutex<String>,
+    // Set by the client with CLIENT SETREQUESTID to correlate its requests
+    // with the server's logs, empty when unset
+    request_id: Mutex<String>,
+}
+
+impl ClientStats {
+    // A command is about to run. `name` is lower case, as CLIENT LIST shows it.
+    pub(crate) fn record(&self, name: &str) {
+        self.commands.fetch_add(1, Ordering::Relaxed);
+        self.last_command_ms.store(
+            self.connected_at.elapsed().as_millis() as u64,
+            Ordering::Relaxed,
+        );
+        let mut last = self.last_command.lock().unwrap_or_else(|p| p.into_inner());
+        last.clear();
+        last.push_str(name);
+    }
+
+    pub(crate) fn request_id(&self) -> Option<String> {
+        let request_id = self.request_id.lock().unwrap_or_else(|p| p.into_inner());
+        (!request_id.is_empty()).then(|| request_id.clone())
+    }
+
+    // An empty id clears it
+    pub(crate) fn set_request_id(&self, request_id: &str) {
+        let mut current = self.request_id.lock().unwrap_or_else(|p| p.into_inner());
+        current.clear();
+        current.push_str(request_id);
+    }
+
+    // The connection as a CLIENT LIST line, with Redis' field names
+    pub(crate) fn info_line(&self) -> String {
+        let age = self.connected_at.elapsed();
+        let idle_ms =
+            (age.as_millis() as u64).saturating_sub(self.last_command_ms.load(Ordering::Relaxed));
+        let addr = self.addr.map_or_else(String::new, |addr| addr.to_string());
+        let last = self.last_command.lock().unwrap_or_else(|p| p.into_inner());
+        let cmd = if last.is_empty() {
+            "NULL"
+        } else {
+            last.as_str()
+        };
+        format!(
+            "id={} addr={} age={} idle={} reqid={} tot-cmds={} cmd={}",
+            self.id,
+            addr,
+            age.as_secs(),
+            idle_ms / 1000,
+            self.request_id().unwrap_or_default(),
+            self.commands.load(Ordering::Relaxed),
+            cmd
+        )
+    }
+}
+
+// A connection's entry in `Clients`, removed when dropped
+pub(crate) struct ClientHandle {
+    clients: Arc<Clients>,
+    stats: Arc<ClientStats>,
+}
+
+impl std::ops::Deref for ClientHandle {
+    type Target = ClientStats;
+
+    fn deref(&self) -> &ClientStats {
+        &self.stats
+    }
+}
+
+impl Drop for ClientHandle {
+    fn drop(&mut self) {
+        self.clients.lock().remove(&self.stats.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_register_and_record() {
+        let clients = Arc::new(Clients::default());
+        let first = clients.register(Some("127.0.0.1:5000".parse().unwrap()));
+        let second = clients.register(None);
+        assert!(first
+            .info_line()
+            .starts_with("id=1 addr=127.0.0.1:5000 age=0 "));
+        assert!(first.info_line().ends_with(" tot-cmds=0 cmd=NULL"));
+
+        second.record("get");
+        second.record("set");
+        assert!(second.info_line().ends_with(" tot-cmds=2 cmd=set"));
+        assert!(second.info_line().contains(" reqid= "));
+        second.set_request_id("trace-42");
+        assert_eq!(second.request_id().as_deref(), Some("trace-42"));
+        assert!(second.info_line().contains(" reqid=trace-42 "));
+        second.set_request_id("");
+        assert_eq!(second.request_id(), None);
+        assert_eq!(clients.list().lines().count(), 2);
+
+        drop(first);
+        let list = clients.list();
+        assert_eq!(list.lines().count(), 1);
+        assert!(list.starts_with("id=2 "));
+    }
+}
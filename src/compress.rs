@@ -0,0 +1,77 @@
+// Compression of GET and MGET values for clients that opt in with HELLO's
+// COMPRESSION option, a non-standard extension for bandwidth-constrained links. RESP
+// has no way to mark a reply as compressed, so once a client opts in, every
+// value those commands send it starts with a tag byte:
+//
+//   R  the value follows as stored
+//   L  the value follows as an LZ4 block, its length prepended as a u32 LE
+//
+// Null replies for missing keys are left as they are. Values shorter than
+// the client's minimum size, or that LZ4 wouldn't shrink, are sent as R.
+
+const RAW: u8 = b'R';
+const LZ4: u8 = b'L';
+
+// Values shorter than this aren't worth compressing, unless the client asks
+// for another minimum
+pub const DEFAULT_COMPRESSION_MIN_SIZE: usize = 1024;
+
+// `value` tagged and, if it is at least `min_size` bytes and compresses,
+// compressed
+pub(crate) fn compress_value(value: &[u8], min_size: usize) -> Vec<u8> {
+    if value.len() >= min_size {
+        let compressed = lz4_flex::compress_prepend_size(value);
+        if compressed.len() < value.len() {
+            let mut tagged = Vec::with_capacity(compressed.len() + 1);
+            tagged.push(LZ4);
+            tagged.extend_from_slice(&compressed);
+            return tagged;
+        }
+    }
+    let mut tagged = Vec::with_capacity(value.len() + 1);
+    tagged.push(RAW);
+    tagged.extend_from_slice(value);
+    tagged
+}
+
+// The value a client that opted in to compression received as `tagged`
+pub fn decompress_value(tagged: &[u8]) -> Result<Vec<u8>, String> {
+    match tagged.split_first() {
+        Some((&RAW, value)) => Ok(value.to_vec()),
+        Some((&LZ4, compressed)) => {
+            lz4_flex::decompress_size_prepended(compressed).map_err(|e| e.to_string())
+        }
+        Some((tag, _)) => Err(format!("unknown compression tag {:?}", *tag as char)),
+        None => Err("empty compressed value".to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let large = b"veifka ".repeat(1000);
+        let compressed = compress_value(&large, DEFAULT_COMPRESSION_MIN_SIZE);
+        assert_eq!(compressed[0], LZ4);
+        assert!(compressed.len() < large.len() / 10);
+        assert_eq!(decompress_value(&compressed).unwrap(), large);
+
+        // Small values, and values LZ4 doesn't shrink, are sent as they are
+        for (value, min_size) in [
+            (&b"small"[..], DEFAULT_COMPRESSION_MIN_SIZE),
+            (b"", 0),
+            (b"abcdefgh", 0),
+        ] {
+            let tagged = compress_value(value, min_size);
+            assert_eq!(tagged[0], RAW);
+            assert_eq!(&tagged[1..], value);
+            assert_eq!(decompress_value(&tagged).unwrap(), value);
+        }
+
+        assert!(decompress_value(b"").is_err());
+        assert!(decompress_value(b"Xvalue").is_err());
+        assert!(decompress_value(b"L\xff\xff\xff\xffjunk").is_err());
+    }
+}
@@ -0,0 +1,123 @@
+use crate::value::StoredValue;
+use crate::{DataStoreError, DataStorePartition, NumericEncoding, ValueKind};
+
+// Counters over a partition, for embedders that would otherwise call
+// `incr_by` and parse values themselves. Values are stored in the numeric
+// encoding of the partition, which every handle on it shares, so INCR and GET
+// over RESP on the same partition see the same counters. Every update is a
+// read-modify-write under the key lock.
+#[derive(Clone)]
+pub struct CounterPartition {
+    partition: DataStorePartition,
+}
+
+impl CounterPartition {
+    pub fn new(partition: DataStorePartition) -> Self {
+        CounterPartition { partition }
+    }
+
+    pub fn partition(&self) -> &DataStorePartition {
+        &self.partition
+    }
+
+    // Adds `delta` to the counter, a missing one counting as 0, and returns
+    // the new value
+    pub fn incr(&self, key: &[u8], delta: i64) -> Result<i64, DataStoreError> {
+        self.partition.incr_by(key, delta)
+    }
+
+    // None if the counter doesn't exist. Fails if the key holds anything but
+    // an integer in the partition's encoding.
+    pub fn get(&self, key: &[u8]) -> Result<Option<i64>, DataStoreError> {
+        let encoding = self.partition.numeric_encoding();
+        self.partition
+            .get_string(key)?
+            .map(|value| encoding.decode(&value))
+            .transpose()
+    }
+
+    // Removes the counter, returning the value it had. Keys that don't hold
+    // a counter are left alone and fail as in `get`.
+    pub fn reset(&self, key: &[u8]) -> Result<Option<i64>, DataStoreError> {
+        let encoding = self.partition.numeric_encoding();
+        self.partition.fetch_update(key, |current| {
+            let value = current
+                .map(|stored| decode_counter(encoding, &stored))
+                .transpose()?;
+            Ok((None, value))
+        })
+    }
+}
+
+fn decode_counter(encoding: NumericEncoding, stored: &StoredValue) -> Result<i64, DataStoreError> {
+    if stored.kind != ValueKind::String {
+        return Err(DataStoreError::WrongType);
+    }
+    encoding.decode(&stored.payload)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::DataStore;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_concurrent_increments() {
+        let temp_dir = TempDir::new().unwrap();
+        let data_store = DataStore::new(temp_dir.path().to_str().unwrap()).unwrap();
+        let counters = CounterPartition::new(data_store.partition("counters").unwrap());
+        std::thread::scope(|scope| {
+            for _ in 0..8 {
+                scope.spawn(|| {
+                    for _ in 0..250 {
+                        counters.incr(b"hits", 2).unwrap();
+                    }
+                });
+            }
+        });
+        assert_eq!(counters.get(b"hits").unwrap(), Some(4000));
+        assert_eq!(
+            counters.partition().get(b"hits").unwrap(),
+            Some(b"4000".to_vec())
+        );
+
+        assert_eq!(counters.reset(b"hits").unwrap(), Some(4000));
+        assert_eq!(counters.get(b"hits").unwrap(), None);
+        assert_eq!(counters.reset(b"hits").unwrap(), None);
+        assert_eq!(counters.incr(b"hits", -1).unwrap(), -1);
+    }
+
+    #[test]
+    fn test_non_numeric_keys() {
+        let temp_dir = TempDir::new().unwrap();
+        let data_store = DataStore::new(temp_dir.path().to_str().unwrap()).unwrap();
+        let partition = data_store
+            .partition_builder("counters")
+            .numeric_encoding(NumericEncoding::BigEndianI64)
+            .open()
+            .unwrap();
+        let counters = CounterPartition::new(partition.clone());
+        assert_eq!(counters.incr(b"binary", 7).unwrap(), 7);
+        assert_eq!(counters.get(b"binary").unwrap(), Some(7));
+        // Any other handle on the partition, like the server's, shares the encoding
+        let other = CounterPartition::new(data_store.partition("counters").unwrap());
+        assert_eq!(other.get(b"binary").unwrap(), Some(7));
+
+        partition.set(b"text", b"hello").unwrap();
+        partition.set_add(b"set", &[b"member"]).unwrap();
+        for key in [&b"text"[..], b"set"] {
+            assert!(counters.get(key).is_err());
+            assert!(counters.reset(key).is_err());
+            assert!(partition.exists(key).unwrap());
+        }
+        assert!(matches!(
+            counters.get(b"text"),
+            Err(DataStoreError::NotAnInteger)
+        ));
+        assert!(matches!(
+            counters.get(b"set"),
+            Err(DataStoreError::WrongType)
+        ));
+    }
+}
@@ -1,19 +1,207 @@
-use crate::DataStoreError;
-use fjall::{Config, Keyspace, PartitionCreateOptions, PartitionHandle};
-use std::sync::Arc;
+use crate::evict::DEFAULT_MAXMEMORY_SAMPLES;
+use crate::fsync::SharedFsyncPolicy;
+use crate::group_commit::{GroupCommit, GroupedWrite, WriteGroups};
+use crate::keycodec::{composite_prefix, decode_composite, encode_composite};
+use crate::keylock::KeyLocks;
+use crate::lcs::DEFAULT_LCS_MAX_CELLS;
+use crate::lfu::Lfu;
+use crate::notify::{self, Notifier};
+use crate::quota::KeyQuota;
+use crate::scan::ScanCursors;
+use crate::set::intset_members;
+use crate::stats::StatsCounters;
+use crate::value::{
+    decode_items, now_millis, now_secs, parse_canonical_integer, NumericEncoding, StoredValue,
+    ValueKind,
+};
+use crate::waiters::KeyWaiters;
+use crate::{
+    BatchOp, DataStoreError, FsyncPolicy, KeySlot, KeyspaceEvents, PartitionStats, PubSub,
+    ShardedPartition, TtlLimits,
+};
+use fjall::{
+    Batch, CompressionType, Config, Keyspace, PartitionCreateOptions, PartitionHandle, PersistMode,
+};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, RwLock};
+use std::time::{Duration, Instant};
+use tokio::task::JoinError;
 
 #[derive(Clone)]
 pub struct DataStore {
     // Keep keyspace around as long as we need its partitions!
     keyspace: Keyspace,
+    path: PathBuf,
+    fsync_policy: SharedFsyncPolicy,
+    notifier: Notifier,
+    // Shared by every partition of the keyspace
+    block_cache_size: u64,
+    maxmemory_samples: Arc<AtomicUsize>,
+    read_only: bool,
+    max_keys: Option<u64>,
+    lcs_max_cells: Arc<AtomicUsize>,
+    // TTL in seconds SET ... REFRESH gives a key, 0 when unset
+    refresh_ttl_secs: Arc<AtomicU64>,
+    group_commit: Arc<RwLock<GroupCommit>>,
+    // Whether storage calls run inline on the async thread, see
+    // `set_single_thread`
+    single_thread: Arc<AtomicBool>,
+    ttl_limits: Arc<RwLock<TtlLimits>>,
+    // Master host and port set with REPLICAOF
+    replica_of: Arc<RwLock<Option<(String, u16)>>>,
+    // Identifies this run of the store in INFO, see `run_id`
+    run_id: Arc<RwLock<String>>,
+    scan_cursors: Arc<ScanCursors>,
+    // Every partition opened so far, so that all callers share one set of
+    // key locks, waiters and statistics per partition
+    partitions: Arc<Mutex<HashMap<String, OpenPartition>>>,
+    partition_limit: Arc<RwLock<PartitionLimit>>,
+    // Shared by the clones, so the last one dropped flushes
+    _flush_on_drop: Arc<FlushOnDrop>,
     // partition_handle: Arc<PartitionHandle>,
 }
 
+struct OpenPartition {
+    partition: DataStorePartition,
+    settings: PartitionSettings,
+    // Last time the partition was looked up in the registry
+    last_used: Instant,
+}
+
+// Cap on the number of partitions open at once, each of which holds files
+// and disk space, so that clients selecting partitions by name can't create
+// them without bound
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct PartitionLimit {
+    // None for no cap
+    pub max: Option<usize>,
+    // At the cap, close the least recently used partition no one holds a
+    // handle on rather than failing the open
+    pub evict_idle: bool,
+}
+
+// Best-effort journal sync once the last `DataStore` handle goes away.
+// Errors can't be reported from a drop, so they are ignored; embedders that
+// need to know the data made it to disk call `DataStore::close` instead.
+struct FlushOnDrop(Keyspace);
+
+impl Drop for FlushOnDrop {
+    fn drop(&mut self) {
+        let _ = self.0.persist(PersistMode::SyncAll);
+    }
+}
+
+// Length in hex digits of run ids, as in Redis
+pub const RUN_ID_LEN: usize = 40;
+
+// RUN_ID_LEN random hex digits
+fn random_run_id() -> String {
+    format!(
+        "{:032x}{:08x}",
+        rand::random::<u128>(),
+        rand::random::<u32>()
+    )
+}
+
+// Size of the block cache when none is configured, as fjall's default
+const DEFAULT_BLOCK_CACHE_SIZE: u64 = 16 * 1024 * 1024;
+
+// Snapshots fjall frees between two moves of its GC watermark. fjall has no
+// API for the watermark, so this is its internal `safety_gap`: Cargo.toml pins
+// the exact fjall version and test_compact_keeps_deletes_across_reopen fails
+// if an upgrade changes it.
+const SNAPSHOT_GC_ROUND: usize = 50;
+
+#[derive(Debug, Clone)]
+pub struct DataStoreBuilder {
+    block_cache_size: u64,
+    read_only: bool,
+    max_keys: Option<u64>,
+}
+
+impl DataStoreBuilder {
+    // Memory budget in bytes of the block cache that all partitions share
+    pub fn block_cache_size(mut self, bytes: u64) -> Self {
+        self.block_cache_size = bytes;
+        self
+    }
+
+    // Opens an existing store without ever writing to it, see
+    // `DataStore::open_read_only`
+    pub fn read_only(mut self, read_only: bool) -> Self {
+        self.read_only = read_only;
+        self
+    }
+
+    // Caps the number of keys of every partition, for multi-tenant hosting:
+    // writes that would add a key past it fail with
+    // `DataStoreError::KeyQuotaExceeded`, while overwrites and deletes still
+    // go through. Each partition's keys are counted when it is opened, which
+    // reads the whole partition.
+    pub fn max_keys(mut self, max_keys: u64) -> Self {
+        self.max_keys = Some(max_keys);
+        self
+    }
+
+    pub fn open(self, keyspace_name: &str) -> Result<DataStore, DataStoreError> {
+        DataStore::open(keyspace_name, self)
+    }
+}
+
+impl Default for DataStoreBuilder {
+    fn default() -> Self {
+        DataStoreBuilder {
+            block_cache_size: DEFAULT_BLOCK_CACHE_SIZE,
+            read_only: false,
+            max_keys: None,
+        }
+    }
+}
+
 impl DataStore {
     // pub fn new(keyspace_name: &str, partition_name: &str) -> Result<Self, DataStoreError> {
     pub fn new(keyspace_name: &str) -> Result<Self, DataStoreError> {
+        DataStore::builder().open(keyspace_name)
+    }
+
+    pub fn builder() -> DataStoreBuilder {
+        DataStoreBuilder::default()
+    }
+
+    // Opens an existing store for reading only, e.g. to inspect a backup
+    // that another process also has open. Every write through a partition
+    // fails, whichever command or API call attempts it, and reads don't
+    // record accesses or remove expired keys (which read as missing). fjall
+    // may still replay its journal when opening the store. Missing
+    // partitions are not created. This differs from restricting users to
+    // read commands (`+@read`), which leaves the store writable by others.
+    pub fn open_read_only(keyspace_name: &str) -> Result<Self, DataStoreError> {
+        DataStore::builder().read_only(true).open(keyspace_name)
+    }
+
+    fn open(keyspace_name: &str, builder: DataStoreBuilder) -> Result<Self, DataStoreError> {
+        // The path, or the closest ancestor that exists, must be a directory;
+        // fjall's own error for this is opaque
+        let path = Path::new(keyspace_name);
+        if builder.read_only && !path.is_dir() {
+            return Err(DataStoreError::KeyspaceError(format!(
+                "data path '{}' is not an existing store",
+                path.display()
+            )));
+        }
+        if let Some(existing) = path.ancestors().find(|ancestor| ancestor.exists()) {
+            if !existing.is_dir() {
+                return Err(DataStoreError::KeyspaceError(format!(
+                    "data path '{}' exists but is not a directory",
+                    existing.display()
+                )));
+            }
+        }
         // A keyspace is a database, which may contain multiple collections ("partitions")
         let keyspace = Config::new(keyspace_name)
+            .cache_size(builder.block_cache_size)
             .open()
             .map_err(|e| DataStoreError::KeyspaceError(e.to_string()))?;
 
@@ -27,22 +215,355 @@ impl DataStore {
         //     .map_err(|e| DataStoreError::PartitionError(e.to_string()))?;
 
         Ok(DataStore {
+            _flush_on_drop: Arc::new(FlushOnDrop(keyspace.clone())),
             keyspace,
+            path: PathBuf::from(keyspace_name),
+            fsync_policy: SharedFsyncPolicy::new(FsyncPolicy::default()),
+            notifier: Notifier::new(PubSub::new()),
+            block_cache_size: builder.block_cache_size,
+            maxmemory_samples: Arc::new(AtomicUsize::new(DEFAULT_MAXMEMORY_SAMPLES)),
+            lcs_max_cells: Arc::new(AtomicUsize::new(DEFAULT_LCS_MAX_CELLS)),
+            refresh_ttl_secs: Arc::default(),
+            group_commit: Arc::default(),
+            single_thread: Arc::default(),
+            read_only: builder.read_only,
+            max_keys: builder.max_keys,
+            ttl_limits: Arc::default(),
+            replica_of: Arc::default(),
+            run_id: Arc::new(RwLock::new(random_run_id())),
+            scan_cursors: Arc::default(),
+            partitions: Arc::new(Mutex::new(HashMap::new())),
+            partition_limit: Arc::default(),
             // partition_handle: Arc::new(partition_handle),
         })
     }
 
+    // Cluster hash slot of a key, honouring `{hash tags}`
+    pub fn slot_for(key: &[u8]) -> u16 {
+        KeySlot::for_key(key).value()
+    }
+
+    // Opens (creating if needed) a partition along with the companion fjall
+    // partition holding the members of its sets. Opening a name again, from
+    // any thread, returns a handle on the same partition.
+    pub fn partition(&self, partition_name: &str) -> Result<DataStorePartition, DataStoreError> {
+        self.partition_with(partition_name, None)
+    }
+
+    // Opens the partition with `settings`, or with whatever settings it is
+    // already open with when `None`. A partition that is already open with
+    // other settings is an error: every handle on it shares one set.
+    fn partition_with(
+        &self,
+        partition_name: &str,
+        settings: Option<&PartitionSettings>,
+    ) -> Result<DataStorePartition, DataStoreError> {
+        // Held while opening, so concurrent first opens of a name don't race
+        let mut partitions = self.partitions.lock().unwrap_or_else(|p| p.into_inner());
+        if let Some(open) = partitions.get_mut(partition_name) {
+            if settings.is_some_and(|settings| *settings != open.settings) {
+                return Err(DataStoreError::PartitionError(format!(
+                    "partition '{}' is already open with other settings",
+                    partition_name
+                )));
+            }
+            open.last_used = Instant::now();
+            return Ok(open.partition.clone());
+        }
+        let settings = settings.copied().unwrap_or_default();
+        let limit = self.partition_limit();
+        if let Some(max) = limit.max {
+            if partitions.len() >= max && !(limit.evict_idle && evict_idle(&mut partitions)) {
+                return Err(DataStoreError::PartitionError(format!(
+                    "max number of partitions reached ({})",
+                    max
+                )));
+            }
+        }
+        if self.read_only && !self.keyspace.partition_exists(partition_name) {
+            return Err(DataStoreError::PartitionError(format!(
+                "partition '{}' does not exist in the read-only store",
+                partition_name
+            )));
+        }
+        // fjall keeps the options a partition was created with
+        let options = || match settings.compression {
+            Some(compression) => PartitionCreateOptions::default().compression(compression),
+            None => PartitionCreateOptions::default(),
+        };
+        let partition_handle = self.create_partition_with(partition_name, options())?;
+        let members_handle =
+            self.create_partition_with(&members_partition_name(partition_name), options())?;
+        let mut partition = DataStorePartition::with_shared(
+            self.keyspace.clone(),
+            partition_handle,
+            members_handle,
+            self.fsync_policy.clone(),
+            self.notifier.clone(),
+            self.maxmemory_samples.clone(),
+            self.single_thread.clone(),
+        );
+        partition.read_only = self.read_only;
+        partition.write_groups = Arc::new(WriteGroups::new(self.group_commit.clone()));
+        partition.numeric_encoding = settings.numeric_encoding;
+        partition.track_reads = settings.track_reads;
+        partition.access_time_resolution_secs = settings.access_time_resolution_secs;
+        partition.lfu = settings.lfu;
+        partition.default_ttl = settings.default_ttl;
+        if let Some(max_keys) = self.max_keys {
+            let count = partition.partition_handle.len()? as u64;
+            partition.key_quota = Some(Arc::new(KeyQuota::new(max_keys, count)));
+        }
+        partitions.insert(
+            partition_name.to_string(),
+            OpenPartition {
+                partition: partition.clone(),
+                settings,
+                last_used: Instant::now(),
+            },
+        );
+        Ok(partition)
+    }
+
+    pub fn partition_limit(&self) -> PartitionLimit {
+        *self
+            .partition_limit
+            .read()
+            .unwrap_or_else(|p| p.into_inner())
+    }
+
+    // Applies to partitions opened from now on; ones already open stay open
+    pub fn set_partition_limit(&self, limit: PartitionLimit) {
+        *self
+            .partition_limit
+            .write()
+            .unwrap_or_else(|p| p.into_inner()) = limit;
+    }
+
+    pub fn partition_builder(&self, partition_name: &str) -> PartitionBuilder<'_> {
+        PartitionBuilder {
+            data_store: self,
+            name: partition_name.to_string(),
+            settings: PartitionSettings::default(),
+        }
+    }
+
+    // Opens `shard_count` partitions named `<name>_shard<i>` and spreads keys
+    // across them by cluster slot
+    pub fn sharded_partition(
+        &self,
+        partition_name: &str,
+        shard_count: usize,
+    ) -> Result<ShardedPartition, DataStoreError> {
+        if shard_count == 0 {
+            return Err(DataStoreError::PartitionError(
+                "a sharded partition needs at least one shard".to_string(),
+            ));
+        }
+        let shards = (0..shard_count)
+            .map(|i| self.partition(&format!("{}_shard{}", partition_name, i)))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(ShardedPartition::new(shards))
+    }
+
+    // Filesystem path the keyspace was opened at
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
     pub fn keyspace(&self) -> &Keyspace {
         &self.keyspace
     }
 
+    pub fn fsync_policy(&self) -> FsyncPolicy {
+        self.fsync_policy.get()
+    }
+
+    // Takes effect immediately for every partition opened from this store
+    pub fn set_fsync_policy(&self, policy: FsyncPolicy) {
+        self.fsync_policy.set(policy);
+    }
+
+    // Keys sampled per `DataStorePartition::evict`
+    pub fn maxmemory_samples(&self) -> usize {
+        self.maxmemory_samples.load(Ordering::Relaxed)
+    }
+
+    // Takes effect immediately for every partition, at least 1
+    pub fn set_maxmemory_samples(&self, samples: usize) {
+        self.maxmemory_samples
+            .store(samples.max(1), Ordering::Relaxed);
+    }
+
+    // Largest DP table the LCS command computes, see `DEFAULT_LCS_MAX_CELLS`
+    pub fn lcs_max_cells(&self) -> usize {
+        self.lcs_max_cells.load(Ordering::Relaxed)
+    }
+
+    pub fn set_lcs_max_cells(&self, cells: usize) {
+        self.lcs_max_cells.store(cells, Ordering::Relaxed);
+    }
+
+    // The sliding TTL SET ... REFRESH resets a key's expiry to on every write,
+    // for session-like keys that live as long as they keep being written
+    pub fn refresh_ttl(&self) -> Option<Duration> {
+        match self.refresh_ttl_secs.load(Ordering::Relaxed) {
+            0 => None,
+            secs => Some(Duration::from_secs(secs)),
+        }
+    }
+
+    // Whole seconds; None, or less than a second, disables REFRESH
+    pub fn set_refresh_ttl(&self, ttl: Option<Duration>) {
+        self.refresh_ttl_secs
+            .store(ttl.map_or(0, |ttl| ttl.as_secs()), Ordering::Relaxed);
+    }
+
+    // Group commit of plain SETs and deletes, see `group_commit`. Disabled by
+    // default.
+    pub fn group_commit(&self) -> GroupCommit {
+        *self.group_commit.read().unwrap()
+    }
+
+    pub fn set_group_commit(&self, group_commit: GroupCommit) {
+        *self.group_commit.write().unwrap() = group_commit;
+    }
+
+    pub(crate) fn scan_cursors(&self) -> &ScanCursors {
+        &self.scan_cursors
+    }
+
+    pub fn single_thread(&self) -> bool {
+        self.single_thread.load(Ordering::Relaxed)
+    }
+
+    // Runs storage calls inline on the calling async task rather than on
+    // tokio's blocking pool, for servers on a current-thread runtime. Calls
+    // then block the runtime while they last, so commands from different
+    // connections run strictly one after the other. Applies to every
+    // partition of the store.
+    pub fn set_single_thread(&self, single_thread: bool) {
+        self.single_thread.store(single_thread, Ordering::Relaxed);
+    }
+
+    // Runs a blocking storage call `f` as configured by `set_single_thread`
+    pub(crate) async fn run_blocking<T, F>(&self, f: F) -> Result<T, JoinError>
+    where
+        T: Send + 'static,
+        F: FnOnce() -> T + Send + 'static,
+    {
+        run_blocking(&self.single_thread, f).await
+    }
+
+    // Bounds on client-requested TTLs, enforced by the server
+    pub fn ttl_limits(&self) -> TtlLimits {
+        *self.ttl_limits.read().unwrap()
+    }
+
+    pub fn set_ttl_limits(&self, limits: TtlLimits) {
+        *self.ttl_limits.write().unwrap() = limits;
+    }
+
+    // The master this store is meant to replicate, as reported by INFO.
+    // Replication itself is not implemented, so nothing is ever synced from
+    // it.
+    pub fn replica_of(&self) -> Option<(String, u16)> {
+        self.replica_of.read().unwrap().clone()
+    }
+
+    // None makes the store a master again
+    pub fn set_replica_of(&self, master: Option<(String, u16)>) {
+        *self.replica_of.write().unwrap() = master;
+    }
+
+    // Random id of this run, reported by INFO as both the run id and the
+    // replication id, which tools use to tell a restarted server apart
+    pub fn run_id(&self) -> String {
+        self.run_id.read().unwrap().clone()
+    }
+
+    // Pins the run id, for deterministic INFO output in tests
+    pub fn set_run_id(&self, run_id: &str) {
+        *self.run_id.write().unwrap() = run_id.to_string();
+    }
+
+    // Replaces the run id with a new random one, as DEBUG CHANGE-REPL-ID
+    pub fn change_run_id(&self) -> String {
+        let run_id = random_run_id();
+        *self.run_id.write().unwrap() = run_id.clone();
+        run_id
+    }
+
+    // Configured size of the shared block cache, in bytes
+    pub fn block_cache_capacity(&self) -> u64 {
+        self.block_cache_size
+    }
+
+    // Bytes held by the memtables of every partition, not yet flushed to
+    // segments. fjall doesn't report how full its cache is, so this is the
+    // memory it does account for.
+    pub fn write_buffer_usage(&self) -> u64 {
+        self.keyspace.write_buffer_size()
+    }
+
+    // Pub/sub hub that keyspace notifications are published on
+    pub fn pubsub(&self) -> &PubSub {
+        self.notifier.pubsub()
+    }
+
+    pub fn keyspace_events(&self) -> KeyspaceEvents {
+        self.notifier.events()
+    }
+
+    // Takes effect immediately for every partition opened from this store
+    pub fn set_keyspace_events(&self, events: KeyspaceEvents) {
+        self.notifier.set_events(events);
+    }
+
+    // Syncs the journal to disk
+    pub fn persist(&self) -> Result<(), DataStoreError> {
+        Ok(self.keyspace.persist(PersistMode::SyncAll)?)
+    }
+
+    // Syncs the journal's data but not necessarily its file metadata, which
+    // is enough for the writes in it to survive a crash
+    pub fn sync_data(&self) -> Result<(), DataStoreError> {
+        Ok(self.keyspace.persist(PersistMode::SyncData)?)
+    }
+
+    // Syncs the journal and releases this handle, reporting whether the sync
+    // succeeded. Dropping the store syncs too, but silently. The keyspace
+    // itself closes once every clone of the store and every partition opened
+    // from it are dropped as well.
+    pub fn close(self) -> Result<(), DataStoreError> {
+        self.persist()
+    }
+
+    // Bytes on disk across every partition, the journal included
+    pub fn disk_space(&self) -> u64 {
+        self.keyspace.disk_space()
+    }
+
+    // A raw fjall handle on a partition. fjall hands out handles on the one
+    // open partition per name, but the key locks, statistics, group commit
+    // queue and key quota live on `DataStorePartition`: anything reading or
+    // writing veifka's values goes through `partition` instead, which shares
+    // them per name.
     pub fn create_partition(
         &self,
         partition_name: &str,
+    ) -> Result<PartitionHandle, DataStoreError> {
+        self.create_partition_with(partition_name, PartitionCreateOptions::default())
+    }
+
+    pub fn create_partition_with(
+        &self,
+        partition_name: &str,
+        options: PartitionCreateOptions,
     ) -> Result<PartitionHandle, DataStoreError> {
         let partition_handle = self
             .keyspace
-            .open_partition(partition_name, PartitionCreateOptions::default())
+            .open_partition(partition_name, options)
             .map_err(|e| DataStoreError::PartitionError(e.to_string()))?;
 
         Ok(partition_handle)
@@ -71,63 +592,2371 @@ impl DataStore {
     // }
 }
 
+// An owned key/value pair as returned by scans
+pub type KeyValue = (Vec<u8>, Vec<u8>);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Expiry {
+    // The key does not exist
+    Missing,
+    // The key exists but has no associated expiry
+    Persistent,
+    // The key expires at this unix time (milliseconds)
+    At(u64),
+}
+
+// Key counts of a partition, as reported by INFO keyspace
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct KeyspaceStats {
+    pub keys: u64,
+    // Keys that have an expiry set
+    pub expires: u64,
+}
+
+// Outcome of `DataStorePartition::verify`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct VerifyReport {
+    // Entries read, container members included
+    pub entries: u64,
+    // Key and value bytes of those entries
+    pub bytes: u64,
+    // Keys whose stored value failed to decode
+    pub corrupted: Vec<Vec<u8>>,
+}
+
+const DEFAULT_ACCESS_TIME_RESOLUTION_SECS: u32 = 60;
+
+// Members of a container read by `memory_usage` to estimate its size, as
+// Redis' MEMORY USAGE default
+pub const DEFAULT_MEMORY_USAGE_SAMPLES: usize = 5;
+
+// Longest string OBJECT ENCODING reports as embstr rather than raw, Redis'
+// OBJ_ENCODING_EMBSTR_SIZE_LIMIT
+const EMBSTR_SIZE_LIMIT: usize = 44;
+
+// Rough per-entry cost of an LSM tree entry beyond its key and value bytes:
+// sequence number, value type, length prefixes and block index share
+const ENTRY_OVERHEAD: u64 = 32;
+
+// Veifka-level settings of an open partition, shared by all its handles
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct PartitionSettings {
+    numeric_encoding: NumericEncoding,
+    track_reads: bool,
+    access_time_resolution_secs: u32,
+    lfu: Lfu,
+    default_ttl: Option<Duration>,
+    compression: Option<CompressionType>,
+}
+
+impl Default for PartitionSettings {
+    fn default() -> Self {
+        PartitionSettings {
+            numeric_encoding: NumericEncoding::default(),
+            track_reads: false,
+            access_time_resolution_secs: DEFAULT_ACCESS_TIME_RESOLUTION_SECS,
+            lfu: Lfu::default(),
+            default_ttl: None,
+            compression: None,
+        }
+    }
+}
+
+// Builder for a partition with non-default veifka-level settings
+pub struct PartitionBuilder<'a> {
+    data_store: &'a DataStore,
+    name: String,
+    settings: PartitionSettings,
+}
+
+impl PartitionBuilder<'_> {
+    pub fn numeric_encoding(mut self, numeric_encoding: NumericEncoding) -> Self {
+        self.settings.numeric_encoding = numeric_encoding;
+        self
+    }
+
+    // Whether reads count as accesses, refreshing the access time OBJECT
+    // IDLETIME and LRU eviction go by and the frequency counter OBJECT FREQ
+    // and LFU eviction go by. Off by default, where only writes do: recording
+    // an access is a full rewrite of the value, made on the read path, which
+    // turns reads into writes.
+    pub fn track_reads(mut self, enabled: bool) -> Self {
+        self.settings.track_reads = enabled;
+        self
+    }
+
+    // Writes always refresh a key's access time, but with `track_reads` reads
+    // only rewrite it once it is at least `secs` old. A coarser resolution
+    // keeps read-heavy keys from turning into write traffic at the cost of
+    // idle times (OBJECT IDLETIME, LRU eviction) being up to `secs` too high.
+    // 0 refreshes on every read.
+    pub fn access_time_resolution(mut self, secs: u32) -> Self {
+        self.settings.access_time_resolution_secs = secs;
+        self
+    }
+
+    // How hard it is for the access frequency counter (OBJECT FREQ) to grow:
+    // an access increments it with probability 1 / ((counter - 5) * factor + 1).
+    // Higher factors spread the 8-bit counter over more accesses. Defaults to
+    // 10, like Redis' lfu-log-factor.
+    pub fn lfu_log_factor(mut self, factor: u8) -> Self {
+        self.settings.lfu.log_factor = factor;
+        self
+    }
+
+    // Seconds a key has to go untouched for its access frequency counter to
+    // drop by one. 0 disables decay. Defaults to 60, like Redis'
+    // lfu-decay-time of one minute.
+    pub fn lfu_decay_time(mut self, secs: u32) -> Self {
+        self.settings.lfu.decay_secs = secs;
+        self
+    }
+
+    // Expiry given to keys written by `set` without an explicit deadline, for
+    // partitions used as a pure cache. Explicit deadlines override it and
+    // PERSIST still clears it per key.
+    pub fn default_ttl(mut self, ttl: Duration) -> Self {
+        self.settings.default_ttl = Some(ttl);
+        self
+    }
+
+    // Block compression of the partition and its members, fjall's (LZ4)
+    // unless set. Unlike the other settings this is stored with the
+    // partition: it only applies when `open` creates it.
+    pub fn compression(mut self, compression: CompressionType) -> Self {
+        self.settings.compression = Some(compression);
+        self
+    }
+
+    // Fails if the partition is already open with other settings
+    pub fn open(self) -> Result<DataStorePartition, DataStoreError> {
+        self.data_store
+            .partition_with(&self.name, Some(&self.settings))
+    }
+}
+
+// Hands `f` to tokio's blocking pool, or calls it right away in single-thread
+// mode, where there is no other thread to hand it to
+async fn run_blocking<T, F>(single_thread: &AtomicBool, f: F) -> Result<T, JoinError>
+where
+    T: Send + 'static,
+    F: FnOnce() -> T + Send + 'static,
+{
+    if single_thread.load(Ordering::Relaxed) {
+        return Ok(f());
+    }
+    tokio::task::spawn_blocking(f).await
+}
+
+// Drops the least recently used partition of the registry that is idle,
+// that is no one but the registry holds a handle on. Returns false if every
+// partition is in use.
+fn evict_idle(partitions: &mut HashMap<String, OpenPartition>) -> bool {
+    let idle = partitions
+        .iter()
+        .filter(|(_, open)| Arc::strong_count(&open.partition.partition_handle) == 1)
+        .min_by_key(|(_, open)| open.last_used)
+        .map(|(name, _)| name.clone());
+    match idle {
+        Some(name) => partitions.remove(&name).is_some(),
+        None => false,
+    }
+}
+
+// Name of the fjall partition holding the members of a partition's sets
+fn members_partition_name(partition_name: &str) -> String {
+    format!("{}__members", partition_name)
+}
+
+// Key of a container member in the members partition, a composite of the
+// owning key and the member (or hash field). Every member key of `key`
+// shares `member_prefix(key)`, which no other key's members start with,
+// whatever bytes the key or member contain.
+pub(crate) fn member_key(key: &[u8], member: &[u8]) -> Vec<u8> {
+    encode_composite(&[key, member])
+}
+
+pub(crate) fn member_prefix(key: &[u8]) -> Vec<u8> {
+    composite_prefix(&[key])
+}
+
 #[derive(Clone)]
 pub struct DataStorePartition {
+    name: Arc<str>,
+    keyspace: Keyspace,
     partition_handle: Arc<PartitionHandle>,
+    // Members of container values, keyed by `member_key`
+    members_handle: Arc<PartitionHandle>,
+    key_locks: Arc<KeyLocks>,
+    numeric_encoding: NumericEncoding,
+    track_reads: bool,
+    access_time_resolution_secs: u32,
+    lfu: Lfu,
+    default_ttl: Option<Duration>,
+    fsync_policy: SharedFsyncPolicy,
+    notifier: Notifier,
+    maxmemory_samples: Arc<AtomicUsize>,
+    single_thread: Arc<AtomicBool>,
+    // Set for partitions of a store opened with `open_read_only`
+    read_only: bool,
+    key_waiters: Arc<KeyWaiters>,
+    write_groups: Arc<WriteGroups>,
+    key_quota: Option<Arc<KeyQuota>>,
+    // Shared by clones, like the handles
+    stats: Arc<StatsCounters>,
 }
 
 impl DataStorePartition {
-    pub fn new(partition_handle: PartitionHandle) -> Self {
+    // A partition over handles opened by the caller, `members_handle` holding
+    // the members of its sets, hashes and lists. It gets default settings and
+    // none of the state partitions of a data store share, such as its fsync
+    // policy or keyspace notifications.
+    pub fn new(
+        keyspace: Keyspace,
+        partition_handle: PartitionHandle,
+        members_handle: PartitionHandle,
+    ) -> Self {
+        DataStorePartition::with_shared(
+            keyspace,
+            partition_handle,
+            members_handle,
+            SharedFsyncPolicy::new(FsyncPolicy::default()),
+            Notifier::new(PubSub::new()),
+            Arc::new(AtomicUsize::new(DEFAULT_MAXMEMORY_SAMPLES)),
+            Arc::default(),
+        )
+    }
+
+    fn with_shared(
+        keyspace: Keyspace,
+        partition_handle: PartitionHandle,
+        members_handle: PartitionHandle,
+        fsync_policy: SharedFsyncPolicy,
+        notifier: Notifier,
+        maxmemory_samples: Arc<AtomicUsize>,
+        single_thread: Arc<AtomicBool>,
+    ) -> Self {
         DataStorePartition {
+            name: Arc::from(&*partition_handle.name),
+            keyspace,
             partition_handle: Arc::new(partition_handle),
+            members_handle: Arc::new(members_handle),
+            key_locks: Arc::new(KeyLocks::new()),
+            numeric_encoding: NumericEncoding::default(),
+            track_reads: false,
+            access_time_resolution_secs: DEFAULT_ACCESS_TIME_RESOLUTION_SECS,
+            lfu: Lfu::default(),
+            default_ttl: None,
+            fsync_policy,
+            notifier,
+            maxmemory_samples,
+            single_thread,
+            read_only: false,
+            key_waiters: Arc::new(KeyWaiters::default()),
+            write_groups: Arc::new(WriteGroups::new(Arc::default())),
+            key_quota: None,
+            stats: Arc::new(StatsCounters::default()),
         }
     }
 
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn numeric_encoding(&self) -> NumericEncoding {
+        self.numeric_encoding
+    }
+
+    pub fn default_ttl(&self) -> Option<Duration> {
+        self.default_ttl
+    }
+
+    // Counts of gets, sets and deletes since the partition was opened
+    pub fn stats(&self) -> PartitionStats {
+        self.stats.snapshot()
+    }
+
+    // Number of keys, kept up to date for partitions of a store opened with
+    // `DataStoreBuilder::max_keys` only; None for others
+    pub fn key_count(&self) -> Option<u64> {
+        self.key_quota.as_ref().map(|quota| quota.count())
+    }
+
+    // Batches committed by group commit since the partition was opened
+    pub fn group_commits(&self) -> u64 {
+        self.write_groups.groups()
+    }
+
+    // Writes a string value, expiring after the partition's default TTL if
+    // it has one
     pub fn set(&self, key: &[u8], value: &[u8]) -> Result<(), fjall::Error> {
-        self.partition_handle.insert(key, value)
+        self.set_expiring(key, value, self.default_deadline())
+    }
+
+    // Writes a string value expiring at `deadline_ms` (unix ms), or never
+    // with None, regardless of the default TTL
+    pub fn set_expiring(
+        &self,
+        key: &[u8],
+        value: &[u8],
+        deadline_ms: Option<u64>,
+    ) -> Result<(), fjall::Error> {
+        if let Some(group_commit) = self.group_commit() {
+            let write = GroupedWrite::Set {
+                key: key.to_vec(),
+                value: value.to_vec(),
+                deadline_ms,
+            };
+            self.write_groups.write(self, write, group_commit)?;
+            return Ok(());
+        }
+        let mut stored = StoredValue::string(value);
+        stored.expires_at = deadline_ms;
+        if self.key_quota.is_some() {
+            // The quota needs to know whether the key is new
+            self.fetch_update::<_, fjall::Error, _>(key, |_| Ok((Some(stored), ())))?;
+        } else {
+            // A blind write, without reading the value it replaces. The
+            // members of a container it replaces are left behind, for
+            // `read_for_update` to drop before the key next holds one.
+            let _guard = self.key_locks.lock(key);
+            stored.accessed_at = now_secs();
+            let mut batch = self.keyspace.batch();
+            batch.insert(&self.partition_handle, key, stored.encode());
+            self.commit_batch(batch)?;
+        }
+        self.stats.record_set();
+        self.notifier.notify(notify::STRING, "set", key);
+        Ok(())
+    }
+
+    // Returns the value at `key`, first storing the value computed by `f` if
+    // the key is missing. Concurrent callers for the same key wait on its
+    // lock, so `f` runs once and they all get the value it computed.
+    pub fn get_or_insert_with<F>(&self, key: &[u8], f: F) -> Result<Vec<u8>, fjall::Error>
+    where
+        F: FnOnce() -> Vec<u8>,
+    {
+        if let Some(value) = self.get(key)? {
+            return Ok(value);
+        }
+        let deadline = self.default_deadline();
+        let (value, inserted) = self.fetch_update::<_, fjall::Error, _>(key, |current| {
+            Ok(match current {
+                // Inserted by another caller since the lookup above
+                Some(stored) => {
+                    let value = stored.payload.clone();
+                    (Some(stored), (value, false))
+                }
+                None => {
+                    let value = f();
+                    let mut stored = StoredValue::string(&value);
+                    stored.expires_at = deadline;
+                    (Some(stored), (value, true))
+                }
+            })
+        })?;
+        if inserted {
+            self.stats.record_set();
+            self.notifier.notify(notify::STRING, "set", key);
+        }
+        Ok(value)
     }
+
     pub fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, fjall::Error> {
-        self.partition_handle
-            .get(key)
-            .map(|opt| opt.map(|v| v.to_vec()))
+        let stored = self.get_stored(key)?;
+        self.stats.record_get(stored.is_some());
+        Ok(stored.map(|stored| stored.payload))
+    }
+
+    // Like `get`, but only for string keys
+    pub fn get_string(&self, key: &[u8]) -> Result<Option<Vec<u8>>, DataStoreError> {
+        let stored = self.get_stored(key)?;
+        self.stats.record_get(stored.is_some());
+        match stored {
+            Some(stored) if stored.kind != ValueKind::String => Err(DataStoreError::WrongType),
+            other => Ok(other.map(|stored| stored.payload)),
+        }
+    }
+
+    // Like `get_string`, along with the key's expiry, both taken from the
+    // one read
+    pub fn get_string_with_expiry(
+        &self,
+        key: &[u8],
+    ) -> Result<(Option<Vec<u8>>, Expiry), DataStoreError> {
+        let stored = self.get_stored(key)?;
+        self.stats.record_get(stored.is_some());
+        match stored {
+            None => Ok((None, Expiry::Missing)),
+            Some(stored) if stored.kind != ValueKind::String => Err(DataStoreError::WrongType),
+            Some(stored) => {
+                let expiry = stored.expires_at.map_or(Expiry::Persistent, Expiry::At);
+                Ok((Some(stored.payload), expiry))
+            }
+        }
+    }
+
+    pub fn key_type(&self, key: &[u8]) -> Result<Option<ValueKind>, fjall::Error> {
+        self.peek_stored(key)
+            .map(|opt| opt.map(|stored| stored.kind))
     }
 
-    pub fn delete(&self, key: &[u8]) -> Result<(), fjall::Error> {
-        self.partition_handle.remove(key)
+    // Removes a key, returning whether it existed
+    pub fn delete(&self, key: &[u8]) -> Result<bool, fjall::Error> {
+        if let Some(group_commit) = self.group_commit() {
+            let write = GroupedWrite::Delete(key.to_vec());
+            return self.write_groups.write(self, write, group_commit);
+        }
+        let existed =
+            self.fetch_update::<_, fjall::Error, _>(key, |current| Ok((None, current.is_some())))?;
+        self.stats.record_delete();
+        if existed {
+            self.notifier.notify(notify::GENERIC, "del", key);
+        }
+        Ok(existed)
     }
 
     pub fn exists(&self, key: &[u8]) -> Result<bool, fjall::Error> {
-        self.get(key).map(|opt| opt.is_some())
+        self.peek_stored(key).map(|opt| opt.is_some())
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use tempfile::TempDir;
+    // Whether each of `keys` exists, in the order given, as EXISTS checks
+    // them. Sorted keys are checked in one pass over the range they span,
+    // unless it turns out to hold more other keys than were asked for, in
+    // which case the rest are looked up one by one, as unsorted keys are.
+    // Expired keys found by the pass read as missing but are left for the
+    // next access to remove.
+    pub fn exists_many(&self, keys: &[&[u8]]) -> Result<Vec<bool>, fjall::Error> {
+        let sorted = keys.windows(2).all(|pair| pair[0] <= pair[1]);
+        let (Some(&first), Some(&last), true) = (keys.first(), keys.last(), sorted) else {
+            return keys.iter().map(|key| self.exists(key)).collect();
+        };
+        let now = now_millis();
+        let mut exists = vec![false; keys.len()];
+        // Index of the first key the pass hasn't reached yet
+        let mut next = 0;
+        let mut skipped = 0;
+        for entry in self.partition_handle.range(first..=last) {
+            let (key, bytes) = entry?;
+            while next < keys.len() && keys[next] < &*key {
+                next += 1;
+            }
+            if next < keys.len() && keys[next] == &*key {
+                let live = !StoredValue::decode(&bytes)
+                    .map_err(corrupted)?
+                    .is_expired(now);
+                while next < keys.len() && keys[next] == &*key {
+                    exists[next] = live;
+                    next += 1;
+                }
+            } else {
+                skipped += 1;
+                if skipped > keys.len() {
+                    for (exists, key) in exists.iter_mut().zip(keys).skip(next) {
+                        *exists = self.exists(key)?;
+                    }
+                    break;
+                }
+            }
+        }
+        Ok(exists)
+    }
 
-    fn create_test_store() -> (DataStore, DataStorePartition) {
-        let temp_dir = TempDir::new().expect("Failed to create temp dir");
-        let data_store = DataStore::new(temp_dir.path().to_str().unwrap()).unwrap();
-        let partition_handle = data_store.create_partition("test_partition").unwrap();
-        let partition = DataStorePartition::new(partition_handle);
-        (data_store, partition)
+    // Seconds since the key was last written or read, within the partition's
+    // access time resolution. Does not count as an access itself.
+    pub fn idle_time(&self, key: &[u8]) -> Result<Option<u64>, fjall::Error> {
+        self.peek_stored(key)
+            .map(|opt| opt.map(|stored| stored.idle_secs(now_secs())))
     }
 
-    #[test]
-    fn test_basic_operations() {
-        let (_data_store, store) = create_test_store();
+    // Logarithmic access frequency counter of the key, decayed to now. Does
+    // not count as an access itself.
+    pub fn access_frequency(&self, key: &[u8]) -> Result<Option<u8>, fjall::Error> {
+        self.peek_stored(key)
+            .map(|opt| opt.map(|stored| self.lfu.decayed(&stored, now_secs())))
+    }
 
-        // Test set and get
-        store.set(b"key1", b"value1").unwrap();
-        assert_eq!(store.get(b"key1").unwrap(), Some(b"value1".to_vec()));
+    // Approximate bytes the key takes in storage: its entry, plus for sets
+    // and hashes their members, extrapolated from the first `samples` of them
+    // (all members if 0). Does not count as an access. None if missing.
+    pub fn memory_usage(&self, key: &[u8], samples: usize) -> Result<Option<u64>, fjall::Error> {
+        let Some(stored) = self.peek_stored(key)? else {
+            return Ok(None);
+        };
+        let mut usage = ENTRY_OVERHEAD + key.len() as u64 + stored.encoded_len() as u64;
+        if stored.kind.has_members() {
+            let len = stored.container_len().map_err(corrupted)?;
+            let (mut sampled, mut sampled_bytes) = (0u64, 0u64);
+            for entry in self.members_handle.prefix(member_prefix(key)) {
+                if samples > 0 && sampled == samples as u64 {
+                    break;
+                }
+                let (member, value) = entry?;
+                sampled += 1;
+                sampled_bytes += ENTRY_OVERHEAD + member.len() as u64 + value.len() as u64;
+            }
+            usage += (sampled_bytes * len.max(sampled))
+                .checked_div(sampled)
+                .unwrap_or(0);
+        }
+        Ok(Some(usage))
+    }
 
-        // Test exists
-        assert!(store.exists(b"key1").unwrap());
-        assert!(!store.exists(b"nonexistent").unwrap());
+    // Internal representation of the value, named as OBJECT ENCODING reports
+    // it. Does not count as an access. None if missing.
+    pub fn encoding(&self, key: &[u8]) -> Result<Option<&'static str>, DataStoreError> {
+        let Some(stored) = self.peek_stored(key)? else {
+            return Ok(None);
+        };
+        Ok(Some(match stored.kind {
+            ValueKind::String if parse_canonical_integer(&stored.payload).is_some() => "int",
+            ValueKind::String if stored.payload.len() <= EMBSTR_SIZE_LIMIT => "embstr",
+            ValueKind::String => "raw",
+            ValueKind::List => "listpack",
+            ValueKind::Set if intset_members(&stored)?.is_some() => "intset",
+            ValueKind::Set | ValueKind::Hash => "hashtable",
+        }))
+    }
 
-        // Test delete
-        store.delete(b"key1").unwrap();
-        assert_eq!(store.get(b"key1").unwrap(), None);
+    // Atomically adds `delta` to the counter stored at `key` (missing keys
+    // count as 0) using the partition's numeric encoding. The key's expiry is
+    // preserved.
+    pub fn incr_by(&self, key: &[u8], delta: i64) -> Result<i64, DataStoreError> {
+        self.incr_expiring_new(key, delta, None)
+    }
+
+    // Increments like `incr_by`, and expires the key after `ttl_if_new` when
+    // this call creates it; later increments keep the deadline. Both happen
+    // under the key lock, so unlike INCR then EXPIRE no counter can be left
+    // without a TTL, as rate limiters over a fixed window need.
+    pub fn incr_with_ttl(
+        &self,
+        key: &[u8],
+        delta: i64,
+        ttl_if_new: Duration,
+    ) -> Result<i64, DataStoreError> {
+        let deadline = now_millis().saturating_add(ttl_if_new.as_millis() as u64);
+        self.incr_expiring_new(key, delta, Some(deadline))
+    }
+
+    fn incr_expiring_new(
+        &self,
+        key: &[u8],
+        delta: i64,
+        deadline_if_new: Option<u64>,
+    ) -> Result<i64, DataStoreError> {
+        let encoding = self.numeric_encoding;
+        self.fetch_update(key, |current| {
+            let (expires_at, value) = match current {
+                Some(stored) if stored.kind != ValueKind::String => {
+                    return Err(DataStoreError::WrongType)
+                }
+                Some(stored) => (stored.expires_at, encoding.decode(&stored.payload)?),
+                None => (deadline_if_new, 0),
+            };
+            let updated = value.checked_add(delta).ok_or(DataStoreError::Overflow)?;
+            let mut stored = StoredValue::string(&encoding.encode(updated));
+            stored.expires_at = expires_at;
+            Ok((Some(stored), updated))
+        })
+    }
+
+    // Sets an absolute expiry deadline (unix ms) on an existing key. Returns
+    // false if the key does not exist. A deadline in the past deletes the key.
+    pub fn expire_at(&self, key: &[u8], deadline_ms: u64) -> Result<bool, fjall::Error> {
+        let event = self.fetch_update::<_, fjall::Error, _>(key, |current| {
+            Ok(match current {
+                None => (None, None),
+                Some(_) if deadline_ms <= now_millis() => (None, Some("del")),
+                Some(mut stored) => {
+                    stored.expires_at = Some(deadline_ms);
+                    (Some(stored), Some("expire"))
+                }
+            })
+        })?;
+        if let Some(event) = event {
+            self.notifier.notify(notify::GENERIC, event, key);
+        }
+        Ok(event.is_some())
+    }
+
+    // Removes the expiry of a key. Returns true only if an expiry was removed.
+    pub fn clear_expiry(&self, key: &[u8]) -> Result<bool, fjall::Error> {
+        self.fetch_update(key, |current| {
+            Ok(match current {
+                Some(mut stored) if stored.expires_at.is_some() => {
+                    stored.expires_at = None;
+                    (Some(stored), true)
+                }
+                other => (other, false),
+            })
+        })
+    }
+
+    // Atomically exchanges the values of two keys, expiry and members
+    // included. A missing key swaps too, leaving the other key absent.
+    pub fn swap(&self, key1: &[u8], key2: &[u8]) -> Result<(), fjall::Error> {
+        if key1 == key2 {
+            return Ok(());
+        }
+        let _guards = self.key_locks.lock_pair(key1, key2);
+        let value1 = self.read_for_update(key1)?;
+        let value2 = self.read_for_update(key2)?;
+        if value1.is_none() && value2.is_none() {
+            return Ok(());
+        }
+        let members1 = self.members_of(key1, value1.as_ref())?;
+        let members2 = self.members_of(key2, value2.as_ref())?;
+
+        let mut batch = self.keyspace.batch();
+        // Each key takes the other's members. Removing a member that is also
+        // inserted would put both in one batch, so only drop the rest.
+        for (key, old, new) in [(key1, &members1, &members2), (key2, &members2, &members1)] {
+            for (member, _) in old {
+                if !new.iter().any(|(other, _)| other == member) {
+                    batch.remove(&self.members_handle, member_key(key, member));
+                }
+            }
+            for (member, value) in new {
+                batch.insert(&self.members_handle, member_key(key, member), value);
+            }
+        }
+        // Values move as they are, metadata included, so not through
+        // `commit_update`
+        for (key, value) in [(key1, &value2), (key2, &value1)] {
+            match value {
+                Some(stored) => batch.insert(&self.partition_handle, key, stored.encode()),
+                None => batch.remove(&self.partition_handle, key),
+            }
+        }
+        self.commit_batch(batch)?;
+
+        for (key, value) in [(key1, &value2), (key2, &value1)] {
+            self.notifier.notify(notify::GENERIC, "swap", key);
+            if value.is_some() {
+                self.key_waiters.wake(key);
+            }
+        }
+        Ok(())
+    }
+
+    // Writes string values and deletes keys in one atomic batch, holding the
+    // locks of every key involved. The last write to a key wins.
+    pub fn write_batch(&self, ops: &[BatchOp]) -> Result<(), fjall::Error> {
+        let mut last: BTreeMap<&[u8], Option<&[u8]>> = BTreeMap::new();
+        for op in ops {
+            let value = match op {
+                BatchOp::Set(_, value) => Some(value.as_slice()),
+                BatchOp::Delete(_) => None,
+            };
+            last.insert(op.key(), value);
+        }
+        self.write_final_values(last, false).map(|_| ())
+    }
+
+    // Sets every key to its value in one atomic batch, but only if none of
+    // the keys exists, as MSETNX. Returns whether the values were written.
+    pub fn multi_set_nx(&self, pairs: &[(&[u8], &[u8])]) -> Result<bool, fjall::Error> {
+        let last = pairs
+            .iter()
+            .map(|&(key, value)| (key, Some(value)))
+            .collect();
+        self.write_final_values(last, true)
+    }
+
+    // Writes the final value (None deletes) of each key in one batch while
+    // holding all their locks. With `only_if_absent` nothing is written if any
+    // of the keys exists, and false is returned.
+    fn write_final_values(
+        &self,
+        last: BTreeMap<&[u8], Option<&[u8]>>,
+        only_if_absent: bool,
+    ) -> Result<bool, fjall::Error> {
+        let _guards = self.key_locks.lock_many(last.keys().copied());
+        let mut previous = Vec::with_capacity(last.len());
+        for &key in last.keys() {
+            previous.push(self.read_for_update(key)?);
+        }
+        if only_if_absent && previous.iter().any(Option::is_some) {
+            return Ok(false);
+        }
+
+        let deadline = self.default_deadline();
+        let mut batch = self.keyspace.batch();
+        let mut written = Vec::with_capacity(last.len());
+        let mut added = 0;
+        for ((key, value), previous) in last.into_iter().zip(previous) {
+            let updated = value.map(|value| {
+                let mut stored = StoredValue::string(value);
+                stored.expires_at = deadline;
+                stored
+            });
+            added += self.queue_update(&mut batch, key, previous.as_ref(), updated.as_ref())?;
+            written.push((key, value.is_some(), previous.is_some()));
+        }
+        self.commit_counted(batch, added)?;
+
+        for (key, set, existed) in written {
+            if set {
+                self.stats.record_set();
+                self.notifier.notify(notify::STRING, "set", key);
+            } else {
+                self.stats.record_delete();
+                if existed {
+                    self.notifier.notify(notify::GENERIC, "del", key);
+                }
+            }
+        }
+        Ok(true)
+    }
+
+    // The members of a container value, keyed without the key's prefix
+    pub(crate) fn members_of(
+        &self,
+        key: &[u8],
+        stored: Option<&StoredValue>,
+    ) -> Result<Vec<KeyValue>, fjall::Error> {
+        let mut members = Vec::new();
+        if stored.is_some_and(|stored| stored.kind.has_members()) {
+            let prefix = member_prefix(key);
+            for entry in self.members_handle.prefix(&prefix) {
+                let (member, value) = entry?;
+                members.push((member[prefix.len()..].to_vec(), value.to_vec()));
+            }
+        }
+        Ok(members)
+    }
+
+    // Reads the stored expiry deadline of a key
+    pub fn expire_time(&self, key: &[u8]) -> Result<Expiry, fjall::Error> {
+        Ok(match self.peek_stored(key)? {
+            None => Expiry::Missing,
+            Some(StoredValue {
+                expires_at: None, ..
+            }) => Expiry::Persistent,
+            Some(StoredValue {
+                expires_at: Some(deadline),
+                ..
+            }) => Expiry::At(deadline),
+        })
+    }
+
+    // Returns all live string entries whose key starts with `prefix`, in key order
+    pub fn scan_prefix(&self, prefix: &[u8]) -> Result<Vec<KeyValue>, fjall::Error> {
+        let now = now_millis();
+        let mut entries = Vec::new();
+        for entry in self.partition_handle.prefix(prefix) {
+            let (key, bytes) = entry?;
+            let stored = StoredValue::decode(&bytes).map_err(corrupted)?;
+            if stored.kind == ValueKind::String && !stored.is_expired(now) {
+                entries.push((key.to_vec(), stored.payload));
+            }
+        }
+        Ok(entries)
+    }
+
+    // Counts the live keys starting with `prefix`, of any type, reading only
+    // the header of each value. O(N) in the number of keys under the prefix.
+    pub fn count_prefix(&self, prefix: &[u8]) -> Result<usize, fjall::Error> {
+        let now = now_millis();
+        let mut count = 0;
+        for entry in self.partition_handle.prefix(prefix) {
+            let (_, bytes) = entry?;
+            match StoredValue::peek_expires_at(&bytes).map_err(corrupted)? {
+                Some(deadline) if deadline <= now => {}
+                _ => count += 1,
+            }
+        }
+        Ok(count)
+    }
+
+    // Reads every entry (optionally only those under `prefix`) so their blocks
+    // land in fjall's block cache. Returns the number of keys touched.
+    pub fn warm_up(&self, prefix: Option<&[u8]>) -> Result<u64, fjall::Error> {
+        let mut touched = 0;
+        match prefix {
+            Some(prefix) => {
+                for entry in self.partition_handle.prefix(prefix) {
+                    entry?;
+                    touched += 1;
+                }
+            }
+            None => {
+                for entry in self.partition_handle.iter() {
+                    entry?;
+                    touched += 1;
+                }
+            }
+        }
+        Ok(touched)
+    }
+
+    // Counts the live keys and how many of them expire. This scans the whole
+    // partition, so it is O(N) in the number of keys; expired keys that were
+    // not purged yet are skipped.
+    pub fn keyspace_stats(&self) -> Result<KeyspaceStats, fjall::Error> {
+        let now = now_millis();
+        let mut stats = KeyspaceStats::default();
+        for entry in self.partition_handle.iter() {
+            let (_, bytes) = entry?;
+            let stored = StoredValue::decode(&bytes).map_err(corrupted)?;
+            if stored.is_expired(now) {
+                continue;
+            }
+            stats.keys += 1;
+            if stored.expires_at.is_some() {
+                stats.expires += 1;
+            }
+        }
+        Ok(stats)
+    }
+
+    // Reads every entry of the partition, members included, and decodes each
+    // stored value down to its payload, reporting the keys that fail to. fjall
+    // checks block checksums as it reads, so this catches what the LSM tree
+    // itself doesn't: values that were written or read back malformed, e.g.
+    // after a crash or a disk issue. O(N) in the number of entries.
+    pub fn verify(&self) -> Result<VerifyReport, DataStoreError> {
+        let mut report = VerifyReport::default();
+        for entry in self.partition_handle.iter() {
+            let (key, bytes) = entry?;
+            report.entries += 1;
+            report.bytes += (key.len() + bytes.len()) as u64;
+            if check_value(&bytes).is_err() {
+                report.corrupted.push(key.to_vec());
+            }
+        }
+        // Member values are raw user bytes, with nothing to decode, but
+        // their keys must be composites
+        for entry in self.members_handle.iter() {
+            let (key, value) = entry?;
+            report.entries += 1;
+            report.bytes += (key.len() + value.len()) as u64;
+            if decode_composite(&key, 2).is_none() {
+                report.corrupted.push(key.to_vec());
+            }
+        }
+        Ok(report)
+    }
+
+    // Syncs the journal to disk and seals the partition's memtables, so they
+    // get flushed into segments that later reads are served from. The handles
+    // themselves stay open, as every connection shares them.
+    pub fn reload(&self) -> Result<(), DataStoreError> {
+        self.keyspace.persist(PersistMode::SyncAll)?;
+        self.partition_handle.rotate_memtable()?;
+        self.members_handle.rotate_memtable()?;
+        Ok(())
+    }
+
+    // Makes the partition's writes so far durable. fjall keeps one journal
+    // for the whole keyspace, so this syncs the pending writes of every other
+    // partition along and costs as much as `DataStore::persist`; it spares
+    // code that only holds the partition from needing the store.
+    pub fn flush(&self) -> Result<(), fjall::Error> {
+        self.keyspace.persist(PersistMode::SyncAll)
+    }
+
+    // Runs a major compaction of the partition and its members, merging all
+    // segments and dropping overwritten values and tombstones. Blocks until
+    // done. Memtables are not included; `reload` seals them first.
+    pub fn compact(&self) -> Result<(), fjall::Error> {
+        if self.read_only {
+            return Err(read_only());
+        }
+        // A major compaction drops the tombstones it merges into the last
+        // level, but only drops the values they shadow below fjall's GC
+        // watermark, which moves up once per round of freed snapshots and
+        // stays at zero until then. Freeing a round taken now raises it to
+        // now, or to the oldest snapshot still open, so no deleted value
+        // comes back.
+        for _ in 0..SNAPSHOT_GC_ROUND {
+            drop(self.partition_handle.snapshot());
+        }
+        self.partition_handle.major_compact()?;
+        self.members_handle.major_compact()
+    }
+
+    // Bytes on disk used by the partition's segments, members included
+    pub fn disk_space(&self) -> u64 {
+        self.partition_handle.disk_space() + self.members_handle.disk_space()
+    }
+
+    // Number of on-disk segments, members included
+    pub fn segment_count(&self) -> usize {
+        self.partition_handle.segment_count() + self.members_handle.segment_count()
+    }
+
+    // Read-modify-write of a single key under its lock. `f` receives the live
+    // value (None if missing or expired) and returns the value to store (None
+    // deletes the key) together with the operation's result. Leaving the value
+    // untouched is done by handing `current` back.
+    pub(crate) fn fetch_update<T, E, F>(&self, key: &[u8], f: F) -> Result<T, E>
+    where
+        E: From<fjall::Error>,
+        F: FnOnce(Option<StoredValue>) -> Result<(Option<StoredValue>, T), E>,
+    {
+        let _guard = self.key_locks.lock(key);
+        let current = self.read_for_update(key)?;
+        let (updated, result) = f(current.clone())?;
+        if updated != current {
+            let batch = self.keyspace.batch();
+            self.commit_update(batch, key, current.as_ref(), updated.as_ref())?;
+        }
+        Ok(result)
+    }
+
+    // Expiry deadline of a key written now without an explicit one
+    fn default_deadline(&self) -> Option<u64> {
+        self.default_ttl
+            .map(|ttl| now_millis().saturating_add(ttl.as_millis() as u64))
+    }
+
+    // A live key at a random position, None if the partition is empty. Keys
+    // are picked by seeking to a random byte string, so keys after large gaps
+    // in the key space come up more often than others.
+    pub fn random_key(&self) -> Result<Option<Vec<u8>>, fjall::Error> {
+        Ok(self.sample_keys(1)?.pop())
+    }
+
+    // Up to `samples` live keys following a random position, wrapping around
+    // to the start of the partition. One seek rather than one per sample.
+    pub(crate) fn sample_keys(&self, samples: usize) -> Result<Vec<Vec<u8>>, fjall::Error> {
+        let probe = rand::random::<u64>().to_be_bytes().to_vec();
+        let now = now_millis();
+        let mut keys = Vec::new();
+        let after = self.partition_handle.range(probe.clone()..);
+        let before = self.partition_handle.range(..probe);
+        for entry in after.chain(before) {
+            if keys.len() >= samples {
+                break;
+            }
+            let (key, value) = entry?;
+            if !StoredValue::decode(&value)
+                .map_err(corrupted)?
+                .is_expired(now)
+            {
+                keys.push(key.to_vec());
+            }
+        }
+        Ok(keys)
+    }
+
+    pub(crate) fn maxmemory_samples(&self) -> usize {
+        self.maxmemory_samples.load(Ordering::Relaxed)
+    }
+
+    pub(crate) async fn run_blocking<T, F>(&self, f: F) -> Result<T, JoinError>
+    where
+        T: Send + 'static,
+        F: FnOnce() -> T + Send + 'static,
+    {
+        run_blocking(&self.single_thread, f).await
+    }
+
+    pub(crate) fn lock_key(&self, key: &[u8]) -> MutexGuard<'_, ()> {
+        self.key_locks.lock(key)
+    }
+
+    // The locks of all `keys` at once, for writes that span several keys
+    pub(crate) fn lock_keys<'a>(
+        &self,
+        keys: impl IntoIterator<Item = &'a [u8]>,
+    ) -> Vec<MutexGuard<'_, ()>> {
+        self.key_locks.lock_many(keys)
+    }
+
+    // Returns the live value of a key, first removing an expired value along
+    // with its members, and for a key that isn't live, the members a blind
+    // SET over a container left behind. Must be called while holding the key
+    // lock.
+    pub(crate) fn read_for_update(&self, key: &[u8]) -> Result<Option<StoredValue>, fjall::Error> {
+        let expired = match self.decode_at(key)? {
+            Some(stored) if !stored.is_expired(now_millis()) => return Ok(Some(stored)),
+            other => other,
+        };
+        // Left for a writable store to remove
+        if self.read_only {
+            return Ok(None);
+        }
+        if let Some(stored) = expired {
+            let batch = self.keyspace.batch();
+            self.commit_update(batch, key, Some(&stored), None)?;
+            // Keys are only expired lazily, so this fires when the key is
+            // next touched rather than right at its deadline
+            self.notifier.notify(notify::EXPIRED, "expired", key);
+            if stored.kind.has_members() {
+                return Ok(None);
+            }
+        }
+        self.drop_stale_members(key)?;
+        Ok(None)
+    }
+
+    // Removes members of `key` that no container owns, as a blind SET over a
+    // container leaves them
+    fn drop_stale_members(&self, key: &[u8]) -> Result<(), fjall::Error> {
+        let mut batch = self.keyspace.batch();
+        for entry in self.members_handle.prefix(member_prefix(key)) {
+            let (member, _) = entry?;
+            batch.remove(&self.members_handle, member);
+        }
+        self.commit_batch(batch)
+    }
+
+    // Replaces `previous` (the live value read under the key lock) by
+    // `updated` in one batch, together with whatever member writes the caller
+    // already queued. Members of a previous container are dropped unless the
+    // key still holds a container of the same kind.
+    pub(crate) fn commit_update(
+        &self,
+        mut batch: Batch,
+        key: &[u8],
+        previous: Option<&StoredValue>,
+        updated: Option<&StoredValue>,
+    ) -> Result<(), fjall::Error> {
+        let added = self.queue_update(&mut batch, key, previous, updated)?;
+        self.commit_counted(batch, added)
+    }
+
+    // The writes of `commit_update`, queued on `batch` without committing it.
+    // Returns the change in the number of keys they make.
+    fn queue_update(
+        &self,
+        batch: &mut Batch,
+        key: &[u8],
+        previous: Option<&StoredValue>,
+        updated: Option<&StoredValue>,
+    ) -> Result<i64, fjall::Error> {
+        if let Some(previous) = previous {
+            let same_kind = updated.is_some_and(|updated| updated.kind == previous.kind);
+            if previous.kind.has_members() && !same_kind {
+                for entry in self.members_handle.prefix(member_prefix(key)) {
+                    let (member, _) = entry?;
+                    batch.remove(&self.members_handle, member);
+                }
+            }
+        }
+        match updated {
+            Some(stored) => {
+                let mut stored = stored.clone();
+                let now = now_secs();
+                // A write counts as an access, and overwrites keep the key's
+                // access frequency
+                if let Some(previous) = previous {
+                    stored.freq = self.lfu.access(previous, now);
+                }
+                stored.accessed_at = now;
+                batch.insert(&self.partition_handle, key, stored.encode())
+            }
+            // Avoid writing a tombstone for a key that was already absent
+            None if previous.is_none() => {}
+            None => batch.remove(&self.partition_handle, key),
+        }
+        Ok(i64::from(updated.is_some()) - i64::from(previous.is_some()))
+    }
+
+    // Commits a batch adding `added` keys (removing, if negative), keeping
+    // the key count of a partition under quota
+    fn commit_counted(&self, batch: Batch, added: i64) -> Result<(), fjall::Error> {
+        let Some(quota) = &self.key_quota else {
+            return self.commit_batch(batch);
+        };
+        let reserved = added.max(0) as u64;
+        quota.reserve(reserved)?;
+        if let Err(e) = self.commit_batch(batch) {
+            quota.release(reserved);
+            return Err(e);
+        }
+        quota.release(added.min(0).unsigned_abs());
+        Ok(())
+    }
+
+    // The group commit settings if writes are to be grouped. Inline storage
+    // calls would hold up the only thread for the whole window, so
+    // single-thread mode never groups.
+    fn group_commit(&self) -> Option<GroupCommit> {
+        let group_commit = self.write_groups.config();
+        (group_commit.enabled() && !self.single_thread.load(Ordering::Relaxed))
+            .then_some(group_commit)
+    }
+
+    // Commits the writes of a group (see `group_commit`) in one batch while
+    // holding the locks of every key involved. Writes to the same key apply
+    // in order. Returns, for each write, whether its key existed before it,
+    // or None if the write was dropped for adding a key past the quota.
+    pub(crate) fn commit_group(
+        &self,
+        writes: &[GroupedWrite],
+    ) -> Result<Vec<Option<bool>>, fjall::Error> {
+        let _guards = self
+            .key_locks
+            .lock_many(writes.iter().map(GroupedWrite::key));
+        let mut previous = BTreeMap::new();
+        for write in writes {
+            if !previous.contains_key(write.key()) {
+                previous.insert(write.key(), self.read_for_update(write.key())?);
+            }
+        }
+
+        let mut current = previous.clone();
+        // Keys absent before the group that it took room for under the quota
+        let mut reserved = BTreeSet::new();
+        let mut existed = Vec::with_capacity(writes.len());
+        for write in writes {
+            let key = write.key();
+            let updated = match write {
+                GroupedWrite::Set {
+                    value, deadline_ms, ..
+                } => {
+                    if let Some(quota) = &self.key_quota {
+                        if previous[key].is_none() && !reserved.contains(key) {
+                            if quota.reserve(1).is_err() {
+                                existed.push(None);
+                                continue;
+                            }
+                            reserved.insert(key);
+                        }
+                    }
+                    let mut stored = StoredValue::string(value);
+                    stored.expires_at = *deadline_ms;
+                    Some(stored)
+                }
+                GroupedWrite::Delete(_) => None,
+            };
+            let value = current.get_mut(key).expect("read above");
+            existed.push(Some(value.is_some()));
+            *value = updated;
+        }
+        let committed = self.commit_final_values(&previous, &current);
+        if let Some(quota) = &self.key_quota {
+            match &committed {
+                // Room goes back for keys the group deleted again and for
+                // the keys it removed
+                Ok((added, removed)) => quota.release(reserved.len() as u64 - added + removed),
+                Err(_) => quota.release(reserved.len() as u64),
+            }
+        }
+        committed?;
+
+        for (write, existed) in writes.iter().zip(&existed) {
+            match (write, existed) {
+                (_, None) => {}
+                (GroupedWrite::Set { key, .. }, Some(_)) => {
+                    self.stats.record_set();
+                    self.notifier.notify(notify::STRING, "set", key);
+                }
+                (GroupedWrite::Delete(key), Some(existed)) => {
+                    self.stats.record_delete();
+                    if *existed {
+                        self.notifier.notify(notify::GENERIC, "del", key);
+                    }
+                }
+            }
+        }
+        Ok(existed)
+    }
+
+    // Replaces the `previous` value of every key by its `current` one in one
+    // batch, returning how many keys that added and removed. Leaves the key
+    // count to the caller.
+    fn commit_final_values(
+        &self,
+        previous: &BTreeMap<&[u8], Option<StoredValue>>,
+        current: &BTreeMap<&[u8], Option<StoredValue>>,
+    ) -> Result<(u64, u64), fjall::Error> {
+        let mut batch = self.keyspace.batch();
+        let (mut added, mut removed) = (0, 0);
+        for (key, updated) in current {
+            match self.queue_update(&mut batch, key, previous[key].as_ref(), updated.as_ref())? {
+                1 => added += 1,
+                -1 => removed += 1,
+                _ => {}
+            }
+        }
+        self.commit_batch(batch)?;
+        Ok((added, removed))
+    }
+
+    // Commits a batch, syncing it to disk under the always fsync policy
+    fn commit_batch(&self, batch: Batch) -> Result<(), fjall::Error> {
+        if self.read_only {
+            return Err(read_only());
+        }
+        if batch.is_empty() {
+            return Ok(());
+        }
+        batch.commit()?;
+        if self.fsync_policy.get() == FsyncPolicy::Always {
+            self.keyspace.persist(PersistMode::SyncAll)?;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn batch(&self) -> Batch {
+        self.keyspace.batch()
+    }
+
+    pub(crate) fn partition_handle(&self) -> &PartitionHandle {
+        &self.partition_handle
+    }
+
+    pub(crate) fn members_handle(&self) -> &PartitionHandle {
+        &self.members_handle
+    }
+
+    pub(crate) fn notifier(&self) -> &Notifier {
+        &self.notifier
+    }
+
+    pub(crate) fn key_waiters(&self) -> &Arc<KeyWaiters> {
+        &self.key_waiters
+    }
+
+    fn decode_at(&self, key: &[u8]) -> Result<Option<StoredValue>, fjall::Error> {
+        match self.partition_handle.get(key)? {
+            Some(bytes) => StoredValue::decode(&bytes).map(Some).map_err(corrupted),
+            None => Ok(None),
+        }
+    }
+
+    // Reads the live value of a key, as an access on partitions tracking
+    // reads. The access is only written back when it changes the frequency
+    // counter or the access time is older than the partition's resolution.
+    pub(crate) fn get_stored(&self, key: &[u8]) -> Result<Option<StoredValue>, fjall::Error> {
+        let stored = self.peek_stored(key)?;
+        if !self.track_reads || self.read_only {
+            return Ok(stored);
+        }
+        if let Some(stored) = &stored {
+            let now = now_secs();
+            let decayed = self.lfu.decayed(stored, now);
+            let freq = self.lfu.increment(decayed);
+            // Decay is relative to the access time, so applying it also
+            // requires moving the access time forward
+            let refresh_time = decayed != stored.freq
+                || stored.idle_secs(now) >= self.access_time_resolution_secs as u64;
+            if refresh_time || freq != stored.freq {
+                self.touch(key, freq, refresh_time)?;
+            }
+        }
+        Ok(stored)
+    }
+
+    // Records a read access. Bypasses `commit_update`, which would count the
+    // access a second time.
+    fn touch(&self, key: &[u8], freq: u8, refresh_time: bool) -> Result<(), fjall::Error> {
+        let _guard = self.key_locks.lock(key);
+        if let Some(mut current) = self.read_for_update(key)? {
+            current.freq = freq;
+            if refresh_time {
+                current.accessed_at = now_secs();
+            }
+            self.partition_handle.insert(key, current.encode())?;
+        }
+        Ok(())
+    }
+
+    // Reads the live value of a key without counting as an access, lazily
+    // removing it if its expiry has passed
+    pub(crate) fn peek_stored(&self, key: &[u8]) -> Result<Option<StoredValue>, fjall::Error> {
+        match self.decode_at(key)? {
+            Some(stored) if stored.is_expired(now_millis()) => {
+                // Re-check under the lock so a concurrent write isn't removed
+                let _guard = self.key_locks.lock(key);
+                self.read_for_update(key)?;
+                Ok(None)
+            }
+            other => Ok(other),
+        }
+    }
+}
+
+// Decodes a stored value and the structure of its payload
+fn check_value(bytes: &[u8]) -> Result<(), DataStoreError> {
+    let stored = StoredValue::decode(bytes)?;
+    match stored.kind {
+        ValueKind::String => {}
+        ValueKind::List => {
+            decode_items(&stored.payload)?;
+        }
+        ValueKind::Set | ValueKind::Hash => {
+            stored.container_len()?;
+            intset_members(&stored)?;
+        }
+    }
+    Ok(())
+}
+
+fn read_only() -> fjall::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::PermissionDenied,
+        "data store is opened read-only",
+    )
+    .into()
+}
+
+// Surfaces an undecodable stored value through fjall's error type
+pub(crate) fn corrupted(e: DataStoreError) -> fjall::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, e).into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lfu::{DEFAULT_LFU_DECAY_SECS, LFU_INIT_VAL};
+    use tempfile::TempDir;
+
+    fn create_test_store() -> (DataStore, DataStorePartition) {
+        let temp_dir = TempDir::new().expect("Failed to create temp dir");
+        let data_store = DataStore::new(temp_dir.path().to_str().unwrap()).unwrap();
+        let partition = data_store.partition("test_partition").unwrap();
+        (data_store, partition)
+    }
+
+    #[test]
+    fn test_basic_operations() {
+        let (_data_store, store) = create_test_store();
+
+        // Test set and get
+        store.set(b"key1", b"value1").unwrap();
+        assert_eq!(store.get(b"key1").unwrap(), Some(b"value1".to_vec()));
+
+        // Test exists
+        assert!(store.exists(b"key1").unwrap());
+        assert!(!store.exists(b"nonexistent").unwrap());
+
+        // Test delete
+        store.delete(b"key1").unwrap();
+        assert_eq!(store.get(b"key1").unwrap(), None);
+    }
+
+    #[test]
+    fn test_exists_many() {
+        let temp_dir = TempDir::new().unwrap();
+        let data_store = DataStore::new(temp_dir.path().to_str().unwrap()).unwrap();
+        let store = data_store.partition("test_partition").unwrap();
+        for key in [&b"b"[..], b"d", b"f"] {
+            store.set(key, b"value").unwrap();
+        }
+        store.set_expiring(b"e", b"value", Some(1)).unwrap();
+
+        let sorted: [&[u8]; 7] = [b"a", b"b", b"b", b"c", b"d", b"e", b"f"];
+        assert_eq!(
+            store.exists_many(&sorted).unwrap(),
+            [false, true, true, false, true, false, true]
+        );
+        let unsorted: [&[u8]; 5] = [b"f", b"a", b"d", b"e", b"b"];
+        assert_eq!(
+            store.exists_many(&unsorted).unwrap(),
+            [true, false, true, false, true]
+        );
+        assert!(store.exists_many(&[]).unwrap().is_empty());
+
+        // Keys spread thin over the partition are looked up one by one
+        for i in 0..100 {
+            store
+                .set(format!("m{:03}", i).as_bytes(), b"value")
+                .unwrap();
+        }
+        let sparse: [&[u8]; 4] = [b"a", b"f", b"m000x", b"z"];
+        assert_eq!(
+            store.exists_many(&sparse).unwrap(),
+            [false, true, false, false]
+        );
+    }
+
+    #[test]
+    fn test_expire_time() {
+        let (_data_store, store) = create_test_store();
+
+        assert_eq!(store.expire_time(b"missing").unwrap(), Expiry::Missing);
+
+        store.set(b"plain", b"value").unwrap();
+        assert_eq!(store.expire_time(b"plain").unwrap(), Expiry::Persistent);
+
+        let deadline = now_millis() + 60_000;
+        store.set(b"volatile", b"value").unwrap();
+        assert!(store.expire_at(b"volatile", deadline).unwrap());
+        assert_eq!(
+            store.expire_time(b"volatile").unwrap(),
+            Expiry::At(deadline)
+        );
+
+        // Overwriting with SET drops the expiry
+        store.set(b"volatile", b"other").unwrap();
+        assert_eq!(store.expire_time(b"volatile").unwrap(), Expiry::Persistent);
+
+        // A deadline in the past removes the key
+        assert!(store.expire_at(b"plain", now_millis() - 1).unwrap());
+        assert_eq!(store.get(b"plain").unwrap(), None);
+        assert_eq!(store.expire_time(b"plain").unwrap(), Expiry::Missing);
+    }
+
+    #[test]
+    fn test_warm_up() {
+        let (_data_store, store) = create_test_store();
+
+        for i in 0..10 {
+            store
+                .set(format!("user:{}", i).as_bytes(), b"value")
+                .unwrap();
+        }
+        for i in 0..5 {
+            store
+                .set(format!("session:{}", i).as_bytes(), b"value")
+                .unwrap();
+        }
+
+        assert_eq!(store.warm_up(None).unwrap(), 15);
+        assert_eq!(store.warm_up(Some(b"user:")).unwrap(), 10);
+        assert_eq!(store.warm_up(Some(b"session:")).unwrap(), 5);
+        assert_eq!(store.warm_up(Some(b"missing:")).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_incr_by() {
+        let (_data_store, store) = create_test_store();
+
+        assert_eq!(store.incr_by(b"counter", 5).unwrap(), 5);
+        assert_eq!(store.incr_by(b"counter", -7).unwrap(), -2);
+        assert_eq!(store.get(b"counter").unwrap(), Some(b"-2".to_vec()));
+
+        store.set(b"text", b"abc").unwrap();
+        assert!(matches!(
+            store.incr_by(b"text", 1),
+            Err(DataStoreError::NotAnInteger)
+        ));
+
+        store.set(b"max", i64::MAX.to_string().as_bytes()).unwrap();
+        assert!(matches!(
+            store.incr_by(b"max", 1),
+            Err(DataStoreError::Overflow)
+        ));
+    }
+
+    #[test]
+    fn test_incr_with_ttl_rate_limit() {
+        let (_data_store, store) = create_test_store();
+        let window = Duration::from_millis(200);
+        let allowed =
+            |store: &DataStorePartition| store.incr_with_ttl(b"requests", 1, window).unwrap() <= 3;
+
+        assert!(allowed(&store));
+        let Expiry::At(deadline) = store.expire_time(b"requests").unwrap() else {
+            panic!("the first increment sets the TTL");
+        };
+        assert!(allowed(&store));
+        assert!(allowed(&store));
+        assert!(!allowed(&store));
+        // Later increments don't push the window back
+        assert_eq!(
+            store.expire_time(b"requests").unwrap(),
+            Expiry::At(deadline)
+        );
+
+        std::thread::sleep(window + Duration::from_millis(50));
+        assert!(allowed(&store));
+        assert_eq!(store.get(b"requests").unwrap(), Some(b"1".to_vec()));
+    }
+
+    #[test]
+    fn test_big_endian_counters() {
+        let temp_dir = TempDir::new().expect("Failed to create temp dir");
+        let data_store = DataStore::new(temp_dir.path().to_str().unwrap()).unwrap();
+        let store = data_store
+            .partition_builder("counters")
+            .numeric_encoding(NumericEncoding::BigEndianI64)
+            .open()
+            .unwrap();
+
+        assert_eq!(store.incr_by(b"hits", 1).unwrap(), 1);
+        assert_eq!(store.incr_by(b"hits", 41).unwrap(), 42);
+        assert_eq!(
+            store.get(b"hits").unwrap(),
+            Some(42i64.to_be_bytes().to_vec())
+        );
+
+        // ASCII digits are the wrong width for this encoding
+        store.set(b"ascii", b"42").unwrap();
+        assert!(matches!(
+            store.incr_by(b"ascii", 1),
+            Err(DataStoreError::NotAnInteger)
+        ));
+    }
+
+    #[test]
+    fn test_path() {
+        let temp_dir = TempDir::new().expect("Failed to create temp dir");
+        let path = temp_dir.path().to_str().unwrap();
+        let data_store = DataStore::new(path).unwrap();
+        assert_eq!(data_store.path(), temp_dir.path());
+
+        let partition = data_store.partition("named").unwrap();
+        assert_eq!(partition.name(), "named");
+    }
+
+    #[test]
+    fn test_idle_time() {
+        let (data_store, _) = create_test_store();
+        let store = data_store
+            .partition_builder("tracked")
+            .track_reads(true)
+            .open()
+            .unwrap();
+        store.set(b"key", b"value").unwrap();
+        assert!(store.idle_time(b"key").unwrap().unwrap() <= 1);
+        assert_eq!(store.idle_time(b"missing").unwrap(), None);
+
+        // Pretend the key was last touched a while ago
+        let age_key = |secs: u32| {
+            let mut stored = store.peek_stored(b"key").unwrap().unwrap();
+            stored.accessed_at = now_secs() - secs;
+            store
+                .partition_handle
+                .insert(b"key", stored.encode())
+                .unwrap();
+        };
+        age_key(30);
+        let idle = store.idle_time(b"key").unwrap().unwrap();
+        assert!((30..=31).contains(&idle));
+        // Neither IDLETIME nor EXISTS count as an access
+        assert!(store.exists(b"key").unwrap());
+        assert!(store.idle_time(b"key").unwrap().unwrap() >= 30);
+
+        // Reads within the resolution leave the access time alone
+        store.get(b"key").unwrap();
+        assert!(store.idle_time(b"key").unwrap().unwrap() >= 30);
+
+        // Older than the resolution: the read refreshes it
+        age_key(DEFAULT_ACCESS_TIME_RESOLUTION_SECS + 10);
+        store.get(b"key").unwrap();
+        assert!(store.idle_time(b"key").unwrap().unwrap() <= 1);
+
+        // Without read tracking only writes refresh it
+        let untracked = data_store.partition("untracked").unwrap();
+        let mut stored = StoredValue::string(b"value");
+        stored.accessed_at = now_secs() - DEFAULT_ACCESS_TIME_RESOLUTION_SECS - 10;
+        untracked
+            .partition_handle
+            .insert(b"key", stored.encode())
+            .unwrap();
+        untracked.get(b"key").unwrap();
+        assert!(
+            untracked.idle_time(b"key").unwrap().unwrap()
+                > DEFAULT_ACCESS_TIME_RESOLUTION_SECS as u64
+        );
+        untracked.set(b"key", b"value").unwrap();
+        assert!(untracked.idle_time(b"key").unwrap().unwrap() <= 1);
+    }
+
+    #[test]
+    fn test_reload_and_reopen() {
+        let temp_dir = TempDir::new().expect("Failed to create temp dir");
+        let path = temp_dir.path().to_str().unwrap();
+        let entries: [(&[u8], &[u8]); 3] = [
+            (b"plain", b"value"),
+            (b"empty", b""),
+            (b"binary", b"\x00\r\n\xff"),
+        ];
+        {
+            let data_store = DataStore::new(path).unwrap();
+            let partition = data_store.partition("reload").unwrap();
+            for (key, value) in entries {
+                partition.set(key, value).unwrap();
+            }
+            partition
+                .expire_at(b"plain", now_millis() + 60_000)
+                .unwrap();
+            partition.set_add(b"set", &[b"a", b"b"]).unwrap();
+            partition.reload().unwrap();
+            for (key, value) in entries {
+                assert_eq!(partition.get(key).unwrap(), Some(value.to_vec()));
+            }
+        }
+
+        let data_store = DataStore::new(path).unwrap();
+        let partition = data_store.partition("reload").unwrap();
+        for (key, value) in entries {
+            assert_eq!(partition.get(key).unwrap(), Some(value.to_vec()));
+        }
+        assert!(matches!(
+            partition.expire_time(b"plain").unwrap(),
+            Expiry::At(_)
+        ));
+        assert_eq!(
+            partition.set_members(b"set").unwrap(),
+            vec![b"a".to_vec(), b"b".to_vec()]
+        );
+    }
+
+    #[test]
+    fn test_access_frequency() {
+        let (data_store, untracked) = create_test_store();
+        let store = data_store
+            .partition_builder("tracked")
+            .track_reads(true)
+            .open()
+            .unwrap();
+        store.set(b"key", b"value").unwrap();
+        assert_eq!(store.access_frequency(b"key").unwrap(), Some(LFU_INIT_VAL));
+        assert_eq!(store.access_frequency(b"missing").unwrap(), None);
+
+        // The first access past the initial value always counts
+        for _ in 0..100 {
+            store.get(b"key").unwrap();
+        }
+        let freq = store.access_frequency(b"key").unwrap().unwrap();
+        assert!(freq > LFU_INIT_VAL);
+
+        // Three decay periods without access cost three points
+        let mut stored = store.peek_stored(b"key").unwrap().unwrap();
+        stored.accessed_at = now_secs() - 3 * DEFAULT_LFU_DECAY_SECS - 1;
+        store
+            .partition_handle
+            .insert(b"key", stored.encode())
+            .unwrap();
+        assert_eq!(store.access_frequency(b"key").unwrap(), Some(freq - 3));
+
+        // Without read tracking, reads write nothing
+        untracked.set(b"key", b"value").unwrap();
+        let instant = data_store.keyspace.instant();
+        for _ in 0..100 {
+            untracked.get(b"key").unwrap();
+        }
+        assert_eq!(data_store.keyspace.instant(), instant);
+        assert_eq!(
+            untracked.access_frequency(b"key").unwrap(),
+            Some(LFU_INIT_VAL)
+        );
+    }
+
+    #[test]
+    fn test_string_encoding() {
+        let (_data_store, store) = create_test_store();
+        store.set(b"int", b"-12345").unwrap();
+        store.set(b"short", b"hello").unwrap();
+        store.set(b"limit", &[b'x'; EMBSTR_SIZE_LIMIT]).unwrap();
+        store.set(b"long", &[b'x'; EMBSTR_SIZE_LIMIT + 1]).unwrap();
+        // Not canonical, so not stored as an integer by Redis either
+        store.set(b"padded", b"007").unwrap();
+
+        assert_eq!(store.encoding(b"int").unwrap(), Some("int"));
+        assert_eq!(store.encoding(b"short").unwrap(), Some("embstr"));
+        assert_eq!(store.encoding(b"limit").unwrap(), Some("embstr"));
+        assert_eq!(store.encoding(b"long").unwrap(), Some("raw"));
+        assert_eq!(store.encoding(b"padded").unwrap(), Some("embstr"));
+        assert_eq!(store.encoding(b"missing").unwrap(), None);
+
+        // The encoding follows the value as it changes
+        store.set(b"short", &[b'y'; 100]).unwrap();
+        assert_eq!(store.encoding(b"short").unwrap(), Some("raw"));
+        store.set(b"short", b"42").unwrap();
+        assert_eq!(store.encoding(b"short").unwrap(), Some("int"));
+    }
+
+    #[test]
+    fn test_memory_usage() {
+        let (_data_store, store) = create_test_store();
+        store.set(b"small", b"x").unwrap();
+        store.set(b"large", &[b'x'; 1000]).unwrap();
+        let small = store.memory_usage(b"small", 0).unwrap().unwrap();
+        let large = store.memory_usage(b"large", 0).unwrap().unwrap();
+        assert!(large > small + 900);
+        assert_eq!(store.memory_usage(b"missing", 0).unwrap(), None);
+
+        // Sampling extrapolates from members of equal size
+        let members: Vec<Vec<u8>> = (0..100).map(|i| format!("{:04}", i).into_bytes()).collect();
+        let members: Vec<&[u8]> = members.iter().map(|m| m.as_slice()).collect();
+        store.set_add(b"set", &members).unwrap();
+        let exact = store.memory_usage(b"set", 0).unwrap().unwrap();
+        assert_eq!(store.memory_usage(b"set", 5).unwrap(), Some(exact));
+        assert!(exact > 100 * 4);
+    }
+
+    #[test]
+    fn test_close_is_durable() {
+        let temp_dir = TempDir::new().expect("Failed to create temp dir");
+        let live = temp_dir.path().join("live");
+        let crashed = temp_dir.path().join("crashed");
+
+        let data_store = DataStore::new(live.to_str().unwrap()).unwrap();
+        // Writes with manual journal persistence stay in fjall's journal
+        // buffer, and a clone of the store keeps the keyspace open, so only
+        // `close` can have put the write on disk before the files are copied
+        let handle = data_store
+            .create_partition_with(
+                "closed",
+                PartitionCreateOptions::default().manual_journal_persist(true),
+            )
+            .unwrap();
+        handle.insert(b"key", b"value").unwrap();
+        let open = data_store.clone();
+        data_store.close().unwrap();
+        copy_dir(&live, &crashed);
+
+        let recovered = DataStore::new(crashed.to_str().unwrap()).unwrap();
+        let recovered_handle = recovered.create_partition("closed").unwrap();
+        assert_eq!(
+            recovered_handle.get(b"key").unwrap().as_deref(),
+            Some(&b"value"[..])
+        );
+        drop(handle);
+        drop(open);
+    }
+
+    #[test]
+    fn test_read_only_store_rejects_writes() {
+        let temp_dir = TempDir::new().expect("Failed to create temp dir");
+        let path = temp_dir.path().to_str().unwrap();
+        let data_store = DataStore::new(path).unwrap();
+        let partition = data_store.partition("data").unwrap();
+        partition.set(b"key", b"value").unwrap();
+        partition.set_add(b"set", &[b"a"]).unwrap();
+        drop(partition);
+        data_store.close().unwrap();
+
+        let data_store = DataStore::open_read_only(path).unwrap();
+        let partition = data_store.partition("data").unwrap();
+        assert_eq!(partition.get(b"key").unwrap(), Some(b"value".to_vec()));
+        assert!(partition.set(b"key", b"other").is_err());
+        assert!(partition.delete(b"key").is_err());
+        assert!(partition.set_add(b"set", &[b"b"]).is_err());
+        assert!(partition.compact().is_err());
+        assert_eq!(partition.get(b"key").unwrap(), Some(b"value".to_vec()));
+        assert!(data_store.partition("missing").is_err());
+
+        let missing = temp_dir.path().join("missing");
+        assert!(DataStore::open_read_only(missing.to_str().unwrap()).is_err());
+    }
+
+    // Copies a data directory as it is on disk, like the state a crash leaves
+    fn copy_dir(from: &Path, to: &Path) {
+        std::fs::create_dir_all(to).unwrap();
+        for entry in std::fs::read_dir(from).unwrap() {
+            let entry = entry.unwrap();
+            let target = to.join(entry.file_name());
+            if entry.file_type().unwrap().is_dir() {
+                copy_dir(&entry.path(), &target);
+            } else {
+                std::fs::copy(entry.path(), target).unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn test_fsync_always_survives_crash() {
+        let temp_dir = TempDir::new().expect("Failed to create temp dir");
+        let live = temp_dir.path().join("live");
+        let crashed = temp_dir.path().join("crashed");
+
+        let data_store = DataStore::new(live.to_str().unwrap()).unwrap();
+        data_store.set_fsync_policy(FsyncPolicy::Always);
+        let partition = data_store.partition("durable").unwrap();
+        assert_eq!(partition.fsync_policy.get(), FsyncPolicy::Always);
+        for i in 0..10u8 {
+            partition.set(&[b'k', i], &[i; 16]).unwrap();
+        }
+        // Snapshot the files while the store is still open, without any
+        // shutdown flush
+        copy_dir(&live, &crashed);
+
+        let recovered = DataStore::new(crashed.to_str().unwrap()).unwrap();
+        let partition = recovered.partition("durable").unwrap();
+        for i in 0..10u8 {
+            assert_eq!(partition.get(&[b'k', i]).unwrap(), Some(vec![i; 16]));
+        }
+        drop(data_store);
+    }
+
+    #[test]
+    fn test_sync_data_survives_crash() {
+        let temp_dir = TempDir::new().expect("Failed to create temp dir");
+        let live = temp_dir.path().join("live");
+        let crashed = temp_dir.path().join("crashed");
+
+        let data_store = DataStore::new(live.to_str().unwrap()).unwrap();
+        data_store.set_fsync_policy(FsyncPolicy::No);
+        let partition = data_store.partition("durable").unwrap();
+        partition.set(b"key", b"value").unwrap();
+        data_store.sync_data().unwrap();
+        copy_dir(&live, &crashed);
+
+        let recovered = DataStore::new(crashed.to_str().unwrap()).unwrap();
+        let partition = recovered.partition("durable").unwrap();
+        assert_eq!(partition.get(b"key").unwrap(), Some(b"value".to_vec()));
+        drop(data_store);
+    }
+
+    #[test]
+    fn test_flushed_partition_survives_crash() {
+        let temp_dir = TempDir::new().expect("Failed to create temp dir");
+        let live = temp_dir.path().join("live");
+        let crashed = temp_dir.path().join("crashed");
+
+        let data_store = DataStore::new(live.to_str().unwrap()).unwrap();
+        data_store.set_fsync_policy(FsyncPolicy::No);
+        let partition = data_store.partition("hot").unwrap();
+        partition.set(b"key", b"value").unwrap();
+        partition.set_add(b"set", &[b"member"]).unwrap();
+        partition.flush().unwrap();
+        copy_dir(&live, &crashed);
+
+        let recovered = DataStore::new(crashed.to_str().unwrap()).unwrap();
+        let partition = recovered.partition("hot").unwrap();
+        assert_eq!(partition.get(b"key").unwrap(), Some(b"value".to_vec()));
+        assert_eq!(
+            partition.set_members(b"set").unwrap(),
+            vec![b"member".to_vec()]
+        );
+        drop(data_store);
+    }
+
+    #[test]
+    fn test_path_is_a_file() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let error = DataStore::new(file.path().to_str().unwrap()).err().unwrap();
+        assert_eq!(
+            error.to_string(),
+            format!(
+                "Keyspace error: data path '{}' exists but is not a directory",
+                file.path().display()
+            )
+        );
+        // Nor can a directory be created under it
+        let nested = file.path().join("data");
+        assert!(DataStore::new(nested.to_str().unwrap()).is_err());
+    }
+
+    #[test]
+    fn test_concurrent_partition_opens_share_one_partition() {
+        let (data_store, _) = create_test_store();
+        let partitions = std::thread::scope(|scope| {
+            let opens: Vec<_> = (0..16)
+                .map(|_| scope.spawn(|| data_store.partition("shared").unwrap()))
+                .collect();
+            opens
+                .into_iter()
+                .map(|open| open.join().unwrap())
+                .collect::<Vec<_>>()
+        });
+        for partition in &partitions {
+            assert!(Arc::ptr_eq(&partition.key_locks, &partitions[0].key_locks));
+            assert!(Arc::ptr_eq(&partition.stats, &partitions[0].stats));
+            assert!(Arc::ptr_eq(
+                &partition.write_groups,
+                &partitions[0].write_groups
+            ));
+        }
+        partitions[0].set(b"key", b"value").unwrap();
+        assert_eq!(partitions[15].stats().sets, 1);
+
+        // Settings other than the ones the partition is open with are rejected
+        let error = data_store
+            .partition_builder("shared")
+            .numeric_encoding(NumericEncoding::BigEndianI64)
+            .open()
+            .err()
+            .unwrap();
+        assert_eq!(
+            error.to_string(),
+            "Partition error: partition 'shared' is already open with other settings"
+        );
+        let reopened = data_store.partition_builder("shared").open().unwrap();
+        assert!(Arc::ptr_eq(&reopened.key_locks, &partitions[0].key_locks));
+    }
+
+    #[test]
+    fn test_partition_opens_share_settings() {
+        let (data_store, _) = create_test_store();
+        let configured = data_store
+            .partition_builder("counters")
+            .numeric_encoding(NumericEncoding::BigEndianI64)
+            .default_ttl(Duration::from_secs(60))
+            .open()
+            .unwrap();
+        let plain = data_store.partition("counters").unwrap();
+        assert_eq!(plain.numeric_encoding, NumericEncoding::BigEndianI64);
+        assert_eq!(plain.default_ttl, Some(Duration::from_secs(60)));
+        plain.incr_by(b"hits", 5).unwrap();
+        assert_eq!(configured.incr_by(b"hits", 1).unwrap(), 6);
+
+        assert!(data_store
+            .partition_builder("counters")
+            .numeric_encoding(NumericEncoding::BigEndianI64)
+            .open()
+            .is_err());
+        assert!(data_store
+            .partition_builder("counters")
+            .numeric_encoding(NumericEncoding::BigEndianI64)
+            .default_ttl(Duration::from_secs(60))
+            .compression(CompressionType::None)
+            .open()
+            .is_err());
+        assert!(data_store
+            .partition_builder("counters")
+            .numeric_encoding(NumericEncoding::BigEndianI64)
+            .default_ttl(Duration::from_secs(60))
+            .open()
+            .is_ok());
+    }
+
+    #[test]
+    fn test_partition_opens_share_key_quota() {
+        let dir = TempDir::new().unwrap();
+        let data_store = DataStore::builder()
+            .max_keys(2)
+            .open(dir.path().to_str().unwrap())
+            .unwrap();
+        let first = data_store.partition("x").unwrap();
+        let second = data_store.partition("x").unwrap();
+        first.set(b"a", b"1").unwrap();
+        second.set(b"b", b"2").unwrap();
+        assert_eq!(first.key_count(), Some(2));
+        assert_eq!(second.key_count(), Some(2));
+        assert!(first.set(b"c", b"3").is_err());
+        assert_eq!(first.stats().sets, second.stats().sets);
+    }
+
+    #[test]
+    fn test_partition_limit() {
+        // The test store's own partition counts, and is in use
+        let (data_store, _partition) = create_test_store();
+        data_store.set_partition_limit(PartitionLimit {
+            max: Some(3),
+            evict_idle: false,
+        });
+        drop(data_store.partition("a").unwrap());
+        let b = data_store.partition("b").unwrap();
+        assert!(matches!(
+            data_store.partition("c"),
+            Err(DataStoreError::PartitionError(_))
+        ));
+        // Opening one that is already open is always allowed
+        data_store.partition("a").unwrap();
+
+        data_store.set_partition_limit(PartitionLimit {
+            max: Some(3),
+            evict_idle: true,
+        });
+        b.set(b"key", b"value").unwrap();
+        let c = data_store.partition("c").unwrap();
+        // "a" was the only partition no one held
+        assert!(data_store.partitions.lock().unwrap().get("a").is_none());
+        assert!(data_store.partition("d").is_err());
+
+        drop(c);
+        data_store.partition("a").unwrap();
+        assert!(data_store.partitions.lock().unwrap().get("c").is_none());
+        assert_eq!(b.get(b"key").unwrap(), Some(b"value".to_vec()));
+    }
+
+    #[test]
+    fn test_verify_reports_corruption() {
+        let (_data_store, store) = create_test_store();
+        store.set(b"string", b"value").unwrap();
+        store.hash_set(b"hash", &[(b"field", b"1")]).unwrap();
+        store.set_add(b"set", &[b"1", b"2"]).unwrap();
+        let report = store.verify().unwrap();
+        assert_eq!(report.entries, 4);
+        assert!(report.bytes > 0);
+        assert!(report.corrupted.is_empty());
+
+        // An unknown format version, and a hash value cut short
+        store
+            .partition_handle
+            .insert(b"garbage", b"\xbf\x00\x01")
+            .unwrap();
+        let mut truncated = StoredValue::container(ValueKind::Hash, None, 1).encode();
+        truncated.truncate(truncated.len() - 4);
+        store.partition_handle.insert(b"hash", truncated).unwrap();
+        let report = store.verify().unwrap();
+        assert_eq!(report.entries, 5);
+        assert_eq!(
+            report.corrupted,
+            vec![b"garbage".to_vec(), b"hash".to_vec()]
+        );
+    }
+
+    #[test]
+    fn test_headerless_values_read_as_strings() {
+        let (_data_store, store) = create_test_store();
+        store.partition_handle.insert(b"legacy", b"value").unwrap();
+        assert_eq!(store.get(b"legacy").unwrap(), Some(b"value".to_vec()));
+        assert_eq!(store.key_type(b"legacy").unwrap(), Some(ValueKind::String));
+        assert_eq!(store.expire_time(b"legacy").unwrap(), Expiry::Persistent);
+        assert_eq!(store.verify().unwrap().corrupted, Vec::<Vec<u8>>::new());
+
+        // Rewritten with a header on the next write
+        assert!(store.expire_at(b"legacy", now_millis() + 60_000).unwrap());
+        let bytes = store.partition_handle.get(b"legacy").unwrap().unwrap();
+        assert_eq!(bytes[0], 0x81);
+        assert_eq!(store.get(b"legacy").unwrap(), Some(b"value".to_vec()));
+    }
+
+    #[test]
+    fn test_keyspace_stats() {
+        let (data_store, first) = create_test_store();
+        let second = data_store.partition("second_partition").unwrap();
+        assert_eq!(first.keyspace_stats().unwrap(), KeyspaceStats::default());
+
+        for key in [b"a", b"b", b"c"] {
+            first.set(key, b"value").unwrap();
+        }
+        first.expire_at(b"a", now_millis() + 60_000).unwrap();
+        second.set(b"x", b"value").unwrap();
+        second.set(b"gone", b"value").unwrap();
+        second.expire_at(b"x", now_millis() + 60_000).unwrap();
+        // A deadline in the past deletes the key
+        second.expire_at(b"gone", now_millis() - 1).unwrap();
+
+        assert_eq!(
+            first.keyspace_stats().unwrap(),
+            KeyspaceStats {
+                keys: 3,
+                expires: 1
+            }
+        );
+        assert_eq!(
+            second.keyspace_stats().unwrap(),
+            KeyspaceStats {
+                keys: 1,
+                expires: 1
+            }
+        );
+    }
+
+    #[test]
+    fn test_count_prefix() {
+        let (_data_store, partition) = create_test_store();
+        for key in [
+            &b"session:1"[..],
+            b"session:2",
+            b"session:3",
+            b"user:1",
+            b"sessions",
+        ] {
+            partition.set(key, b"value").unwrap();
+        }
+        partition.set_add(b"session:set", &[b"a", b"b"]).unwrap();
+        // Expired but not purged yet
+        let mut expired = StoredValue::string(b"value");
+        expired.expires_at = Some(now_millis() - 1);
+        partition
+            .partition_handle
+            .insert(b"session:old", expired.encode())
+            .unwrap();
+
+        assert_eq!(partition.count_prefix(b"session:").unwrap(), 4);
+        assert_eq!(partition.count_prefix(b"session").unwrap(), 5);
+        assert_eq!(partition.count_prefix(b"user:").unwrap(), 1);
+        assert_eq!(partition.count_prefix(b"none:").unwrap(), 0);
+        assert_eq!(partition.count_prefix(b"").unwrap(), 6);
+    }
+
+    // Seals the memtables and waits for the background flush to write them
+    // out as segments
+    fn flush(partition: &DataStorePartition) {
+        partition.flush().unwrap();
+        // Waits for every sealed memtable to be written out, which counting
+        // segments can't tell while background compactions merge them
+        partition
+            .partition_handle
+            .rotate_memtable_and_wait()
+            .unwrap();
+        partition.members_handle.rotate_memtable_and_wait().unwrap();
+    }
+
+    #[test]
+    fn test_compact_reclaims_deleted_data() {
+        let temp_dir = TempDir::new().expect("Failed to create temp dir");
+        let data_store = DataStore::new(temp_dir.path().to_str().unwrap()).unwrap();
+        let partition = data_store.partition("compact").unwrap();
+
+        let value = vec![7u8; 1024];
+        for i in 0..2_000u32 {
+            partition.set(&i.to_be_bytes(), &value).unwrap();
+        }
+        flush(&partition);
+        for i in 0..2_000u32 {
+            partition.delete(&i.to_be_bytes()).unwrap();
+        }
+        flush(&partition);
+
+        // A major compaction does nothing while a background compaction
+        // holds any of the segments, so retry until it has merged them all.
+        // Merging drops the values the deletes shadow; the deletes
+        // themselves may stay behind in the merged segment.
+        for _ in 0..500 {
+            partition.compact().unwrap();
+            if partition.segment_count() <= 1 {
+                break;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        assert!(partition.segment_count() <= 1);
+        let written = 2_000 * value.len() as u64;
+        assert!(partition.disk_space() < written / 10);
+        for i in 0..2_000u32 {
+            assert_eq!(partition.get(&i.to_be_bytes()).unwrap(), None);
+        }
+    }
+
+    #[test]
+    fn test_compact_keeps_deletes_across_reopen() {
+        let temp_dir = TempDir::new().expect("Failed to create temp dir");
+        let path = temp_dir.path().to_str().unwrap();
+        {
+            let data_store = DataStore::new(path).unwrap();
+            let partition = data_store.partition("compact").unwrap();
+            partition.set(b"deleted", b"value").unwrap();
+            partition.set(b"kept", b"value").unwrap();
+            flush(&partition);
+            partition.delete(b"deleted").unwrap();
+            flush(&partition);
+            for _ in 0..500 {
+                partition.compact().unwrap();
+                if partition.segment_count() <= 1 {
+                    break;
+                }
+                std::thread::sleep(std::time::Duration::from_millis(10));
+            }
+            assert_eq!(partition.segment_count(), 1);
+        }
+
+        let data_store = DataStore::new(path).unwrap();
+        let partition = data_store.partition("compact").unwrap();
+        assert_eq!(partition.get(b"deleted").unwrap(), None);
+        assert_eq!(partition.get(b"kept").unwrap(), Some(b"value".to_vec()));
+    }
+
+    #[test]
+    fn test_small_shared_block_cache() {
+        let temp_dir = TempDir::new().expect("Failed to create temp dir");
+        let data_store = DataStore::builder()
+            .block_cache_size(64 * 1024)
+            .open(temp_dir.path().to_str().unwrap())
+            .unwrap();
+        assert_eq!(data_store.block_cache_capacity(), 64 * 1024);
+        let partitions = [
+            data_store.partition("first").unwrap(),
+            data_store.partition("second").unwrap(),
+        ];
+
+        // Far more data than the cache holds, read back from segments
+        let value = vec![3u8; 1024];
+        for partition in &partitions {
+            for i in 0..1_000u32 {
+                partition.set(&i.to_be_bytes(), &value).unwrap();
+            }
+            flush(partition);
+        }
+        for partition in &partitions {
+            for i in 0..1_000u32 {
+                assert_eq!(
+                    partition.get(&i.to_be_bytes()).unwrap(),
+                    Some(value.clone())
+                );
+            }
+        }
+        // The one cache fjall shares between partitions has the size asked for
+        assert_eq!(data_store.keyspace.cache_capacity(), 64 * 1024);
+    }
+
+    #[test]
+    fn test_stats() {
+        let (_data_store, store) = create_test_store();
+        let clone = store.clone();
+        assert_eq!(store.stats(), PartitionStats::default());
+        assert_eq!(store.stats().hit_rate(), None);
+
+        store.set(b"a", b"1").unwrap();
+        clone.set(b"b", b"2").unwrap();
+        store.get(b"a").unwrap();
+        clone.get_string(b"b").unwrap();
+        store.get(b"missing").unwrap();
+        store.delete(b"a").unwrap();
+        store.get(b"a").unwrap();
+
+        let stats = clone.stats();
+        assert_eq!(
+            stats,
+            PartitionStats {
+                gets: 4,
+                sets: 2,
+                deletes: 1,
+                hits: 2,
+                misses: 2,
+            }
+        );
+        assert_eq!(stats.hit_rate(), Some(0.5));
+    }
+
+    #[test]
+    fn test_swap() {
+        let (_data_store, store) = create_test_store();
+        store.set(b"blue", b"v1").unwrap();
+        let deadline = now_millis() + 60_000;
+        store.expire_at(b"blue", deadline).unwrap();
+        store.set_add(b"green", &[b"a", b"b"]).unwrap();
+
+        store.swap(b"blue", b"green").unwrap();
+        assert_eq!(store.key_type(b"blue").unwrap(), Some(ValueKind::Set));
+        assert_eq!(
+            store.set_members(b"blue").unwrap(),
+            vec![b"a".to_vec(), b"b".to_vec()]
+        );
+        assert_eq!(store.get(b"green").unwrap(), Some(b"v1".to_vec()));
+        assert_eq!(store.expire_time(b"green").unwrap(), Expiry::At(deadline));
+        assert_eq!(store.expire_time(b"blue").unwrap(), Expiry::Persistent);
+
+        // Swapping two sets keeps members they share on the right side
+        store.delete(b"green").unwrap();
+        store.set_add(b"green", &[b"b", b"c"]).unwrap();
+        store.swap(b"blue", b"green").unwrap();
+        assert_eq!(
+            store.set_members(b"blue").unwrap(),
+            vec![b"b".to_vec(), b"c".to_vec()]
+        );
+        assert_eq!(
+            store.set_members(b"green").unwrap(),
+            vec![b"a".to_vec(), b"b".to_vec()]
+        );
+    }
+
+    #[test]
+    fn test_swap_with_missing_key() {
+        let (_data_store, store) = create_test_store();
+        store.set_add(b"present", &[b"a"]).unwrap();
+
+        store.swap(b"present", b"missing").unwrap();
+        assert!(!store.exists(b"present").unwrap());
+        assert_eq!(store.set_members(b"missing").unwrap(), vec![b"a".to_vec()]);
+        assert_eq!(
+            store
+                .members_handle
+                .prefix(member_prefix(b"present"))
+                .count(),
+            0
+        );
+
+        store.swap(b"gone", b"absent").unwrap();
+        assert!(!store.exists(b"gone").unwrap());
+        assert!(!store.exists(b"absent").unwrap());
+    }
+
+    #[test]
+    fn test_multi_set_nx() {
+        let (_data_store, store) = create_test_store();
+        assert!(store
+            .multi_set_nx(&[(b"a", b"1"), (b"b", b"2"), (b"a", b"3")])
+            .unwrap());
+        assert_eq!(store.get(b"a").unwrap(), Some(b"3".to_vec()));
+        assert_eq!(store.get(b"b").unwrap(), Some(b"2".to_vec()));
+
+        // One existing key blocks the whole batch
+        assert!(!store
+            .multi_set_nx(&[(b"c", b"1"), (b"b", b"new"), (b"d", b"1")])
+            .unwrap());
+        assert!(!store.exists(b"c").unwrap());
+        assert!(!store.exists(b"d").unwrap());
+        assert_eq!(store.get(b"b").unwrap(), Some(b"2".to_vec()));
+
+        // Keys of any type count, expired ones don't
+        store.set_add(b"set", &[b"m"]).unwrap();
+        assert!(!store.multi_set_nx(&[(b"set", b"1")]).unwrap());
+        store
+            .set_expiring(b"expired", b"old", Some(now_millis() - 1))
+            .unwrap();
+        assert!(store.multi_set_nx(&[(b"expired", b"new")]).unwrap());
+        assert_eq!(store.get(b"expired").unwrap(), Some(b"new".to_vec()));
+    }
+
+    #[test]
+    fn test_default_ttl() {
+        let temp_dir = TempDir::new().expect("Failed to create temp dir");
+        let data_store = DataStore::new(temp_dir.path().to_str().unwrap()).unwrap();
+        let cache = data_store
+            .partition_builder("cache")
+            .default_ttl(Duration::from_millis(50))
+            .open()
+            .unwrap();
+        assert_eq!(cache.default_ttl(), Some(Duration::from_millis(50)));
+
+        cache.set(b"default", b"1").unwrap();
+        assert!(matches!(
+            cache.expire_time(b"default").unwrap(),
+            Expiry::At(_)
+        ));
+        let deadline = now_millis() + 60_000;
+        cache
+            .set_expiring(b"explicit", b"2", Some(deadline))
+            .unwrap();
+        cache.set(b"persisted", b"3").unwrap();
+        assert!(cache.clear_expiry(b"persisted").unwrap());
+
+        std::thread::sleep(Duration::from_millis(100));
+        assert_eq!(cache.get(b"default").unwrap(), None);
+        assert_eq!(
+            cache.expire_time(b"explicit").unwrap(),
+            Expiry::At(deadline)
+        );
+        assert_eq!(cache.get(b"persisted").unwrap(), Some(b"3".to_vec()));
+    }
+
+    #[test]
+    fn test_partition_compression() {
+        let temp_dir = TempDir::new().expect("Failed to create temp dir");
+        let data_store = DataStore::new(temp_dir.path().to_str().unwrap()).unwrap();
+        let open = |name: &str, compression| {
+            data_store
+                .partition_builder(name)
+                .compression(compression)
+                .open()
+                .unwrap()
+        };
+        let plain = open("plain", CompressionType::None);
+        let compressed = open("compressed", CompressionType::Miniz(6));
+        let value = vec![b'x'; 1024];
+        for partition in [&plain, &compressed] {
+            for i in 0..200u32 {
+                partition.set(&i.to_be_bytes(), &value).unwrap();
+            }
+            flush(partition);
+        }
+        assert_eq!(compressed.get(&7u32.to_be_bytes()).unwrap(), Some(value));
+        assert!(compressed.disk_space() < plain.disk_space());
+    }
+
+    #[test]
+    fn test_get_or_insert_with_runs_once() {
+        let (_data_store, store) = create_test_store();
+        let calls = std::sync::atomic::AtomicUsize::new(0);
+        let barrier = std::sync::Barrier::new(8);
+        std::thread::scope(|scope| {
+            for i in 0..8u8 {
+                let (store, calls, barrier) = (&store, &calls, &barrier);
+                scope.spawn(move || {
+                    barrier.wait();
+                    let value = store
+                        .get_or_insert_with(b"cached", || {
+                            calls.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                            std::thread::sleep(Duration::from_millis(20));
+                            vec![i]
+                        })
+                        .unwrap();
+                    // Every caller sees the single computed value
+                    assert_eq!(store.get(b"cached").unwrap(), Some(value));
+                });
+            }
+        });
+        assert_eq!(calls.load(std::sync::atomic::Ordering::SeqCst), 1);
+
+        store.set(b"present", b"v").unwrap();
+        let value = store
+            .get_or_insert_with(b"present", || panic!("key exists"))
+            .unwrap();
+        assert_eq!(value, b"v".to_vec());
     }
 }
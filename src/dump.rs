@@ -0,0 +1,124 @@
+use crate::datastore::{member_key, member_prefix};
+use crate::notify;
+use crate::value::{now_millis, StoredValue};
+use crate::{DataStoreError, DataStorePartition};
+use std::collections::HashSet;
+
+impl DataStorePartition {
+    // Serializes the value at `key`, members included, for `restore`. Returns
+    // None if the key does not exist.
+    pub fn dump(&self, key: &[u8]) -> Result<Option<Vec<u8>>, DataStoreError> {
+        let _guard = self.lock_key(key);
+        let Some(current) = self.read_for_update(key)? else {
+            return Ok(None);
+        };
+        let members = self.members_of(key, Some(&current))?;
+        Ok(Some(current.dump(&members)))
+    }
+
+    // Length of the key's `dump`, which DEBUG OBJECT reports as its
+    // serialized length. None if the key does not exist.
+    pub fn serialized_length(&self, key: &[u8]) -> Result<Option<u64>, DataStoreError> {
+        Ok(self.dump(key)?.map(|dump| dump.len() as u64))
+    }
+
+    // Recreates a key from a `dump`, expiring at `expires_at` (unix ms). An
+    // existing key is only overwritten with `replace`; otherwise this fails
+    // with `BusyKey`. A deadline in the past leaves the key absent.
+    pub fn restore(
+        &self,
+        key: &[u8],
+        dump: &[u8],
+        expires_at: Option<u64>,
+        replace: bool,
+    ) -> Result<(), DataStoreError> {
+        let (mut restored, members) = StoredValue::from_dump(dump)?;
+        restored.expires_at = expires_at;
+
+        let _guard = self.lock_key(key);
+        let current = self.read_for_update(key)?;
+        if current.is_some() && !replace {
+            return Err(DataStoreError::BusyKey);
+        }
+        let mut batch = self.batch();
+        let updated = if restored.is_expired(now_millis()) {
+            None
+        } else {
+            // Members of a replaced container of the same kind are not dropped
+            // by `commit_update`, so remove the ones the dump doesn't overwrite
+            if current.as_ref().is_some_and(|c| c.kind == restored.kind) {
+                let keep: HashSet<Vec<u8>> = members
+                    .iter()
+                    .map(|(member, _)| member_key(key, member))
+                    .collect();
+                for entry in self.members_handle().prefix(member_prefix(key)) {
+                    let (composite, _) = entry?;
+                    if !keep.contains(&*composite) {
+                        batch.remove(self.members_handle(), composite);
+                    }
+                }
+            }
+            for (member, value) in &members {
+                batch.insert(self.members_handle(), member_key(key, member), value);
+            }
+            Some(restored)
+        };
+        self.commit_update(batch, key, current.as_ref(), updated.as_ref())?;
+        if updated.is_some() {
+            self.notifier().notify(notify::GENERIC, "restore", key);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{DataStore, Expiry, ValueKind};
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_dump_restore_into_other_partition() {
+        let temp_dir = TempDir::new().expect("Failed to create temp dir");
+        let data_store = DataStore::new(temp_dir.path().to_str().unwrap()).unwrap();
+        let source = data_store.partition("source").unwrap();
+        let target = data_store.partition("target").unwrap();
+
+        source.set(b"string", b"\x00value").unwrap();
+        source
+            .hash_set(b"hash", &[(b"a", b"1"), (b"b", b"2")])
+            .unwrap();
+        assert_eq!(source.dump(b"missing").unwrap(), None);
+
+        let deadline = now_millis() + 60_000;
+        let dump = source.dump(b"string").unwrap().unwrap();
+        target
+            .restore(b"copy", &dump, Some(deadline), false)
+            .unwrap();
+        assert_eq!(target.get(b"copy").unwrap(), Some(b"\x00value".to_vec()));
+        assert_eq!(target.expire_time(b"copy").unwrap(), Expiry::At(deadline));
+        assert!(matches!(
+            target.restore(b"copy", &dump, None, false),
+            Err(DataStoreError::BusyKey)
+        ));
+
+        // Replacing a hash keeps only the dumped fields
+        target
+            .hash_set(b"hash", &[(b"a", b"old"), (b"c", b"3")])
+            .unwrap();
+        let dump = source.dump(b"hash").unwrap().unwrap();
+        target.restore(b"hash", &dump, None, true).unwrap();
+        assert_eq!(target.key_type(b"hash").unwrap(), Some(ValueKind::Hash));
+        assert_eq!(target.hash_len(b"hash").unwrap(), 2);
+        assert_eq!(
+            target.hash_get_many(b"hash", &[b"a", b"b", b"c"]).unwrap(),
+            vec![Some(b"1".to_vec()), Some(b"2".to_vec()), None]
+        );
+        assert_eq!(target.expire_time(b"hash").unwrap(), Expiry::Persistent);
+
+        target
+            .restore(b"expired", &dump, Some(now_millis() - 1), false)
+            .unwrap();
+        assert!(!target.exists(b"expired").unwrap());
+    }
+}
@@ -7,4 +7,39 @@ pub enum DataStoreError {
     PartitionError(String),
     #[error("Data error: {0}")]
     DataError(String),
+    #[error("value is not an integer or out of range")]
+    NotAnInteger,
+    #[error("increment or decrement would overflow")]
+    Overflow,
+    #[error("hash value is not an integer")]
+    HashValueNotAnInteger,
+    #[error("hash value is not a float")]
+    HashValueNotAFloat,
+    #[error("increment would produce NaN or Infinity")]
+    NanOrInfinity,
+    #[error("WRONGTYPE Operation against a key holding the wrong kind of value")]
+    WrongType,
+    #[error("BUSYKEY Target key name already exists.")]
+    BusyKey,
+    #[error("DUMP payload version or checksum are wrong")]
+    InvalidDump,
+    #[error("key quota exceeded")]
+    KeyQuotaExceeded,
+}
+
+// Carried inside fjall's error type by the write paths, which return it, see
+// `quota::key_quota_exceeded`
+#[derive(Error, Debug)]
+#[error("key quota exceeded")]
+pub(crate) struct KeyQuotaExceeded;
+
+impl From<fjall::Error> for DataStoreError {
+    fn from(e: fjall::Error) -> Self {
+        match e {
+            fjall::Error::Io(e) if e.get_ref().is_some_and(|e| e.is::<KeyQuotaExceeded>()) => {
+                DataStoreError::KeyQuotaExceeded
+            }
+            e => DataStoreError::PartitionError(e.to_string()),
+        }
+    }
 }
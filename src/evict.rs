@@ -0,0 +1,91 @@
+use crate::notify;
+use crate::DataStorePartition;
+
+// Number of keys sampled per eviction when none is configured, as Redis'
+// maxmemory-samples
+pub const DEFAULT_MAXMEMORY_SAMPLES: usize = 5;
+
+// Which of the sampled keys an eviction removes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EvictionPolicy {
+    // The one idle the longest
+    Lru,
+    // The one with the lowest access frequency
+    Lfu,
+}
+
+impl DataStorePartition {
+    // Evicts one key, chosen among the data store's maxmemory-samples keys
+    // sampled at random. More samples pick a colder key but cost more reads;
+    // this never scans the whole partition. Returns the evicted key, or None
+    // if the partition is empty.
+    pub fn evict(&self, policy: EvictionPolicy) -> Result<Option<Vec<u8>>, fjall::Error> {
+        let mut coldest = None;
+        for key in self.sample_keys(self.maxmemory_samples())? {
+            // Higher is colder. Keys that expired meanwhile are skipped.
+            let score = match policy {
+                EvictionPolicy::Lru => self.idle_time(&key)?,
+                EvictionPolicy::Lfu => self
+                    .access_frequency(&key)?
+                    .map(|freq| u8::MAX as u64 - freq as u64),
+            };
+            if let Some(score) = score {
+                if coldest.as_ref().is_none_or(|(coldest, _)| score > *coldest) {
+                    coldest = Some((score, key));
+                }
+            }
+        }
+        let Some((_, key)) = coldest else {
+            return Ok(None);
+        };
+        let evicted =
+            self.fetch_update::<_, fjall::Error, _>(&key, |current| Ok((None, current.is_some())))?;
+        if !evicted {
+            return Ok(None);
+        }
+        self.notifier().notify(notify::EVICTED, "evicted", &key);
+        Ok(Some(key))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::DataStore;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_evict_with_samples() {
+        let temp_dir = TempDir::new().expect("Failed to create temp dir");
+        let data_store = DataStore::new(temp_dir.path().to_str().unwrap()).unwrap();
+        let partition = data_store
+            .partition_builder("cache")
+            .track_reads(true)
+            .lfu_log_factor(0)
+            .open()
+            .unwrap();
+        for key in [&b"a"[..], b"b", b"c", b"d"] {
+            partition.set(key, b"value").unwrap();
+        }
+        // A log factor of 0 counts every access, so all keys but "c" get hot
+        for _ in 0..20 {
+            for key in [&b"a"[..], b"b", b"d"] {
+                partition.get(key).unwrap();
+            }
+        }
+
+        // Sampling every key finds the cold one
+        data_store.set_maxmemory_samples(10);
+        assert_eq!(
+            partition.evict(EvictionPolicy::Lfu).unwrap(),
+            Some(b"c".to_vec())
+        );
+        assert_eq!(partition.keyspace_stats().unwrap().keys, 3);
+
+        // A single sample still makes room, if not for the coldest key
+        data_store.set_maxmemory_samples(1);
+        let evicted = partition.evict(EvictionPolicy::Lfu).unwrap().unwrap();
+        assert!(!partition.exists(&evicted).unwrap());
+        assert_eq!(partition.keyspace_stats().unwrap().keys, 2);
+    }
+}
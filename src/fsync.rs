@@ -0,0 +1,101 @@
+use std::fmt;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::Arc;
+
+// When the journal is synced to disk, after Redis' appendfsync
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum FsyncPolicy {
+    // After every write, before it is acknowledged. Slowest, loses nothing.
+    Always,
+    // Once per second. The data store does not sync on its own in this mode:
+    // whoever owns it calls `DataStore::persist` on a timer, as the server
+    // does, and a crash loses at most the last second of writes.
+    EverySec,
+    // Left to fjall and the OS
+    #[default]
+    No,
+}
+
+impl FsyncPolicy {
+    // Name used by `--fsync` and CONFIG
+    pub fn name(self) -> &'static str {
+        match self {
+            FsyncPolicy::Always => "always",
+            FsyncPolicy::EverySec => "everysec",
+            FsyncPolicy::No => "no",
+        }
+    }
+
+    fn to_byte(self) -> u8 {
+        match self {
+            FsyncPolicy::Always => 0,
+            FsyncPolicy::EverySec => 1,
+            FsyncPolicy::No => 2,
+        }
+    }
+
+    fn from_byte(byte: u8) -> Self {
+        match byte {
+            0 => FsyncPolicy::Always,
+            1 => FsyncPolicy::EverySec,
+            _ => FsyncPolicy::No,
+        }
+    }
+}
+
+impl FromStr for FsyncPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "always" => Ok(FsyncPolicy::Always),
+            "everysec" => Ok(FsyncPolicy::EverySec),
+            "no" => Ok(FsyncPolicy::No),
+            _ => Err(format!("invalid fsync policy '{}'", s)),
+        }
+    }
+}
+
+impl fmt::Display for FsyncPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+// Policy shared between a data store and its partitions, changeable at runtime
+#[derive(Clone)]
+pub(crate) struct SharedFsyncPolicy(Arc<AtomicU8>);
+
+impl SharedFsyncPolicy {
+    pub(crate) fn new(policy: FsyncPolicy) -> Self {
+        SharedFsyncPolicy(Arc::new(AtomicU8::new(policy.to_byte())))
+    }
+
+    pub(crate) fn get(&self) -> FsyncPolicy {
+        FsyncPolicy::from_byte(self.0.load(Ordering::Relaxed))
+    }
+
+    pub(crate) fn set(&self, policy: FsyncPolicy) {
+        self.0.store(policy.to_byte(), Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_and_share() {
+        for policy in [FsyncPolicy::Always, FsyncPolicy::EverySec, FsyncPolicy::No] {
+            assert_eq!(policy.name().parse::<FsyncPolicy>(), Ok(policy));
+        }
+        assert_eq!("EVERYSEC".parse::<FsyncPolicy>(), Ok(FsyncPolicy::EverySec));
+        assert!("sometimes".parse::<FsyncPolicy>().is_err());
+
+        let shared = SharedFsyncPolicy::new(FsyncPolicy::No);
+        let clone = shared.clone();
+        clone.set(FsyncPolicy::Always);
+        assert_eq!(shared.get(), FsyncPolicy::Always);
+    }
+}
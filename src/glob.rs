@@ -0,0 +1,192 @@
+// Recursion depth past which a pattern is given up on as not matching, as
+// Redis does against patterns made of many stars
+const MAX_NESTING: usize = 1000;
+
+// Whether `string` matches the glob `pattern`, a port of Redis'
+// `stringmatchlen` over raw bytes, so binary keys and patterns match byte by
+// byte:
+//
+//   *       any run of bytes, including none
+//   ?       any single byte
+//   [abc]   one of the listed bytes; [a-z] a range, either way round
+//   [^abc]  any byte but the listed ones
+//   \x      the byte x itself, in a class too
+//
+// As in Redis, an unterminated class ends with the pattern, and a pattern
+// that isn't empty never matches an empty string, "*" included.
+pub(crate) fn glob_match(pattern: &[u8], string: &[u8]) -> bool {
+    let mut skip_longer_matches = false;
+    match_from(pattern, string, &mut skip_longer_matches, 0)
+}
+
+fn match_from(
+    mut pattern: &[u8],
+    mut string: &[u8],
+    skip_longer_matches: &mut bool,
+    nesting: usize,
+) -> bool {
+    if nesting > MAX_NESTING {
+        return false;
+    }
+    while !pattern.is_empty() && !string.is_empty() {
+        match pattern[0] {
+            b'*' => {
+                while pattern.get(1) == Some(&b'*') {
+                    pattern = &pattern[1..];
+                }
+                if pattern.len() == 1 {
+                    return true;
+                }
+                while !string.is_empty() {
+                    if match_from(&pattern[1..], string, skip_longer_matches, nesting + 1) {
+                        return true;
+                    }
+                    if *skip_longer_matches {
+                        return false;
+                    }
+                    string = &string[1..];
+                }
+                // The rest of the pattern matches nowhere in the rest of the
+                // string, so neither can it after a longer match of any star
+                // before this one
+                *skip_longer_matches = true;
+                return false;
+            }
+            b'?' => pattern = &pattern[1..],
+            b'[' => {
+                let mut class = &pattern[1..];
+                let negated = class.first() == Some(&b'^');
+                if negated {
+                    class = &class[1..];
+                }
+                let byte = string[0];
+                let mut matched = false;
+                loop {
+                    match *class {
+                        [] => break,
+                        [b'\\', escaped, ref rest @ ..] => {
+                            matched |= escaped == byte;
+                            class = rest;
+                        }
+                        [b']', ref rest @ ..] => {
+                            class = rest;
+                            break;
+                        }
+                        [start, b'-', end, ref rest @ ..] => {
+                            matched |= (start.min(end)..=start.max(end)).contains(&byte);
+                            class = rest;
+                        }
+                        [listed, ref rest @ ..] => {
+                            matched |= listed == byte;
+                            class = rest;
+                        }
+                    }
+                }
+                if matched == negated {
+                    return false;
+                }
+                pattern = class;
+            }
+            first => {
+                let (literal, rest) = match *pattern {
+                    [b'\\', escaped, ref rest @ ..] => (escaped, rest),
+                    _ => (first, &pattern[1..]),
+                };
+                if literal != string[0] {
+                    return false;
+                }
+                pattern = rest;
+            }
+        }
+        string = &string[1..];
+        if string.is_empty() {
+            while pattern.first() == Some(&b'*') {
+                pattern = &pattern[1..];
+            }
+        }
+    }
+    pattern.is_empty() && string.is_empty()
+}
+
+// The bytes every match of `pattern` starts with, up to its first special
+// byte
+pub(crate) fn literal_prefix(pattern: &[u8]) -> &[u8] {
+    let end = pattern
+        .iter()
+        .position(|byte| matches!(byte, b'*' | b'?' | b'[' | b'\\'))
+        .unwrap_or(pattern.len());
+    &pattern[..end]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match(b"*", b"anything"));
+        assert!(glob_match(b"user:*", b"user:1"));
+        assert!(!glob_match(b"user:*", b"session:1"));
+        assert!(glob_match(b"h?llo", b"hello"));
+        assert!(!glob_match(b"h?llo", b"hllo"));
+        assert!(glob_match(b"a**b*c", b"axxbyyc"));
+        assert!(!glob_match(b"a*b", b"a"));
+        assert!(glob_match(b"a*", b"a"));
+        assert!(glob_match(b"exact", b"exact"));
+        assert!(!glob_match(b"exact", b"exactly"));
+        assert!(!glob_match(b"exactly", b"exact"));
+        // As stringmatchlen, nothing but the empty pattern matches ""
+        assert!(!glob_match(b"*", b""));
+        assert!(glob_match(b"", b""));
+
+        // Classes, ranges either way round, negation
+        assert!(glob_match(b"h[ae]llo", b"hallo"));
+        assert!(!glob_match(b"h[ae]llo", b"hillo"));
+        assert!(glob_match(b"h[^e]llo", b"hallo"));
+        assert!(!glob_match(b"h[^e]llo", b"hello"));
+        assert!(glob_match(b"key[0-9]", b"key7"));
+        assert!(glob_match(b"key[9-0]", b"key7"));
+        assert!(!glob_match(b"key[0-9]", b"keyx"));
+        assert!(glob_match(b"[a-cx-z]", b"y"));
+        assert!(!glob_match(b"[^a-z]", b"q"));
+        // "[]" is an empty class, so matches nothing
+        assert!(!glob_match(b"[]", b"a"));
+        // An unterminated class ends with the pattern
+        assert!(glob_match(b"[ab", b"b"));
+        assert!(!glob_match(b"[ab", b"bc"));
+
+        // Escaped metacharacters match themselves
+        assert!(glob_match(b"a\\*b", b"a*b"));
+        assert!(!glob_match(b"a\\*b", b"axb"));
+        assert!(glob_match(b"\\?", b"?"));
+        assert!(!glob_match(b"\\?", b"x"));
+        assert!(glob_match(b"[\\]]", b"]"));
+        assert!(glob_match(b"[\\^a]", b"^"));
+        assert!(glob_match(b"\\[x\\]", b"[x]"));
+        // A trailing backslash is a literal one
+        assert!(glob_match(b"a\\", b"a\\"));
+
+        // Binary keys and patterns are matched byte by byte
+        assert!(glob_match(b"\xff?\x00*", b"\xff\x01\x00\xfe"));
+        assert!(glob_match(b"[\x80-\xff]*", b"\xc3\xa9t\xc3\xa9"));
+        assert!(!glob_match(b"[\x80-\xff]*", b"ete"));
+        assert!(glob_match(b"[^\x00]", b"\x01"));
+        // A multibyte character is several bytes to `?`
+        assert!(!glob_match("?".as_bytes(), "é".as_bytes()));
+        assert!(glob_match("??".as_bytes(), "é".as_bytes()));
+        assert!(glob_match("caf*".as_bytes(), "café".as_bytes()));
+
+        // Many stars don't take exponential time
+        let pattern = b"a*a*a*a*a*a*a*a*a*a*a*a*a*a*a*a*b";
+        assert!(!glob_match(pattern, &[b'a'; 100]));
+    }
+
+    #[test]
+    fn test_literal_prefix() {
+        assert_eq!(literal_prefix(b"user:*:name"), b"user:");
+        assert_eq!(literal_prefix(b"?"), b"");
+        assert_eq!(literal_prefix(b"key[0-9]"), b"key");
+        assert_eq!(literal_prefix(b"a\\*"), b"a");
+        assert_eq!(literal_prefix(b"exact"), b"exact");
+    }
+}
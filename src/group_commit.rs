@@ -0,0 +1,259 @@
+// Group commit of plain string writes and deletes. The first writer to arrive
+// leads a group: it waits up to the window for other writers to join, then
+// commits every write of the group to fjall as one batch, so that under many
+// small concurrent writes the journal is appended to (and, under the always
+// fsync policy, synced) once per group rather than once per write. Every
+// writer blocks until the batch holding its write is committed, so its reply
+// still reflects its own write's durability, at the cost of up to one window
+// of latency. A client's next command only runs once it has that reply, so
+// its writes stay in order; writes to one key within a group apply in the
+// order they joined it.
+
+use crate::datastore::DataStorePartition;
+use crate::quota::key_quota_exceeded;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, SyncSender};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, RwLock};
+use std::time::{Duration, Instant};
+
+// Largest group when none is configured
+pub const DEFAULT_GROUP_COMMIT_MAX_WRITES: usize = 128;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GroupCommit {
+    // How long the leader of a group waits for writes to join it. Zero
+    // disables grouping, committing every write on its own.
+    pub window: Duration,
+    // A group is committed as soon as it holds this many writes
+    pub max_writes: usize,
+}
+
+impl GroupCommit {
+    pub fn enabled(&self) -> bool {
+        !self.window.is_zero() && self.max_writes > 1
+    }
+}
+
+impl Default for GroupCommit {
+    fn default() -> Self {
+        GroupCommit {
+            window: Duration::ZERO,
+            max_writes: DEFAULT_GROUP_COMMIT_MAX_WRITES,
+        }
+    }
+}
+
+pub(crate) enum GroupedWrite {
+    Set {
+        key: Vec<u8>,
+        value: Vec<u8>,
+        deadline_ms: Option<u64>,
+    },
+    Delete(Vec<u8>),
+}
+
+impl GroupedWrite {
+    pub(crate) fn key(&self) -> &[u8] {
+        match self {
+            GroupedWrite::Set { key, .. } | GroupedWrite::Delete(key) => key,
+        }
+    }
+}
+
+// Whether the key of a write existed before it, None if the write would have
+// taken the partition past its key quota. fjall errors can't be cloned, so a
+// failed group reaches each of its writers as the error's text.
+type WriteResult = Result<Option<bool>, String>;
+
+// The groups forming on one partition
+pub(crate) struct WriteGroups {
+    config: Arc<RwLock<GroupCommit>>,
+    queue: Mutex<Queue>,
+    // Signalled when a write joins, so the leader can commit a full group
+    // before its window ends
+    joined: Condvar,
+    // Groups committed so far
+    groups: AtomicU64,
+}
+
+#[derive(Default)]
+struct Queue {
+    pending: Vec<(GroupedWrite, SyncSender<WriteResult>)>,
+    // Whether a writer is waiting to commit the pending writes
+    leader: bool,
+}
+
+impl WriteGroups {
+    pub(crate) fn new(config: Arc<RwLock<GroupCommit>>) -> Self {
+        WriteGroups {
+            config,
+            queue: Mutex::default(),
+            joined: Condvar::new(),
+            groups: AtomicU64::new(0),
+        }
+    }
+
+    pub(crate) fn config(&self) -> GroupCommit {
+        *self.config.read().unwrap_or_else(|p| p.into_inner())
+    }
+
+    pub(crate) fn groups(&self) -> u64 {
+        self.groups.load(Ordering::Relaxed)
+    }
+
+    // Commits `write` to `partition` together with the writes that join it
+    // within the window, returning once they are committed
+    pub(crate) fn write(
+        &self,
+        partition: &DataStorePartition,
+        write: GroupedWrite,
+        config: GroupCommit,
+    ) -> Result<bool, fjall::Error> {
+        let (sender, receiver) = mpsc::sync_channel(1);
+        let mut queue = self.lock_queue();
+        queue.pending.push((write, sender));
+        if queue.leader {
+            self.joined.notify_one();
+            drop(queue);
+        } else {
+            queue.leader = true;
+            let deadline = Instant::now() + config.window;
+            while queue.pending.len() < config.max_writes {
+                let now = Instant::now();
+                if now >= deadline {
+                    break;
+                }
+                queue = self
+                    .joined
+                    .wait_timeout(queue, deadline - now)
+                    .unwrap_or_else(|p| p.into_inner())
+                    .0;
+            }
+            // Writes arriving from now on form the next group, while this
+            // one commits
+            let group = std::mem::take(&mut queue.pending);
+            queue.leader = false;
+            drop(queue);
+
+            let (writes, senders): (Vec<_>, Vec<_>) = group.into_iter().unzip();
+            let results = partition.commit_group(&writes);
+            self.groups.fetch_add(1, Ordering::Relaxed);
+            match results {
+                Ok(existed) => {
+                    for (sender, existed) in senders.iter().zip(existed) {
+                        let _ = sender.send(Ok(existed));
+                    }
+                }
+                Err(e) => {
+                    for sender in &senders {
+                        let _ = sender.send(Err(e.to_string()));
+                    }
+                }
+            }
+        }
+        match receiver.recv() {
+            Ok(Ok(Some(existed))) => Ok(existed),
+            Ok(Ok(None)) => Err(key_quota_exceeded()),
+            Ok(Err(e)) => Err(std::io::Error::other(e).into()),
+            // The leader panicked before committing the group
+            Err(_) => Err(std::io::Error::other("group commit failed").into()),
+        }
+    }
+
+    fn lock_queue(&self) -> MutexGuard<'_, Queue> {
+        // Writes are only moved in and out whole, so a poisoned queue is
+        // still consistent
+        self.queue.lock().unwrap_or_else(|p| p.into_inner())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{DataStore, FsyncPolicy};
+    use tempfile::TempDir;
+
+    const WRITERS: usize = 8;
+    const WRITES_PER_WRITER: usize = 100;
+
+    // Has every writer set its own keys concurrently, under the always fsync
+    // policy, returning the partition once they are done. Timings are left to
+    // the throughput example.
+    fn concurrent_sets(datastore: &DataStore, group_commit: GroupCommit) -> DataStorePartition {
+        datastore.set_group_commit(group_commit);
+        let name = format!("window_{}", group_commit.window.as_micros());
+        let partition = datastore.partition(&name).unwrap();
+        std::thread::scope(|scope| {
+            for writer in 0..WRITERS {
+                let partition = &partition;
+                scope.spawn(move || {
+                    for i in 0..WRITES_PER_WRITER {
+                        let key = format!("{}:{}", writer, i);
+                        partition.set(key.as_bytes(), key.as_bytes()).unwrap();
+                    }
+                });
+            }
+        });
+        partition
+    }
+
+    #[test]
+    fn test_group_commit_throughput() {
+        let dir = TempDir::new().unwrap();
+        let datastore = DataStore::new(dir.path().to_str().unwrap()).unwrap();
+        datastore.set_fsync_policy(FsyncPolicy::Always);
+        let writes = WRITERS * WRITES_PER_WRITER;
+
+        let ungrouped = concurrent_sets(&datastore, GroupCommit::default());
+        assert_eq!(ungrouped.group_commits(), 0);
+
+        let grouped_config = GroupCommit {
+            window: Duration::from_millis(1),
+            max_writes: WRITERS,
+        };
+        let grouped = concurrent_sets(&datastore, grouped_config);
+        let groups = grouped.group_commits();
+        assert!(groups > 0 && groups < writes as u64, "{} groups", groups);
+
+        for partition in [&ungrouped, &grouped] {
+            assert_eq!(partition.stats().sets, writes as u64);
+            for writer in 0..WRITERS {
+                let key = format!("{}:{}", writer, WRITES_PER_WRITER - 1);
+                assert_eq!(
+                    partition.get(key.as_bytes()).unwrap(),
+                    Some(key.into_bytes())
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_group_applies_writes_in_order() {
+        let dir = TempDir::new().unwrap();
+        let datastore = DataStore::new(dir.path().to_str().unwrap()).unwrap();
+        datastore.set_group_commit(GroupCommit {
+            window: Duration::from_millis(1),
+            max_writes: 16,
+        });
+        let partition = datastore.partition("test_partition").unwrap();
+        partition.set(b"key", b"old").unwrap();
+
+        let existed = partition
+            .commit_group(&[
+                GroupedWrite::Delete(b"key".to_vec()),
+                GroupedWrite::Delete(b"key".to_vec()),
+                GroupedWrite::Set {
+                    key: b"key".to_vec(),
+                    value: b"new".to_vec(),
+                    deadline_ms: None,
+                },
+                GroupedWrite::Delete(b"missing".to_vec()),
+            ])
+            .unwrap();
+        assert_eq!(existed, [Some(true), Some(false), Some(false), Some(false)]);
+        assert_eq!(partition.get(b"key").unwrap(), Some(b"new".to_vec()));
+
+        assert!(partition.delete(b"key").unwrap());
+        assert!(!partition.delete(b"key").unwrap());
+    }
+}
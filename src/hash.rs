@@ -0,0 +1,511 @@
+use crate::datastore::member_key;
+use crate::value::{format_float, parse_float, StoredValue, ValueKind};
+use crate::{DataStoreError, DataStorePartition, KeyValue};
+
+// One call's worth of HSCAN
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct HashScanPage {
+    pub fields: Vec<KeyValue>,
+    // The last field examined, to carry on after; None once the scan is done
+    pub next: Option<Vec<u8>>,
+}
+
+// A hash is a container header in the partition plus one entry per field in
+// the members partition, holding the field's value.
+impl DataStorePartition {
+    // Sets each field to its value, creating the hash if needed, and returns
+    // how many fields were newly created. All fields are written in one batch.
+    pub fn hash_set(&self, key: &[u8], fields: &[(&[u8], &[u8])]) -> Result<usize, DataStoreError> {
+        if fields.is_empty() {
+            return Ok(0);
+        }
+        let _guard = self.lock_key(key);
+        let current = self.read_for_update(key)?;
+        let (expires_at, len) = hash_header(current.as_ref())?;
+
+        let mut batch = self.batch();
+        let mut created: Vec<&[u8]> = Vec::new();
+        for &(field, value) in fields {
+            let composite = member_key(key, field);
+            if !created.contains(&field) && !self.members_handle().contains_key(&composite)? {
+                created.push(field);
+            }
+            batch.insert(self.members_handle(), composite, value);
+        }
+        let updated = hash_value(expires_at, len + created.len() as u64);
+        self.commit_update(batch, key, current.as_ref(), Some(&updated))?;
+        Ok(created.len())
+    }
+
+    // Removes each of `fields` and returns how many existed, deleting the hash
+    // with its last field. All removals are committed in one batch, so a
+    // failure leaves every field in place.
+    pub fn hash_delete(&self, key: &[u8], fields: &[&[u8]]) -> Result<usize, DataStoreError> {
+        let _guard = self.lock_key(key);
+        let current = self.read_for_update(key)?;
+        let (expires_at, len) = hash_header(current.as_ref())?;
+        if current.is_none() {
+            return Ok(0);
+        }
+
+        let mut batch = self.batch();
+        let mut removed: Vec<&[u8]> = Vec::new();
+        for &field in fields {
+            let composite = member_key(key, field);
+            if removed.contains(&field) || !self.members_handle().contains_key(&composite)? {
+                continue;
+            }
+            batch.remove(self.members_handle(), composite);
+            removed.push(field);
+        }
+        if removed.is_empty() {
+            return Ok(0);
+        }
+        let remaining = len.saturating_sub(removed.len() as u64);
+        let updated = (remaining > 0).then(|| hash_value(expires_at, remaining));
+        self.commit_update(batch, key, current.as_ref(), updated.as_ref())?;
+        Ok(removed.len())
+    }
+
+    pub fn hash_get(&self, key: &[u8], field: &[u8]) -> Result<Option<Vec<u8>>, DataStoreError> {
+        Ok(self.hash_get_many(key, &[field])?.remove(0))
+    }
+
+    // Values of each of `fields`, in order, None for absent fields. A missing
+    // key is an empty hash.
+    pub fn hash_get_many(
+        &self,
+        key: &[u8],
+        fields: &[&[u8]],
+    ) -> Result<Vec<Option<Vec<u8>>>, DataStoreError> {
+        if self.hash_len(key)? == 0 {
+            return Ok(vec![None; fields.len()]);
+        }
+        fields
+            .iter()
+            .map(|field| {
+                Ok(self
+                    .members_handle()
+                    .get(member_key(key, field))?
+                    .map(|value| value.to_vec()))
+            })
+            .collect()
+    }
+
+    pub fn hash_len(&self, key: &[u8]) -> Result<u64, DataStoreError> {
+        let current = self.get_stored(key)?;
+        hash_header(current.as_ref()).map(|(_, len)| len)
+    }
+
+    // Examines up to `count` fields of the hash in byte order, starting after
+    // the field `after`, and returns those matching `pattern` with their
+    // values. As the position is a field rather than an offset, fields
+    // present throughout a scan are returned exactly once whatever is written
+    // meanwhile. A missing key is an empty hash.
+    pub fn hash_scan(
+        &self,
+        key: &[u8],
+        after: Option<&[u8]>,
+        count: usize,
+        pattern: Option<&[u8]>,
+    ) -> Result<HashScanPage, DataStoreError> {
+        if self.hash_len(key)? == 0 {
+            return Ok(HashScanPage::default());
+        }
+        let (fields, next) = self.scan_members(key, after, count, pattern)?;
+        Ok(HashScanPage { fields, next })
+    }
+
+    // Atomically adds `delta` to the integer stored in a hash field, creating
+    // the field (and hash) as 0 if absent
+    pub fn hash_incr_by(
+        &self,
+        key: &[u8],
+        field: &[u8],
+        delta: i64,
+    ) -> Result<i64, DataStoreError> {
+        self.update_field(key, field, |current| {
+            let value = match current {
+                Some(bytes) => std::str::from_utf8(&bytes)
+                    .ok()
+                    .and_then(|s| s.parse::<i64>().ok())
+                    .ok_or(DataStoreError::HashValueNotAnInteger)?,
+                None => 0,
+            };
+            let updated = value.checked_add(delta).ok_or(DataStoreError::Overflow)?;
+            Ok((updated.to_string().into_bytes(), updated))
+        })
+    }
+
+    // Float counterpart of `hash_incr_by`. Returns the new value as stored,
+    // formatted without exponent or trailing zeros.
+    pub fn hash_incr_by_float(
+        &self,
+        key: &[u8],
+        field: &[u8],
+        delta: f64,
+    ) -> Result<Vec<u8>, DataStoreError> {
+        self.update_field(key, field, |current| {
+            let value = match current {
+                Some(bytes) => parse_float(&bytes).ok_or(DataStoreError::HashValueNotAFloat)?,
+                None => 0.0,
+            };
+            let updated = value + delta;
+            if !updated.is_finite() {
+                return Err(DataStoreError::NanOrInfinity);
+            }
+            let formatted = format_float(updated).into_bytes();
+            Ok((formatted.clone(), formatted))
+        })
+    }
+
+    // Read-modify-write of a single hash field under the key lock. `f`
+    // receives the field's current value and returns the value to store
+    // together with the operation's result.
+    fn update_field<T, F>(&self, key: &[u8], field: &[u8], f: F) -> Result<T, DataStoreError>
+    where
+        F: FnOnce(Option<Vec<u8>>) -> Result<(Vec<u8>, T), DataStoreError>,
+    {
+        let _guard = self.lock_key(key);
+        let current = self.read_for_update(key)?;
+        let (expires_at, len) = hash_header(current.as_ref())?;
+
+        let composite = member_key(key, field);
+        let existing = match current {
+            Some(_) => self.members_handle().get(&composite)?.map(|v| v.to_vec()),
+            None => None,
+        };
+        let is_new = existing.is_none();
+        let (value, result) = f(existing)?;
+
+        let mut batch = self.batch();
+        batch.insert(self.members_handle(), composite, value);
+        let updated = hash_value(expires_at, len + is_new as u64);
+        self.commit_update(batch, key, current.as_ref(), Some(&updated))?;
+        Ok(result)
+    }
+}
+
+// Splits a stored hash header into its expiry and field count, treating a
+// missing key as an empty hash
+fn hash_header(stored: Option<&StoredValue>) -> Result<(Option<u64>, u64), DataStoreError> {
+    match stored {
+        None => Ok((None, 0)),
+        Some(stored) if stored.kind != ValueKind::Hash => Err(DataStoreError::WrongType),
+        Some(stored) => Ok((stored.expires_at, stored.container_len()?)),
+    }
+}
+
+fn hash_value(expires_at: Option<u64>, len: u64) -> StoredValue {
+    StoredValue::container(ValueKind::Hash, expires_at, len)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::DataStore;
+    use tempfile::TempDir;
+
+    fn create_test_store() -> (TempDir, DataStorePartition) {
+        let temp_dir = TempDir::new().expect("Failed to create temp dir");
+        let data_store = DataStore::new(temp_dir.path().to_str().unwrap()).unwrap();
+        let partition = data_store.partition("hashes").unwrap();
+        (temp_dir, partition)
+    }
+
+    #[test]
+    fn test_set_get_fields() {
+        let (_dir, partition) = create_test_store();
+        assert_eq!(
+            partition
+                .hash_set(b"hash", &[(b"a", b"1"), (b"b", b"2"), (b"a", b"3")])
+                .unwrap(),
+            2
+        );
+        assert_eq!(partition.hash_set(b"hash", &[(b"b", b"4")]).unwrap(), 0);
+        assert_eq!(partition.hash_len(b"hash").unwrap(), 2);
+        assert_eq!(
+            partition.hash_get(b"hash", b"a").unwrap(),
+            Some(b"3".to_vec())
+        );
+        assert_eq!(
+            partition.hash_get(b"hash", b"b").unwrap(),
+            Some(b"4".to_vec())
+        );
+        assert_eq!(partition.hash_get(b"hash", b"z").unwrap(), None);
+        assert_eq!(partition.key_type(b"hash").unwrap(), Some(ValueKind::Hash));
+    }
+
+    #[test]
+    fn test_delete_fields() {
+        let temp_dir = TempDir::new().expect("Failed to create temp dir");
+        let path = temp_dir.path().to_str().unwrap();
+        {
+            let data_store = DataStore::new(path).unwrap();
+            let partition = data_store.partition("hashes").unwrap();
+            partition
+                .hash_set(b"hash", &[(b"a", b"1"), (b"b", b"2"), (b"c", b"3")])
+                .unwrap();
+            partition.hash_set(b"kept", &[(b"x", b"1")]).unwrap();
+            assert_eq!(
+                partition
+                    .hash_delete(b"hash", &[b"a", b"missing", b"b", b"a"])
+                    .unwrap(),
+                2
+            );
+            assert_eq!(partition.hash_len(b"hash").unwrap(), 1);
+            assert_eq!(
+                partition
+                    .hash_get_many(b"hash", &[b"a", b"b", b"c"])
+                    .unwrap(),
+                vec![None, None, Some(b"3".to_vec())]
+            );
+
+            // Removing the last field removes the hash
+            assert_eq!(partition.hash_delete(b"hash", &[b"c"]).unwrap(), 1);
+            assert!(!partition.exists(b"hash").unwrap());
+            assert_eq!(partition.key_type(b"hash").unwrap(), None);
+            assert_eq!(partition.hash_delete(b"hash", &[b"c"]).unwrap(), 0);
+
+            partition.set(b"string", b"value").unwrap();
+            assert!(matches!(
+                partition.hash_delete(b"string", &[b"a"]),
+                Err(DataStoreError::WrongType)
+            ));
+            data_store.close().unwrap();
+        }
+
+        // A failed commit leaves every field in place
+        let data_store = DataStore::open_read_only(path).unwrap();
+        let partition = data_store.partition("hashes").unwrap();
+        assert!(partition.hash_delete(b"kept", &[b"x"]).is_err());
+        assert_eq!(
+            partition.hash_get(b"kept", b"x").unwrap(),
+            Some(b"1".to_vec())
+        );
+    }
+
+    #[test]
+    fn test_incr_by_creates_missing() {
+        let (_dir, partition) = create_test_store();
+        assert_eq!(partition.hash_incr_by(b"hash", b"count", 5).unwrap(), 5);
+        assert_eq!(partition.hash_incr_by(b"hash", b"count", -7).unwrap(), -2);
+        assert_eq!(partition.hash_incr_by(b"hash", b"other", 1).unwrap(), 1);
+        assert_eq!(partition.hash_len(b"hash").unwrap(), 2);
+        assert_eq!(
+            partition.hash_get(b"hash", b"count").unwrap(),
+            Some(b"-2".to_vec())
+        );
+
+        partition.hash_set(b"hash", &[(b"text", b"abc")]).unwrap();
+        assert!(matches!(
+            partition.hash_incr_by(b"hash", b"text", 1),
+            Err(DataStoreError::HashValueNotAnInteger)
+        ));
+        partition.set(b"string", b"1").unwrap();
+        assert!(matches!(
+            partition.hash_incr_by(b"string", b"field", 1),
+            Err(DataStoreError::WrongType)
+        ));
+    }
+
+    #[test]
+    fn test_incr_by_rejects_overflow() {
+        let (_dir, partition) = create_test_store();
+        partition.hash_incr_by(b"hash", b"count", i64::MAX).unwrap();
+        assert!(matches!(
+            partition.hash_incr_by(b"hash", b"count", 1),
+            Err(DataStoreError::Overflow)
+        ));
+        // The failed increment left the field untouched
+        assert_eq!(
+            partition.hash_get(b"hash", b"count").unwrap(),
+            Some(i64::MAX.to_string().into_bytes())
+        );
+    }
+
+    #[test]
+    fn test_incr_by_float_formatting() {
+        let (_dir, partition) = create_test_store();
+        partition
+            .hash_set(b"hash", &[(b"price", b"10.50")])
+            .unwrap();
+        assert_eq!(
+            partition
+                .hash_incr_by_float(b"hash", b"price", 0.1)
+                .unwrap(),
+            b"10.6"
+        );
+        assert_eq!(
+            partition
+                .hash_incr_by_float(b"hash", b"price", -5.6)
+                .unwrap(),
+            b"5"
+        );
+        assert_eq!(
+            partition
+                .hash_incr_by_float(b"hash", b"new", 2.5e3)
+                .unwrap(),
+            b"2500"
+        );
+
+        partition.hash_set(b"hash", &[(b"text", b"abc")]).unwrap();
+        assert!(matches!(
+            partition.hash_incr_by_float(b"hash", b"text", 1.0),
+            Err(DataStoreError::HashValueNotAFloat)
+        ));
+        let max = format_float(f64::MAX);
+        partition
+            .hash_set(b"hash", &[(b"max", max.as_bytes())])
+            .unwrap();
+        assert!(matches!(
+            partition.hash_incr_by_float(b"hash", b"max", f64::MAX),
+            Err(DataStoreError::NanOrInfinity)
+        ));
+    }
+
+    #[test]
+    fn test_get_many_partially_present() {
+        let (_dir, partition) = create_test_store();
+        partition
+            .hash_set(b"hash", &[(b"a", b"1"), (b"c", b"3")])
+            .unwrap();
+        assert_eq!(
+            partition
+                .hash_get_many(b"hash", &[b"a", b"b", b"c"])
+                .unwrap(),
+            vec![Some(b"1".to_vec()), None, Some(b"3".to_vec())]
+        );
+        assert_eq!(
+            partition.hash_get_many(b"missing", &[b"a", b"b"]).unwrap(),
+            vec![None, None]
+        );
+
+        partition.set(b"string", b"value").unwrap();
+        assert!(matches!(
+            partition.hash_get_many(b"string", &[b"a"]),
+            Err(DataStoreError::WrongType)
+        ));
+    }
+
+    #[test]
+    fn test_set_many_is_atomic() {
+        let (_dir, partition) = create_test_store();
+        partition
+            .hash_set(b"hash", &[(b"a", b"1"), (b"b", b"2"), (b"c", b"3")])
+            .unwrap();
+        assert_eq!(partition.hash_len(b"hash").unwrap(), 3);
+        assert_eq!(
+            partition
+                .hash_get_many(b"hash", &[b"a", b"b", b"c"])
+                .unwrap(),
+            vec![
+                Some(b"1".to_vec()),
+                Some(b"2".to_vec()),
+                Some(b"3".to_vec())
+            ]
+        );
+
+        // A rejected write leaves no field behind
+        partition.set(b"string", b"value").unwrap();
+        assert!(matches!(
+            partition.hash_set(b"string", &[(b"a", b"1"), (b"b", b"2")]),
+            Err(DataStoreError::WrongType)
+        ));
+        assert_eq!(partition.get(b"string").unwrap(), Some(b"value".to_vec()));
+        partition.delete(b"string").unwrap();
+        assert_eq!(
+            partition.hash_get_many(b"string", &[b"a", b"b"]).unwrap(),
+            vec![None, None]
+        );
+    }
+
+    #[test]
+    fn test_scan_pages_through_fields() {
+        let (_dir, partition) = create_test_store();
+        let fields: Vec<String> = (0..250).map(|i| format!("field:{:03}", i)).collect();
+        let pairs: Vec<(&[u8], &[u8])> = fields
+            .iter()
+            .map(|field| (field.as_bytes(), field.as_bytes()))
+            .collect();
+        partition.hash_set(b"hash", &pairs).unwrap();
+        // Keys sharing a prefix with the hash's, whose fields must not leak in
+        partition.hash_set(b"hash2", &[(b"other", b"x")]).unwrap();
+        partition.hash_set(b"has", &[(b"other", b"x")]).unwrap();
+
+        let mut seen = Vec::new();
+        let mut after = None;
+        let mut pages = 0;
+        loop {
+            let page = partition
+                .hash_scan(b"hash", after.as_deref(), 20, None)
+                .unwrap();
+            pages += 1;
+            if pages == 3 {
+                // Fields written mid-scan don't disturb it
+                partition
+                    .hash_set(b"hash", &[(b"field:000a", b"new"), (b"zzz", b"new")])
+                    .unwrap();
+                partition.hash_delete(b"hash", &[b"field:001"]).unwrap();
+            }
+            seen.extend(page.fields);
+            match page.next {
+                Some(next) => after = Some(next),
+                None => break,
+            }
+        }
+        let seen_fields: Vec<&[u8]> = seen.iter().map(|(field, _)| field.as_slice()).collect();
+        for field in &fields {
+            assert_eq!(
+                seen_fields
+                    .iter()
+                    .filter(|seen| **seen == field.as_bytes())
+                    .count(),
+                1
+            );
+        }
+        assert!(seen_fields.contains(&&b"zzz"[..]));
+        assert!(!seen_fields.contains(&&b"other"[..]));
+
+        let page = partition
+            .hash_scan(b"hash", None, 1000, Some(b"field:24?"))
+            .unwrap();
+        assert_eq!(page.fields.len(), 10);
+        assert_eq!(page.next, None);
+        assert_eq!(
+            partition.hash_scan(b"missing", None, 10, None).unwrap(),
+            HashScanPage::default()
+        );
+        partition.set(b"string", b"value").unwrap();
+        assert!(matches!(
+            partition.hash_scan(b"string", None, 10, None),
+            Err(DataStoreError::WrongType)
+        ));
+    }
+
+    #[test]
+    fn test_fields_with_separator_bytes() {
+        let (_dir, partition) = create_test_store();
+        let fields: [(&[u8], &[u8]); 4] = [
+            (b"a\x00b", b"1"),
+            (b"a", b"2"),
+            (b"\x00", b"3"),
+            (b"\x00\x00\x00\x01", b"4"),
+        ];
+        assert_eq!(partition.hash_set(b"h\x00", &fields).unwrap(), 4);
+        // A key that would share a separator-delimited prefix with "h\0"
+        partition.hash_set(b"h", &[(b"\x00a", b"other")]).unwrap();
+        for (field, value) in fields {
+            assert_eq!(
+                partition.hash_get(b"h\x00", field).unwrap(),
+                Some(value.to_vec())
+            );
+        }
+        assert_eq!(partition.hash_len(b"h\x00").unwrap(), 4);
+        assert_eq!(
+            partition.hash_get(b"h", b"\x00a").unwrap(),
+            Some(b"other".to_vec())
+        );
+        assert_eq!(partition.hash_get(b"h", b"a").unwrap(), None);
+    }
+}
@@ -0,0 +1,128 @@
+use crate::reply;
+use redis_protocol::resp2::types::BytesFrame;
+
+// Subcommands of the container commands, as usage and description, listed
+// by `<command> HELP`
+const SUBCOMMANDS: &[(&str, &[(&str, &str)])] = &[
+    (
+        "OBJECT",
+        &[
+            (
+                "ENCODING <key>",
+                "Return the kind of internal representation used in order to store the value associated with a <key>.",
+            ),
+            (
+                "FREQ <key>",
+                "Return the access frequency index of the <key>. The returned integer is proportional to the logarithm of the recent access frequency of the key.",
+            ),
+            (
+                "IDLETIME <key>",
+                "Return the idle time of the <key>, that is the approximated number of seconds elapsed since the last access to the key.",
+            ),
+        ],
+    ),
+    (
+        "MEMORY",
+        &[(
+            "USAGE <key> [SAMPLES <count>]",
+            "Return memory in bytes used by <key> and its value. Nested values are sampled up to <count> times (default: 5).",
+        )],
+    ),
+    (
+        "CONFIG",
+        &[
+            (
+                "GET <pattern>",
+                "Return parameters matching the glob-like <pattern> and their values.",
+            ),
+            ("SET <parameter> <value>", "Set the configuration <parameter> to <value>."),
+        ],
+    ),
+    (
+        "DEBUG",
+        &[
+            (
+                "BIGKEYS [COUNT <count>]",
+                "Report the biggest key of every type among the first <count> keys (default: 100000).",
+            ),
+            ("ERROR <string>", "Return a Redis protocol error with <string> as message."),
+            ("LOG <message>", "Write <message> to the server log."),
+            (
+                "OBJECT <key>",
+                "Show low level info about the <key> and associated value.",
+            ),
+            (
+                "RELOAD",
+                "Sync the journal and seal the memtables, so reads are served from disk segments.",
+            ),
+            (
+                "VERIFY",
+                "Decode every stored value and report entries, bytes and corrupted keys.",
+            ),
+            ("CHANGE-REPL-ID", "Replace the run id INFO reports with a new random one."),
+            (
+                "JMAP | SET-ACTIVE-EXPIRE <0|1>",
+                "Accepted for compatibility, without effect.",
+            ),
+        ],
+    ),
+    (
+        "CLUSTER",
+        &[("KEYSLOT <key>", "Return the hash slot for <key>.")],
+    ),
+    (
+        "CLIENT",
+        &[
+            ("INFO", "Return information about the current client connection."),
+            ("LIST", "Return information about client connections."),
+            (
+                "SETREQUESTID <id>",
+                "Tag the connection's audit log entries with <id>, an empty one clearing it.",
+            ),
+            ("GETREQUESTID", "Return the request id set with SETREQUESTID."),
+            (
+                "COMPRESSION LZ4 [<min-size>]|OFF",
+                "Tag GET and MGET values, LZ4 compressing those of at least <min-size> bytes (non-standard).",
+            ),
+        ],
+    ),
+    (
+        "ACL",
+        &[("WHOAMI", "Return the current connection username.")],
+    ),
+];
+
+// The reply to `<cmd> HELP`, None for commands without subcommands. As in
+// Redis, an array of lines: a synopsis, then every subcommand's usage
+// followed by its indented description.
+pub(crate) fn help(cmd: &str) -> Option<BytesFrame> {
+    let (cmd, subcommands) = SUBCOMMANDS.iter().find(|(name, _)| *name == cmd)?;
+    let mut lines = vec![format!(
+        "{} <subcommand> [<arg> [value] [opt] ...]. Subcommands are:",
+        cmd
+    )];
+    let help = [("HELP", "Print this help.")];
+    for (usage, description) in subcommands.iter().chain(&help) {
+        lines.push(usage.to_string());
+        lines.push(format!("    {}", description));
+    }
+    Some(reply::array(lines.into_iter().map(reply::bulk)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_object_help() {
+        let Some(BytesFrame::Array(lines)) = help("OBJECT") else {
+            panic!("OBJECT has subcommands");
+        };
+        assert_eq!(lines.len(), 1 + 2 * 4);
+        assert!(lines
+            .iter()
+            .all(|line| matches!(line, BytesFrame::BulkString(line) if !line.is_empty())));
+        assert_eq!(lines[1], BytesFrame::BulkString("ENCODING <key>".into()));
+        assert!(help("GET").is_none());
+    }
+}
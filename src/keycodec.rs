@@ -0,0 +1,81 @@
+// Composite keys made of several byte strings, such as a container key and
+// one of its members in the members partition. Every component but the last
+// is prefixed with its length as a 4-byte big-endian integer; the last runs
+// to the end. Components can hold any bytes, NUL and other would-be
+// separators included, and no two component lists encode the same way.
+// Composites starting with the same leading components share the encoding
+// of those components as a prefix, which no other composite starts with.
+
+const LEN_SIZE: usize = 4;
+
+pub(crate) fn encode_composite(components: &[&[u8]]) -> Vec<u8> {
+    let Some((last, leading)) = components.split_last() else {
+        return Vec::new();
+    };
+    let mut composite = composite_prefix(leading);
+    composite.extend_from_slice(last);
+    composite
+}
+
+// The prefix shared by every composite starting with `leading`
+pub(crate) fn composite_prefix(leading: &[&[u8]]) -> Vec<u8> {
+    let len = leading.iter().map(|c| LEN_SIZE + c.len()).sum();
+    let mut prefix = Vec::with_capacity(len);
+    for component in leading {
+        prefix.extend_from_slice(&(component.len() as u32).to_be_bytes());
+        prefix.extend_from_slice(component);
+    }
+    prefix
+}
+
+// Splits a composite of `count` components, None if it isn't one
+pub(crate) fn decode_composite(mut composite: &[u8], count: usize) -> Option<Vec<&[u8]>> {
+    let mut components = Vec::with_capacity(count);
+    for _ in 1..count {
+        let len = u32::from_be_bytes(composite.get(..LEN_SIZE)?.try_into().ok()?) as usize;
+        let rest = &composite[LEN_SIZE..];
+        components.push(rest.get(..len)?);
+        composite = &rest[len..];
+    }
+    if count > 0 {
+        components.push(composite);
+    }
+    Some(components)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip_with_separator_bytes() {
+        let cases: [&[&[u8]]; 5] = [
+            &[b"key", b"field"],
+            &[b"k\x00ey", b"\x00field\x00"],
+            &[b"", b""],
+            &[b"\x00\x00\x00\x05", b":\xff\r\n"],
+            &[b"a", b"b\x00c", b"\x00"],
+        ];
+        for components in cases {
+            let composite = encode_composite(components);
+            assert_eq!(
+                decode_composite(&composite, components.len()).unwrap(),
+                components
+            );
+            assert!(composite.starts_with(&composite_prefix(&components[..1])));
+        }
+    }
+
+    #[test]
+    fn test_no_collisions() {
+        // Separator-delimited encodings would give these the same bytes
+        let a = encode_composite(&[b"user\x00", b"name"]);
+        let b = encode_composite(&[b"user", b"\x00name"]);
+        assert_ne!(a, b);
+        assert!(!b.starts_with(&composite_prefix(&[b"user\x00"])));
+        assert!(!a.starts_with(&composite_prefix(&[b"user"])));
+
+        assert_eq!(decode_composite(b"\x00\x00\x00\x09short", 2), None);
+        assert_eq!(decode_composite(b"\x00\x00", 2), None);
+    }
+}
@@ -0,0 +1,64 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::sync::{Mutex, MutexGuard};
+
+const SHARD_COUNT: usize = 64;
+
+// Striped locks serializing read-modify-write operations per key. Keys hash
+// onto a fixed number of shards, so unrelated keys occasionally share a lock,
+// which is harmless as long as an operation holds one shard, or takes several
+// through `lock_pair`.
+pub(crate) struct KeyLocks {
+    shards: Vec<Mutex<()>>,
+}
+
+impl KeyLocks {
+    pub(crate) fn new() -> Self {
+        KeyLocks {
+            shards: (0..SHARD_COUNT).map(|_| Mutex::new(())).collect(),
+        }
+    }
+
+    pub(crate) fn lock(&self, key: &[u8]) -> MutexGuard<'_, ()> {
+        self.lock_shard(shard_index(key))
+    }
+
+    // Locks the shards of two keys. Shards are always taken in index order,
+    // so two pair operations can't deadlock each other, and only once when
+    // both keys share a shard.
+    pub(crate) fn lock_pair(&self, a: &[u8], b: &[u8]) -> Vec<MutexGuard<'_, ()>> {
+        let (a, b) = (shard_index(a), shard_index(b));
+        if a == b {
+            return vec![self.lock_shard(a)];
+        }
+        let first = self.lock_shard(a.min(b));
+        vec![first, self.lock_shard(a.max(b))]
+    }
+
+    // Locks the shards of any number of keys, in index order like `lock_pair`
+    pub(crate) fn lock_many<'a>(
+        &self,
+        keys: impl IntoIterator<Item = &'a [u8]>,
+    ) -> Vec<MutexGuard<'_, ()>> {
+        let mut shards: Vec<usize> = keys.into_iter().map(shard_index).collect();
+        shards.sort_unstable();
+        shards.dedup();
+        shards
+            .into_iter()
+            .map(|index| self.lock_shard(index))
+            .collect()
+    }
+
+    fn lock_shard(&self, index: usize) -> MutexGuard<'_, ()> {
+        // The guarded data is `()`, so a poisoned lock carries no broken state
+        self.shards[index]
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+fn shard_index(key: &[u8]) -> usize {
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    hasher.finish() as usize % SHARD_COUNT
+}
@@ -0,0 +1,85 @@
+// Redis Cluster compatible key slot computation: CRC16 (XMODEM) of the key,
+// or of its hash tag when present, modulo 16384.
+
+pub const SLOT_COUNT: u16 = 16384;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct KeySlot(u16);
+
+impl KeySlot {
+    pub fn for_key(key: &[u8]) -> Self {
+        KeySlot(crc16(hash_tag(key)) % SLOT_COUNT)
+    }
+
+    pub fn value(&self) -> u16 {
+        self.0
+    }
+}
+
+/// CRC16-CCITT (XMODEM variant), the checksum used by Redis Cluster.
+pub fn crc16(data: &[u8]) -> u16 {
+    let mut crc: u16 = 0;
+    for &byte in data {
+        crc ^= (byte as u16) << 8;
+        for _ in 0..8 {
+            if crc & 0x8000 != 0 {
+                crc = (crc << 1) ^ 0x1021;
+            } else {
+                crc <<= 1;
+            }
+        }
+    }
+    crc
+}
+
+/// Returns the part of the key that is hashed. If the key contains a `{`
+/// followed later by a `}` with at least one byte in between, only the bytes
+/// between the first `{` and the first `}` after it are hashed.
+pub fn hash_tag(key: &[u8]) -> &[u8] {
+    if let Some(open) = key.iter().position(|&b| b == b'{') {
+        if let Some(len) = key[open + 1..].iter().position(|&b| b == b'}') {
+            if len > 0 {
+                return &key[open + 1..open + 1 + len];
+            }
+        }
+    }
+    key
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_crc16_vectors() {
+        assert_eq!(crc16(b""), 0x0000);
+        assert_eq!(crc16(b"123456789"), 0x31C3);
+        assert_eq!(crc16(b"A"), 0x58E5);
+    }
+
+    #[test]
+    fn test_key_slots() {
+        assert_eq!(KeySlot::for_key(b"123456789").value(), 12739);
+        assert_eq!(KeySlot::for_key(b"somekey").value(), 11058);
+        assert_eq!(KeySlot::for_key(b"foo").value(), 12182);
+        assert_eq!(KeySlot::for_key(b"bar").value(), 5061);
+        assert_eq!(KeySlot::for_key(b"foo{hash_tag}").value(), 2515);
+    }
+
+    #[test]
+    fn test_hash_tags() {
+        assert_eq!(hash_tag(b"{user1000}.following"), b"user1000");
+        assert_eq!(hash_tag(b"foo{bar}{zap}"), b"bar");
+        assert_eq!(hash_tag(b"foo{{bar}}zap"), b"{bar");
+        // Empty tag: the whole key is hashed
+        assert_eq!(hash_tag(b"foo{}{bar}"), b"foo{}{bar}");
+        // Unterminated tag: the whole key is hashed
+        assert_eq!(hash_tag(b"foo{bar"), b"foo{bar");
+        assert_eq!(hash_tag(b"plainkey"), b"plainkey");
+
+        assert_eq!(
+            KeySlot::for_key(b"{user1000}.following"),
+            KeySlot::for_key(b"{user1000}.followers")
+        );
+    }
+}
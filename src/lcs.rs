@@ -0,0 +1,104 @@
+// Largest (len1 + 1) * (len2 + 1) table LCS computes, bounding the memory
+// (4 bytes a cell) and time a single command can take
+pub const DEFAULT_LCS_MAX_CELLS: usize = 16 * 1024 * 1024;
+
+// A run of the common subsequence found contiguous in both strings, as
+// inclusive index ranges
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct LcsMatch {
+    pub a: (usize, usize),
+    pub b: (usize, usize),
+}
+
+impl LcsMatch {
+    pub fn len(&self) -> usize {
+        self.a.1 - self.a.0 + 1
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct Lcs {
+    pub sequence: Vec<u8>,
+    // From the end of the strings backwards, as Redis reports them
+    pub matches: Vec<LcsMatch>,
+}
+
+// Longest common subsequence of `a` and `b` by dynamic programming, picking
+// the same subsequence as Redis' LCS when several are as long
+pub(crate) fn lcs(a: &[u8], b: &[u8]) -> Lcs {
+    let width = b.len() + 1;
+    // table[i * width + j]: LCS length of a[..i] and b[..j]
+    let mut table = vec![0u32; (a.len() + 1) * width];
+    for i in 1..=a.len() {
+        for j in 1..=b.len() {
+            table[i * width + j] = if a[i - 1] == b[j - 1] {
+                table[(i - 1) * width + j - 1] + 1
+            } else {
+                table[(i - 1) * width + j].max(table[i * width + j - 1])
+            };
+        }
+    }
+
+    let mut result = Lcs::default();
+    let mut current: Option<LcsMatch> = None;
+    let (mut i, mut j) = (a.len(), b.len());
+    while i > 0 && j > 0 {
+        if a[i - 1] == b[j - 1] {
+            result.sequence.push(a[i - 1]);
+            // Walking backwards, a match extends the run at its start
+            match &mut current {
+                Some(run) => {
+                    run.a.0 = i - 1;
+                    run.b.0 = j - 1;
+                }
+                None => {
+                    current = Some(LcsMatch {
+                        a: (i - 1, i - 1),
+                        b: (j - 1, j - 1),
+                    })
+                }
+            }
+            i -= 1;
+            j -= 1;
+        } else {
+            if table[(i - 1) * width + j] > table[i * width + j - 1] {
+                i -= 1;
+            } else {
+                j -= 1;
+            }
+            result.matches.extend(current.take());
+        }
+    }
+    result.matches.extend(current);
+    result.sequence.reverse();
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lcs() {
+        let result = lcs(b"ohmytext", b"mynewtext");
+        assert_eq!(result.sequence, b"mytext");
+        assert_eq!(
+            result.matches,
+            vec![
+                LcsMatch {
+                    a: (4, 7),
+                    b: (5, 8)
+                },
+                LcsMatch {
+                    a: (2, 3),
+                    b: (0, 1)
+                },
+            ]
+        );
+        assert_eq!(result.matches[0].len(), 4);
+
+        assert_eq!(lcs(b"", b"abc"), Lcs::default());
+        assert_eq!(lcs(b"abc", b"xyz").sequence, b"");
+        assert_eq!(lcs(b"same", b"same").matches.len(), 1);
+    }
+}
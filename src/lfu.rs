@@ -0,0 +1,100 @@
+use crate::value::StoredValue;
+
+// Counter given to new keys, so they are not evicted before they had a chance
+// to be accessed again
+pub const LFU_INIT_VAL: u8 = 5;
+
+pub const DEFAULT_LFU_LOG_FACTOR: u8 = 10;
+pub const DEFAULT_LFU_DECAY_SECS: u32 = 60;
+
+// Approximate access frequency as a logarithmic 8-bit counter, following
+// Redis' LFU. Every access increments the counter with probability
+// 1 / ((counter - LFU_INIT_VAL) * log_factor + 1), so with the default factor
+// of 10 it saturates at 255 after roughly a million accesses. The counter
+// loses one point for every `decay_secs` the key goes untouched; a decay of 0
+// disables decay.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Lfu {
+    pub(crate) log_factor: u8,
+    pub(crate) decay_secs: u32,
+}
+
+impl Lfu {
+    // The stored counter minus the decay accumulated since the last access
+    pub(crate) fn decayed(&self, stored: &StoredValue, now_secs: u32) -> u8 {
+        if self.decay_secs == 0 {
+            return stored.freq;
+        }
+        let periods = stored.idle_secs(now_secs) / self.decay_secs as u64;
+        stored
+            .freq
+            .saturating_sub(periods.min(u8::MAX as u64) as u8)
+    }
+
+    // The counter after one more access to `stored`
+    pub(crate) fn access(&self, stored: &StoredValue, now_secs: u32) -> u8 {
+        self.increment(self.decayed(stored, now_secs))
+    }
+
+    // Probabilistically counts one access
+    pub(crate) fn increment(&self, counter: u8) -> u8 {
+        self.increment_with(counter, rand::random::<f64>())
+    }
+
+    // `roll` is a uniform sample from [0, 1)
+    fn increment_with(&self, counter: u8, roll: f64) -> u8 {
+        if counter == u8::MAX {
+            return counter;
+        }
+        let base = counter.saturating_sub(LFU_INIT_VAL) as f64;
+        let probability = 1.0 / (base * self.log_factor as f64 + 1.0);
+        if roll < probability {
+            counter + 1
+        } else {
+            counter
+        }
+    }
+}
+
+impl Default for Lfu {
+    fn default() -> Self {
+        Lfu {
+            log_factor: DEFAULT_LFU_LOG_FACTOR,
+            decay_secs: DEFAULT_LFU_DECAY_SECS,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_increment_is_logarithmic() {
+        let lfu = Lfu::default();
+        // At or below the initial value every access counts
+        assert_eq!(lfu.increment_with(0, 0.99), 1);
+        assert_eq!(lfu.increment_with(LFU_INIT_VAL, 0.99), LFU_INIT_VAL + 1);
+        // Above it, increments get less likely: 1/11 at INIT + 1
+        assert_eq!(lfu.increment_with(LFU_INIT_VAL + 1, 0.05), LFU_INIT_VAL + 2);
+        assert_eq!(lfu.increment_with(LFU_INIT_VAL + 1, 0.1), LFU_INIT_VAL + 1);
+        assert_eq!(lfu.increment_with(u8::MAX, 0.0), u8::MAX);
+    }
+
+    #[test]
+    fn test_decay() {
+        let lfu = Lfu::default();
+        let mut stored = StoredValue::string(b"value");
+        stored.freq = 10;
+        stored.accessed_at = 1_000;
+        assert_eq!(lfu.decayed(&stored, 1_000 + 59), 10);
+        assert_eq!(lfu.decayed(&stored, 1_000 + 3 * 60), 7);
+        assert_eq!(lfu.decayed(&stored, 1_000 + 3600), 0);
+
+        let no_decay = Lfu {
+            decay_secs: 0,
+            ..Lfu::default()
+        };
+        assert_eq!(no_decay.decayed(&stored, 1_000 + 3600), 10);
+    }
+}
@@ -1,6 +1,77 @@
+mod acl;
+mod backend;
+mod backup;
+mod bigkeys;
+mod clients;
+mod compress;
+mod counter;
 mod datastore;
+mod dump;
 mod error;
+mod evict;
+mod fsync;
+mod glob;
+mod group_commit;
+mod hash;
+mod help;
+mod keycodec;
+mod keylock;
+mod keyslot;
+mod lcs;
+mod lfu;
+mod list;
+mod notify;
+mod pubsub;
+mod quota;
+mod reply;
+mod scan;
+mod server;
+mod set;
+mod sharded;
+mod stats;
+#[cfg(feature = "test-util")]
+pub mod test_util;
+mod ttl;
+mod value;
+mod waiters;
 
+pub use acl::{AclUser, Permissions};
+#[cfg(feature = "mem-backend")]
+pub use backend::MemBackend;
+pub use backend::{BatchOp, KvBackend};
+pub use backup::KeyspaceSnapshot;
+pub use bigkeys::{BigKeysReport, KindSizes, DEFAULT_BIGKEYS_SCAN_LIMIT};
+pub use compress::{decompress_value, DEFAULT_COMPRESSION_MIN_SIZE};
+pub use counter::CounterPartition;
 pub use datastore::DataStore;
+pub use datastore::DataStoreBuilder;
 pub use datastore::DataStorePartition;
+pub use datastore::Expiry;
+pub use datastore::KeyValue;
+pub use datastore::KeyspaceStats;
+pub use datastore::PartitionBuilder;
+pub use datastore::PartitionLimit;
+pub use datastore::VerifyReport;
+pub use datastore::DEFAULT_MEMORY_USAGE_SAMPLES;
+pub use datastore::RUN_ID_LEN;
 pub use error::DataStoreError;
+pub use evict::{EvictionPolicy, DEFAULT_MAXMEMORY_SAMPLES};
+pub use fsync::FsyncPolicy;
+pub use group_commit::{GroupCommit, DEFAULT_GROUP_COMMIT_MAX_WRITES};
+pub use hash::HashScanPage;
+pub use keyslot::KeySlot;
+pub use lcs::DEFAULT_LCS_MAX_CELLS;
+pub use list::ListEnd;
+pub use notify::KeyspaceEvents;
+pub use pubsub::{Message, PubSub, Subscriber};
+pub use scan::ScanPage;
+pub use server::{
+    serve, serve_all, serve_all_until, Databases, ProtoLimits, ServerContext, ShutdownSummary,
+    UnknownCommandPolicy, DEFAULT_ACCEPT_BACKOFF, DEFAULT_MAX_MULTIBULK_LEN,
+    DEFAULT_MAX_PIPELINE_DEPTH, DEFAULT_MAX_REQUEST_LEN, DEFAULT_PROTO_MAX_BULK_LEN,
+};
+pub use set::SetScanPage;
+pub use sharded::ShardedPartition;
+pub use stats::PartitionStats;
+pub use ttl::{TtlLimits, TtlPolicy};
+pub use value::{now_millis, parse_float, NumericEncoding, ValueKind};
@@ -0,0 +1,387 @@
+use crate::value::{decode_items, StoredValue, ValueKind};
+use crate::{DataStoreError, DataStorePartition};
+use std::collections::VecDeque;
+use std::time::Duration;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ListEnd {
+    Left,
+    Right,
+}
+
+// A key with the items popped from its list
+type PoppedItems = (Vec<u8>, Vec<Vec<u8>>);
+
+impl DataStorePartition {
+    // Pushes `values` one by one onto the given end of the list at `key`,
+    // creating it if needed, and returns the new length. Pushing a, b, c to
+    // the left therefore yields [c, b, a], as with LPUSH.
+    pub fn list_push(
+        &self,
+        key: &[u8],
+        values: &[&[u8]],
+        end: ListEnd,
+    ) -> Result<usize, DataStoreError> {
+        let len = self.fetch_update::<_, DataStoreError, _>(key, |current| {
+            let (expires_at, mut items) = list_items(current)?;
+            for value in values {
+                match end {
+                    ListEnd::Left => items.push_front(value.to_vec()),
+                    ListEnd::Right => items.push_back(value.to_vec()),
+                }
+            }
+            let len = items.len();
+            let mut stored = StoredValue::list(&items);
+            stored.expires_at = expires_at;
+            Ok((Some(stored), len))
+        })?;
+        self.key_waiters().wake(key);
+        Ok(len)
+    }
+
+    // Removes up to `count` items from the given end of the list. Returns None
+    // if the key does not exist. The key is deleted once the list is empty.
+    pub fn list_pop(
+        &self,
+        key: &[u8],
+        count: usize,
+        end: ListEnd,
+    ) -> Result<Option<Vec<Vec<u8>>>, DataStoreError> {
+        self.fetch_update(key, |current| {
+            if current.is_none() {
+                return Ok((None, None));
+            }
+            let (expires_at, mut items) = list_items(current)?;
+            let count = count.min(items.len());
+            let popped: Vec<Vec<u8>> = match end {
+                ListEnd::Left => items.drain(..count).collect(),
+                ListEnd::Right => items.drain(items.len() - count..).rev().collect(),
+            };
+            let updated = if items.is_empty() {
+                None
+            } else {
+                let mut stored = StoredValue::list(&items);
+                stored.expires_at = expires_at;
+                Some(stored)
+            };
+            Ok((updated, Some(popped)))
+        })
+    }
+
+    // Pops up to `count` items from the first non-empty list among `keys`,
+    // checked in order, as with LMPOP. Returns that key with the items, or
+    // None if all lists are empty. Each list is popped under its own lock,
+    // so a concurrent pusher may fill a list already passed over.
+    pub fn list_multi_pop(
+        &self,
+        keys: &[Vec<u8>],
+        count: usize,
+        end: ListEnd,
+    ) -> Result<Option<PoppedItems>, DataStoreError> {
+        for key in keys {
+            if let Some(items) = self.list_pop(key, count, end)? {
+                return Ok(Some((key.clone(), items)));
+            }
+        }
+        Ok(None)
+    }
+
+    // Pops one item from the first non-empty list among `keys`, waiting for
+    // one to be pushed if they are all empty, as with BLPOP. Returns the key
+    // and item, or None once `timeout` elapses (None waits forever). Storage
+    // access runs on tokio's blocking pool (or inline in single-thread mode),
+    // so waiting ties up no thread.
+    pub async fn list_blocking_pop(
+        &self,
+        keys: &[Vec<u8>],
+        end: ListEnd,
+        timeout: Option<Duration>,
+    ) -> Result<Option<(Vec<u8>, Vec<u8>)>, DataStoreError> {
+        let deadline = timeout.map(|timeout| tokio::time::Instant::now() + timeout);
+        // Registered before the first attempt, so a push in between wakes us
+        let waiter = self.key_waiters().register(keys);
+        loop {
+            let partition = self.clone();
+            let owned_keys = keys.to_vec();
+            let popped = self
+                .run_blocking(move || {
+                    Ok::<_, DataStoreError>(
+                        partition
+                            .list_multi_pop(&owned_keys, 1, end)?
+                            .and_then(|(key, mut items)| items.pop().map(|item| (key, item))),
+                    )
+                })
+                .await
+                .map_err(|e| DataStoreError::PartitionError(e.to_string()))??;
+            if popped.is_some() {
+                return Ok(popped);
+            }
+            match deadline {
+                Some(deadline) => {
+                    if tokio::time::timeout_at(deadline, waiter.woken())
+                        .await
+                        .is_err()
+                    {
+                        return Ok(None);
+                    }
+                }
+                None => waiter.woken().await,
+            }
+        }
+    }
+
+    pub fn list_len(&self, key: &[u8]) -> Result<usize, DataStoreError> {
+        Ok(self.read_list(key)?.len())
+    }
+
+    // Items between `start` and `stop` inclusive; negative indexes count from
+    // the tail, as with LRANGE
+    pub fn list_range(
+        &self,
+        key: &[u8],
+        start: i64,
+        stop: i64,
+    ) -> Result<Vec<Vec<u8>>, DataStoreError> {
+        let items = self.read_list(key)?;
+        let len = items.len() as i64;
+        let start = if start < 0 {
+            (len + start).max(0)
+        } else {
+            start
+        };
+        let stop = if stop < 0 {
+            len + stop
+        } else {
+            stop.min(len - 1)
+        };
+        if start > stop || start >= len {
+            return Ok(Vec::new());
+        }
+        Ok(items
+            .into_iter()
+            .skip(start as usize)
+            .take((stop - start + 1) as usize)
+            .collect())
+    }
+
+    // Indexes (always counted from the head) of items equal to `element`.
+    // `rank` selects the first match to report, counting from the tail when
+    // negative; `count` caps the number of matches (0 = all) and `max_len`
+    // caps the number of items compared (0 = all), as with LPOS.
+    pub fn list_positions(
+        &self,
+        key: &[u8],
+        element: &[u8],
+        rank: i64,
+        count: usize,
+        max_len: usize,
+    ) -> Result<Vec<usize>, DataStoreError> {
+        debug_assert!(rank != 0, "rank is 1-based");
+        let items = self.read_list(key)?;
+        let scan_len = if max_len == 0 {
+            items.len()
+        } else {
+            max_len.min(items.len())
+        };
+        let indexes: Box<dyn Iterator<Item = usize>> = if rank > 0 {
+            Box::new(0..scan_len)
+        } else {
+            Box::new((items.len() - scan_len..items.len()).rev())
+        };
+        Ok(indexes
+            .filter(|&index| items[index] == element)
+            .skip(rank.unsigned_abs() as usize - 1)
+            .take(if count == 0 { usize::MAX } else { count })
+            .collect())
+    }
+
+    fn read_list(&self, key: &[u8]) -> Result<VecDeque<Vec<u8>>, DataStoreError> {
+        let current = self.get_stored(key)?;
+        list_items(current).map(|(_, items)| items)
+    }
+}
+
+// Splits a stored list into its expiry and items, treating a missing key as
+// an empty list
+fn list_items(
+    stored: Option<StoredValue>,
+) -> Result<(Option<u64>, VecDeque<Vec<u8>>), DataStoreError> {
+    match stored {
+        None => Ok((None, VecDeque::new())),
+        Some(stored) if stored.kind != ValueKind::List => Err(DataStoreError::WrongType),
+        Some(stored) => Ok((stored.expires_at, decode_items(&stored.payload)?)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::DataStore;
+    use tempfile::TempDir;
+
+    fn create_test_list() -> (TempDir, DataStorePartition) {
+        let temp_dir = TempDir::new().expect("Failed to create temp dir");
+        let data_store = DataStore::new(temp_dir.path().to_str().unwrap()).unwrap();
+        let partition = data_store.partition("lists").unwrap();
+        // [a, b, c, 1, 2, 3, c, c]
+        partition
+            .list_push(
+                b"list",
+                &[b"a", b"b", b"c", b"1", b"2", b"3", b"c", b"c"],
+                ListEnd::Right,
+            )
+            .unwrap();
+        (temp_dir, partition)
+    }
+
+    #[test]
+    fn test_push_pop_range() {
+        let (_dir, partition) = create_test_list();
+        assert_eq!(partition.list_len(b"list").unwrap(), 8);
+        assert_eq!(
+            partition.list_range(b"list", 0, 2).unwrap(),
+            vec![b"a".to_vec(), b"b".to_vec(), b"c".to_vec()]
+        );
+        assert_eq!(
+            partition.list_range(b"list", -2, -1).unwrap(),
+            vec![b"c".to_vec(), b"c".to_vec()]
+        );
+
+        partition
+            .list_push(b"left", &[b"a", b"b"], ListEnd::Left)
+            .unwrap();
+        assert_eq!(
+            partition.list_pop(b"left", 5, ListEnd::Left).unwrap(),
+            Some(vec![b"b".to_vec(), b"a".to_vec()])
+        );
+        // Popping the last item removes the key
+        assert!(!partition.exists(b"left").unwrap());
+        assert_eq!(partition.list_pop(b"left", 1, ListEnd::Left).unwrap(), None);
+
+        partition.set(b"string", b"value").unwrap();
+        assert!(matches!(
+            partition.list_push(b"string", &[b"x"], ListEnd::Left),
+            Err(DataStoreError::WrongType)
+        ));
+    }
+
+    #[test]
+    fn test_multi_pop() {
+        let (_dir, partition) = create_test_list();
+        let keys = [b"empty".to_vec(), b"list".to_vec(), b"other".to_vec()];
+        partition
+            .list_push(b"other", &[b"x"], ListEnd::Right)
+            .unwrap();
+
+        // The first non-empty list is popped, and only that one
+        assert_eq!(
+            partition.list_multi_pop(&keys, 3, ListEnd::Right).unwrap(),
+            Some((
+                b"list".to_vec(),
+                vec![b"c".to_vec(), b"c".to_vec(), b"3".to_vec()]
+            ))
+        );
+        assert_eq!(partition.list_len(b"other").unwrap(), 1);
+        // COUNT beyond the length takes what there is
+        assert_eq!(
+            partition.list_multi_pop(&keys, 10, ListEnd::Left).unwrap(),
+            Some((
+                b"list".to_vec(),
+                vec![
+                    b"a".to_vec(),
+                    b"b".to_vec(),
+                    b"c".to_vec(),
+                    b"1".to_vec(),
+                    b"2".to_vec()
+                ]
+            ))
+        );
+        assert_eq!(
+            partition.list_multi_pop(&keys, 10, ListEnd::Left).unwrap(),
+            Some((b"other".to_vec(), vec![b"x".to_vec()]))
+        );
+        assert_eq!(
+            partition.list_multi_pop(&keys, 1, ListEnd::Left).unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn test_list_positions() {
+        let (_dir, partition) = create_test_list();
+
+        // First match
+        assert_eq!(
+            partition.list_positions(b"list", b"c", 1, 1, 0).unwrap(),
+            vec![2]
+        );
+        // RANK skips earlier matches
+        assert_eq!(
+            partition.list_positions(b"list", b"c", 2, 1, 0).unwrap(),
+            vec![6]
+        );
+        // Negative RANK searches from the tail
+        assert_eq!(
+            partition.list_positions(b"list", b"c", -1, 1, 0).unwrap(),
+            vec![7]
+        );
+        // COUNT 0 returns every match
+        assert_eq!(
+            partition.list_positions(b"list", b"c", 1, 0, 0).unwrap(),
+            vec![2, 6, 7]
+        );
+        assert_eq!(
+            partition.list_positions(b"list", b"c", -1, 0, 0).unwrap(),
+            vec![7, 6, 2]
+        );
+        // MAXLEN limits the scanned items
+        assert_eq!(
+            partition.list_positions(b"list", b"c", 1, 0, 3).unwrap(),
+            vec![2]
+        );
+        assert!(partition
+            .list_positions(b"list", b"missing", 1, 0, 0)
+            .unwrap()
+            .is_empty());
+        assert!(partition
+            .list_positions(b"nolist", b"c", 1, 0, 0)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_blocking_pop() {
+        let (_dir, partition) = create_test_list();
+        let keys = [b"empty".to_vec(), b"list".to_vec()];
+        assert_eq!(
+            partition
+                .list_blocking_pop(&keys, ListEnd::Right, None)
+                .await
+                .unwrap(),
+            Some((b"list".to_vec(), b"c".to_vec()))
+        );
+
+        let keys = [b"empty".to_vec()];
+        assert_eq!(
+            partition
+                .list_blocking_pop(&keys, ListEnd::Left, Some(Duration::from_millis(20)))
+                .await
+                .unwrap(),
+            None
+        );
+
+        let pusher = partition.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            pusher.list_push(b"empty", &[b"x"], ListEnd::Left).unwrap();
+        });
+        assert_eq!(
+            partition
+                .list_blocking_pop(&keys, ListEnd::Left, None)
+                .await
+                .unwrap(),
+            Some((b"empty".to_vec(), b"x".to_vec()))
+        );
+        assert_eq!(partition.list_len(b"empty").unwrap(), 0);
+    }
+}
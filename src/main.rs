@@ -1,198 +1,792 @@
-use futures::stream::StreamExt;
-use futures::SinkExt;
-use redis_protocol::resp2::types::BytesFrame;
-use tokio::net::TcpStream;
-use tokio_util::codec::Framed;
+use clap::{CommandFactory, Parser};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
-use veifka::{DataStore, DataStoreError, DataStorePartition};
+use veifka::{
+    serve_all_until, AclUser, DataStore, DataStoreError, Databases, FsyncPolicy, GroupCommit,
+    PartitionLimit, ProtoLimits, ServerContext, TtlLimits, TtlPolicy, UnknownCommandPolicy,
+    DEFAULT_GROUP_COMMIT_MAX_WRITES, DEFAULT_LCS_MAX_CELLS, DEFAULT_MAX_MULTIBULK_LEN,
+    DEFAULT_MAX_PIPELINE_DEPTH, DEFAULT_MAX_REQUEST_LEN, DEFAULT_PROTO_MAX_BULK_LEN, RUN_ID_LEN,
+};
 
-#[tokio::main]
-async fn main() -> Result<(), DataStoreError> {
-    let datastore = DataStore::new("test_datastore")?;
-    let partition_handle = datastore.create_partition("default_partition")?;
-    let partition = DataStorePartition::new(partition_handle);
+#[derive(Parser, Debug, Clone)]
+// A flag given twice takes its last value, which lets command line flags
+// override the config file
+#[command(author, version, about, long_about = None, args_override_self = true)]
+struct Args {
+    /// Read settings from a redis.conf style file: one `name value` per line,
+    /// named like the long flags. Flags on the command line take precedence.
+    /// On SIGHUP the file is read again and the settings that can change at
+    /// runtime are applied; the others need a restart.
+    #[arg(long)]
+    config: Option<PathBuf>,
 
-    let listener = tokio::net::TcpListener::bind("127.0.0.1:6379")
-        .await
-        .expect("Failed to bind to port");
+    /// Address to listen on, either a host using --port or a host:port pair.
+    /// Repeat to listen on several addresses at once; startup fails if any of
+    /// them can't be bound.
+    #[arg(long, default_value = "127.0.0.1")]
+    bind: Vec<String>,
 
-    loop {
-        let (socket, _) = listener
-            .accept()
-            .await
-            .expect("Failed to accept connection");
+    /// Port to listen on for --bind addresses without one
+    #[arg(long, default_value_t = 6379)]
+    port: u16,
 
-        let partition = partition.clone();
-        tokio::spawn(async move {
-            if let Err(e) = handle_client(socket, partition).await {
-                eprintln!("Error handling client: {:?}", e)
-            }
-        });
+    /// Read the whole partition into the block cache before accepting connections
+    #[arg(long)]
+    preload: bool,
+
+    /// Only preload keys starting with this prefix (implies --preload)
+    #[arg(long)]
+    preload_prefix: Option<String>,
+
+    /// Initial delay before accepting again after a transient accept error,
+    /// doubled on every consecutive failure up to one second
+    #[arg(long, default_value_t = 10)]
+    accept_backoff_ms: u64,
+
+    /// When to sync the journal to disk: always, everysec or no, as Redis'
+    /// appendfsync. Can be changed at runtime with CONFIG SET appendfsync.
+    #[arg(long, alias = "appendfsync", default_value_t = FsyncPolicy::EverySec)]
+    fsync: FsyncPolicy,
+
+    /// Memory budget in bytes of the block cache shared by all partitions
+    /// (defaults to fjall's 16 MiB)
+    #[arg(long)]
+    block_cache_size: Option<u64>,
+
+    /// Serve every connection from one thread, running storage calls inline
+    /// rather than on a thread pool: less overhead for small deployments and
+    /// commands executed strictly in the order they arrive, at the cost of
+    /// one slow command stalling all clients
+    #[arg(long)]
+    single_thread: bool,
+
+    /// Open an existing data store without ever writing to it: every write
+    /// fails in the storage layer, whoever sends it, and expired keys read as
+    /// missing without being removed. Unlike users limited to +@read, this
+    /// protects the files themselves, e.g. a backup another process serves.
+    /// The store must already hold a partition for every database.
+    #[arg(long)]
+    read_only_store: bool,
+
+    /// Largest (len1 + 1) * (len2 + 1) LCS computes, 4 bytes each; LCS of
+    /// longer strings fails. Can be changed at runtime with CONFIG SET
+    /// lcs-max-cells.
+    #[arg(long, default_value_t = DEFAULT_LCS_MAX_CELLS)]
+    lcs_max_cells: usize,
+
+    /// TTL in seconds that SET ... REFRESH gives a key, so sessions written
+    /// with it stay alive while they keep being written; 0 disables REFRESH.
+    /// Can be changed at runtime with CONFIG SET refresh-ttl.
+    #[arg(long, default_value_t = 0)]
+    refresh_ttl: u64,
+
+    /// Microseconds a plain SET or DEL waits for others to commit with it as
+    /// one batch, trading that much latency for fewer, larger journal writes
+    /// (and syncs, with --fsync always) under many concurrent writers; 0
+    /// commits every write on its own. Can be changed at runtime with CONFIG
+    /// SET group-commit-window-us.
+    #[arg(long, default_value_t = 0)]
+    group_commit_window_us: u64,
+
+    /// Most writes committed as one group, which is committed as soon as it
+    /// is full; 1 disables group commit. Can be changed at runtime with
+    /// CONFIG SET group-commit-max-writes.
+    #[arg(long, default_value_t = DEFAULT_GROUP_COMMIT_MAX_WRITES)]
+    group_commit_max_writes: usize,
+
+    /// Run id INFO reports, as 40 hex digits, instead of a random one, for
+    /// deterministic output in tests; DEBUG CHANGE-REPL-ID still replaces it
+    #[arg(long, value_parser = parse_run_id)]
+    run_id: Option<String>,
+
+    /// Number of numbered databases selectable with SELECT
+    #[arg(long, default_value_t = 16)]
+    databases: usize,
+
+    /// Largest number of keys in each database; writes that would add a key
+    /// past it fail with "key quota exceeded", while overwrites and deletes
+    /// still go through. Keys are counted when a database is opened, which
+    /// reads it whole. There is no FLUSHDB to reset the count: it drops as
+    /// keys are deleted, or removed once expired.
+    #[arg(long)]
+    max_keys: Option<u64>,
+
+    /// Largest number of partitions open at once, numbered databases
+    /// included; SELECT of a new partition name fails beyond it
+    #[arg(long)]
+    max_partitions: Option<usize>,
+
+    /// At --max-partitions, close the least recently used partition no
+    /// client has selected instead of failing SELECT
+    #[arg(long)]
+    evict_idle_partitions: bool,
+
+    /// Password clients must AUTH with before running other commands
+    #[arg(long)]
+    requirepass: Option<String>,
+
+    /// A user clients can AUTH as, as name:password:rules where rules grant
+    /// command categories, e.g. reader:s3cret:+@read or app:pw:+@all,-@admin.
+    /// Categories are read, write, admin and all. Repeat for several users.
+    #[arg(long = "user")]
+    users: Vec<AclUser>,
+
+    /// Rename a command, as COMMAND NEW_NAME: the command then answers to
+    /// NEW_NAME only, and an empty NEW_NAME ("") disables it. Clients calling
+    /// a command by a name it doesn't answer to get the unknown command error.
+    /// Repeat for several commands.
+    #[arg(long, num_args = 2, value_names = ["COMMAND", "NEW_NAME"])]
+    rename_command: Vec<String>,
+
+    /// Send replies without delaying small writes to coalesce them (Nagle's
+    /// algorithm); set to no to trade latency for fewer packets
+    #[arg(
+        long,
+        default_value_t = true,
+        action = clap::ArgAction::Set,
+        value_parser = clap::builder::BoolishValueParser::new()
+    )]
+    tcp_nodelay: bool,
+
+    /// Seconds a connection is idle before the OS sends keepalive probes to
+    /// detect dead peers, 0 to disable keepalive
+    #[arg(long, default_value_t = 0)]
+    tcp_keepalive: u64,
+
+    /// How to answer commands veifka doesn't implement: error, as Redis does,
+    /// ignore with a nil reply, or ok
+    #[arg(long, default_value_t = UnknownCommandPolicy::Error)]
+    unknown_command_policy: UnknownCommandPolicy,
+
+    /// Append a line to this file for every rejected command (failed
+    /// authentication, oversized or malformed requests)
+    #[arg(long)]
+    audit_log: Option<PathBuf>,
+
+    /// Largest bulk string accepted in a request, in bytes. Can be changed at
+    /// runtime with CONFIG SET proto-max-bulk-len.
+    #[arg(long, default_value_t = DEFAULT_PROTO_MAX_BULK_LEN)]
+    proto_max_bulk_len: usize,
+
+    /// Largest number of arguments accepted in a single command
+    #[arg(long, default_value_t = DEFAULT_MAX_MULTIBULK_LEN)]
+    max_multibulk_len: usize,
+
+    /// Largest total size of a single command, in bytes
+    #[arg(long, default_value_t = DEFAULT_MAX_REQUEST_LEN)]
+    max_request_len: usize,
+
+    /// Replies to pipelined commands a connection holds before writing them
+    /// out; no further command is read until they are written. Can be changed
+    /// at runtime with CONFIG SET max-pipeline-depth.
+    #[arg(long, default_value_t = DEFAULT_MAX_PIPELINE_DEPTH)]
+    max_pipeline_depth: usize,
+
+    /// Largest TTL in seconds clients can set with EXPIRE and friends or
+    /// SET EX/PX; see --ttl-policy for longer ones
+    #[arg(long)]
+    max_ttl: Option<u64>,
+
+    /// Smallest TTL in seconds clients can set, other than one deleting the
+    /// key outright
+    #[arg(long)]
+    min_ttl: Option<u64>,
+
+    /// What happens to a TTL outside of --min-ttl and --max-ttl: clamp
+    /// brings it within them, reject fails the command
+    #[arg(long, default_value_t = TtlPolicy::Clamp)]
+    ttl_policy: TtlPolicy,
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let mut args = Args::parse();
+    if let Some(path) = &args.config {
+        args = parse_with_config_file(path, std::env::args_os())?;
     }
+    let mut runtime = if args.single_thread {
+        tokio::runtime::Builder::new_current_thread()
+    } else {
+        tokio::runtime::Builder::new_multi_thread()
+    };
+    runtime.enable_all().build()?.block_on(run(args))
 }
 
-async fn handle_client(
-    socket: TcpStream,
-    partition: DataStorePartition,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let mut framed = Framed::new(socket, redis_protocol::codec::Resp2);
-    while let Some(result) = framed.next().await {
-        match result {
-            Ok(frame) => {
-                let response = handle_command(frame, &partition).await;
-                framed.send(response).await?;
+async fn run(args: Args) -> Result<(), Box<dyn std::error::Error>> {
+    let startup = args.clone();
+    let mut builder = DataStore::builder();
+    if let Some(bytes) = args.block_cache_size {
+        builder = builder.block_cache_size(bytes);
+    }
+    builder = builder.read_only(args.read_only_store);
+    if let Some(max_keys) = args.max_keys {
+        builder = builder.max_keys(max_keys);
+    }
+    let datastore = builder.open("test_datastore")?;
+    datastore.set_fsync_policy(args.fsync);
+    datastore.set_single_thread(args.single_thread);
+    datastore.set_lcs_max_cells(args.lcs_max_cells);
+    datastore.set_refresh_ttl(Some(Duration::from_secs(args.refresh_ttl)));
+    datastore.set_group_commit(group_commit(&args));
+    if let Some(run_id) = &args.run_id {
+        datastore.set_run_id(run_id);
+    }
+    datastore.set_ttl_limits(TtlLimits {
+        min: args.min_ttl.map(Duration::from_secs),
+        max: args.max_ttl.map(Duration::from_secs),
+        policy: args.ttl_policy,
+    });
+    datastore.set_partition_limit(PartitionLimit {
+        max: args.max_partitions,
+        evict_idle: args.evict_idle_partitions,
+    });
+    let databases = Databases::open(&datastore, args.databases)?;
+    let partition = databases.get(0).expect("at least one database");
+    tokio::spawn(fsync_every_second(datastore.clone()));
+
+    if args.preload || args.preload_prefix.is_some() {
+        let start = std::time::Instant::now();
+        let prefix = args.preload_prefix.map(String::into_bytes);
+        let keys = partition
+            .warm_up(prefix.as_deref())
+            .map_err(|e| DataStoreError::PartitionError(e.to_string()))?;
+        println!("Preloaded {} keys in {:?}", keys, start.elapsed());
+    }
+
+    let mut listeners = Vec::with_capacity(args.bind.len());
+    for bind in &args.bind {
+        let listener = match bind.parse::<std::net::SocketAddr>() {
+            Ok(addr) => tokio::net::TcpListener::bind(addr).await,
+            Err(_) => tokio::net::TcpListener::bind((bind.as_str(), args.port)).await,
+        }
+        .map_err(|e| format!("Failed to bind to '{}': {}", bind, e))?;
+        listeners.push(listener);
+    }
+
+    let limits = ProtoLimits::new(
+        args.proto_max_bulk_len,
+        args.max_multibulk_len,
+        args.max_request_len,
+        args.max_pipeline_depth,
+    );
+    #[cfg(unix)]
+    if let Some(path) = args.config.clone() {
+        let hangups = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())?;
+        tokio::spawn(reload_on_hangup(
+            hangups,
+            path,
+            std::env::args_os().collect(),
+            startup,
+            datastore.clone(),
+            limits.clone(),
+        ));
+    }
+    let mut context = ServerContext::new(databases, limits);
+    if let Some(password) = &args.requirepass {
+        context.set_requirepass(password);
+    }
+    for user in args.users {
+        context.add_user(user);
+    }
+    for rename in args.rename_command.chunks(2) {
+        context.rename_command(&rename[0], &rename[1]);
+    }
+    if let Some(path) = &args.audit_log {
+        context.set_audit_log(path)?;
+    }
+    context.set_unknown_command_policy(args.unknown_command_policy);
+    context.set_tcp_nodelay(args.tcp_nodelay);
+    context.set_tcp_keepalive(
+        (args.tcp_keepalive > 0).then(|| Duration::from_secs(args.tcp_keepalive)),
+    );
+    context.set_accept_backoff(Duration::from_millis(args.accept_backoff_ms));
+    let summary = serve_all_until(listeners, context, shutdown_signal()).await?;
+    println!("{}", summary);
+    Ok(())
+}
+
+// Completes on Ctrl-C, or on the SIGTERM service managers and orchestrators
+// stop the server with
+async fn shutdown_signal() {
+    let interrupt = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            eprintln!("Error listening for Ctrl-C: {}", e);
+            std::future::pending::<()>().await;
+        }
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                terminate.recv().await;
             }
             Err(e) => {
-                eprintln!("Error reading frame: {:?}", e);
-                let err_response = BytesFrame::Error(format!("ERR {:?}", e).into());
-                framed.send(err_response).await?;
+                eprintln!("Error listening for SIGTERM: {}", e);
+                std::future::pending::<()>().await;
             }
         }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+    tokio::select! {
+        () = interrupt => {},
+        () = terminate => {},
     }
+}
 
-    Ok(())
+fn group_commit(args: &Args) -> GroupCommit {
+    GroupCommit {
+        window: Duration::from_micros(args.group_commit_window_us),
+        max_writes: args.group_commit_max_writes,
+    }
 }
 
-async fn handle_command(frame: BytesFrame, partition: &DataStorePartition) -> BytesFrame {
-    match frame {
-        BytesFrame::SimpleString(_bytes) => todo!(),
-        BytesFrame::Error(_str_inner) => todo!(),
-        BytesFrame::Integer(_) => todo!(),
-        BytesFrame::BulkString(_bytes) => todo!(),
-        BytesFrame::Array(commands) => {
-            if commands.is_empty() {
-                return BytesFrame::Error("ERR Empty command".into());
+fn parse_run_id(s: &str) -> Result<String, String> {
+    if s.len() == RUN_ID_LEN && s.bytes().all(|b| b.is_ascii_hexdigit()) {
+        Ok(s.to_ascii_lowercase())
+    } else {
+        Err(format!("expected {} hex digits", RUN_ID_LEN))
+    }
+}
+
+// Parses the command line `args` on top of the settings in the config file
+// at `path`
+fn parse_with_config_file(
+    path: &Path,
+    args: impl IntoIterator<Item = std::ffi::OsString>,
+) -> Result<Args, Box<dyn std::error::Error>> {
+    let contents = std::fs::read_to_string(path)
+        .map_err(|e| format!("Error reading config file '{}': {}", path.display(), e))?;
+    let mut args = args.into_iter();
+    let mut combined: Vec<std::ffi::OsString> = args.next().into_iter().collect();
+    combined.extend(config_file_args(&contents)?.into_iter().map(Into::into));
+    combined.extend(args);
+    Ok(Args::try_parse_from(combined)?)
+}
+
+// Turns config file lines into the equivalent flags. Values may be double
+// quoted; flags that take no value are set with `yes` and left out with `no`.
+fn config_file_args(contents: &str) -> Result<Vec<String>, String> {
+    let command = Args::command();
+    let mut flags = Vec::new();
+    for (number, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (name, value) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+        let name = name.to_ascii_lowercase();
+        let value = value.trim();
+        let value = value
+            .strip_prefix('"')
+            .and_then(|value| value.strip_suffix('"'))
+            .unwrap_or(value);
+        let error = |message: &str| format!("config file line {}: {}", number + 1, message);
+        if name == "config" {
+            return Err(error("config files can't include other config files"));
+        }
+        let arg = command
+            .get_arguments()
+            .find(|arg| {
+                arg.get_long() == Some(name.as_str())
+                    || arg
+                        .get_all_aliases()
+                        .is_some_and(|aliases| aliases.contains(&name.as_str()))
+            })
+            .ok_or_else(|| error(&format!("unknown setting '{}'", name)))?;
+        if arg
+            .get_num_args()
+            .is_some_and(|values| values.max_values() > 1)
+        {
+            // Several values, e.g. `rename-command FLUSHALL ""`
+            flags.push(format!("--{}", name));
+            flags.extend(config_values(line[name.len()..].trim()));
+        } else if arg.get_action().takes_values() {
+            flags.push(format!("--{}", name));
+            flags.push(value.to_string());
+        } else {
+            match value.to_ascii_lowercase().as_str() {
+                "yes" => flags.push(format!("--{}", name)),
+                "no" => {}
+                _ => return Err(error(&format!("'{}' takes yes or no", name))),
             }
+        }
+    }
+    Ok(flags)
+}
 
-            let cmd = match &commands[0] {
-                BytesFrame::BulkString(bytes) => {
-                    String::from_utf8_lossy(bytes).to_ascii_uppercase()
-                }
-                BytesFrame::SimpleString(s) => String::from_utf8_lossy(s).to_ascii_uppercase(),
-                _ => return BytesFrame::Error("ERR invalid command type".into()),
-            };
-
-            match cmd.as_str() {
-                "PING" => BytesFrame::SimpleString("PONG".into()),
-                "SET" => {
-                    if commands.len() != 3 {
-                        return BytesFrame::Error("ERR Wrong number of arguments for SET".into());
-                    }
-                    let key = match &commands[1] {
-                        BytesFrame::BulkString(bytes) => bytes.clone(),
-                        _ => return BytesFrame::Error("ERR Invalid key type".into()),
-                    };
-                    let value = match &commands[2] {
-                        BytesFrame::BulkString(bytes) => bytes.clone(),
-                        _ => return BytesFrame::Error("ERR Invalid value type".into()),
-                    };
-                    let partition = partition.clone();
-                    match tokio::task::spawn_blocking(move || partition.set(&key, &value)).await {
-                        Ok(Ok(_)) => BytesFrame::SimpleString("OK".into()),
-                        Ok(Err(e)) => BytesFrame::Error(format!("ERR SET error: {:?}", e).into()),
-                        Err(e) => BytesFrame::Error(format!("ERR task error: {:?}", e).into()),
-                    }
-                }
-                "GET" => {
-                    if commands.len() != 2 {
-                        return BytesFrame::Error("ERR Wrong number of arguments for GET".into());
-                    }
-                    let key = match &commands[1] {
-                        BytesFrame::BulkString(bytes) => bytes.clone(),
-                        _ => return BytesFrame::Error("ERR Invalid key type".into()),
-                    };
-                    let partition = partition.clone();
-                    match tokio::task::spawn_blocking(move || partition.get(&key)).await {
-                        Ok(Ok(Some(value))) => BytesFrame::BulkString(value.to_vec().into()),
-                        Ok(Ok(None)) => BytesFrame::Null,
-                        Ok(Err(e)) => BytesFrame::Error(format!("ERR GET error: {:?}", e).into()),
-                        Err(e) => BytesFrame::Error(format!("ERR task error: {:?}", e).into()),
-                    }
-                }
-                "DEL" => {
-                    if commands.len() < 2 {
-                        return BytesFrame::Error("ERR Wrong number of arguments for DEL".into());
-                    }
-                    let keys: Vec<_> = commands[1..]
-                        .iter()
-                        .filter_map(|cmd| match cmd {
-                            BytesFrame::BulkString(bytes) => Some(bytes.clone()),
-                            _ => None,
-                        })
-                        .collect();
-
-                    let partition = partition.clone();
-                    match tokio::task::spawn_blocking(move || {
-                        let mut deleted = 0;
-                        for key in keys {
-                            if partition.delete(&key).is_ok() {
-                                deleted += 1;
-                            }
-                        }
-                        Ok::<i64, fjall::Error>(deleted)
-                    })
-                    .await
-                    {
-                        // or should we send over SimpleString with "deleted X keys"?
-                        Ok(Ok(amount_deleted)) => BytesFrame::Integer(amount_deleted),
-                        Ok(Err(e)) => BytesFrame::Error(format!("ERR DEL error: {:?}", e).into()),
-                        Err(e) => BytesFrame::Error(format!("ERR task error: {:?}", e).into()),
-                    }
-                }
-                "EXISTS" => {
-                    if commands.len() != 2 {
-                        return BytesFrame::Error(
-                            "ERR Wrong number of arguments for EXISTS".into(),
-                        );
-                    }
-                    let key = match &commands[1] {
-                        BytesFrame::BulkString(bytes) => bytes.clone(),
-                        _ => return BytesFrame::Error("ERR Invalid key type".into()),
-                    };
-                    let partition = partition.clone();
-                    match tokio::task::spawn_blocking(move || partition.get(&key)).await {
-                        Ok(Ok(Some(_))) => BytesFrame::Integer(1),
-                        Ok(Ok(None)) => BytesFrame::Integer(0),
-                        Ok(Err(e)) => {
-                            BytesFrame::Error(format!("ERR EXISTS error: {:?}", e).into())
-                        }
-                        Err(e) => BytesFrame::Error(format!("ERR task error: {:?}", e).into()),
-                    }
-                }
-                "MGET" => {
-                    if commands.len() < 2 {
-                        return BytesFrame::Error("ERR Wrong number of arguments for MGET".into());
-                    }
-                    let keys: Vec<_> = commands[1..]
-                        .iter()
-                        .filter_map(|cmd| match cmd {
-                            BytesFrame::BulkString(bytes) => Some(bytes.clone()),
-                            _ => None,
-                        })
-                        .collect();
-                    let partition = partition.clone();
-                    match tokio::task::spawn_blocking(move || {
-                        let mut results = Vec::with_capacity(keys.len());
-                        for key in keys {
-                            match partition.get(&key)? {
-                                Some(value) => {
-                                    results.push(BytesFrame::BulkString(value.to_vec().into()))
-                                }
-                                None => results.push(BytesFrame::Null),
-                            }
-                        }
-                        Ok::<Vec<BytesFrame>, fjall::Error>(results)
-                    })
-                    .await
-                    {
-                        Ok(Ok(results)) => BytesFrame::Array(results),
-                        Ok(Err(e)) => BytesFrame::Error(format!("ERR MGET error: {:?}", e).into()),
-                        Err(e) => BytesFrame::Error(format!("ERR task error: {:?}", e).into()),
-                    }
+// Splits the values of a setting that takes several, separated by
+// whitespace, each optionally double quoted. `""` is an empty value.
+fn config_values(values: &str) -> Vec<String> {
+    let mut split = Vec::new();
+    let mut current: Option<String> = None;
+    let mut quoted = false;
+    for c in values.chars() {
+        match c {
+            '"' => {
+                quoted = !quoted;
+                current.get_or_insert_with(String::new);
+            }
+            c if c.is_whitespace() && !quoted => split.extend(current.take()),
+            c => current.get_or_insert_with(String::new).push(c),
+        }
+    }
+    split.extend(current);
+    split
+}
+
+// Reads the config file at `path` again on every SIGHUP, on top of the same
+// `command_line` as at startup, and applies what changed
+#[cfg(unix)]
+async fn reload_on_hangup(
+    mut hangups: tokio::signal::unix::Signal,
+    path: PathBuf,
+    command_line: Vec<std::ffi::OsString>,
+    startup: Args,
+    datastore: DataStore,
+    limits: ProtoLimits,
+) {
+    while hangups.recv().await.is_some() {
+        match parse_with_config_file(&path, command_line.iter().cloned()) {
+            Ok(args) => {
+                println!("Reloading config file '{}'", path.display());
+                for change in apply_reload(&startup, &args, &datastore, &limits) {
+                    println!("{}", change);
                 }
-                _ => BytesFrame::Error(format!("ERR unknown command '{}'", cmd).into()),
             }
+            Err(e) => eprintln!(
+                "Error reloading config file, keeping current settings: {}",
+                e
+            ),
+        }
+    }
+}
+
+// Applies the settings in `args` that can change while running, as CONFIG
+// SET would, and describes each change. Settings only read at startup are
+// compared with `startup` and reported as ignored until a restart.
+fn apply_reload(
+    startup: &Args,
+    args: &Args,
+    datastore: &DataStore,
+    limits: &ProtoLimits,
+) -> Vec<String> {
+    let mut changes = Vec::new();
+    let mut changed = |name: &str, old: String, new: String| {
+        if old != new {
+            changes.push(format!("{}: {} -> {}", name, old, new));
+        }
+    };
+
+    changed(
+        "appendfsync",
+        datastore.fsync_policy().to_string(),
+        args.fsync.to_string(),
+    );
+    datastore.set_fsync_policy(args.fsync);
+
+    changed(
+        "lcs-max-cells",
+        datastore.lcs_max_cells().to_string(),
+        args.lcs_max_cells.to_string(),
+    );
+    datastore.set_lcs_max_cells(args.lcs_max_cells);
+
+    changed(
+        "refresh-ttl",
+        datastore
+            .refresh_ttl()
+            .map_or(0, |ttl| ttl.as_secs())
+            .to_string(),
+        args.refresh_ttl.to_string(),
+    );
+    datastore.set_refresh_ttl(Some(Duration::from_secs(args.refresh_ttl)));
+
+    let group_commit = group_commit(args);
+    changed(
+        "group-commit-window-us",
+        datastore.group_commit().window.as_micros().to_string(),
+        group_commit.window.as_micros().to_string(),
+    );
+    changed(
+        "group-commit-max-writes",
+        datastore.group_commit().max_writes.to_string(),
+        group_commit.max_writes.to_string(),
+    );
+    datastore.set_group_commit(group_commit);
+
+    let ttl_limits = TtlLimits {
+        min: args.min_ttl.map(Duration::from_secs),
+        max: args.max_ttl.map(Duration::from_secs),
+        policy: args.ttl_policy,
+    };
+    changed(
+        "ttl limits",
+        format!("{:?}", datastore.ttl_limits()),
+        format!("{:?}", ttl_limits),
+    );
+    datastore.set_ttl_limits(ttl_limits);
+
+    let partition_limit = PartitionLimit {
+        max: args.max_partitions,
+        evict_idle: args.evict_idle_partitions,
+    };
+    changed(
+        "partition limit",
+        format!("{:?}", datastore.partition_limit()),
+        format!("{:?}", partition_limit),
+    );
+    datastore.set_partition_limit(partition_limit);
+
+    changed(
+        "proto-max-bulk-len",
+        limits.max_bulk_len().to_string(),
+        args.proto_max_bulk_len.to_string(),
+    );
+    limits.set_max_bulk_len(args.proto_max_bulk_len);
+
+    changed(
+        "max-pipeline-depth",
+        limits.max_pipeline_depth().to_string(),
+        args.max_pipeline_depth.max(1).to_string(),
+    );
+    limits.set_max_pipeline_depth(args.max_pipeline_depth);
+
+    let restart_only: [(&str, &dyn std::fmt::Debug, &dyn std::fmt::Debug); 18] = [
+        ("bind", &startup.bind, &args.bind),
+        ("port", &startup.port, &args.port),
+        ("databases", &startup.databases, &args.databases),
+        ("run-id", &startup.run_id, &args.run_id),
+        ("max-keys", &startup.max_keys, &args.max_keys),
+        (
+            "block-cache-size",
+            &startup.block_cache_size,
+            &args.block_cache_size,
+        ),
+        ("single-thread", &startup.single_thread, &args.single_thread),
+        (
+            "read-only-store",
+            &startup.read_only_store,
+            &args.read_only_store,
+        ),
+        ("requirepass", &startup.requirepass, &args.requirepass),
+        ("user", &startup.users, &args.users),
+        (
+            "rename-command",
+            &startup.rename_command,
+            &args.rename_command,
+        ),
+        ("audit-log", &startup.audit_log, &args.audit_log),
+        ("tcp-nodelay", &startup.tcp_nodelay, &args.tcp_nodelay),
+        ("tcp-keepalive", &startup.tcp_keepalive, &args.tcp_keepalive),
+        (
+            "unknown-command-policy",
+            &startup.unknown_command_policy,
+            &args.unknown_command_policy,
+        ),
+        (
+            "max-multibulk-len",
+            &startup.max_multibulk_len,
+            &args.max_multibulk_len,
+        ),
+        (
+            "max-request-len",
+            &startup.max_request_len,
+            &args.max_request_len,
+        ),
+        (
+            "accept-backoff-ms",
+            &startup.accept_backoff_ms,
+            &args.accept_backoff_ms,
+        ),
+    ];
+    for (name, old, new) in restart_only {
+        if format!("{:?}", old) != format!("{:?}", new) {
+            changes.push(format!("{}: changed, ignored until restart", name));
+        }
+    }
+    changes
+}
+
+// Syncs the journal once per second while the policy is everysec. Runs for
+// the lifetime of the server, so the policy can be switched at runtime.
+async fn fsync_every_second(datastore: DataStore) {
+    let mut interval = tokio::time::interval(Duration::from_secs(1));
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        interval.tick().await;
+        if datastore.fsync_policy() != FsyncPolicy::EverySec {
+            continue;
         }
-        BytesFrame::Null => todo!(),
+        let synced = if datastore.single_thread() {
+            Ok(datastore.persist())
+        } else {
+            let datastore = datastore.clone();
+            tokio::task::spawn_blocking(move || datastore.persist()).await
+        };
+        match synced {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => eprintln!("Error syncing journal: {:?}", e),
+            Err(e) => eprintln!("Error syncing journal: {:?}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    fn parse(config: &str, args: &[&str]) -> Result<Args, Box<dyn std::error::Error>> {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        file.write_all(config.as_bytes()).unwrap();
+        let args = std::iter::once("veifka").chain(args.iter().copied());
+        parse_with_config_file(file.path(), args.map(Into::into))
+    }
+
+    #[test]
+    fn test_config_file() {
+        let config = "# Cache node\n\
+                      port 7000\n\
+                      requirepass \"s3cret pass\"\n\
+                      databases 4\n\
+                      appendfsync always\n\
+                      preload yes\n";
+        let args = parse(config, &[]).unwrap();
+        assert_eq!(args.port, 7000);
+        assert_eq!(args.requirepass.as_deref(), Some("s3cret pass"));
+        assert_eq!(args.databases, 4);
+        assert_eq!(args.fsync, FsyncPolicy::Always);
+        assert!(args.preload);
+        assert_eq!(args.bind, ["127.0.0.1"]);
+
+        // bind can be repeated
+        let args = parse("bind 127.0.0.1\nbind ::1\n", &[]).unwrap();
+        assert_eq!(args.bind, ["127.0.0.1", "::1"]);
+
+        // Command line flags win
+        let args = parse(config, &["--port", "7001"]).unwrap();
+        assert_eq!(args.port, 7001);
+        assert_eq!(args.databases, 4);
+
+        let args = parse("max-ttl 86400\nttl-policy reject\n", &[]).unwrap();
+        assert_eq!(args.max_ttl, Some(86400));
+        assert_eq!(args.ttl_policy, TtlPolicy::Reject);
+
+        let args = parse("user reader:pw:+@read\nuser app:pw:+@all\n", &[]).unwrap();
+        assert_eq!(args.users.len(), 2);
+        assert!(parse("user reader\n", &[]).is_err());
+
+        let args = parse("tcp-nodelay no\ntcp-keepalive 300\n", &[]).unwrap();
+        assert!(!args.tcp_nodelay);
+        assert_eq!(args.tcp_keepalive, 300);
+        assert!(parse("", &[]).unwrap().tcp_nodelay);
+
+        let run_id = "0123456789ABCDEF0123456789abcdef01234567";
+        let args = parse("", &["--run-id", run_id]).unwrap();
+        assert_eq!(args.run_id.as_deref(), Some(&*run_id.to_ascii_lowercase()));
+        assert!(parse("run-id 0123\n", &[]).is_err());
+
+        let args = parse("group-commit-window-us 1000\n", &[]).unwrap();
+        assert_eq!(group_commit(&args).window, Duration::from_millis(1));
+        assert_eq!(
+            args.group_commit_max_writes,
+            DEFAULT_GROUP_COMMIT_MAX_WRITES
+        );
+        assert!(parse_run_id(&"g".repeat(RUN_ID_LEN)).is_err());
+
+        assert!(parse("maxclients 10\n", &[]).is_err());
+        assert!(parse("preload maybe\n", &[]).is_err());
+    }
+
+    #[test]
+    fn test_rename_command() {
+        let config = "rename-command FLUSHDB \"\"\n\
+                      rename-command CONFIG \"my config\"\n";
+        let args = parse(config, &["--rename-command", "DEBUG", "dbg"]).unwrap();
+        assert_eq!(
+            args.rename_command,
+            ["FLUSHDB", "", "CONFIG", "my config", "DEBUG", "dbg"]
+        );
+        assert!(parse("rename-command FLUSHDB\n", &[]).is_err());
+
+        assert_eq!(config_values("a  \"b c\"\t\"\""), ["a", "b c", ""]);
+        assert_eq!(config_values("x\"y\"z"), ["xyz"]);
+        assert!(config_values("").is_empty());
+    }
+
+    fn open_datastore() -> (tempfile::TempDir, DataStore) {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let datastore = DataStore::new(temp_dir.path().to_str().unwrap()).unwrap();
+        (temp_dir, datastore)
+    }
+
+    fn limits(args: &Args) -> ProtoLimits {
+        ProtoLimits::new(
+            args.proto_max_bulk_len,
+            args.max_multibulk_len,
+            args.max_request_len,
+            args.max_pipeline_depth,
+        )
+    }
+
+    #[test]
+    fn test_apply_reload() {
+        let (_temp_dir, datastore) = open_datastore();
+        let startup = parse("port 7000\nmax-ttl 100\n", &[]).unwrap();
+        let limits = limits(&startup);
+        apply_reload(&startup, &startup, &datastore, &limits);
+        assert!(apply_reload(&startup, &startup, &datastore, &limits).is_empty());
+
+        let args = parse(
+            "port 7001\nmax-ttl 200\nappendfsync always\nproto-max-bulk-len 1024\n",
+            &[],
+        )
+        .unwrap();
+        let changes = apply_reload(&startup, &args, &datastore, &limits);
+        assert_eq!(changes.len(), 4);
+        assert_eq!(changes[0], "appendfsync: everysec -> always");
+        assert_eq!(changes[3], "port: changed, ignored until restart");
+        assert_eq!(datastore.fsync_policy(), FsyncPolicy::Always);
+        assert_eq!(datastore.ttl_limits().max, Some(Duration::from_secs(200)));
+        assert_eq!(limits.max_bulk_len(), 1024);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_reload_on_hangup() {
+        let (_temp_dir, datastore) = open_datastore();
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        file.write_all(b"max-ttl 100\n").unwrap();
+        let command_line = vec![std::ffi::OsString::from("veifka")];
+        let startup = parse_with_config_file(file.path(), command_line.clone()).unwrap();
+        let limits = limits(&startup);
+        apply_reload(&startup, &startup, &datastore, &limits);
+
+        let hangups =
+            tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup()).unwrap();
+        tokio::spawn(reload_on_hangup(
+            hangups,
+            file.path().to_path_buf(),
+            command_line,
+            startup,
+            datastore.clone(),
+            limits,
+        ));
+        std::fs::write(file.path(), "max-ttl 200\n").unwrap();
+        let status = std::process::Command::new("kill")
+            .args(["-HUP", &std::process::id().to_string()])
+            .status()
+            .unwrap();
+        assert!(status.success());
+
+        let reloaded = async {
+            while datastore.ttl_limits().max != Some(Duration::from_secs(200)) {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(5), reloaded)
+            .await
+            .expect("config reloaded");
     }
 }
@@ -0,0 +1,191 @@
+use crate::PubSub;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::sync::Arc;
+
+// Event classes and channel kinds, one bit each. Letters follow Redis'
+// notify-keyspace-events.
+const KEYSPACE: u16 = 1 << 0;
+const KEYEVENT: u16 = 1 << 1;
+pub(crate) const GENERIC: u16 = 1 << 2;
+pub(crate) const STRING: u16 = 1 << 3;
+const LIST: u16 = 1 << 4;
+const SET: u16 = 1 << 5;
+const HASH: u16 = 1 << 6;
+const ZSET: u16 = 1 << 7;
+pub(crate) const EXPIRED: u16 = 1 << 8;
+pub(crate) const EVICTED: u16 = 1 << 9;
+const STREAM: u16 = 1 << 10;
+const MODULE: u16 = 1 << 11;
+const KEY_MISS: u16 = 1 << 12;
+const NEW: u16 = 1 << 13;
+
+const FLAGS: [(char, u16); 14] = [
+    ('g', GENERIC),
+    ('$', STRING),
+    ('l', LIST),
+    ('s', SET),
+    ('h', HASH),
+    ('z', ZSET),
+    ('x', EXPIRED),
+    ('e', EVICTED),
+    ('t', STREAM),
+    ('d', MODULE),
+    ('m', KEY_MISS),
+    ('n', NEW),
+    ('K', KEYSPACE),
+    ('E', KEYEVENT),
+];
+
+// Classes enabled by 'A'
+const ALL: u16 = GENERIC | STRING | LIST | SET | HASH | ZSET | EXPIRED | EVICTED | STREAM | MODULE;
+
+// The keyspace notifications to publish, as set with
+// `CONFIG SET notify-keyspace-events`. Nothing is published unless at least
+// one class and one of K (`__keyspace@0__:<key>` channels) or E
+// (`__keyevent@0__:<event>` channels) are enabled. Partitions are not
+// numbered, so every event is reported for db 0.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct KeyspaceEvents(u16);
+
+impl KeyspaceEvents {
+    pub fn none() -> Self {
+        KeyspaceEvents(0)
+    }
+
+    fn publishes(self, class: u16) -> bool {
+        self.0 & class != 0 && self.0 & (KEYSPACE | KEYEVENT) != 0
+    }
+}
+
+impl FromStr for KeyspaceEvents {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut flags = 0;
+        for c in s.chars() {
+            flags |= match c {
+                'A' => ALL,
+                c => FLAGS
+                    .iter()
+                    .find(|(flag, _)| *flag == c)
+                    .map(|(_, bit)| *bit)
+                    .ok_or_else(|| format!("invalid event class '{}'", c))?,
+            };
+        }
+        Ok(KeyspaceEvents(flags))
+    }
+}
+
+impl fmt::Display for KeyspaceEvents {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut remaining = self.0;
+        if remaining & ALL == ALL {
+            f.write_str("A")?;
+            remaining &= !ALL;
+        }
+        for (flag, bit) in FLAGS {
+            if remaining & bit != 0 {
+                write!(f, "{}", flag)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+// Publishes keyspace notifications for a data store and all its partitions
+#[derive(Clone)]
+pub(crate) struct Notifier {
+    pubsub: PubSub,
+    events: Arc<AtomicU16>,
+}
+
+impl Notifier {
+    pub(crate) fn new(pubsub: PubSub) -> Self {
+        Notifier {
+            pubsub,
+            events: Arc::new(AtomicU16::new(0)),
+        }
+    }
+
+    pub(crate) fn pubsub(&self) -> &PubSub {
+        &self.pubsub
+    }
+
+    pub(crate) fn events(&self) -> KeyspaceEvents {
+        KeyspaceEvents(self.events.load(Ordering::Relaxed))
+    }
+
+    pub(crate) fn set_events(&self, events: KeyspaceEvents) {
+        self.events.store(events.0, Ordering::Relaxed);
+    }
+
+    // Publishes `event` on `key` if its class is enabled
+    pub(crate) fn notify(&self, class: u16, event: &str, key: &[u8]) {
+        let events = self.events();
+        if !events.publishes(class) {
+            return;
+        }
+        if events.0 & KEYSPACE != 0 {
+            let mut channel = b"__keyspace@0__:".to_vec();
+            channel.extend_from_slice(key);
+            self.pubsub.publish(&channel, event.as_bytes());
+        }
+        if events.0 & KEYEVENT != 0 {
+            let channel = format!("__keyevent@0__:{}", event);
+            self.pubsub.publish(channel.as_bytes(), key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{DataStore, Message};
+    use std::time::Duration;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_parse_and_format() {
+        assert_eq!("".parse::<KeyspaceEvents>(), Ok(KeyspaceEvents::none()));
+        assert_eq!("Eg$".parse::<KeyspaceEvents>().unwrap().to_string(), "g$E");
+        assert_eq!("KEA".parse::<KeyspaceEvents>().unwrap().to_string(), "AKE");
+        assert!("Kq".parse::<KeyspaceEvents>().is_err());
+
+        // Needs both a class and a channel kind
+        assert!(!"K".parse::<KeyspaceEvents>().unwrap().publishes(GENERIC));
+        assert!(!"g".parse::<KeyspaceEvents>().unwrap().publishes(GENERIC));
+        assert!("Kg".parse::<KeyspaceEvents>().unwrap().publishes(GENERIC));
+        assert!(!"Kg".parse::<KeyspaceEvents>().unwrap().publishes(STRING));
+    }
+
+    #[tokio::test]
+    async fn test_expired_event() {
+        let temp_dir = TempDir::new().expect("Failed to create temp dir");
+        let data_store = DataStore::new(temp_dir.path().to_str().unwrap()).unwrap();
+        let partition = data_store.partition("events").unwrap();
+        data_store.set_keyspace_events("Ex".parse().unwrap());
+
+        let mut subscriber = data_store.pubsub().subscriber();
+        subscriber.subscribe(b"__keyevent@0__:expired");
+        subscriber.subscribe(b"__keyevent@0__:set");
+
+        partition.set(b"key", b"value").unwrap();
+        partition
+            .expire_at(b"key", crate::now_millis() + 20)
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(40)).await;
+        // Keys expire lazily, when next accessed
+        assert_eq!(partition.get(b"key").unwrap(), None);
+
+        // The set event is filtered out by class
+        assert_eq!(
+            subscriber.recv().await,
+            Some(Message {
+                channel: b"__keyevent@0__:expired".to_vec(),
+                payload: b"key".to_vec(),
+            })
+        );
+    }
+}
@@ -0,0 +1,176 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex, MutexGuard};
+use tokio::sync::broadcast;
+
+// Messages buffered per subscriber before a slow one starts missing messages
+const CHANNEL_CAPACITY: usize = 1024;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Message {
+    pub channel: Vec<u8>,
+    pub payload: Vec<u8>,
+}
+
+// Fan-out of published messages to every subscribed connection. Messages go
+// through a single broadcast channel and each `Subscriber` filters on its own
+// channels; a registry of per-channel subscriber counts answers PUBLISH.
+#[derive(Clone)]
+pub struct PubSub {
+    sender: broadcast::Sender<Message>,
+    subscribers: Arc<Mutex<HashMap<Vec<u8>, usize>>>,
+}
+
+impl PubSub {
+    pub fn new() -> Self {
+        let (sender, _) = broadcast::channel(CHANNEL_CAPACITY);
+        PubSub {
+            sender,
+            subscribers: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    // Sends `payload` to the subscribers of `channel` and returns how many
+    // there were
+    pub fn publish(&self, channel: &[u8], payload: &[u8]) -> usize {
+        let receivers = self.registry().get(channel).copied().unwrap_or(0);
+        if receivers > 0 {
+            // Only fails when no connection is listening at all
+            let _ = self.sender.send(Message {
+                channel: channel.to_vec(),
+                payload: payload.to_vec(),
+            });
+        }
+        receivers
+    }
+
+    pub fn subscriber(&self) -> Subscriber {
+        Subscriber {
+            pubsub: self.clone(),
+            receiver: None,
+            channels: HashSet::new(),
+        }
+    }
+
+    fn registry(&self) -> MutexGuard<'_, HashMap<Vec<u8>, usize>> {
+        // Counts are updated in single steps, so a poisoned lock is still usable
+        self.subscribers
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl Default for PubSub {
+    fn default() -> Self {
+        PubSub::new()
+    }
+}
+
+// The channel subscriptions of a single connection. Dropping it unsubscribes
+// from everything.
+pub struct Subscriber {
+    pubsub: PubSub,
+    // Only held while subscribed, so idle connections don't buffer messages
+    receiver: Option<broadcast::Receiver<Message>>,
+    channels: HashSet<Vec<u8>>,
+}
+
+impl Subscriber {
+    // Subscribes to `channel` and returns the number of channels this
+    // subscriber is now subscribed to
+    pub fn subscribe(&mut self, channel: &[u8]) -> usize {
+        if self.receiver.is_none() {
+            self.receiver = Some(self.pubsub.sender.subscribe());
+        }
+        if self.channels.insert(channel.to_vec()) {
+            *self.pubsub.registry().entry(channel.to_vec()).or_insert(0) += 1;
+        }
+        self.channels.len()
+    }
+
+    // Unsubscribes from `channel` and returns the number of channels this
+    // subscriber is still subscribed to
+    pub fn unsubscribe(&mut self, channel: &[u8]) -> usize {
+        if self.channels.remove(channel) {
+            let mut registry = self.pubsub.registry();
+            if let Some(count) = registry.get_mut(channel) {
+                *count -= 1;
+                if *count == 0 {
+                    registry.remove(channel);
+                }
+            }
+        }
+        if self.channels.is_empty() {
+            self.receiver = None;
+        }
+        self.channels.len()
+    }
+
+    // Subscribed channels, in no particular order
+    pub fn channels(&self) -> Vec<Vec<u8>> {
+        self.channels.iter().cloned().collect()
+    }
+
+    pub fn is_subscribed(&self) -> bool {
+        !self.channels.is_empty()
+    }
+
+    // Waits for the next message on one of the subscribed channels, forever
+    // if there are none. Returns
+    // None if messages were dropped because this subscriber fell too far
+    // behind, in which case the connection should be closed, as Redis does
+    // with slow pub/sub clients.
+    pub async fn recv(&mut self) -> Option<Message> {
+        let Some(receiver) = self.receiver.as_mut() else {
+            return std::future::pending().await;
+        };
+        loop {
+            match receiver.recv().await {
+                Ok(message) if self.channels.contains(&message.channel) => return Some(message),
+                Ok(_) => continue,
+                Err(broadcast::error::RecvError::Lagged(_)) => return None,
+                // Unreachable while `self.pubsub` keeps a sender alive
+                Err(broadcast::error::RecvError::Closed) => return None,
+            }
+        }
+    }
+}
+
+impl Drop for Subscriber {
+    fn drop(&mut self) {
+        for channel in self.channels() {
+            self.unsubscribe(&channel);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_publish_reaches_subscribers() {
+        let pubsub = PubSub::new();
+        let mut news = pubsub.subscriber();
+        let mut other = pubsub.subscriber();
+        assert_eq!(news.subscribe(b"news"), 1);
+        assert_eq!(news.subscribe(b"news"), 1);
+        other.subscribe(b"other");
+
+        assert_eq!(pubsub.publish(b"other", b"skipped"), 1);
+        assert_eq!(pubsub.publish(b"news", b"hello"), 1);
+        assert_eq!(pubsub.publish(b"nobody", b"lost"), 0);
+        // Messages on channels the subscriber does not follow are skipped
+        assert_eq!(
+            news.recv().await,
+            Some(Message {
+                channel: b"news".to_vec(),
+                payload: b"hello".to_vec(),
+            })
+        );
+
+        assert_eq!(news.unsubscribe(b"news"), 0);
+        assert_eq!(pubsub.publish(b"news", b"hello"), 0);
+        drop(other);
+        assert_eq!(pubsub.publish(b"other", b"hello"), 0);
+    }
+}
@@ -0,0 +1,120 @@
+use crate::error::KeyQuotaExceeded;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+// Cap on the number of keys of a partition, see `DataStoreBuilder::max_keys`.
+// The count is taken by iterating the partition when it is opened, then kept
+// by every write that adds or removes a key. Keys past their expiry count
+// until they are removed, which happens lazily as they are next accessed.
+// Anything emptying a partition wholesale, as a FLUSHDB would, has to reset
+// the count along with it.
+pub(crate) struct KeyQuota {
+    max: u64,
+    count: AtomicU64,
+}
+
+impl KeyQuota {
+    pub(crate) fn new(max: u64, count: u64) -> Self {
+        KeyQuota {
+            max,
+            count: AtomicU64::new(count),
+        }
+    }
+
+    pub(crate) fn count(&self) -> u64 {
+        self.count.load(Ordering::SeqCst)
+    }
+
+    // Takes room for `keys` keys about to be added, failing if that would
+    // take the count past the quota
+    pub(crate) fn reserve(&self, keys: u64) -> Result<(), fjall::Error> {
+        if keys == 0 {
+            return Ok(());
+        }
+        self.count
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |count| {
+                count.checked_add(keys).filter(|&count| count <= self.max)
+            })
+            .map(|_| ())
+            .map_err(|_| key_quota_exceeded())
+    }
+
+    // Gives back room, for keys removed or reserved but not added after all
+    pub(crate) fn release(&self, keys: u64) {
+        let _ = self
+            .count
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |count| {
+                Some(count.saturating_sub(keys))
+            });
+    }
+}
+
+// Write paths return fjall's error type, so the quota error travels in it and
+// is turned back into `DataStoreError::KeyQuotaExceeded` on conversion
+pub(crate) fn key_quota_exceeded() -> fjall::Error {
+    std::io::Error::other(KeyQuotaExceeded).into()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{DataStore, DataStoreError, GroupCommit};
+    use std::time::Duration;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_key_quota() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().to_str().unwrap();
+        let data_store = DataStore::builder().max_keys(3).open(path).unwrap();
+        let partition = data_store.partition("tenant").unwrap();
+        for key in [&b"a"[..], b"b", b"c"] {
+            partition.set(key, b"value").unwrap();
+        }
+        assert_eq!(partition.key_count(), Some(3));
+
+        let e = partition.set(b"d", b"value").unwrap_err();
+        assert!(matches!(
+            DataStoreError::from(e),
+            DataStoreError::KeyQuotaExceeded
+        ));
+        assert!(matches!(
+            partition.set_add(b"set", &[b"member"]),
+            Err(DataStoreError::KeyQuotaExceeded)
+        ));
+        assert_eq!(partition.get(b"d").unwrap(), None);
+
+        // Overwrites don't take room, deletes free it
+        partition.set(b"a", b"new value").unwrap();
+        assert!(partition.delete(b"b").unwrap());
+        assert_eq!(partition.key_count(), Some(2));
+        partition.set(b"d", b"value").unwrap();
+        assert_eq!(partition.key_count(), Some(3));
+
+        // Expired keys count until they are removed
+        partition.set_expiring(b"c", b"value", Some(1)).unwrap();
+        assert!(partition.set(b"e", b"value").is_err());
+        assert_eq!(partition.get(b"c").unwrap(), None);
+        partition.set(b"e", b"value").unwrap();
+
+        // Grouped writes past the quota fail on their own
+        data_store.set_group_commit(GroupCommit {
+            window: Duration::from_millis(1),
+            max_writes: 16,
+        });
+        let e = partition.set(b"f", b"value").unwrap_err();
+        assert!(matches!(
+            DataStoreError::from(e),
+            DataStoreError::KeyQuotaExceeded
+        ));
+        assert!(partition.delete(b"e").unwrap());
+        partition.set(b"f", b"value").unwrap();
+        assert_eq!(partition.key_count(), Some(3));
+
+        // The count is taken again on open
+        drop(partition);
+        drop(data_store);
+        let data_store = DataStore::builder().max_keys(3).open(path).unwrap();
+        let partition = data_store.partition("tenant").unwrap();
+        assert_eq!(partition.key_count(), Some(3));
+        assert!(partition.set(b"g", b"value").is_err());
+    }
+}
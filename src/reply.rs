@@ -0,0 +1,108 @@
+use bytes::Bytes;
+use redis_protocol::resp2::types::BytesFrame;
+use redis_protocol::resp3::types::BytesFrame as Resp3Frame;
+
+// Builders for command replies. Replies are built as RESP2 frames, nested as
+// deep as a command needs, and converted whole by `to_resp3` for connections
+// speaking RESP3, so each reply's shape is only spelled out once.
+
+pub(crate) fn ok() -> BytesFrame {
+    simple("OK")
+}
+
+pub(crate) fn simple(status: impl Into<Bytes>) -> BytesFrame {
+    BytesFrame::SimpleString(status.into())
+}
+
+// `message` starts with the error code, e.g. "ERR" or "WRONGTYPE"
+pub(crate) fn error(message: impl Into<String>) -> BytesFrame {
+    BytesFrame::Error(message.into().into())
+}
+
+pub(crate) fn integer(value: impl Into<i64>) -> BytesFrame {
+    BytesFrame::Integer(value.into())
+}
+
+pub(crate) fn bulk(data: impl Into<Bytes>) -> BytesFrame {
+    BytesFrame::BulkString(data.into())
+}
+
+pub(crate) fn nil() -> BytesFrame {
+    BytesFrame::Null
+}
+
+pub(crate) fn array(items: impl IntoIterator<Item = BytesFrame>) -> BytesFrame {
+    BytesFrame::Array(items.into_iter().collect())
+}
+
+// Field/value pairs as the flat [field1, value1, field2, value2, ...] array
+// Redis replies with for CONFIG GET and friends
+pub(crate) fn map_as_array(
+    pairs: impl IntoIterator<Item = (BytesFrame, BytesFrame)>,
+) -> BytesFrame {
+    array(pairs.into_iter().flat_map(|(field, value)| [field, value]))
+}
+
+pub(crate) fn to_resp3(frame: BytesFrame) -> Resp3Frame {
+    match frame {
+        BytesFrame::SimpleString(data) => Resp3Frame::SimpleString {
+            data,
+            attributes: None,
+        },
+        BytesFrame::Error(data) => Resp3Frame::SimpleError {
+            data,
+            attributes: None,
+        },
+        BytesFrame::Integer(data) => Resp3Frame::Number {
+            data,
+            attributes: None,
+        },
+        BytesFrame::BulkString(data) => Resp3Frame::BlobString {
+            data,
+            attributes: None,
+        },
+        BytesFrame::Array(frames) => Resp3Frame::Array {
+            data: frames.into_iter().map(to_resp3).collect(),
+            attributes: None,
+        },
+        BytesFrame::Null => Resp3Frame::Null,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_nested_reply() {
+        let reply = array([
+            map_as_array([
+                (bulk("field"), integer(1)),
+                (bulk("entries"), array([bulk("a"), nil()])),
+            ]),
+            error("ERR nope"),
+        ]);
+        assert_eq!(
+            reply,
+            BytesFrame::Array(vec![
+                BytesFrame::Array(vec![
+                    BytesFrame::BulkString("field".into()),
+                    BytesFrame::Integer(1),
+                    BytesFrame::BulkString("entries".into()),
+                    BytesFrame::Array(vec![BytesFrame::BulkString("a".into()), BytesFrame::Null]),
+                ]),
+                BytesFrame::Error("ERR nope".into()),
+            ])
+        );
+
+        let Resp3Frame::Array { data, .. } = to_resp3(reply) else {
+            panic!("expected an array");
+        };
+        let Resp3Frame::Array { data: inner, .. } = &data[0] else {
+            panic!("expected a nested array");
+        };
+        assert_eq!(inner.len(), 4);
+        assert!(matches!(&inner[3], Resp3Frame::Array { data, .. } if data.len() == 2));
+        assert!(matches!(&data[1], Resp3Frame::SimpleError { .. }));
+    }
+}
@@ -0,0 +1,330 @@
+use crate::datastore::{corrupted, member_key, member_prefix};
+use crate::glob::{glob_match, literal_prefix};
+use crate::value::{now_millis, StoredValue};
+use crate::{DataStoreError, DataStorePartition, KeyValue};
+use futures::Stream;
+use std::collections::BTreeMap;
+use std::ops::Bound;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+// Cursors SCAN keeps before forgetting the oldest
+const MAX_SCAN_CURSORS: usize = 4096;
+
+// Entries `scan_stream` reads ahead of its consumer
+const SCAN_STREAM_BUFFER: usize = 64;
+
+// One call's worth of SCAN
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ScanPage {
+    pub keys: Vec<Vec<u8>>,
+    // The last key examined, to carry on after; None once the scan is done
+    pub next: Option<Vec<u8>>,
+}
+
+impl DataStorePartition {
+    // The live keys matching the glob `pattern`, in key order. Reads every key
+    // under the pattern's literal prefix, so this is O(N) for patterns
+    // starting with a wildcard.
+    pub fn keys(&self, pattern: &[u8]) -> Result<Vec<Vec<u8>>, DataStoreError> {
+        let now = now_millis();
+        let mut keys = Vec::new();
+        for entry in self.partition_handle().prefix(literal_prefix(pattern)) {
+            let (key, bytes) = entry?;
+            if matches_pattern(Some(pattern), &key) && !is_expired(&bytes, now)? {
+                keys.push(key.to_vec());
+            }
+        }
+        Ok(keys)
+    }
+
+    // Examines up to `count` keys in key order, starting after `after`, and
+    // returns the live ones matching `pattern`. Like Redis' SCAN a page may
+    // hold fewer keys than examined, or none, without the scan being over.
+    pub fn scan(
+        &self,
+        after: Option<&[u8]>,
+        count: usize,
+        pattern: Option<&[u8]>,
+    ) -> Result<ScanPage, DataStoreError> {
+        let start = match after {
+            Some(key) => Bound::Excluded(key.to_vec()),
+            None => Bound::Unbounded,
+        };
+        let now = now_millis();
+        let mut page = ScanPage::default();
+        let mut last = None;
+        for (examined, entry) in self
+            .partition_handle()
+            .range::<Vec<u8>, _>((start, Bound::Unbounded))
+            .enumerate()
+        {
+            let (key, bytes) = entry?;
+            if examined == count.max(1) {
+                page.next = last;
+                break;
+            }
+            if matches_pattern(pattern, &key) && !is_expired(&bytes, now)? {
+                page.keys.push(key.to_vec());
+            }
+            last = Some(key.to_vec());
+        }
+        Ok(page)
+    }
+
+    // Examines up to `count` members of the container at `key` in byte
+    // order, starting after the member `after`, and returns those matching
+    // `pattern` with their values, and the last member examined unless none
+    // are left. Leaves checking the container's type to the caller.
+    pub(crate) fn scan_members(
+        &self,
+        key: &[u8],
+        after: Option<&[u8]>,
+        count: usize,
+        pattern: Option<&[u8]>,
+    ) -> Result<(Vec<KeyValue>, Option<Vec<u8>>), DataStoreError> {
+        let prefix = member_prefix(key);
+        let start = match after {
+            Some(member) => Bound::Excluded(member_key(key, member)),
+            None => Bound::Included(prefix.clone()),
+        };
+        let mut members = Vec::new();
+        let mut last = None;
+        for (examined, entry) in self
+            .members_handle()
+            .range::<Vec<u8>, _>((start, Bound::Unbounded))
+            .enumerate()
+        {
+            let (composite, value) = entry?;
+            let Some(member) = composite.strip_prefix(prefix.as_slice()) else {
+                break;
+            };
+            if examined == count.max(1) {
+                return Ok((members, last));
+            }
+            if matches_pattern(pattern, member) {
+                members.push((member.to_vec(), value.to_vec()));
+            }
+            last = Some(member.to_vec());
+        }
+        Ok((members, None))
+    }
+
+    // Every live key with the value `get` returns for it, in key order, for
+    // async embedders going through partitions too large to collect. A
+    // blocking task reads the partition, staying at most SCAN_STREAM_BUFFER
+    // entries ahead of the consumer, and stops at its next entry once the
+    // stream is dropped. The stream ends after the first error. Must be
+    // called within a tokio runtime.
+    pub fn scan_stream(&self) -> impl Stream<Item = Result<KeyValue, fjall::Error>> + Send {
+        self.spawn_scan_stream(SCAN_STREAM_BUFFER).0
+    }
+
+    // `scan_stream`, with the task feeding it
+    fn spawn_scan_stream(
+        &self,
+        buffer: usize,
+    ) -> (
+        impl Stream<Item = Result<KeyValue, fjall::Error>> + Send,
+        JoinHandle<()>,
+    ) {
+        let (sender, receiver) = mpsc::channel(buffer);
+        let partition = self.clone();
+        let feeder = tokio::task::spawn_blocking(move || partition.feed_scan_stream(&sender));
+        let stream = futures::stream::unfold(receiver, |mut receiver| async move {
+            receiver.recv().await.map(|entry| (entry, receiver))
+        });
+        (stream, feeder)
+    }
+
+    fn feed_scan_stream(&self, sender: &mpsc::Sender<Result<KeyValue, fjall::Error>>) {
+        let now = now_millis();
+        for entry in self.partition_handle().iter() {
+            let entry = entry.and_then(|(key, bytes)| {
+                let stored = StoredValue::decode(&bytes).map_err(corrupted)?;
+                Ok((!stored.is_expired(now)).then(|| (key.to_vec(), stored.payload)))
+            });
+            let entry = match entry {
+                Ok(None) => continue,
+                Ok(Some(entry)) => Ok(entry),
+                Err(e) => Err(e),
+            };
+            let failed = entry.is_err();
+            // Sending fails once the stream is dropped
+            if sender.blocking_send(entry).is_err() || failed {
+                return;
+            }
+        }
+    }
+}
+
+// "*" matches every key, the empty one too, which `glob_match` alone doesn't
+pub(crate) fn matches_pattern(pattern: Option<&[u8]>, key: &[u8]) -> bool {
+    match pattern {
+        None | Some(b"*") => true,
+        Some(pattern) => glob_match(pattern, key),
+    }
+}
+
+fn is_expired(bytes: &[u8], now: u64) -> Result<bool, DataStoreError> {
+    Ok(StoredValue::peek_expires_at(bytes)?.is_some_and(|deadline| deadline <= now))
+}
+
+// The positions of the scans in progress behind the integer cursors SCAN
+// replies with, as clients expect integers. A cursor stands for the last key
+// a page examined, so a scan returns every key that exists throughout it
+// whatever is written meanwhile. Shared by the partitions of a data store.
+#[derive(Default)]
+pub(crate) struct ScanCursors {
+    last: AtomicU64,
+    positions: Mutex<BTreeMap<u64, Vec<u8>>>,
+}
+
+impl ScanCursors {
+    // A new cursor, never 0, to carry on after `position`
+    pub(crate) fn save(&self, position: Vec<u8>) -> u64 {
+        let cursor = self.last.fetch_add(1, Ordering::Relaxed) + 1;
+        let mut positions = self.lock();
+        positions.insert(cursor, position);
+        while positions.len() > MAX_SCAN_CURSORS {
+            positions.pop_first();
+        }
+        cursor
+    }
+
+    // Left in place, so a client can retry a page
+    pub(crate) fn position(&self, cursor: u64) -> Option<Vec<u8>> {
+        self.lock().get(&cursor).cloned()
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, BTreeMap<u64, Vec<u8>>> {
+        self.positions.lock().unwrap_or_else(|p| p.into_inner())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::DataStore;
+    use futures::StreamExt;
+    use std::time::Duration;
+    use tempfile::TempDir;
+
+    #[tokio::test]
+    async fn test_scan_stream() {
+        let temp_dir = TempDir::new().expect("Failed to create temp dir");
+        let data_store = DataStore::new(temp_dir.path().to_str().unwrap()).unwrap();
+        let partition = data_store.partition("test").unwrap();
+        partition.set(b"b", b"2").unwrap();
+        partition.set(b"a", b"1").unwrap();
+        partition
+            .set_expiring(b"expiring", b"x", Some(now_millis() + 20))
+            .unwrap();
+        partition.set(b"c", b"3").unwrap();
+        tokio::time::sleep(Duration::from_millis(40)).await;
+
+        let entries: Vec<_> = partition.scan_stream().collect().await;
+        let entries: Vec<KeyValue> = entries.into_iter().map(Result::unwrap).collect();
+        assert_eq!(
+            entries,
+            vec![
+                (b"a".to_vec(), b"1".to_vec()),
+                (b"b".to_vec(), b"2".to_vec()),
+                (b"c".to_vec(), b"3".to_vec()),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_dropped_scan_stream_stops_feeder() {
+        let temp_dir = TempDir::new().expect("Failed to create temp dir");
+        let data_store = DataStore::new(temp_dir.path().to_str().unwrap()).unwrap();
+        let partition = data_store.partition("test").unwrap();
+        for i in 0..1000u32 {
+            partition.set(&i.to_be_bytes(), b"value").unwrap();
+        }
+
+        let (stream, feeder) = partition.spawn_scan_stream(4);
+        let mut stream = Box::pin(stream);
+        for i in 0..10u32 {
+            let (key, _) = stream.next().await.unwrap().unwrap();
+            assert_eq!(key, i.to_be_bytes());
+        }
+        // The feeder is blocked on the full buffer, far from the end
+        assert!(!feeder.is_finished());
+        drop(stream);
+        tokio::time::timeout(Duration::from_secs(5), feeder)
+            .await
+            .expect("the feeder kept running")
+            .unwrap();
+    }
+
+    #[test]
+    fn test_keys_and_scan() {
+        let temp_dir = TempDir::new().expect("Failed to create temp dir");
+        let data_store = DataStore::new(temp_dir.path().to_str().unwrap()).unwrap();
+        let partition = data_store.partition("test").unwrap();
+        assert!(partition.keys(b"*").unwrap().is_empty());
+        assert_eq!(partition.scan(None, 10, None).unwrap(), ScanPage::default());
+
+        for key in [&b"user:1"[..], b"user:2", b"user:3", b"session:1", b"other"] {
+            partition.set(key, b"value").unwrap();
+        }
+        partition.set_add(b"user:set", &[b"a"]).unwrap();
+        assert_eq!(
+            partition.keys(b"user:*").unwrap(),
+            vec![
+                b"user:1".to_vec(),
+                b"user:2".to_vec(),
+                b"user:3".to_vec(),
+                b"user:set".to_vec()
+            ]
+        );
+        assert_eq!(partition.keys(b"*:1").unwrap().len(), 2);
+        assert!(partition.keys(b"cart:*").unwrap().is_empty());
+
+        // Binary keys are matched byte by byte
+        partition.set(b"bin:\xff\x00", b"value").unwrap();
+        assert_eq!(
+            partition.keys(b"bin:[\x80-\xff]?").unwrap(),
+            vec![b"bin:\xff\x00".to_vec()]
+        );
+        assert!(partition.keys(b"bin:[^\xff]*").unwrap().is_empty());
+        partition.delete(b"bin:\xff\x00").unwrap();
+
+        let mut keys = Vec::new();
+        let mut after = None;
+        let mut pages = 0;
+        loop {
+            let page = partition.scan(after.as_deref(), 2, None).unwrap();
+            keys.extend(page.keys);
+            pages += 1;
+            match page.next {
+                Some(next) => after = Some(next),
+                None => break,
+            }
+        }
+        assert_eq!(keys.len(), 6);
+        assert_eq!(pages, 3);
+
+        let page = partition.scan(None, 100, Some(b"session:*")).unwrap();
+        assert_eq!(page.keys, vec![b"session:1".to_vec()]);
+        assert_eq!(page.next, None);
+    }
+
+    #[test]
+    fn test_scan_cursors() {
+        let cursors = ScanCursors::default();
+        let first = cursors.save(b"a".to_vec());
+        assert_ne!(first, 0);
+        assert_eq!(cursors.position(first), Some(b"a".to_vec()));
+        assert_eq!(cursors.position(first), Some(b"a".to_vec()));
+        for _ in 0..MAX_SCAN_CURSORS {
+            cursors.save(b"b".to_vec());
+        }
+        assert_eq!(cursors.position(first), None);
+        assert_eq!(cursors.position(0), None);
+    }
+}
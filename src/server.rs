@@ -0,0 +1,6476 @@
+use bytes::{Bytes, BytesMut};
+use futures::stream::StreamExt;
+use futures::{FutureExt, SinkExt};
+use redis_protocol::codec::{Resp2, Resp3};
+use redis_protocol::error::{RedisProtocolError, RedisProtocolErrorKind};
+use redis_protocol::resp2::types::BytesFrame;
+use redis_protocol::resp3::types::BytesFrame as Resp3Frame;
+use redis_protocol::resp3::types::VerbatimStringFormat;
+use socket2::{SockRef, TcpKeepalive};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::future::Future;
+use std::io::{self, BufWriter, Write};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_util::codec::{Decoder, Encoder, Framed};
+use tokio_util::sync::CancellationToken;
+
+use crate::clients::{ClientHandle, Clients};
+use crate::compress::{compress_value, DEFAULT_COMPRESSION_MIN_SIZE};
+use crate::help;
+use crate::lcs::lcs;
+use crate::reply::{self, to_resp3};
+use crate::{
+    now_millis, parse_float, AclUser, BatchOp, BigKeysReport, DataStore, DataStoreError,
+    DataStorePartition, Expiry, FsyncPolicy, GroupCommit, KeyspaceEvents, KvBackend, ListEnd,
+    Message, Permissions, PubSub, Subscriber, DEFAULT_BIGKEYS_SCAN_LIMIT,
+    DEFAULT_MEMORY_USAGE_SAMPLES,
+};
+
+const MAX_ACCEPT_BACKOFF: Duration = Duration::from_secs(1);
+pub const DEFAULT_ACCEPT_BACKOFF: Duration = Duration::from_millis(10);
+
+// Defaults follow Redis' proto-max-bulk-len, multibulk limit and
+// client-query-buffer-limit
+pub const DEFAULT_PROTO_MAX_BULK_LEN: usize = 512 * 1024 * 1024;
+pub const DEFAULT_MAX_MULTIBULK_LEN: usize = 1024 * 1024;
+pub const DEFAULT_MAX_REQUEST_LEN: usize = 1024 * 1024 * 1024;
+pub const DEFAULT_MAX_PIPELINE_DEPTH: usize = 1024;
+// Longest header line (`*<count>` or `$<len>`) worth waiting for
+const MAX_HEADER_LINE: usize = 32;
+// Smallest encoded argument, `$0\r\n\r\n`
+const MIN_BULK_ENCODED_LEN: usize = 6;
+// Smallest proto-max-bulk-len CONFIG SET accepts, as Redis
+const MIN_CONFIG_BULK_LEN: usize = 1024 * 1024;
+// How long a graceful shutdown waits for connections to finish the command
+// they are running, e.g. a blocked BLPOP, before syncing the journal anyway
+const SHUTDOWN_DRAIN_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Accepts RESP connections on `listener` until accepting fails for good,
+// serving each one on its own task
+pub async fn serve(listener: TcpListener, context: ServerContext) -> io::Result<()> {
+    loop {
+        let (socket, _) = accept_with_retry(|| listener.accept(), context.accept_backoff).await?;
+        // The connection is still usable with default options
+        if let Err(e) = context.configure_socket(&socket) {
+            eprintln!("Error setting socket options: {}", e);
+        }
+
+        let datastore = context.databases.datastore.clone();
+        let context = context.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_client(socket, datastore, context).await {
+                eprintln!("Error handling client: {:?}", e)
+            }
+        });
+    }
+}
+
+// Runs `serve` on every listener at once, all of them sharing `context`.
+// Returns as soon as one listener fails for good, closing the others.
+pub async fn serve_all(listeners: Vec<TcpListener>, context: ServerContext) -> io::Result<()> {
+    let servers = listeners
+        .into_iter()
+        .map(|listener| serve(listener, context.clone()));
+    futures::future::try_join_all(servers).await?;
+    Ok(())
+}
+
+// Runs `serve_all` until `shutdown` completes, then shuts down gracefully:
+// stops accepting, closes every connection once the command it is running
+// has replied, and syncs the journal. A listener failing for good returns its
+// error without shutting down.
+pub async fn serve_all_until(
+    listeners: Vec<TcpListener>,
+    context: ServerContext,
+    shutdown: impl Future<Output = ()>,
+) -> io::Result<ShutdownSummary> {
+    tokio::select! {
+        result = serve_all(listeners, context.clone()) => result?,
+        () = shutdown => {}
+    }
+    Ok(context.shut_down().await)
+}
+
+// What a graceful shutdown did, so operators and orchestration reading the
+// logs can tell it was clean and the data is durable. Displays as a single
+// line of key=value fields.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ShutdownSummary {
+    // Connections that closed once told to
+    pub connections_drained: usize,
+    // Connections still open after SHUTDOWN_DRAIN_TIMEOUT
+    pub connections_abandoned: usize,
+    // Time spent syncing the journal
+    pub flush_duration: Duration,
+    // Bytes on disk once synced
+    pub disk_space: u64,
+    // Why syncing the journal failed, None if it succeeded
+    pub persist_error: Option<String>,
+}
+
+impl ShutdownSummary {
+    pub fn persisted(&self) -> bool {
+        self.persist_error.is_none()
+    }
+}
+
+impl fmt::Display for ShutdownSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "shutdown connections_drained={} connections_abandoned={} flush_ms={} disk_space={} persisted={}",
+            self.connections_drained,
+            self.connections_abandoned,
+            self.flush_duration.as_millis(),
+            self.disk_space,
+            self.persisted()
+        )?;
+        if let Some(e) = &self.persist_error {
+            write!(f, " error={:?}", e)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum AcceptErrorAction {
+    Retry,
+    Fatal,
+}
+
+// Errors that concern a single pending connection, or resource exhaustion
+// that clears once other connections close, are worth retrying. Anything else
+// means the listener itself is unusable.
+fn classify_accept_error(e: &io::Error) -> AcceptErrorAction {
+    match e.kind() {
+        io::ErrorKind::ConnectionAborted
+        | io::ErrorKind::ConnectionReset
+        | io::ErrorKind::ConnectionRefused
+        | io::ErrorKind::Interrupted
+        | io::ErrorKind::WouldBlock
+        | io::ErrorKind::TimedOut
+        | io::ErrorKind::OutOfMemory => AcceptErrorAction::Retry,
+        // EMFILE, ENFILE and ENOBUFS have no ErrorKind of their own
+        _ if is_resource_exhaustion(e) => AcceptErrorAction::Retry,
+        _ => AcceptErrorAction::Fatal,
+    }
+}
+
+#[cfg(unix)]
+fn is_resource_exhaustion(e: &io::Error) -> bool {
+    const ENFILE: i32 = 23;
+    const EMFILE: i32 = 24;
+    #[cfg(target_os = "linux")]
+    const ENOBUFS: i32 = 105;
+    #[cfg(not(target_os = "linux"))]
+    const ENOBUFS: i32 = 55;
+    matches!(e.raw_os_error(), Some(ENFILE | EMFILE | ENOBUFS))
+}
+
+#[cfg(not(unix))]
+fn is_resource_exhaustion(_e: &io::Error) -> bool {
+    false
+}
+
+// Waits for the next connection, logging and retrying transient accept errors
+// with an exponential backoff starting at `backoff`. Only fatal errors are
+// returned.
+async fn accept_with_retry<T, F, Fut>(mut accept: F, backoff: Duration) -> io::Result<T>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = io::Result<T>>,
+{
+    let mut delay = backoff;
+    loop {
+        match accept().await {
+            Ok(conn) => return Ok(conn),
+            Err(e) if classify_accept_error(&e) == AcceptErrorAction::Retry => {
+                eprintln!("Error accepting connection, retrying in {:?}: {}", delay, e);
+                tokio::time::sleep(delay).await;
+                delay = (delay * 2).min(MAX_ACCEPT_BACKOFF);
+            }
+            Err(e) => {
+                eprintln!("Fatal error accepting connection: {}", e);
+                return Err(e);
+            }
+        }
+    }
+}
+
+const MAX_PARTITION_NAME_LEN: usize = 128;
+
+// The partitions clients can SELECT: numbered databases, db 0 being the
+// default partition, plus partitions selected by name.
+//
+// Numbered databases are slots holding a partition. Connections look their
+// slot up for every command, so SWAPDB only swaps two slots: connections that
+// selected either database see the other's contents from their next command
+// on, while a command already running (including a blocked BLPOP) finishes
+// against the partition it started with. A partition selected by name stays
+// selected wherever SWAPDB moves it.
+#[derive(Clone)]
+pub struct Databases {
+    datastore: DataStore,
+    numbered: Arc<RwLock<Vec<DataStorePartition>>>,
+}
+
+impl Databases {
+    pub fn open(datastore: &DataStore, count: usize) -> Result<Self, DataStoreError> {
+        let numbered = (0..count.max(1))
+            .map(|index| match index {
+                0 => datastore.partition("default_partition"),
+                index => datastore.partition(&format!("db{}", index)),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Databases {
+            datastore: datastore.clone(),
+            numbered: Arc::new(RwLock::new(numbered)),
+        })
+    }
+
+    pub fn get(&self, index: usize) -> Option<DataStorePartition> {
+        self.slots().get(index).cloned()
+    }
+
+    fn len(&self) -> usize {
+        self.slots().len()
+    }
+
+    // Swaps the partitions of two numbered databases. Returns false if
+    // either index is out of range.
+    fn swap(&self, index1: usize, index2: usize) -> bool {
+        let mut slots = self.numbered.write().unwrap_or_else(|p| p.into_inner());
+        if index1.max(index2) >= slots.len() {
+            return false;
+        }
+        slots.swap(index1, index2);
+        true
+    }
+
+    fn slots(&self) -> std::sync::RwLockReadGuard<'_, Vec<DataStorePartition>> {
+        self.numbered.read().unwrap_or_else(|p| p.into_inner())
+    }
+
+    // The partition called `name`, opened on first use. The partitions of
+    // numbered databases are found under their own names.
+    fn named(&self, name: &str) -> Result<DataStorePartition, DataStoreError> {
+        // fjall panics on names outside this charset, and the members
+        // partition appends to the name, so check before opening
+        let valid = !name.is_empty()
+            && name.len() <= MAX_PARTITION_NAME_LEN
+            && name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '#'));
+        if !valid {
+            return Err(DataStoreError::PartitionError(format!(
+                "invalid partition name '{}'",
+                name
+            )));
+        }
+        self.datastore.partition(name)
+    }
+}
+
+// Server-wide settings and state shared by every connection
+#[derive(Clone)]
+pub struct ServerContext {
+    pubsub: PubSub,
+    databases: Databases,
+    limits: ProtoLimits,
+    requirepass: Option<Arc<str>>,
+    // Users besides "default", who has every permission
+    users: Arc<Vec<AclUser>>,
+    audit_log: Option<AuditLog>,
+    accept_backoff: Duration,
+    unknown_command_policy: UnknownCommandPolicy,
+    tcp_nodelay: bool,
+    tcp_keepalive: Option<Duration>,
+    clients: Arc<Clients>,
+    command_renames: Arc<CommandRenames>,
+    // Cancelled to close every connection for a graceful shutdown
+    shutdown: CancellationToken,
+}
+
+impl ServerContext {
+    pub fn new(databases: Databases, limits: ProtoLimits) -> Self {
+        ServerContext {
+            pubsub: databases.datastore.pubsub().clone(),
+            databases,
+            limits,
+            requirepass: None,
+            users: Arc::default(),
+            audit_log: None,
+            accept_backoff: DEFAULT_ACCEPT_BACKOFF,
+            unknown_command_policy: UnknownCommandPolicy::default(),
+            tcp_nodelay: true,
+            tcp_keepalive: None,
+            clients: Arc::default(),
+            command_renames: Arc::default(),
+            shutdown: CancellationToken::new(),
+        }
+    }
+
+    // Closes every connection, waiting up to SHUTDOWN_DRAIN_TIMEOUT for
+    // them, then syncs the journal
+    async fn shut_down(&self) -> ShutdownSummary {
+        let open = self.clients.len();
+        self.shutdown.cancel();
+        let deadline = Instant::now() + SHUTDOWN_DRAIN_TIMEOUT;
+        let mut still_open = open;
+        while still_open > 0 && Instant::now() < deadline {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            still_open = self.clients.len();
+        }
+
+        let datastore = self.databases.datastore.clone();
+        let start = Instant::now();
+        let persisted = match datastore
+            .run_blocking({
+                let datastore = datastore.clone();
+                move || datastore.persist()
+            })
+            .await
+        {
+            Ok(Ok(())) => None,
+            Ok(Err(e)) => Some(format!("{:?}", e)),
+            Err(e) => Some(format!("task error: {:?}", e)),
+        };
+        ShutdownSummary {
+            connections_drained: open.saturating_sub(still_open),
+            connections_abandoned: still_open,
+            flush_duration: start.elapsed(),
+            disk_space: datastore.disk_space(),
+            persist_error: persisted,
+        }
+    }
+
+    // Password clients must AUTH with before running other commands
+    pub fn set_requirepass(&mut self, password: &str) {
+        self.requirepass = Some(Arc::from(password));
+    }
+
+    // Lets clients AUTH as `user` and run the commands it is granted,
+    // replacing any user of the same name
+    pub fn add_user(&mut self, user: AclUser) {
+        let users = Arc::make_mut(&mut self.users);
+        users.retain(|existing| existing.name != user.name);
+        users.push(user);
+    }
+
+    // Makes `command` answer to `new_name` instead of its own name, or
+    // disables it if `new_name` is empty. Clients calling it by a name it
+    // doesn't answer to get the unknown command reply.
+    pub fn rename_command(&mut self, command: &str, new_name: &str) {
+        Arc::make_mut(&mut self.command_renames).rename(command, new_name);
+    }
+
+    // Appends a line to the file at `path` for every rejected command
+    pub fn set_audit_log(&mut self, path: &Path) -> io::Result<()> {
+        self.audit_log = Some(AuditLog::open(path)?);
+        Ok(())
+    }
+
+    // Initial delay before accepting again after a transient accept error,
+    // doubled on every consecutive failure up to one second
+    pub fn set_accept_backoff(&mut self, backoff: Duration) {
+        self.accept_backoff = backoff;
+    }
+
+    pub fn set_unknown_command_policy(&mut self, policy: UnknownCommandPolicy) {
+        self.unknown_command_policy = policy;
+    }
+
+    // Whether replies are sent without waiting to coalesce small writes
+    // (Nagle's algorithm), on by default as it delays request-response round
+    // trips
+    pub fn set_tcp_nodelay(&mut self, nodelay: bool) {
+        self.tcp_nodelay = nodelay;
+    }
+
+    // Idle time after which the OS probes a connection to detect dead peers,
+    // None to leave keepalive off
+    pub fn set_tcp_keepalive(&mut self, idle: Option<Duration>) {
+        self.tcp_keepalive = idle;
+    }
+
+    fn configure_socket(&self, socket: &TcpStream) -> io::Result<()> {
+        socket.set_nodelay(self.tcp_nodelay)?;
+        if let Some(idle) = self.tcp_keepalive {
+            SockRef::from(socket).set_tcp_keepalive(&TcpKeepalive::new().with_time(idle))?;
+        }
+        Ok(())
+    }
+
+    fn audit(
+        &self,
+        peer: Option<SocketAddr>,
+        request_id: Option<&str>,
+        command: &str,
+        reason: &str,
+    ) {
+        if let Some(audit_log) = &self.audit_log {
+            audit_log.record(peer, request_id, command, reason);
+        }
+    }
+}
+
+// Append-only record of rejected commands, for compliance rather than
+// diagnostics. Lines are handed to a writer thread, so recording one never
+// blocks a connection on disk I/O.
+#[derive(Clone)]
+struct AuditLog {
+    sender: std::sync::mpsc::Sender<String>,
+}
+
+impl AuditLog {
+    fn open(path: &Path) -> io::Result<Self> {
+        // Appends are atomic per write, so lines from several servers or a
+        // rotated file never interleave
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)?;
+        let (sender, receiver) = std::sync::mpsc::channel::<String>();
+        std::thread::spawn(move || {
+            let mut writer = BufWriter::new(file);
+            while let Ok(line) = receiver.recv() {
+                let mut result = writer.write_all(line.as_bytes());
+                // Batch whatever queued up meanwhile into one flush
+                while let Ok(line) = receiver.try_recv() {
+                    result = result.and_then(|_| writer.write_all(line.as_bytes()));
+                }
+                if let Err(e) = result.and_then(|_| writer.flush()) {
+                    eprintln!("Error writing audit log: {:?}", e);
+                }
+            }
+        });
+        Ok(AuditLog { sender })
+    }
+
+    // Only the command name is recorded, never its arguments, which may hold
+    // credentials. Lines read `<unix ms> <peer> <request id> <command> <reason>`,
+    // with `-` for an unknown peer or unset request id.
+    fn record(
+        &self,
+        peer: Option<SocketAddr>,
+        request_id: Option<&str>,
+        command: &str,
+        reason: &str,
+    ) {
+        let peer = peer.map_or_else(|| "-".to_string(), |peer| peer.to_string());
+        // Command names come from the client, so keep them to one token
+        let command: String = command
+            .chars()
+            .map(|c| if c.is_ascii_graphic() { c } else { '?' })
+            .collect();
+        let line = format!(
+            "{} {} {} {} {}\n",
+            now_millis(),
+            peer,
+            request_id.unwrap_or("-"),
+            command,
+            reason
+        );
+        // Only fails if the writer thread died, which it reported
+        let _ = self.sender.send(line);
+    }
+}
+
+async fn handle_client(
+    socket: TcpStream,
+    datastore: DataStore,
+    context: ServerContext,
+) -> Result<(), RedisProtocolError> {
+    let peer = socket.peer_addr().ok();
+    let mut framed = Framed::new(socket, ServerCodec::new(context.limits.clone()));
+    let mut connection = Connection::new(context, peer);
+    match serve_connection(&mut framed, &mut connection, &datastore).await {
+        // Clients hanging up, even while a command is running, is an
+        // ordinary end of the connection
+        Err(e) if is_disconnect(&e) => Ok(()),
+        result => result,
+    }
+}
+
+async fn serve_connection(
+    framed: &mut Framed<TcpStream, ServerCodec>,
+    connection: &mut Connection,
+    datastore: &DataStore,
+) -> Result<(), RedisProtocolError> {
+    // Replies fed to the write buffer but not flushed yet
+    let mut unanswered = 0;
+    loop {
+        // Requests a client pipelines are answered in one write, as long as
+        // the next one has already arrived and the depth limit allows. Replies
+        // are flushed before waiting on the client, and nothing more is read
+        // until they are written.
+        let mut ready = None;
+        if unanswered > 0 {
+            if unanswered < connection.context.limits.max_pipeline_depth() {
+                ready = framed.next().now_or_never();
+            }
+            if ready.is_none() {
+                framed.flush().await?;
+                unanswered = 0;
+            }
+        }
+        let result = match ready {
+            Some(result) => result,
+            None => tokio::select! {
+                result = framed.next() => result,
+                () = connection.context.shutdown.cancelled() => break,
+                message = connection.subscriber.recv(), if connection.subscriber.is_subscribed() => {
+                    let Some(message) = message else {
+                        eprintln!("Closing subscriber connection that fell behind");
+                        break;
+                    };
+                    framed.send(connection.message(message)).await?;
+                    continue;
+                }
+            },
+        };
+        let Some(result) = result else { break };
+        match result {
+            Ok(mut frame) => {
+                if let Err(unknown) = connection.context.command_renames.resolve(&mut frame) {
+                    let unknown = connection.context.unknown_command_policy.apply(unknown);
+                    framed.feed(connection.reply(unknown)).await?;
+                    unanswered += 1;
+                    continue;
+                }
+                if let Some(name) = command_name(&frame) {
+                    connection.client.record(&name);
+                }
+                let replies = match connection.handle_command(&frame) {
+                    Some(replies) => replies,
+                    None => {
+                        // Earlier replies must not wait on a blocked command
+                        if unanswered > 0 && is_blocking(&frame) {
+                            framed.flush().await?;
+                            unanswered = 0;
+                        }
+                        let warning = deprecation_warning(&frame)
+                            .and_then(|warning| connection.push_warning(&warning));
+                        let name = command_name(&frame);
+                        let partition = connection.partition();
+                        let response = handle_command(frame, datastore, &partition).await;
+                        let response = connection.compress(name.as_deref(), response);
+                        let response = connection.context.unknown_command_policy.apply(response);
+                        let mut replies = vec![connection.command_reply(name.as_deref(), response)];
+                        replies.extend(warning);
+                        replies
+                    }
+                };
+                for reply in replies {
+                    framed.feed(reply).await?;
+                }
+                unanswered += 1;
+                if connection.closing {
+                    break;
+                }
+            }
+            Err(e) if is_disconnect(&e) => return Err(e),
+            Err(e) => {
+                // The stream cannot be resynchronised after a malformed or
+                // oversized request, so reply and close
+                eprintln!("Error reading frame: {:?}", e);
+                connection.audit("-", &format!("protocol error: {}", e.details()));
+                let err_response = reply::error(format!("ERR Protocol error: {}", e.details()));
+                framed.feed(connection.reply(err_response)).await?;
+                break;
+            }
+        }
+    }
+    framed.flush().await?;
+
+    Ok(())
+}
+
+// How commands the server doesn't implement are answered
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum UnknownCommandPolicy {
+    // With an error, as Redis does
+    #[default]
+    Error,
+    // With a nil reply
+    Ignore,
+    // With OK, for proxies and tools that only check for errors
+    Ok,
+}
+
+impl UnknownCommandPolicy {
+    // Name used by `--unknown-command-policy`
+    pub fn name(self) -> &'static str {
+        match self {
+            UnknownCommandPolicy::Error => "error",
+            UnknownCommandPolicy::Ignore => "ignore",
+            UnknownCommandPolicy::Ok => "ok",
+        }
+    }
+
+    // `handle_command` has no access to the server settings, so the policy
+    // is applied to the error it answers unknown commands with
+    fn apply(self, response: BytesFrame) -> BytesFrame {
+        let unknown = matches!(&response, BytesFrame::Error(e) if e.starts_with(UNKNOWN_COMMAND));
+        match self {
+            UnknownCommandPolicy::Ignore if unknown => reply::nil(),
+            UnknownCommandPolicy::Ok if unknown => reply::ok(),
+            _ => response,
+        }
+    }
+}
+
+impl FromStr for UnknownCommandPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "error" => Ok(UnknownCommandPolicy::Error),
+            "ignore" => Ok(UnknownCommandPolicy::Ignore),
+            "ok" => Ok(UnknownCommandPolicy::Ok),
+            _ => Err(format!("invalid unknown command policy '{}'", s)),
+        }
+    }
+}
+
+impl fmt::Display for UnknownCommandPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+const UNKNOWN_COMMAND: &str = "ERR unknown command '";
+
+// Commands the operator renamed or disabled, as Redis' rename-command, to
+// keep dangerous commands out of reach on shared deployments
+#[derive(Clone, Default)]
+struct CommandRenames {
+    // Upper case name clients send -> the command it runs
+    aliases: HashMap<String, String>,
+    // Commands that no longer answer to their own name
+    hidden: HashSet<String>,
+}
+
+impl CommandRenames {
+    // Makes `command` answer to `new_name` only, or to no name at all if
+    // `new_name` is empty
+    fn rename(&mut self, command: &str, new_name: &str) {
+        let command = command.to_ascii_uppercase();
+        self.hidden.insert(command.clone());
+        if !new_name.is_empty() {
+            self.aliases.insert(new_name.to_ascii_uppercase(), command);
+        }
+    }
+
+    // Rewrites the name in `frame` to the command it runs, before anything
+    // else looks at it. Fails with the unknown command error for a command
+    // called by a name it no longer answers to.
+    fn resolve(&self, frame: &mut BytesFrame) -> Result<(), BytesFrame> {
+        let BytesFrame::Array(commands) = frame else {
+            return Ok(());
+        };
+        let Some(BytesFrame::BulkString(name)) = commands.first_mut() else {
+            return Ok(());
+        };
+        if self.hidden.is_empty() {
+            return Ok(());
+        }
+        let upper = String::from_utf8_lossy(name).to_ascii_uppercase();
+        if let Some(command) = self.aliases.get(&upper) {
+            *name = Bytes::from(command.clone());
+        } else if self.hidden.contains(&upper) {
+            return Err(reply::error(format!("{}{}'", UNKNOWN_COMMAND, upper)));
+        }
+        Ok(())
+    }
+}
+
+// Deprecated commands, with the command to use instead
+const DEPRECATED_COMMANDS: &[(&str, &str)] = &[("HMSET", "HSET")];
+
+fn deprecation_warning(frame: &BytesFrame) -> Option<String> {
+    let BytesFrame::Array(commands) = frame else {
+        return None;
+    };
+    let Some(BytesFrame::BulkString(cmd)) = commands.first() else {
+        return None;
+    };
+    DEPRECATED_COMMANDS
+        .iter()
+        .find(|(deprecated, _)| cmd.eq_ignore_ascii_case(deprecated.as_bytes()))
+        .map(|(deprecated, replacement)| {
+            format!("{} is deprecated, use {} instead", deprecated, replacement)
+        })
+}
+
+// Lower case name of a command, as CLIENT LIST reports it
+fn command_name(frame: &BytesFrame) -> Option<String> {
+    match frame {
+        BytesFrame::Array(commands) => match commands.first() {
+            Some(BytesFrame::BulkString(cmd)) => {
+                Some(String::from_utf8_lossy(cmd).to_ascii_lowercase())
+            }
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+// Where the scan a SCAN family cursor stands for carries on from, None for
+// the 0 that starts one. Cursors of every kind come from the same store, as
+// each only stands for the last key or field a page examined.
+fn scan_position(
+    datastore: &DataStore,
+    cursor: &BytesFrame,
+) -> Result<Option<Vec<u8>>, BytesFrame> {
+    let cursor = match cursor {
+        BytesFrame::BulkString(bytes) => std::str::from_utf8(bytes)
+            .ok()
+            .and_then(|cursor| cursor.parse::<u64>().ok()),
+        _ => None,
+    };
+    match cursor {
+        Some(0) => Ok(None),
+        Some(cursor) => match datastore.scan_cursors().position(cursor) {
+            Some(position) => Ok(Some(position)),
+            None => Err(reply::error("ERR invalid cursor")),
+        },
+        None => Err(reply::error("ERR invalid cursor")),
+    }
+}
+
+struct ScanOptions {
+    pattern: Option<Bytes>,
+    count: usize,
+    novalues: bool,
+}
+
+// MATCH and COUNT, and NOVALUES where `novalues` allows it, following the
+// cursor of a SCAN family command
+fn parse_scan_options(options: &[BytesFrame], novalues: bool) -> Result<ScanOptions, BytesFrame> {
+    let mut parsed = ScanOptions {
+        pattern: None,
+        count: 10,
+        novalues: false,
+    };
+    let mut rest = options;
+    while let Some((option, after)) = rest.split_first() {
+        let name = match option {
+            BytesFrame::BulkString(bytes) => String::from_utf8_lossy(bytes).to_ascii_uppercase(),
+            _ => return Err(reply::error("ERR syntax error")),
+        };
+        if novalues && name == "NOVALUES" {
+            parsed.novalues = true;
+            rest = after;
+            continue;
+        }
+        match (name.as_str(), after.first()) {
+            ("MATCH", Some(BytesFrame::BulkString(bytes))) => parsed.pattern = Some(bytes.clone()),
+            ("COUNT", Some(value)) => match parse_integer(value) {
+                Some(value) if value >= 1 => parsed.count = value as usize,
+                Some(_) => return Err(reply::error("ERR syntax error")),
+                None => return Err(reply::error("ERR value is not an integer or out of range")),
+            },
+            _ => return Err(reply::error("ERR syntax error")),
+        }
+        rest = &after[1..];
+    }
+    Ok(parsed)
+}
+
+// Commands that may wait indefinitely for another client
+fn is_blocking(frame: &BytesFrame) -> bool {
+    match frame {
+        BytesFrame::Array(commands) => matches!(
+            commands.first(),
+            Some(BytesFrame::BulkString(cmd))
+                if cmd.eq_ignore_ascii_case(b"BLPOP") || cmd.eq_ignore_ascii_case(b"BRPOP")
+        ),
+        _ => false,
+    }
+}
+
+fn is_disconnect(e: &RedisProtocolError) -> bool {
+    match e.kind() {
+        RedisProtocolErrorKind::IO(e) => matches!(
+            e.kind(),
+            io::ErrorKind::BrokenPipe
+                | io::ErrorKind::ConnectionReset
+                | io::ErrorKind::ConnectionAborted
+                | io::ErrorKind::UnexpectedEof
+        ),
+        _ => false,
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Protocol {
+    Resp2,
+    Resp3,
+}
+
+// A frame written to a client. Command replies are built as RESP2 frames and
+// converted when the connection speaks RESP3; frames without a RESP2
+// equivalent (push messages, maps) are built as RESP3 directly.
+#[derive(Debug)]
+enum Outgoing {
+    Resp2(BytesFrame),
+    Resp3(Resp3Frame),
+}
+
+// Size limits on incoming requests, shared by every connection. Only the
+// bulk length and pipeline depth can change at runtime, through CONFIG SET
+// or a config reload.
+#[derive(Clone)]
+pub struct ProtoLimits {
+    max_bulk_len: Arc<AtomicUsize>,
+    max_multibulk_len: usize,
+    max_request_len: usize,
+    // Replies a connection holds back before writing them, bounding the
+    // memory a client pipelining without reading can tie up
+    max_pipeline_depth: Arc<AtomicUsize>,
+}
+
+impl ProtoLimits {
+    pub fn new(
+        max_bulk_len: usize,
+        max_multibulk_len: usize,
+        max_request_len: usize,
+        max_pipeline_depth: usize,
+    ) -> Self {
+        ProtoLimits {
+            max_bulk_len: Arc::new(AtomicUsize::new(max_bulk_len)),
+            max_multibulk_len,
+            max_request_len,
+            max_pipeline_depth: Arc::new(AtomicUsize::new(max_pipeline_depth.max(1))),
+        }
+    }
+
+    pub fn max_bulk_len(&self) -> usize {
+        self.max_bulk_len.load(Ordering::Relaxed)
+    }
+
+    pub fn set_max_bulk_len(&self, len: usize) {
+        self.max_bulk_len.store(len, Ordering::Relaxed);
+    }
+
+    pub fn max_pipeline_depth(&self) -> usize {
+        self.max_pipeline_depth.load(Ordering::Relaxed)
+    }
+
+    pub fn set_max_pipeline_depth(&self, depth: usize) {
+        self.max_pipeline_depth
+            .store(depth.max(1), Ordering::Relaxed);
+    }
+
+    // Checks the headers of the request at the start of `src` as far as it
+    // has arrived, so an oversized or malformed request is rejected before
+    // its payload is buffered. Commands are flat arrays of bulk strings;
+    // anything else, including nested arrays, is refused.
+    fn check(&self, src: &[u8]) -> Result<(), String> {
+        let Some(&first) = src.first() else {
+            return Ok(());
+        };
+        if first != b'*' {
+            return Err(format!("expected '*', got '{}'", first as char));
+        }
+        let Some((count, mut pos)) = header_value(src, 1)? else {
+            return Ok(());
+        };
+        if count > self.max_multibulk_len as i64 {
+            return Err("invalid multibulk length".into());
+        }
+        // The declared lengths alone may already rule the request out, with
+        // every argument not declared yet at its smallest
+        let too_large = |pos: usize, remaining: i64| {
+            pos + remaining as usize * MIN_BULK_ENCODED_LEN > self.max_request_len
+        };
+        if too_large(pos, count.max(0)) {
+            return Err("request too large".into());
+        }
+        for i in 0..count.max(0) {
+            let Some(&marker) = src.get(pos) else {
+                return Ok(());
+            };
+            if marker != b'$' {
+                return Err(format!("expected '$', got '{}'", marker as char));
+            }
+            let Some((len, next)) = header_value(src, pos + 1)? else {
+                return Ok(());
+            };
+            if len < 0 || len > self.max_bulk_len() as i64 {
+                return Err("invalid bulk length".into());
+            }
+            pos = next + len as usize + 2;
+            if too_large(pos, count - i - 1) {
+                return Err("request too large".into());
+            }
+        }
+        Ok(())
+    }
+}
+
+// The limits CONFIG can read and change: name, getter, setter and the
+// smallest value CONFIG SET accepts
+type LimitParameter = (
+    &'static str,
+    fn(&ProtoLimits) -> usize,
+    fn(&ProtoLimits, usize),
+    usize,
+);
+
+const LIMIT_PARAMETERS: &[LimitParameter] = &[
+    (
+        "proto-max-bulk-len",
+        ProtoLimits::max_bulk_len,
+        ProtoLimits::set_max_bulk_len,
+        MIN_CONFIG_BULK_LEN,
+    ),
+    (
+        "max-pipeline-depth",
+        ProtoLimits::max_pipeline_depth,
+        ProtoLimits::set_max_pipeline_depth,
+        1,
+    ),
+];
+
+impl Default for ProtoLimits {
+    fn default() -> Self {
+        ProtoLimits::new(
+            DEFAULT_PROTO_MAX_BULK_LEN,
+            DEFAULT_MAX_MULTIBULK_LEN,
+            DEFAULT_MAX_REQUEST_LEN,
+            DEFAULT_MAX_PIPELINE_DEPTH,
+        )
+    }
+}
+
+// Parses the integer of a header line starting at `start`, returning it
+// with the position after its CRLF, or None if the line is incomplete
+fn header_value(src: &[u8], start: usize) -> Result<Option<(i64, usize)>, String> {
+    let line = &src[start.min(src.len())..];
+    let Some(end) = line.windows(2).position(|w| w == b"\r\n") else {
+        if line.len() > MAX_HEADER_LINE {
+            return Err("too big header line".into());
+        }
+        return Ok(None);
+    };
+    std::str::from_utf8(&line[..end])
+        .ok()
+        .and_then(|s| s.parse::<i64>().ok())
+        .map(|value| Some((value, start + end + 2)))
+        .ok_or_else(|| "invalid header line".into())
+}
+
+// Decodes commands, which clients send as RESP2 arrays under either
+// protocol, and encodes replies in whichever protocol they were built for
+struct ServerCodec {
+    resp2: Resp2,
+    resp3: Resp3,
+    limits: ProtoLimits,
+}
+
+impl ServerCodec {
+    fn new(limits: ProtoLimits) -> Self {
+        ServerCodec {
+            resp2: Resp2,
+            resp3: Resp3::default(),
+            limits,
+        }
+    }
+}
+
+impl Decoder for ServerCodec {
+    type Item = BytesFrame;
+    type Error = RedisProtocolError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<BytesFrame>, RedisProtocolError> {
+        self.limits
+            .check(src)
+            .map_err(|e| RedisProtocolError::new(RedisProtocolErrorKind::DecodeError, e))?;
+        self.resp2.decode(src)
+    }
+}
+
+impl Encoder<Outgoing> for ServerCodec {
+    type Error = RedisProtocolError;
+
+    fn encode(&mut self, item: Outgoing, dst: &mut BytesMut) -> Result<(), RedisProtocolError> {
+        match item {
+            Outgoing::Resp2(frame) => self.resp2.encode(frame, dst),
+            Outgoing::Resp3(frame) => self.resp3.encode(frame, dst),
+        }
+    }
+}
+
+enum Selected {
+    // Looked up in `Databases` for every command, see SWAPDB
+    Index(usize),
+    Named(DataStorePartition),
+}
+
+// Per-connection state, and the commands that act on the connection rather
+// than on the dataset
+struct Connection {
+    protocol: Protocol,
+    context: ServerContext,
+    subscriber: Subscriber,
+    peer: Option<SocketAddr>,
+    // Database data commands run against, db 0 until SELECT
+    selected: Selected,
+    // Always true without requirepass
+    authenticated: bool,
+    // The user authenticated as, "default" until AUTH, and what it may run
+    user: Arc<str>,
+    permissions: Permissions,
+    // Set by QUIT: close once the pending replies are written
+    closing: bool,
+    // Registered in the context's clients while the connection is open
+    client: ClientHandle,
+    // Minimum size of the GET and MGET values compressed for a client that
+    // opted in with HELLO's COMPRESSION option, see `compress`
+    compression: Option<usize>,
+}
+
+impl Connection {
+    fn new(context: ServerContext, peer: Option<SocketAddr>) -> Self {
+        Connection {
+            protocol: Protocol::Resp2,
+            selected: Selected::Index(0),
+            subscriber: context.pubsub.subscriber(),
+            authenticated: context.requirepass.is_none(),
+            user: Arc::from("default"),
+            permissions: Permissions::all(),
+            client: context.clients.register(peer),
+            context,
+            peer,
+            closing: false,
+            compression: None,
+        }
+    }
+
+    // Tags the values in a GET or MGET reply, compressing the large ones,
+    // once the client opted in with HELLO's COMPRESSION option
+    fn compress(&self, command: Option<&str>, response: BytesFrame) -> BytesFrame {
+        let Some(min_size) = self.compression else {
+            return response;
+        };
+        let compress =
+            |value: Bytes| BytesFrame::BulkString(compress_value(&value, min_size).into());
+        match (command, response) {
+            (Some("get"), BytesFrame::BulkString(value)) => compress(value),
+            (Some("mget"), BytesFrame::Array(values)) => BytesFrame::Array(
+                values
+                    .into_iter()
+                    .map(|value| match value {
+                        BytesFrame::BulkString(value) => compress(value),
+                        other => other,
+                    })
+                    .collect(),
+            ),
+            (_, response) => response,
+        }
+    }
+
+    // Wraps a command reply for the negotiated protocol
+    fn reply(&self, frame: BytesFrame) -> Outgoing {
+        match self.protocol {
+            Protocol::Resp2 => Outgoing::Resp2(frame),
+            Protocol::Resp3 => Outgoing::Resp3(to_resp3(frame)),
+        }
+    }
+
+    // Like `reply`, for the reply of `command`, which RESP3 gives a shape of
+    // its own for some commands
+    fn command_reply(&self, command: Option<&str>, frame: BytesFrame) -> Outgoing {
+        match (self.protocol, command, frame) {
+            (Protocol::Resp3, Some("getwithttl"), BytesFrame::Array(pair)) => {
+                match <[BytesFrame; 2]>::try_from(pair) {
+                    // [value, ttl] becomes the value with its TTL as an
+                    // attribute
+                    Ok([BytesFrame::BulkString(data), ttl]) => {
+                        let attributes = [(to_resp3(reply::bulk("ttl")), to_resp3(ttl))];
+                        Outgoing::Resp3(Resp3Frame::BlobString {
+                            data,
+                            attributes: Some(attributes.into_iter().collect()),
+                        })
+                    }
+                    // A missing key has no TTL to attach
+                    Ok([BytesFrame::Null, _]) => Outgoing::Resp3(Resp3Frame::Null),
+                    Ok(pair) => self.reply(BytesFrame::Array(pair.into())),
+                    Err(frames) => self.reply(BytesFrame::Array(frames)),
+                }
+            }
+            // Text meant for people rather than parsers
+            (Protocol::Resp3, Some("info"), BytesFrame::BulkString(data)) => self.verbatim(data),
+            (Protocol::Resp3, Some("hincrbyfloat"), BytesFrame::BulkString(data)) => {
+                match parse_float(&data) {
+                    Some(value) => Outgoing::Resp3(Resp3Frame::Double {
+                        data: value,
+                        attributes: None,
+                    }),
+                    None => self.reply(BytesFrame::BulkString(data)),
+                }
+            }
+            (_, _, frame) => self.reply(frame),
+        }
+    }
+
+    // Human-readable text: a verbatim string marked as plain text under
+    // RESP3, a bulk string under RESP2
+    fn verbatim(&self, text: impl Into<Bytes>) -> Outgoing {
+        match self.protocol {
+            Protocol::Resp2 => Outgoing::Resp2(BytesFrame::BulkString(text.into())),
+            Protocol::Resp3 => Outgoing::Resp3(Resp3Frame::VerbatimString {
+                data: text.into(),
+                format: VerbatimStringFormat::Text,
+                attributes: None,
+            }),
+        }
+    }
+
+    // Wraps an out-of-band message: a Push frame under RESP3, so clients can
+    // tell it apart from command replies, and a plain array under RESP2
+    fn push(&self, items: Vec<BytesFrame>) -> Outgoing {
+        match self.protocol {
+            Protocol::Resp2 => Outgoing::Resp2(reply::array(items)),
+            Protocol::Resp3 => Outgoing::Resp3(Resp3Frame::Push {
+                data: items.into_iter().map(to_resp3).collect(),
+                attributes: None,
+            }),
+        }
+    }
+
+    // A non-fatal warning, sent after the reply it concerns. RESP2 has no
+    // out-of-band frames, so RESP2 clients don't get it.
+    fn push_warning(&self, message: &str) -> Option<Outgoing> {
+        (self.protocol == Protocol::Resp3).then(|| {
+            self.push(vec![
+                reply::bulk("warning"),
+                reply::bulk(message.to_string()),
+            ])
+        })
+    }
+
+    fn message(&self, message: Message) -> Outgoing {
+        self.push(vec![
+            reply::bulk("message"),
+            reply::bulk(message.channel),
+            reply::bulk(message.payload),
+        ])
+    }
+
+    // Handles HELLO and the pub/sub commands. Returns None for any other
+    // command, which is then run by `handle_command`.
+    fn handle_command(&mut self, frame: &BytesFrame) -> Option<Vec<Outgoing>> {
+        let BytesFrame::Array(commands) = frame else {
+            return None;
+        };
+        let cmd = match commands.first() {
+            Some(BytesFrame::BulkString(bytes)) => {
+                String::from_utf8_lossy(bytes).to_ascii_uppercase()
+            }
+            _ => return None,
+        };
+        let mut args = Vec::with_capacity(commands.len() - 1);
+        for arg in &commands[1..] {
+            match arg {
+                BytesFrame::BulkString(bytes) => args.push(bytes.clone()),
+                _ => return Some(vec![self.reply(reply::error("ERR Invalid argument type"))]),
+            }
+        }
+        if !self.authenticated && cmd != "AUTH" && cmd != "QUIT" {
+            self.audit(&cmd, "authentication required");
+            return Some(vec![
+                self.reply(reply::error("NOAUTH Authentication required."))
+            ]);
+        }
+        if !self.permissions.allows(&cmd) {
+            self.audit(&cmd, "no permission");
+            return Some(vec![self.reply(reply::error(format!(
+                "NOPERM User {} has no permissions to run the '{}' command",
+                self.user,
+                cmd.to_lowercase()
+            )))]);
+        }
+        if matches!(args.as_slice(), [subcommand] if subcommand.eq_ignore_ascii_case(b"HELP")) {
+            if let Some(help) = help::help(&cmd) {
+                return Some(vec![self.reply(help)]);
+            }
+        }
+        match cmd.as_str() {
+            "AUTH" => Some(vec![self.auth(&args)]),
+            "ACL" => Some(vec![self.acl(&args)]),
+            "CLIENT" => Some(vec![self.client(&args)]),
+            "SELECT" => Some(vec![self.select(&args)]),
+            "SWAPDB" => Some(vec![self.swapdb(&args)]),
+            "HELLO" => Some(vec![self.hello(&args)]),
+            // Protocol limits belong to the server rather than the data store;
+            // other parameters fall through to `config`
+            "CONFIG" => self.config(&args),
+            "QUIT" => {
+                self.closing = true;
+                Some(vec![self.reply(reply::ok())])
+            }
+            "SUBSCRIBE" => {
+                if args.is_empty() {
+                    return Some(vec![
+                        self.reply(reply::error("ERR Wrong number of arguments for SUBSCRIBE"))
+                    ]);
+                }
+                Some(
+                    args.into_iter()
+                        .map(|channel| {
+                            let count = self.subscriber.subscribe(&channel);
+                            self.push(vec![
+                                reply::bulk("subscribe"),
+                                reply::bulk(channel),
+                                reply::integer(count as i64),
+                            ])
+                        })
+                        .collect(),
+                )
+            }
+            "UNSUBSCRIBE" => {
+                // Without arguments, unsubscribe from every channel
+                let channels = if args.is_empty() {
+                    self.subscriber
+                        .channels()
+                        .into_iter()
+                        .map(Bytes::from)
+                        .collect()
+                } else {
+                    args
+                };
+                if channels.is_empty() {
+                    return Some(vec![self.push(vec![
+                        reply::bulk("unsubscribe"),
+                        reply::nil(),
+                        reply::integer(0),
+                    ])]);
+                }
+                Some(
+                    channels
+                        .into_iter()
+                        .map(|channel| {
+                            let count = self.subscriber.unsubscribe(&channel);
+                            self.push(vec![
+                                reply::bulk("unsubscribe"),
+                                reply::bulk(channel),
+                                reply::integer(count as i64),
+                            ])
+                        })
+                        .collect(),
+                )
+            }
+            "PUBLISH" => {
+                let reply = match args.as_slice() {
+                    [channel, payload] => {
+                        reply::integer(self.context.pubsub.publish(channel, payload) as i64)
+                    }
+                    _ => reply::error("ERR Wrong number of arguments for PUBLISH"),
+                };
+                Some(vec![self.reply(reply)])
+            }
+            _ => None,
+        }
+    }
+
+    // SELECT index, or SELECT name, which selects the partition of that name
+    // and creates it if needed
+    fn select(&mut self, args: &[Bytes]) -> Outgoing {
+        let [target] = args else {
+            return self.reply(reply::error("ERR Wrong number of arguments for SELECT"));
+        };
+        let target = String::from_utf8_lossy(target);
+        let selected = match target.parse::<i64>() {
+            Ok(index) => self
+                .db_index(index)
+                .map(Selected::Index)
+                .ok_or_else(|| "ERR DB index is out of range".to_string()),
+            Err(_) => self
+                .context
+                .databases
+                .named(&target)
+                .map(Selected::Named)
+                .map_err(|e| format!("ERR {}", e)),
+        };
+        match selected {
+            Ok(selected) => {
+                self.selected = selected;
+                self.reply(reply::ok())
+            }
+            Err(e) => self.reply(reply::error(e)),
+        }
+    }
+
+    // SWAPDB index1 index2
+    fn swapdb(&mut self, args: &[Bytes]) -> Outgoing {
+        let [index1, index2] = args else {
+            return self.reply(reply::error("ERR Wrong number of arguments for SWAPDB"));
+        };
+        let parse = |index: &Bytes| {
+            std::str::from_utf8(index)
+                .ok()
+                .and_then(|index| index.parse::<i64>().ok())
+        };
+        let Some(index1) = parse(index1) else {
+            return self.reply(reply::error("ERR invalid first DB index"));
+        };
+        let Some(index2) = parse(index2) else {
+            return self.reply(reply::error("ERR invalid second DB index"));
+        };
+        let reply = match (self.db_index(index1), self.db_index(index2)) {
+            (Some(index1), Some(index2)) if self.context.databases.swap(index1, index2) => {
+                reply::ok()
+            }
+            _ => reply::error("ERR DB index is out of range"),
+        };
+        self.reply(reply)
+    }
+
+    fn db_index(&self, index: i64) -> Option<usize> {
+        usize::try_from(index)
+            .ok()
+            .filter(|&index| index < self.context.databases.len())
+    }
+
+    // The partition of the selected database
+    fn partition(&self) -> DataStorePartition {
+        match &self.selected {
+            Selected::Index(index) => self
+                .context
+                .databases
+                .get(*index)
+                .expect("selected database index is in range"),
+            Selected::Named(partition) => partition.clone(),
+        }
+    }
+
+    // AUTH [username] password. The "default" user's password is
+    // requirepass; other users are added with `ServerContext::add_user`.
+    fn auth(&mut self, args: &[Bytes]) -> Outgoing {
+        let (user, password) = match args {
+            [password] => (&b"default"[..], password),
+            [user, password] => (user.as_ref(), password),
+            _ => return self.reply(reply::error("ERR Wrong number of arguments for AUTH")),
+        };
+        let permissions = if user == b"default" {
+            let Some(requirepass) = &self.context.requirepass else {
+                return self.reply(reply::error("ERR AUTH <password> called without any password configured for the default user. Are you sure your configuration is correct?"));
+            };
+            (password.as_ref() == requirepass.as_bytes()).then(Permissions::all)
+        } else {
+            self.context
+                .users
+                .iter()
+                .find(|acl_user| {
+                    acl_user.name.as_bytes() == user && acl_user.password.as_bytes() == password
+                })
+                .map(|acl_user| acl_user.permissions)
+        };
+        let Some(permissions) = permissions else {
+            self.audit("AUTH", "invalid password");
+            return self.reply(reply::error(
+                "WRONGPASS invalid username-password pair or user is disabled.",
+            ));
+        };
+        self.authenticated = true;
+        self.user = Arc::from(String::from_utf8_lossy(user));
+        self.permissions = permissions;
+        self.reply(reply::ok())
+    }
+
+    // ACL WHOAMI
+    fn acl(&self, args: &[Bytes]) -> Outgoing {
+        match args {
+            [subcommand] if subcommand.eq_ignore_ascii_case(b"WHOAMI") => {
+                self.reply(reply::bulk(self.user.to_string()))
+            }
+            [subcommand, ..] => self.reply(reply::error(format!(
+                "ERR unknown subcommand '{}'",
+                String::from_utf8_lossy(subcommand)
+            ))),
+            [] => self.reply(reply::error("ERR Wrong number of arguments for ACL")),
+        }
+    }
+
+    // CLIENT INFO | LIST
+    fn client(&mut self, args: &[Bytes]) -> Outgoing {
+        match args {
+            [subcommand] if subcommand.eq_ignore_ascii_case(b"INFO") => {
+                self.verbatim(self.client.info_line() + "\n")
+            }
+            [subcommand] if subcommand.eq_ignore_ascii_case(b"LIST") => {
+                self.verbatim(self.context.clients.list())
+            }
+            // Tags the connection's entries in the audit log and CLIENT LIST,
+            // until set again; an empty id clears it
+            [subcommand, request_id] if subcommand.eq_ignore_ascii_case(b"SETREQUESTID") => {
+                if !request_id.iter().all(|byte| byte.is_ascii_graphic()) {
+                    return self.reply(reply::error(
+                        "ERR Request ids cannot contain spaces, newlines or special characters.",
+                    ));
+                }
+                self.client
+                    .set_request_id(std::str::from_utf8(request_id).unwrap_or_default());
+                self.reply(reply::ok())
+            }
+            [subcommand] if subcommand.eq_ignore_ascii_case(b"GETREQUESTID") => {
+                self.reply(match self.client.request_id() {
+                    Some(request_id) => reply::bulk(request_id),
+                    None => reply::nil(),
+                })
+            }
+            [subcommand, ..] => self.reply(reply::error(format!(
+                "ERR unknown subcommand '{}'",
+                String::from_utf8_lossy(subcommand)
+            ))),
+            [] => self.reply(reply::error("ERR Wrong number of arguments for CLIENT")),
+        }
+    }
+
+    fn audit(&self, command: &str, reason: &str) {
+        self.context.audit(
+            self.peer,
+            self.client.request_id().as_deref(),
+            command,
+            reason,
+        );
+    }
+
+    // CONFIG GET|SET proto-max-bulk-len and max-pipeline-depth
+    fn config(&self, args: &[Bytes]) -> Option<Vec<Outgoing>> {
+        let limits = &self.context.limits;
+        let (subcommand, parameter) = match args {
+            [subcommand, parameter] | [subcommand, parameter, _] => (subcommand, parameter),
+            _ => return None,
+        };
+        let &(name, get, set, min) = LIMIT_PARAMETERS
+            .iter()
+            .find(|(name, _, _, _)| parameter.eq_ignore_ascii_case(name.as_bytes()))?;
+        let reply = match args {
+            [_, _] if subcommand.eq_ignore_ascii_case(b"GET") => reply::array(vec![
+                reply::bulk(name),
+                reply::bulk(get(limits).to_string()),
+            ]),
+            [_, _, value] if subcommand.eq_ignore_ascii_case(b"SET") => {
+                match std::str::from_utf8(value).ok().and_then(|v| v.parse().ok()) {
+                    Some(value) if value < min || value > i64::MAX as usize => reply::error(format!("ERR CONFIG SET failed (possibly related to argument '{}') - argument must be between {} and {} inclusive", name, min, i64::MAX)),
+                    Some(value) => {
+                        set(limits, value);
+                        reply::ok()
+                    }
+                    None => reply::error(format!("ERR CONFIG SET failed (possibly related to argument '{}') - argument couldn't be parsed into an integer", name)),
+                }
+            }
+            _ => return None,
+        };
+        Some(vec![self.reply(reply)])
+    }
+
+    // HELLO [protover [COMPRESSION LZ4 [min-size] | COMPRESSION OFF]]: switches
+    // the connection's protocol and describes the server, as a map under RESP3
+    // and a flat array under RESP2. COMPRESSION is non-standard, see
+    // compress.rs for what the client then receives.
+    fn hello(&mut self, args: &[Bytes]) -> Outgoing {
+        let (protocol, options) = match args {
+            [] => (self.protocol, &[][..]),
+            [version, options @ ..] if version.as_ref() == b"2" => (Protocol::Resp2, options),
+            [version, options @ ..] if version.as_ref() == b"3" => (Protocol::Resp3, options),
+            [version, ..] if parse_integer(&reply::bulk(version.clone())).is_some() => {
+                return self.reply(reply::error("NOPROTO unsupported protocol version"))
+            }
+            _ => return self.reply(reply::error("ERR syntax error")),
+        };
+        let compression = match options {
+            [] => self.compression,
+            [option, mode]
+                if option.eq_ignore_ascii_case(b"COMPRESSION")
+                    && mode.eq_ignore_ascii_case(b"OFF") =>
+            {
+                None
+            }
+            [option, mode]
+                if option.eq_ignore_ascii_case(b"COMPRESSION")
+                    && mode.eq_ignore_ascii_case(b"LZ4") =>
+            {
+                Some(DEFAULT_COMPRESSION_MIN_SIZE)
+            }
+            [option, mode, min_size]
+                if option.eq_ignore_ascii_case(b"COMPRESSION")
+                    && mode.eq_ignore_ascii_case(b"LZ4") =>
+            {
+                match std::str::from_utf8(min_size)
+                    .ok()
+                    .and_then(|s| s.parse().ok())
+                {
+                    Some(min_size) => Some(min_size),
+                    None => {
+                        return self
+                            .reply(reply::error("ERR value is not an integer or out of range"))
+                    }
+                }
+            }
+            _ => return self.reply(reply::error("ERR syntax error")),
+        };
+        self.protocol = protocol;
+        self.compression = compression;
+        let proto = match self.protocol {
+            Protocol::Resp2 => 2,
+            Protocol::Resp3 => 3,
+        };
+        let mut fields = vec![
+            ("server", reply::bulk("veifka")),
+            ("version", reply::bulk(env!("CARGO_PKG_VERSION"))),
+            ("proto", reply::integer(proto)),
+            ("mode", reply::bulk("standalone")),
+            ("role", reply::bulk("master")),
+            ("modules", reply::array(Vec::new())),
+        ];
+        // Only shown once opted in, so standard clients see the usual reply
+        if let Some(min_size) = self.compression {
+            fields.push((
+                "compression",
+                reply::array(vec![reply::bulk("lz4"), reply::integer(min_size as i64)]),
+            ));
+        }
+        match self.protocol {
+            Protocol::Resp2 => Outgoing::Resp2(reply::map_as_array(
+                fields
+                    .into_iter()
+                    .map(|(name, value)| (reply::bulk(name), value)),
+            )),
+            Protocol::Resp3 => Outgoing::Resp3(Resp3Frame::Map {
+                data: fields
+                    .into_iter()
+                    .map(|(name, value)| (to_resp3(reply::bulk(name)), to_resp3(value)))
+                    .collect(),
+                attributes: None,
+            }),
+        }
+    }
+}
+
+// Canonical RESP2 replies of the data commands, following the Redis spec:
+//
+// - simple string OK: SET, MSET, RESTORE, HMSET, SWAP, REPLICAOF, DEBUG (but DEBUG ERROR, which
+//   replies its message as an error), COMPACT, SAVE, BACKUP, CONFIG SET
+// - simple string: PING (PONG), TYPE (type name or "none"),
+//   DEBUG OBJECT (the value's internals on one line)
+// - integer: DEL (keys that existed), EXISTS, WAIT (always 0), INCR/DECR/INCRBY/DECRBY, the
+//   pushes (new length), LLEN, SADD/SREM (members changed), SISMEMBER, SCARD,
+//   HSET (new fields), HINCRBY, EXPIRE and friends (0/1), TTL and friends,
+//   PERSIST (0/1), OBJECT IDLETIME/FREQ, MEMORY USAGE, CLUSTER KEYSLOT
+// - bulk string or nil: GET, LCS (integer with LEN, matches and len as
+//   field/value pairs with IDX), DUMP, HGET, HINCRBYFLOAT, INFO, OBJECT ENCODING
+// - array: MGET, LRANGE, SMEMBERS, SMISMEMBER, HMGET, SCAN, SSCAN and HSCAN
+//   (the next cursor, then the page), CONFIG GET, DEBUG VERIFY
+//   (entries, bytes and the corrupted keys, as field/value pairs), DEBUG
+//   BIGKEYS (likewise, nesting the sizes of every type), the HELP
+//   subcommands of OBJECT, MEMORY, CONFIG, DEBUG, CLUSTER and ACL; LPOP/RPOP
+//   without a count give a bulk string, BLPOP/BRPOP a [key, value] pair or nil,
+//   LMPOP a [key, [values]] pair or nil
+async fn handle_command<B: KvBackend>(
+    frame: BytesFrame,
+    datastore: &DataStore,
+    backend: &B,
+) -> BytesFrame {
+    match frame {
+        BytesFrame::SimpleString(_bytes) => todo!(),
+        BytesFrame::Error(_str_inner) => todo!(),
+        BytesFrame::Integer(_) => todo!(),
+        BytesFrame::BulkString(_bytes) => todo!(),
+        BytesFrame::Array(commands) => {
+            if commands.is_empty() {
+                return reply::error("ERR Empty command");
+            }
+
+            let cmd = match &commands[0] {
+                BytesFrame::BulkString(bytes) => {
+                    String::from_utf8_lossy(bytes).to_ascii_uppercase()
+                }
+                BytesFrame::SimpleString(s) => String::from_utf8_lossy(s).to_ascii_uppercase(),
+                _ => return reply::error("ERR invalid command type"),
+            };
+            if let Some(reply) = backend_command(&cmd, &commands, datastore, backend).await {
+                return reply;
+            }
+            // The other commands need a partition's lists, sets, hashes and
+            // the like
+            let Some(partition) = backend.partition() else {
+                return reply::error(format!("ERR '{}' is not supported by this backend", cmd));
+            };
+
+            match cmd.as_str() {
+                "MSETNX" => {
+                    if commands.len() < 3 || commands.len().is_multiple_of(2) {
+                        return reply::error("ERR Wrong number of arguments for MSETNX");
+                    }
+                    let mut pairs = Vec::with_capacity(commands.len() / 2);
+                    for pair in commands[1..].chunks(2) {
+                        match (&pair[0], &pair[1]) {
+                            (BytesFrame::BulkString(key), BytesFrame::BulkString(value)) => {
+                                pairs.push((key.clone(), value.clone()))
+                            }
+                            _ => return reply::error("ERR Invalid key type"),
+                        }
+                    }
+                    let partition = partition.clone();
+                    match datastore
+                        .run_blocking(move || {
+                            let pairs: Vec<(&[u8], &[u8])> = pairs
+                                .iter()
+                                .map(|(key, value)| (key.as_ref(), value.as_ref()))
+                                .collect();
+                            partition.multi_set_nx(&pairs)
+                        })
+                        .await
+                    {
+                        Ok(Ok(written)) => reply::integer(written as i64),
+                        Ok(Err(e)) => reply::error(format!("ERR MSETNX error: {:?}", e)),
+                        Err(e) => reply::error(format!("ERR task error: {:?}", e)),
+                    }
+                }
+                "INCR" | "DECR" | "INCRBY" | "DECRBY" => {
+                    let expected_len = if cmd.ends_with("BY") { 3 } else { 2 };
+                    if commands.len() != expected_len {
+                        return reply::error(format!("ERR Wrong number of arguments for {}", cmd));
+                    }
+                    let key = match &commands[1] {
+                        BytesFrame::BulkString(bytes) => bytes.clone(),
+                        _ => return reply::error("ERR Invalid key type"),
+                    };
+                    let amount = if expected_len == 3 {
+                        match parse_integer(&commands[2]) {
+                            Some(amount) => amount,
+                            None => {
+                                return reply::error("ERR value is not an integer or out of range")
+                            }
+                        }
+                    } else {
+                        1
+                    };
+                    let delta = if cmd.starts_with("DECR") {
+                        match amount.checked_neg() {
+                            Some(delta) => delta,
+                            None => return reply::error("ERR decrement would overflow"),
+                        }
+                    } else {
+                        amount
+                    };
+                    let partition = partition.clone();
+                    match datastore
+                        .run_blocking(move || partition.incr_by(&key, delta))
+                        .await
+                    {
+                        Ok(Ok(value)) => reply::integer(value),
+                        Ok(Err(e)) => storage_error(&cmd, e),
+                        Err(e) => reply::error(format!("ERR task error: {:?}", e)),
+                    }
+                }
+                "TYPE" => {
+                    if commands.len() != 2 {
+                        return reply::error("ERR Wrong number of arguments for TYPE");
+                    }
+                    let key = match &commands[1] {
+                        BytesFrame::BulkString(bytes) => bytes.clone(),
+                        _ => return reply::error("ERR Invalid key type"),
+                    };
+                    let partition = partition.clone();
+                    match datastore
+                        .run_blocking(move || partition.key_type(&key))
+                        .await
+                    {
+                        Ok(Ok(Some(kind))) => reply::simple(kind.name()),
+                        Ok(Ok(None)) => reply::simple("none"),
+                        Ok(Err(e)) => reply::error(format!("ERR TYPE error: {:?}", e)),
+                        Err(e) => reply::error(format!("ERR task error: {:?}", e)),
+                    }
+                }
+                "DUMP" => {
+                    let key = match commands.get(1) {
+                        Some(BytesFrame::BulkString(bytes)) if commands.len() == 2 => bytes.clone(),
+                        _ => return reply::error("ERR Wrong number of arguments for DUMP"),
+                    };
+                    let partition = partition.clone();
+                    match datastore.run_blocking(move || partition.dump(&key)).await {
+                        Ok(Ok(Some(dump))) => reply::bulk(dump),
+                        Ok(Ok(None)) => reply::nil(),
+                        Ok(Err(e)) => storage_error("DUMP", e),
+                        Err(e) => reply::error(format!("ERR task error: {:?}", e)),
+                    }
+                }
+                "RESTORE" => {
+                    let mut args = Vec::with_capacity(commands.len() - 1);
+                    for arg in &commands[1..] {
+                        match arg {
+                            BytesFrame::BulkString(bytes) => args.push(bytes.clone()),
+                            _ => return reply::error("ERR Invalid argument type"),
+                        }
+                    }
+                    let [key, ttl, dump, options @ ..] = args.as_slice() else {
+                        return reply::error("ERR Wrong number of arguments for RESTORE");
+                    };
+                    let (mut replace, mut absolute) = (false, false);
+                    for option in options {
+                        match String::from_utf8_lossy(option)
+                            .to_ascii_uppercase()
+                            .as_str()
+                        {
+                            "REPLACE" => replace = true,
+                            "ABSTTL" => absolute = true,
+                            _ => return reply::error("ERR syntax error"),
+                        }
+                    }
+                    let ttl = match std::str::from_utf8(ttl)
+                        .ok()
+                        .and_then(|s| s.parse::<i64>().ok())
+                    {
+                        Some(ttl) if ttl >= 0 => ttl as u64,
+                        _ => return reply::error("ERR Invalid TTL value, must be >= 0"),
+                    };
+                    // A TTL of 0 means no expiry; ABSTTL makes it a unix time
+                    let expires_at = match ttl {
+                        0 => None,
+                        ttl if absolute => Some(ttl),
+                        ttl => Some(now_millis().saturating_add(ttl)),
+                    };
+                    let (key, dump) = (key.clone(), dump.clone());
+                    let partition = partition.clone();
+                    match datastore
+                        .run_blocking(move || partition.restore(&key, &dump, expires_at, replace))
+                        .await
+                    {
+                        Ok(Ok(())) => reply::ok(),
+                        Ok(Err(e)) => storage_error("RESTORE", e),
+                        Err(e) => reply::error(format!("ERR task error: {:?}", e)),
+                    }
+                }
+                "OBJECT" => {
+                    let subcommand = match commands.get(1) {
+                        Some(BytesFrame::BulkString(bytes)) => {
+                            String::from_utf8_lossy(bytes).to_ascii_uppercase()
+                        }
+                        _ => return reply::error("ERR Wrong number of arguments for OBJECT"),
+                    };
+                    if !matches!(subcommand.as_str(), "IDLETIME" | "FREQ" | "ENCODING") {
+                        return reply::error(format!("ERR unknown subcommand '{}'", subcommand));
+                    }
+                    if commands.len() != 3 {
+                        return reply::error(format!(
+                            "ERR Wrong number of arguments for OBJECT {}",
+                            subcommand
+                        ));
+                    }
+                    let key = match &commands[2] {
+                        BytesFrame::BulkString(bytes) => bytes.clone(),
+                        _ => return reply::error("ERR Invalid key type"),
+                    };
+                    let partition = partition.clone();
+                    match datastore
+                        .run_blocking(move || match subcommand.as_str() {
+                            "IDLETIME" => partition
+                                .idle_time(&key)
+                                .map(|idle| idle.map(|idle| reply::integer(idle as i64)))
+                                .map_err(DataStoreError::from),
+                            "FREQ" => partition
+                                .access_frequency(&key)
+                                .map(|freq| freq.map(reply::integer))
+                                .map_err(DataStoreError::from),
+                            _ => partition
+                                .encoding(&key)
+                                .map(|encoding| encoding.map(reply::bulk)),
+                        })
+                        .await
+                    {
+                        Ok(Ok(Some(reply))) => reply,
+                        Ok(Ok(None)) => reply::nil(),
+                        Ok(Err(e)) => reply::error(format!("ERR OBJECT error: {:?}", e)),
+                        Err(e) => reply::error(format!("ERR task error: {:?}", e)),
+                    }
+                }
+                // MEMORY USAGE key [SAMPLES n]: approximate bytes of a key,
+                // SAMPLES 0 reading every member of a container
+                "MEMORY" => {
+                    let subcommand = match commands.get(1) {
+                        Some(BytesFrame::BulkString(bytes)) => {
+                            String::from_utf8_lossy(bytes).to_ascii_uppercase()
+                        }
+                        _ => return reply::error("ERR Wrong number of arguments for MEMORY"),
+                    };
+                    if subcommand != "USAGE" {
+                        return reply::error(format!("ERR unknown subcommand '{}'", subcommand));
+                    }
+                    let (key, samples) = match &commands[2..] {
+                        [BytesFrame::BulkString(key)] => {
+                            (key.clone(), DEFAULT_MEMORY_USAGE_SAMPLES)
+                        }
+                        [BytesFrame::BulkString(key), BytesFrame::BulkString(option), BytesFrame::BulkString(samples)]
+                            if option.eq_ignore_ascii_case(b"SAMPLES") =>
+                        {
+                            match std::str::from_utf8(samples)
+                                .ok()
+                                .and_then(|s| s.parse::<usize>().ok())
+                            {
+                                Some(samples) => (key.clone(), samples),
+                                None => {
+                                    return reply::error(
+                                        "ERR value is not an integer or out of range",
+                                    )
+                                }
+                            }
+                        }
+                        [_] | [_, _, _] => return reply::error("ERR syntax error"),
+                        _ => return reply::error("ERR Wrong number of arguments for MEMORY USAGE"),
+                    };
+                    let partition = partition.clone();
+                    match datastore
+                        .run_blocking(move || partition.memory_usage(&key, samples))
+                        .await
+                    {
+                        Ok(Ok(Some(bytes))) => reply::integer(bytes as i64),
+                        Ok(Ok(None)) => reply::nil(),
+                        Ok(Err(e)) => reply::error(format!("ERR MEMORY error: {:?}", e)),
+                        Err(e) => reply::error(format!("ERR task error: {:?}", e)),
+                    }
+                }
+                "LPUSH" | "RPUSH" => {
+                    if commands.len() < 3 {
+                        return reply::error(format!("ERR Wrong number of arguments for {}", cmd));
+                    }
+                    let key = match &commands[1] {
+                        BytesFrame::BulkString(bytes) => bytes.clone(),
+                        _ => return reply::error("ERR Invalid key type"),
+                    };
+                    let mut values = Vec::with_capacity(commands.len() - 2);
+                    for value in &commands[2..] {
+                        match value {
+                            BytesFrame::BulkString(bytes) => values.push(bytes.clone()),
+                            _ => return reply::error("ERR Invalid value type"),
+                        }
+                    }
+                    let end = if cmd == "LPUSH" {
+                        ListEnd::Left
+                    } else {
+                        ListEnd::Right
+                    };
+                    let partition = partition.clone();
+                    match datastore
+                        .run_blocking(move || {
+                            let values: Vec<&[u8]> = values.iter().map(|v| v.as_ref()).collect();
+                            partition.list_push(&key, &values, end)
+                        })
+                        .await
+                    {
+                        Ok(Ok(len)) => reply::integer(len as i64),
+                        Ok(Err(e)) => storage_error(&cmd, e),
+                        Err(e) => reply::error(format!("ERR task error: {:?}", e)),
+                    }
+                }
+                "LPOP" | "RPOP" => {
+                    if commands.len() != 2 && commands.len() != 3 {
+                        return reply::error(format!("ERR Wrong number of arguments for {}", cmd));
+                    }
+                    let key = match &commands[1] {
+                        BytesFrame::BulkString(bytes) => bytes.clone(),
+                        _ => return reply::error("ERR Invalid key type"),
+                    };
+                    // Without a count a single item is returned as a bulk string
+                    let count = match commands.get(2).map(parse_integer) {
+                        None => None,
+                        Some(Some(count)) if count >= 0 => Some(count as usize),
+                        Some(_) => {
+                            return reply::error("ERR value is out of range, must be positive")
+                        }
+                    };
+                    let end = if cmd == "LPOP" {
+                        ListEnd::Left
+                    } else {
+                        ListEnd::Right
+                    };
+                    let partition = partition.clone();
+                    match datastore
+                        .run_blocking(move || partition.list_pop(&key, count.unwrap_or(1), end))
+                        .await
+                    {
+                        Ok(Ok(None)) => reply::nil(),
+                        Ok(Ok(Some(mut items))) => match count {
+                            None => reply::bulk(items.remove(0)),
+                            Some(_) => reply::array(items.into_iter().map(reply::bulk)),
+                        },
+                        Ok(Err(e)) => storage_error(&cmd, e),
+                        Err(e) => reply::error(format!("ERR task error: {:?}", e)),
+                    }
+                }
+                // LMPOP numkeys key [key ...] LEFT|RIGHT [COUNT count]
+                "LMPOP" => {
+                    let mut args = Vec::with_capacity(commands.len() - 1);
+                    for arg in &commands[1..] {
+                        match arg {
+                            BytesFrame::BulkString(bytes) => args.push(bytes.to_vec()),
+                            _ => return reply::error("ERR Invalid argument type"),
+                        }
+                    }
+                    let Some((numkeys, args)) = args.split_first() else {
+                        return reply::error("ERR Wrong number of arguments for LMPOP");
+                    };
+                    let numkeys = match std::str::from_utf8(numkeys).ok().map(str::parse::<usize>) {
+                        Some(Ok(numkeys)) if numkeys > 0 => numkeys,
+                        _ => return reply::error("ERR numkeys should be greater than 0"),
+                    };
+                    if args.len() <= numkeys {
+                        return reply::error("ERR syntax error");
+                    }
+                    let (keys, options) = args.split_at(numkeys);
+                    let end = match &options[0] {
+                        end if end.eq_ignore_ascii_case(b"LEFT") => ListEnd::Left,
+                        end if end.eq_ignore_ascii_case(b"RIGHT") => ListEnd::Right,
+                        _ => return reply::error("ERR syntax error"),
+                    };
+                    let count = match &options[1..] {
+                        [] => 1,
+                        [option, count] if option.eq_ignore_ascii_case(b"COUNT") => {
+                            match std::str::from_utf8(count).ok().map(str::parse::<usize>) {
+                                Some(Ok(count)) if count > 0 => count,
+                                _ => return reply::error("ERR count should be greater than 0"),
+                            }
+                        }
+                        _ => return reply::error("ERR syntax error"),
+                    };
+                    let keys = keys.to_vec();
+                    let partition = partition.clone();
+                    match datastore
+                        .run_blocking(move || partition.list_multi_pop(&keys, count, end))
+                        .await
+                    {
+                        Ok(Ok(None)) => reply::nil(),
+                        Ok(Ok(Some((key, items)))) => reply::array([
+                            reply::bulk(key),
+                            reply::array(items.into_iter().map(reply::bulk)),
+                        ]),
+                        Ok(Err(e)) => storage_error("LMPOP", e),
+                        Err(e) => reply::error(format!("ERR task error: {:?}", e)),
+                    }
+                }
+                "BLPOP" | "BRPOP" => {
+                    let mut args = Vec::with_capacity(commands.len() - 1);
+                    for arg in &commands[1..] {
+                        match arg {
+                            BytesFrame::BulkString(bytes) => args.push(bytes.to_vec()),
+                            _ => return reply::error("ERR Invalid argument type"),
+                        }
+                    }
+                    let Some((timeout, keys)) =
+                        args.split_last().filter(|(_, keys)| !keys.is_empty())
+                    else {
+                        return reply::error(format!("ERR Wrong number of arguments for {}", cmd));
+                    };
+                    // Seconds, fractions allowed; 0 blocks forever
+                    let timeout = match parse_float(timeout) {
+                        Some(0.0) => None,
+                        Some(secs) if secs > 0.0 => Some(Duration::from_secs_f64(secs)),
+                        Some(_) => return reply::error("ERR timeout is negative"),
+                        None => return reply::error("ERR timeout is not a float or out of range"),
+                    };
+                    let end = if cmd == "BLPOP" {
+                        ListEnd::Left
+                    } else {
+                        ListEnd::Right
+                    };
+                    match partition.list_blocking_pop(keys, end, timeout).await {
+                        Ok(Some((key, item))) => {
+                            reply::array(vec![reply::bulk(key), reply::bulk(item)])
+                        }
+                        Ok(None) => reply::nil(),
+                        Err(e) => storage_error(&cmd, e),
+                    }
+                }
+                "LLEN" => {
+                    if commands.len() != 2 {
+                        return reply::error("ERR Wrong number of arguments for LLEN");
+                    }
+                    let key = match &commands[1] {
+                        BytesFrame::BulkString(bytes) => bytes.clone(),
+                        _ => return reply::error("ERR Invalid key type"),
+                    };
+                    let partition = partition.clone();
+                    match datastore
+                        .run_blocking(move || partition.list_len(&key))
+                        .await
+                    {
+                        Ok(Ok(len)) => reply::integer(len as i64),
+                        Ok(Err(e)) => storage_error(&cmd, e),
+                        Err(e) => reply::error(format!("ERR task error: {:?}", e)),
+                    }
+                }
+                "LRANGE" => {
+                    if commands.len() != 4 {
+                        return reply::error("ERR Wrong number of arguments for LRANGE");
+                    }
+                    let key = match &commands[1] {
+                        BytesFrame::BulkString(bytes) => bytes.clone(),
+                        _ => return reply::error("ERR Invalid key type"),
+                    };
+                    let (start, stop) =
+                        match (parse_integer(&commands[2]), parse_integer(&commands[3])) {
+                            (Some(start), Some(stop)) => (start, stop),
+                            _ => {
+                                return reply::error("ERR value is not an integer or out of range")
+                            }
+                        };
+                    let partition = partition.clone();
+                    match datastore
+                        .run_blocking(move || partition.list_range(&key, start, stop))
+                        .await
+                    {
+                        Ok(Ok(items)) => reply::array(items.into_iter().map(reply::bulk)),
+                        Ok(Err(e)) => storage_error(&cmd, e),
+                        Err(e) => reply::error(format!("ERR task error: {:?}", e)),
+                    }
+                }
+                "LPOS" => {
+                    if commands.len() < 3 || commands.len().is_multiple_of(2) {
+                        return reply::error("ERR Wrong number of arguments for LPOS");
+                    }
+                    let key = match &commands[1] {
+                        BytesFrame::BulkString(bytes) => bytes.clone(),
+                        _ => return reply::error("ERR Invalid key type"),
+                    };
+                    let element = match &commands[2] {
+                        BytesFrame::BulkString(bytes) => bytes.clone(),
+                        _ => return reply::error("ERR Invalid value type"),
+                    };
+                    let mut rank = 1;
+                    let mut count = None;
+                    let mut max_len = 0;
+                    for option in commands[3..].chunks(2) {
+                        let name = match &option[0] {
+                            BytesFrame::BulkString(bytes) => {
+                                String::from_utf8_lossy(bytes).to_ascii_uppercase()
+                            }
+                            _ => return reply::error("ERR syntax error"),
+                        };
+                        let Some(value) = parse_integer(&option[1]) else {
+                            return reply::error("ERR value is not an integer or out of range");
+                        };
+                        match name.as_str() {
+                            "RANK" if value == 0 => {
+                                return reply::error("ERR RANK can't be zero: use 1 to start from the first match, 2 from the second ... or use negative to start from the end of the list")
+                            }
+                            "RANK" => rank = value,
+                            "COUNT" if value >= 0 => count = Some(value as usize),
+                            "MAXLEN" if value >= 0 => max_len = value as usize,
+                            "COUNT" | "MAXLEN" => {
+                                return reply::error(format!("ERR {} can't be negative", name))
+                            }
+                            _ => return reply::error("ERR syntax error"),
+                        }
+                    }
+                    let partition = partition.clone();
+                    match datastore
+                        .run_blocking(move || {
+                            partition.list_positions(
+                                &key,
+                                &element,
+                                rank,
+                                count.unwrap_or(1),
+                                max_len,
+                            )
+                        })
+                        .await
+                    {
+                        // Without COUNT the reply is a single index or Null
+                        Ok(Ok(positions)) => match count {
+                            None => positions
+                                .first()
+                                .map(|&index| reply::integer(index as i64))
+                                .unwrap_or(reply::nil()),
+                            Some(_) => reply::array(
+                                positions
+                                    .into_iter()
+                                    .map(|index| reply::integer(index as i64)),
+                            ),
+                        },
+                        Ok(Err(e)) => storage_error(&cmd, e),
+                        Err(e) => reply::error(format!("ERR task error: {:?}", e)),
+                    }
+                }
+                "SADD" | "SREM" | "SMISMEMBER" => {
+                    if commands.len() < 3 {
+                        return reply::error(format!("ERR Wrong number of arguments for {}", cmd));
+                    }
+                    let key = match &commands[1] {
+                        BytesFrame::BulkString(bytes) => bytes.clone(),
+                        _ => return reply::error("ERR Invalid key type"),
+                    };
+                    let mut members = Vec::with_capacity(commands.len() - 2);
+                    for member in &commands[2..] {
+                        match member {
+                            BytesFrame::BulkString(bytes) => members.push(bytes.clone()),
+                            _ => return reply::error("ERR Invalid value type"),
+                        }
+                    }
+                    let partition = partition.clone();
+                    let command = cmd.clone();
+                    match datastore
+                        .run_blocking(move || {
+                            let members: Vec<&[u8]> = members.iter().map(|m| m.as_ref()).collect();
+                            match command.as_str() {
+                                "SADD" => partition
+                                    .set_add(&key, &members)
+                                    .map(|n| reply::integer(n as i64)),
+                                "SREM" => partition
+                                    .set_remove(&key, &members)
+                                    .map(|n| reply::integer(n as i64)),
+                                _ => partition.set_members_contain(&key, &members).map(|found| {
+                                    reply::array(
+                                        found
+                                            .into_iter()
+                                            .map(|is_member| reply::integer(is_member as i64)),
+                                    )
+                                }),
+                            }
+                        })
+                        .await
+                    {
+                        Ok(Ok(reply)) => reply,
+                        Ok(Err(e)) => storage_error(&cmd, e),
+                        Err(e) => reply::error(format!("ERR task error: {:?}", e)),
+                    }
+                }
+                "SISMEMBER" => {
+                    if commands.len() != 3 {
+                        return reply::error("ERR Wrong number of arguments for SISMEMBER");
+                    }
+                    let key = match &commands[1] {
+                        BytesFrame::BulkString(bytes) => bytes.clone(),
+                        _ => return reply::error("ERR Invalid key type"),
+                    };
+                    let member = match &commands[2] {
+                        BytesFrame::BulkString(bytes) => bytes.clone(),
+                        _ => return reply::error("ERR Invalid value type"),
+                    };
+                    let partition = partition.clone();
+                    match datastore
+                        .run_blocking(move || partition.set_is_member(&key, &member))
+                        .await
+                    {
+                        Ok(Ok(is_member)) => reply::integer(is_member as i64),
+                        Ok(Err(e)) => storage_error(&cmd, e),
+                        Err(e) => reply::error(format!("ERR task error: {:?}", e)),
+                    }
+                }
+                "SMEMBERS" | "SCARD" => {
+                    if commands.len() != 2 {
+                        return reply::error(format!("ERR Wrong number of arguments for {}", cmd));
+                    }
+                    let key = match &commands[1] {
+                        BytesFrame::BulkString(bytes) => bytes.clone(),
+                        _ => return reply::error("ERR Invalid key type"),
+                    };
+                    let partition = partition.clone();
+                    let list_members = cmd == "SMEMBERS";
+                    match datastore
+                        .run_blocking(move || {
+                            if list_members {
+                                partition.set_members(&key).map(|members| {
+                                    reply::array(members.into_iter().map(reply::bulk))
+                                })
+                            } else {
+                                partition
+                                    .set_card(&key)
+                                    .map(|card| reply::integer(card as i64))
+                            }
+                        })
+                        .await
+                    {
+                        Ok(Ok(reply)) => reply,
+                        Ok(Err(e)) => storage_error(&cmd, e),
+                        Err(e) => reply::error(format!("ERR task error: {:?}", e)),
+                    }
+                }
+                "SUNION" | "SINTER" | "SDIFF" | "SUNIONSTORE" | "SINTERSTORE" | "SDIFFSTORE" => {
+                    let store = cmd.ends_with("STORE");
+                    if commands.len() < 2 + store as usize {
+                        return reply::error(format!("ERR Wrong number of arguments for {}", cmd));
+                    }
+                    let mut keys = Vec::with_capacity(commands.len() - 1);
+                    for key in &commands[1..] {
+                        match key {
+                            BytesFrame::BulkString(bytes) => keys.push(bytes.clone()),
+                            _ => return reply::error("ERR Invalid key type"),
+                        }
+                    }
+                    let partition = partition.clone();
+                    let command = cmd.clone();
+                    match datastore
+                        .run_blocking(move || {
+                            let keys: Vec<&[u8]> = keys.iter().map(|k| k.as_ref()).collect();
+                            if store {
+                                let (destination, keys) = (keys[0], &keys[1..]);
+                                match command.as_str() {
+                                    "SUNIONSTORE" => partition.set_union_store(destination, keys),
+                                    "SINTERSTORE" => partition.set_inter_store(destination, keys),
+                                    _ => partition.set_diff_store(destination, keys),
+                                }
+                                .map(|card| reply::integer(card as i64))
+                            } else {
+                                match command.as_str() {
+                                    "SUNION" => partition.set_union(&keys),
+                                    "SINTER" => partition.set_inter(&keys),
+                                    _ => partition.set_diff(&keys),
+                                }
+                                .map(|members| reply::array(members.into_iter().map(reply::bulk)))
+                            }
+                        })
+                        .await
+                    {
+                        Ok(Ok(reply)) => reply,
+                        Ok(Err(e)) => storage_error(&cmd, e),
+                        Err(e) => reply::error(format!("ERR task error: {:?}", e)),
+                    }
+                }
+                "SINTERCARD" => {
+                    // SINTERCARD numkeys key [key ...] [LIMIT limit]
+                    let numkeys = match commands.get(1).map(parse_integer) {
+                        None => {
+                            return reply::error("ERR Wrong number of arguments for SINTERCARD")
+                        }
+                        Some(Some(numkeys)) if numkeys > 0 => numkeys as usize,
+                        Some(Some(_)) => {
+                            return reply::error("ERR numkeys should be greater than 0")
+                        }
+                        Some(None) => {
+                            return reply::error("ERR value is not an integer or out of range")
+                        }
+                    };
+                    if numkeys > commands.len() - 2 {
+                        return reply::error(
+                            "ERR Number of keys can't be greater than number of args",
+                        );
+                    }
+                    let mut keys = Vec::with_capacity(numkeys);
+                    for key in &commands[2..2 + numkeys] {
+                        match key {
+                            BytesFrame::BulkString(bytes) => keys.push(bytes.clone()),
+                            _ => return reply::error("ERR Invalid key type"),
+                        }
+                    }
+                    // LIMIT 0, like no LIMIT, counts the whole intersection
+                    let limit = match &commands[2 + numkeys..] {
+                        [] => None,
+                        [BytesFrame::BulkString(option), limit]
+                            if option.eq_ignore_ascii_case(b"LIMIT") =>
+                        {
+                            match parse_integer(limit) {
+                                Some(limit) if limit >= 0 => (limit > 0).then_some(limit as usize),
+                                Some(_) => return reply::error("ERR LIMIT can't be negative"),
+                                None => {
+                                    return reply::error(
+                                        "ERR value is not an integer or out of range",
+                                    )
+                                }
+                            }
+                        }
+                        _ => return reply::error("ERR syntax error"),
+                    };
+                    let partition = partition.clone();
+                    match datastore
+                        .run_blocking(move || {
+                            let keys: Vec<&[u8]> = keys.iter().map(|k| k.as_ref()).collect();
+                            partition.set_inter_card(&keys, limit)
+                        })
+                        .await
+                    {
+                        Ok(Ok(card)) => reply::integer(card as i64),
+                        Ok(Err(e)) => storage_error(&cmd, e),
+                        Err(e) => reply::error(format!("ERR task error: {:?}", e)),
+                    }
+                }
+                "HSET" | "HMSET" => {
+                    if commands.len() < 4 || commands.len() % 2 != 0 {
+                        return reply::error(format!("ERR Wrong number of arguments for {}", cmd));
+                    }
+                    let key = match &commands[1] {
+                        BytesFrame::BulkString(bytes) => bytes.clone(),
+                        _ => return reply::error("ERR Invalid key type"),
+                    };
+                    let mut fields = Vec::with_capacity((commands.len() - 2) / 2);
+                    for pair in commands[2..].chunks(2) {
+                        match (&pair[0], &pair[1]) {
+                            (BytesFrame::BulkString(field), BytesFrame::BulkString(value)) => {
+                                fields.push((field.clone(), value.clone()))
+                            }
+                            _ => return reply::error("ERR Invalid value type"),
+                        }
+                    }
+                    let partition = partition.clone();
+                    match datastore
+                        .run_blocking(move || {
+                            let fields: Vec<(&[u8], &[u8])> = fields
+                                .iter()
+                                .map(|(field, value)| (field.as_ref(), value.as_ref()))
+                                .collect();
+                            partition.hash_set(&key, &fields)
+                        })
+                        .await
+                    {
+                        Ok(Ok(_)) if cmd == "HMSET" => reply::ok(),
+                        Ok(Ok(created)) => reply::integer(created as i64),
+                        Ok(Err(e)) => storage_error(&cmd, e),
+                        Err(e) => reply::error(format!("ERR task error: {:?}", e)),
+                    }
+                }
+                "HGET" => {
+                    if commands.len() != 3 {
+                        return reply::error("ERR Wrong number of arguments for HGET");
+                    }
+                    let key = match &commands[1] {
+                        BytesFrame::BulkString(bytes) => bytes.clone(),
+                        _ => return reply::error("ERR Invalid key type"),
+                    };
+                    let field = match &commands[2] {
+                        BytesFrame::BulkString(bytes) => bytes.clone(),
+                        _ => return reply::error("ERR Invalid value type"),
+                    };
+                    let partition = partition.clone();
+                    match datastore
+                        .run_blocking(move || partition.hash_get(&key, &field))
+                        .await
+                    {
+                        Ok(Ok(Some(value))) => reply::bulk(value),
+                        Ok(Ok(None)) => reply::nil(),
+                        Ok(Err(e)) => storage_error(&cmd, e),
+                        Err(e) => reply::error(format!("ERR task error: {:?}", e)),
+                    }
+                }
+                "HDEL" => {
+                    if commands.len() < 3 {
+                        return reply::error("ERR Wrong number of arguments for HDEL");
+                    }
+                    let key = match &commands[1] {
+                        BytesFrame::BulkString(bytes) => bytes.clone(),
+                        _ => return reply::error("ERR Invalid key type"),
+                    };
+                    let mut fields = Vec::with_capacity(commands.len() - 2);
+                    for field in &commands[2..] {
+                        match field {
+                            BytesFrame::BulkString(bytes) => fields.push(bytes.clone()),
+                            _ => return reply::error("ERR Invalid value type"),
+                        }
+                    }
+                    let partition = partition.clone();
+                    match datastore
+                        .run_blocking(move || {
+                            let fields: Vec<&[u8]> = fields.iter().map(|f| f.as_ref()).collect();
+                            partition.hash_delete(&key, &fields)
+                        })
+                        .await
+                    {
+                        Ok(Ok(removed)) => reply::integer(removed as i64),
+                        Ok(Err(e)) => storage_error(&cmd, e),
+                        Err(e) => reply::error(format!("ERR task error: {:?}", e)),
+                    }
+                }
+                "HMGET" => {
+                    if commands.len() < 3 {
+                        return reply::error("ERR Wrong number of arguments for HMGET");
+                    }
+                    let key = match &commands[1] {
+                        BytesFrame::BulkString(bytes) => bytes.clone(),
+                        _ => return reply::error("ERR Invalid key type"),
+                    };
+                    let mut fields = Vec::with_capacity(commands.len() - 2);
+                    for field in &commands[2..] {
+                        match field {
+                            BytesFrame::BulkString(bytes) => fields.push(bytes.clone()),
+                            _ => return reply::error("ERR Invalid value type"),
+                        }
+                    }
+                    let partition = partition.clone();
+                    match datastore
+                        .run_blocking(move || {
+                            let fields: Vec<&[u8]> = fields.iter().map(|f| f.as_ref()).collect();
+                            partition.hash_get_many(&key, &fields)
+                        })
+                        .await
+                    {
+                        Ok(Ok(values)) => {
+                            reply::array(values.into_iter().map(|value| match value {
+                                Some(value) => reply::bulk(value),
+                                None => reply::nil(),
+                            }))
+                        }
+                        Ok(Err(e)) => storage_error(&cmd, e),
+                        Err(e) => reply::error(format!("ERR task error: {:?}", e)),
+                    }
+                }
+                "HINCRBY" | "HINCRBYFLOAT" => {
+                    if commands.len() != 4 {
+                        return reply::error(format!("ERR Wrong number of arguments for {}", cmd));
+                    }
+                    let key = match &commands[1] {
+                        BytesFrame::BulkString(bytes) => bytes.clone(),
+                        _ => return reply::error("ERR Invalid key type"),
+                    };
+                    let field = match &commands[2] {
+                        BytesFrame::BulkString(bytes) => bytes.clone(),
+                        _ => return reply::error("ERR Invalid value type"),
+                    };
+                    let partition = partition.clone();
+                    let result = if cmd == "HINCRBY" {
+                        let Some(delta) = parse_integer(&commands[3]) else {
+                            return reply::error("ERR value is not an integer or out of range");
+                        };
+                        datastore
+                            .run_blocking(move || {
+                                partition
+                                    .hash_incr_by(&key, &field, delta)
+                                    .map(reply::integer)
+                            })
+                            .await
+                    } else {
+                        let delta = match &commands[3] {
+                            BytesFrame::BulkString(bytes) => parse_float(bytes),
+                            _ => None,
+                        };
+                        let Some(delta) = delta else {
+                            return reply::error("ERR value is not a valid float");
+                        };
+                        // Like Redis, the new value is replied as a bulk string,
+                        // RESP3 connections get it as a double instead
+                        datastore
+                            .run_blocking(move || {
+                                partition
+                                    .hash_incr_by_float(&key, &field, delta)
+                                    .map(reply::bulk)
+                            })
+                            .await
+                    };
+                    match result {
+                        Ok(Ok(reply)) => reply,
+                        Ok(Err(e)) => storage_error(&cmd, e),
+                        Err(e) => reply::error(format!("ERR task error: {:?}", e)),
+                    }
+                }
+                "EXPIRE" | "PEXPIRE" | "EXPIREAT" | "PEXPIREAT" => {
+                    if commands.len() != 3 {
+                        return reply::error(format!("ERR Wrong number of arguments for {}", cmd));
+                    }
+                    let key = match &commands[1] {
+                        BytesFrame::BulkString(bytes) => bytes.clone(),
+                        _ => return reply::error("ERR Invalid key type"),
+                    };
+                    let amount = match parse_integer(&commands[2]) {
+                        Some(amount) => amount,
+                        None => return reply::error("ERR value is not an integer or out of range"),
+                    };
+                    let deadline = match cmd.as_str() {
+                        "EXPIRE" => {
+                            (now_millis() as i64).saturating_add(amount.saturating_mul(1000))
+                        }
+                        "PEXPIRE" => (now_millis() as i64).saturating_add(amount),
+                        "EXPIREAT" => amount.saturating_mul(1000),
+                        _ => amount,
+                    };
+                    let Some(deadline) = limit_ttl(datastore, deadline.max(0) as u64) else {
+                        return ttl_out_of_range(&cmd.to_lowercase());
+                    };
+                    let partition = partition.clone();
+                    match datastore
+                        .run_blocking(move || partition.expire_at(&key, deadline))
+                        .await
+                    {
+                        Ok(Ok(updated)) => reply::integer(updated as i64),
+                        Ok(Err(e)) => reply::error(format!("ERR {} error: {:?}", cmd, e)),
+                        Err(e) => reply::error(format!("ERR task error: {:?}", e)),
+                    }
+                }
+                // GETWITHTTL key: the value and its TTL as TTL reports it, in
+                // one read. RESP3 clients get the TTL as an attribute of the
+                // value instead, see `Connection::command_reply`.
+                "GETWITHTTL" => {
+                    let key = match &commands[1..] {
+                        [BytesFrame::BulkString(key)] => key.clone(),
+                        _ => return reply::error("ERR Wrong number of arguments for GETWITHTTL"),
+                    };
+                    let partition = partition.clone();
+                    match datastore
+                        .run_blocking(move || partition.get_string_with_expiry(&key))
+                        .await
+                    {
+                        Ok(Ok((value, expiry))) => {
+                            let ttl = match expiry {
+                                Expiry::Missing => -2,
+                                Expiry::Persistent => -1,
+                                // Rounded to the nearest second, as TTL
+                                Expiry::At(deadline) => {
+                                    ((deadline.saturating_sub(now_millis()) + 500) / 1000) as i64
+                                }
+                            };
+                            reply::array([
+                                value.map_or_else(reply::nil, reply::bulk),
+                                reply::integer(ttl),
+                            ])
+                        }
+                        Ok(Err(e)) => storage_error(&cmd, e),
+                        Err(e) => reply::error(format!("ERR task error: {:?}", e)),
+                    }
+                }
+                "PERSIST" => {
+                    if commands.len() != 2 {
+                        return reply::error("ERR Wrong number of arguments for PERSIST");
+                    }
+                    let key = match &commands[1] {
+                        BytesFrame::BulkString(bytes) => bytes.clone(),
+                        _ => return reply::error("ERR Invalid key type"),
+                    };
+                    let partition = partition.clone();
+                    match datastore
+                        .run_blocking(move || partition.clear_expiry(&key))
+                        .await
+                    {
+                        Ok(Ok(cleared)) => reply::integer(cleared as i64),
+                        Ok(Err(e)) => reply::error(format!("ERR PERSIST error: {:?}", e)),
+                        Err(e) => reply::error(format!("ERR task error: {:?}", e)),
+                    }
+                }
+                // LCS key1 key2 [LEN] [IDX] [MINMATCHLEN len] [WITHMATCHLEN]
+                "LCS" => {
+                    let (key1, key2) = match &commands[1..] {
+                        [BytesFrame::BulkString(key1), BytesFrame::BulkString(key2), ..] => {
+                            (key1.clone(), key2.clone())
+                        }
+                        _ => return reply::error("ERR Wrong number of arguments for LCS"),
+                    };
+                    let (mut len, mut idx, mut with_match_len, mut min_match_len) =
+                        (false, false, false, 0);
+                    let mut options = commands[3..].iter();
+                    while let Some(option) = options.next() {
+                        let BytesFrame::BulkString(option) = option else {
+                            return reply::error("ERR syntax error");
+                        };
+                        match option.to_ascii_uppercase().as_slice() {
+                            b"LEN" => len = true,
+                            b"IDX" => idx = true,
+                            b"WITHMATCHLEN" => with_match_len = true,
+                            b"MINMATCHLEN" => {
+                                match options.next().and_then(parse_integer) {
+                                    // Negative lengths filter nothing, as in Redis
+                                    Some(n) => min_match_len = n.max(0) as usize,
+                                    None => {
+                                        return reply::error(
+                                            "ERR value is not an integer or out of range",
+                                        )
+                                    }
+                                }
+                            }
+                            _ => return reply::error("ERR syntax error"),
+                        }
+                    }
+                    if len && idx {
+                        return reply::error(
+                            "ERR If you want both the length and indexes, please just use IDX.",
+                        );
+                    }
+                    let partition = partition.clone();
+                    let max_cells = datastore.lcs_max_cells();
+                    match datastore
+                        .run_blocking(move || {
+                            // Missing keys compare as empty strings
+                            let a = partition.get_string(&key1)?.unwrap_or_default();
+                            let b = partition.get_string(&key2)?.unwrap_or_default();
+                            let cells = (a.len() + 1).saturating_mul(b.len() + 1);
+                            Ok::<_, DataStoreError>((cells <= max_cells).then(|| lcs(&a, &b)))
+                        })
+                        .await
+                    {
+                        Ok(Ok(Some(result))) if idx => reply::map_as_array([
+                            (
+                                reply::bulk("matches"),
+                                reply::array(
+                                    result
+                                        .matches
+                                        .iter()
+                                        .filter(|found| found.len() >= min_match_len)
+                                        .map(|found| {
+                                            let range = |(start, end): (usize, usize)| {
+                                                reply::array([
+                                                    reply::integer(start as i64),
+                                                    reply::integer(end as i64),
+                                                ])
+                                            };
+                                            let mut fields = vec![range(found.a), range(found.b)];
+                                            if with_match_len {
+                                                fields.push(reply::integer(found.len() as i64));
+                                            }
+                                            reply::array(fields)
+                                        }),
+                                ),
+                            ),
+                            (
+                                reply::bulk("len"),
+                                reply::integer(result.sequence.len() as i64),
+                            ),
+                        ]),
+                        Ok(Ok(Some(result))) if len => reply::integer(result.sequence.len() as i64),
+                        Ok(Ok(Some(result))) => reply::bulk(result.sequence),
+                        Ok(Ok(None)) => {
+                            reply::error("ERR LCS of values this large exceeds lcs-max-cells")
+                        }
+                        Ok(Err(e)) => storage_error("LCS", e),
+                        Err(e) => reply::error(format!("ERR task error: {:?}", e)),
+                    }
+                }
+                // DBSIZE [prefix]: the number of keys, or of keys under prefix
+                "DBSIZE" => {
+                    let prefix = match &commands[1..] {
+                        [] => Bytes::new(),
+                        [BytesFrame::BulkString(prefix)] => prefix.clone(),
+                        _ => return reply::error("ERR Wrong number of arguments for DBSIZE"),
+                    };
+                    let partition = partition.clone();
+                    match datastore
+                        .run_blocking(move || partition.count_prefix(&prefix))
+                        .await
+                    {
+                        Ok(Ok(count)) => reply::integer(count as i64),
+                        Ok(Err(e)) => reply::error(format!("ERR DBSIZE error: {:?}", e)),
+                        Err(e) => reply::error(format!("ERR task error: {:?}", e)),
+                    }
+                }
+                "KEYS" => {
+                    let pattern = match &commands[1..] {
+                        [BytesFrame::BulkString(pattern)] => pattern.clone(),
+                        _ => return reply::error("ERR Wrong number of arguments for KEYS"),
+                    };
+                    let partition = partition.clone();
+                    match datastore
+                        .run_blocking(move || partition.keys(&pattern))
+                        .await
+                    {
+                        Ok(Ok(keys)) => reply::array(keys.into_iter().map(reply::bulk)),
+                        Ok(Err(e)) => storage_error("KEYS", e),
+                        Err(e) => reply::error(format!("ERR task error: {:?}", e)),
+                    }
+                }
+                // SCAN cursor [MATCH pattern] [COUNT count]
+                "SCAN" => {
+                    let Some(cursor) = commands.get(1) else {
+                        return reply::error("ERR Wrong number of arguments for SCAN");
+                    };
+                    let after = match scan_position(datastore, cursor) {
+                        Ok(after) => after,
+                        Err(e) => return e,
+                    };
+                    let options = match parse_scan_options(&commands[2..], false) {
+                        Ok(options) => options,
+                        Err(e) => return e,
+                    };
+                    let partition = partition.clone();
+                    match datastore
+                        .run_blocking(move || {
+                            partition.scan(
+                                after.as_deref(),
+                                options.count,
+                                options.pattern.as_deref(),
+                            )
+                        })
+                        .await
+                    {
+                        Ok(Ok(page)) => {
+                            let next = page
+                                .next
+                                .map_or(0, |position| datastore.scan_cursors().save(position));
+                            reply::array([
+                                reply::bulk(next.to_string()),
+                                reply::array(page.keys.into_iter().map(reply::bulk)),
+                            ])
+                        }
+                        Ok(Err(e)) => storage_error("SCAN", e),
+                        Err(e) => reply::error(format!("ERR task error: {:?}", e)),
+                    }
+                }
+                // SSCAN key cursor [MATCH pattern] [COUNT count]
+                "SSCAN" => {
+                    let (key, cursor) = match commands.get(1..3) {
+                        Some([BytesFrame::BulkString(key), cursor]) => (key.clone(), cursor),
+                        _ => return reply::error("ERR Wrong number of arguments for SSCAN"),
+                    };
+                    let after = match scan_position(datastore, cursor) {
+                        Ok(after) => after,
+                        Err(e) => return e,
+                    };
+                    let options = match parse_scan_options(&commands[3..], false) {
+                        Ok(options) => options,
+                        Err(e) => return e,
+                    };
+                    let partition = partition.clone();
+                    match datastore
+                        .run_blocking(move || {
+                            partition.set_scan(
+                                &key,
+                                after.as_deref(),
+                                options.count,
+                                options.pattern.as_deref(),
+                            )
+                        })
+                        .await
+                    {
+                        Ok(Ok(page)) => {
+                            let next = page
+                                .next
+                                .map_or(0, |position| datastore.scan_cursors().save(position));
+                            reply::array([
+                                reply::bulk(next.to_string()),
+                                reply::array(page.members.into_iter().map(reply::bulk)),
+                            ])
+                        }
+                        Ok(Err(e)) => storage_error("SSCAN", e),
+                        Err(e) => reply::error(format!("ERR task error: {:?}", e)),
+                    }
+                }
+                // HSCAN key cursor [MATCH pattern] [COUNT count] [NOVALUES]
+                "HSCAN" => {
+                    let (key, cursor) = match commands.get(1..3) {
+                        Some([BytesFrame::BulkString(key), cursor]) => (key.clone(), cursor),
+                        _ => return reply::error("ERR Wrong number of arguments for HSCAN"),
+                    };
+                    let after = match scan_position(datastore, cursor) {
+                        Ok(after) => after,
+                        Err(e) => return e,
+                    };
+                    let options = match parse_scan_options(&commands[3..], true) {
+                        Ok(options) => options,
+                        Err(e) => return e,
+                    };
+                    let partition = partition.clone();
+                    match datastore
+                        .run_blocking(move || {
+                            partition.hash_scan(
+                                &key,
+                                after.as_deref(),
+                                options.count,
+                                options.pattern.as_deref(),
+                            )
+                        })
+                        .await
+                    {
+                        Ok(Ok(page)) => {
+                            let next = page
+                                .next
+                                .map_or(0, |position| datastore.scan_cursors().save(position));
+                            let items = page.fields.into_iter().flat_map(|(field, value)| {
+                                let value = (!options.novalues).then(|| reply::bulk(value));
+                                std::iter::once(reply::bulk(field)).chain(value)
+                            });
+                            reply::array([reply::bulk(next.to_string()), reply::array(items)])
+                        }
+                        Ok(Err(e)) => storage_error("HSCAN", e),
+                        Err(e) => reply::error(format!("ERR task error: {:?}", e)),
+                    }
+                }
+                "SWAP" => {
+                    let (key1, key2) = match &commands[1..] {
+                        [BytesFrame::BulkString(key1), BytesFrame::BulkString(key2)] => {
+                            (key1.clone(), key2.clone())
+                        }
+                        _ => return reply::error("ERR Wrong number of arguments for SWAP"),
+                    };
+                    let partition = partition.clone();
+                    match datastore
+                        .run_blocking(move || partition.swap(&key1, &key2))
+                        .await
+                    {
+                        Ok(Ok(())) => reply::ok(),
+                        Ok(Err(e)) => reply::error(format!("ERR SWAP error: {:?}", e)),
+                        Err(e) => reply::error(format!("ERR task error: {:?}", e)),
+                    }
+                }
+                "INFO" => {
+                    let section = match commands.get(1) {
+                        Some(BytesFrame::BulkString(bytes)) => {
+                            String::from_utf8_lossy(bytes).to_ascii_lowercase()
+                        }
+                        Some(_) => return reply::error("ERR invalid section type"),
+                        None => "all".to_string(),
+                    };
+                    let mut info = String::new();
+                    if matches!(section.as_str(), "all" | "default" | "server") {
+                        info.push_str("# Server\r\n");
+                        info.push_str(&format!("veifka_version:{}\r\n", env!("CARGO_PKG_VERSION")));
+                        info.push_str(&format!("run_id:{}\r\n", datastore.run_id()));
+                        info.push_str(&format!("data_path:{}\r\n", datastore.path().display()));
+                        info.push_str(&format!("partition:{}\r\n", partition.name()));
+                    }
+                    if matches!(section.as_str(), "all" | "default" | "stats") {
+                        let stats = partition.stats();
+                        info.push_str("# Stats\r\n");
+                        info.push_str(&format!("total_gets:{}\r\n", stats.gets));
+                        info.push_str(&format!("total_sets:{}\r\n", stats.sets));
+                        info.push_str(&format!("total_deletes:{}\r\n", stats.deletes));
+                        info.push_str(&format!(
+                            "total_group_commits:{}\r\n",
+                            partition.group_commits()
+                        ));
+                        info.push_str(&format!("keyspace_hits:{}\r\n", stats.hits));
+                        info.push_str(&format!("keyspace_misses:{}\r\n", stats.misses));
+                        info.push_str(&format!(
+                            "keyspace_hit_rate:{:.4}\r\n",
+                            stats.hit_rate().unwrap_or(0.0)
+                        ));
+                    }
+                    if matches!(section.as_str(), "all" | "default" | "memory") {
+                        // Only memtables and the block cache's size are
+                        // accounted for
+                        info.push_str("# Memory\r\n");
+                        info.push_str(&format!(
+                            "used_memory:{}\r\n",
+                            datastore.write_buffer_usage()
+                        ));
+                        info.push_str(&format!(
+                            "maxmemory:{}\r\n",
+                            datastore.block_cache_capacity()
+                        ));
+                    }
+                    if matches!(section.as_str(), "all" | "default" | "replication") {
+                        info.push_str("# Replication\r\n");
+                        match datastore.replica_of() {
+                            Some((host, port)) => {
+                                info.push_str("role:slave\r\n");
+                                info.push_str(&format!("master_host:{}\r\n", host));
+                                info.push_str(&format!("master_port:{}\r\n", port));
+                                info.push_str("master_link_status:down\r\n");
+                            }
+                            None => {
+                                info.push_str("role:master\r\n");
+                                info.push_str("connected_slaves:0\r\n");
+                            }
+                        }
+                        info.push_str("master_failover_state:no-failover\r\n");
+                        info.push_str(&format!("master_replid:{}\r\n", datastore.run_id()));
+                        info.push_str("master_repl_offset:0\r\n");
+                    }
+                    // Data is served from disk as soon as the store is open,
+                    // so there is never a dataset being loaded
+                    if matches!(section.as_str(), "all" | "default" | "persistence") {
+                        info.push_str("# Persistence\r\n");
+                        info.push_str("loading:0\r\n");
+                        info.push_str(&format!("appendfsync:{}\r\n", datastore.fsync_policy()));
+                    }
+                    if matches!(section.as_str(), "all" | "default" | "storage") {
+                        // fjall does not expose per-level sizes
+                        info.push_str("# Storage\r\n");
+                        info.push_str(&format!("disk_space:{}\r\n", partition.disk_space()));
+                        info.push_str(&format!("segments:{}\r\n", partition.segment_count()));
+                    }
+                    if matches!(section.as_str(), "all" | "default" | "keyspace") {
+                        let partition = partition.clone();
+                        let stats = match datastore
+                            .run_blocking(move || partition.keyspace_stats())
+                            .await
+                        {
+                            Ok(Ok(stats)) => stats,
+                            Ok(Err(e)) => return reply::error(format!("ERR INFO error: {:?}", e)),
+                            Err(e) => return reply::error(format!("ERR task error: {:?}", e)),
+                        };
+                        info.push_str("# Keyspace\r\n");
+                        // The server serves a single partition as db0. Like
+                        // Redis, empty databases are left out.
+                        if stats.keys > 0 {
+                            info.push_str(&format!(
+                                "db0:keys={},expires={},avg_ttl=0\r\n",
+                                stats.keys, stats.expires
+                            ));
+                        }
+                    }
+                    reply::bulk(info)
+                }
+                "DEBUG" => {
+                    let subcommand = match commands.get(1) {
+                        Some(BytesFrame::BulkString(bytes)) => {
+                            String::from_utf8_lossy(bytes).to_ascii_uppercase()
+                        }
+                        _ => return reply::error("ERR Wrong number of arguments for DEBUG"),
+                    };
+                    match (subcommand.as_str(), &commands[2..]) {
+                        ("RELOAD", []) => {}
+                        // A new run id, as after a restart
+                        ("CHANGE-REPL-ID", []) => {
+                            datastore.change_run_id();
+                            return reply::ok();
+                        }
+                        // BIGKEYS [COUNT n]: the biggest key of every type
+                        // among the first n keys
+                        ("BIGKEYS", options) => {
+                            let limit = match options {
+                                [] => DEFAULT_BIGKEYS_SCAN_LIMIT,
+                                [BytesFrame::BulkString(option), count]
+                                    if option.eq_ignore_ascii_case(b"COUNT") =>
+                                {
+                                    match parse_integer(count).map(usize::try_from) {
+                                        Some(Ok(count)) => count,
+                                        _ => {
+                                            return reply::error(
+                                                "ERR value is not an integer or out of range",
+                                            )
+                                        }
+                                    }
+                                }
+                                _ => return reply::error("ERR syntax error"),
+                            };
+                            let partition = partition.clone();
+                            return match datastore
+                                .run_blocking(move || partition.big_keys(limit))
+                                .await
+                            {
+                                Ok(Ok(report)) => big_keys_reply(report),
+                                Ok(Err(e)) => storage_error("DEBUG BIGKEYS", e),
+                                Err(e) => reply::error(format!("ERR task error: {:?}", e)),
+                            };
+                        }
+                        // OBJECT key: internals of the value on one line,
+                        // in the format of Redis, which tools parse
+                        ("OBJECT", [BytesFrame::BulkString(key)]) => {
+                            let partition = partition.clone();
+                            let key = key.clone();
+                            return match datastore.run_blocking(move || {
+                                let Some(length) = partition.serialized_length(&key)? else {
+                                    return Ok(None);
+                                };
+                                let encoding = partition.encoding(&key)?.unwrap_or("raw");
+                                let idle = partition.idle_time(&key)?.unwrap_or(0);
+                                Ok::<_, DataStoreError>(Some((encoding, length, idle)))
+                            })
+                            .await
+                            {
+                                Ok(Ok(Some((encoding, length, idle)))) => reply::simple(format!(
+                                    "Value at:0x0 refcount:1 encoding:{} serializedlength:{} lru_seconds_idle:{}",
+                                    encoding, length, idle
+                                )),
+                                Ok(Ok(None)) => reply::error("ERR no such key"),
+                                Ok(Err(e)) => storage_error("DEBUG OBJECT", e),
+                                Err(e) => reply::error(format!("ERR task error: {:?}", e)),
+                            };
+                        }
+                        ("VERIFY", []) => {
+                            let partition = partition.clone();
+                            return match datastore.run_blocking(move || partition.verify()).await {
+                                Ok(Ok(report)) => reply::map_as_array([
+                                    (
+                                        reply::bulk("entries"),
+                                        reply::integer(report.entries as i64),
+                                    ),
+                                    (reply::bulk("bytes"), reply::integer(report.bytes as i64)),
+                                    (
+                                        reply::bulk("corrupted"),
+                                        reply::array(report.corrupted.into_iter().map(reply::bulk)),
+                                    ),
+                                ]),
+                                Ok(Err(e)) => storage_error("DEBUG VERIFY", e),
+                                Err(e) => reply::error(format!("ERR task error: {:?}", e)),
+                            };
+                        }
+                        // Replies with the message as an error, for client
+                        // test suites checking their error handling
+                        ("ERROR", [BytesFrame::BulkString(message)]) => {
+                            return reply::error(String::from_utf8_lossy(message).into_owned())
+                        }
+                        ("LOG", [BytesFrame::BulkString(message)]) => {
+                            eprintln!("DEBUG LOG: {}", String::from_utf8_lossy(message));
+                            return reply::ok();
+                        }
+                        // Accepted as no-ops, so Redis conformance suites
+                        // that call them carry on
+                        ("JMAP", []) | ("SET-ACTIVE-EXPIRE", [_]) => return reply::ok(),
+                        (
+                            "RELOAD" | "OBJECT" | "VERIFY" | "ERROR" | "LOG" | "JMAP"
+                            | "CHANGE-REPL-ID" | "SET-ACTIVE-EXPIRE",
+                            _,
+                        ) => {
+                            return reply::error(format!(
+                                "ERR Wrong number of arguments for DEBUG {}",
+                                subcommand
+                            ))
+                        }
+                        _ => {
+                            return reply::error(format!("ERR unknown subcommand '{}'", subcommand))
+                        }
+                    }
+                    let partition = partition.clone();
+                    match datastore.run_blocking(move || partition.reload()).await {
+                        Ok(Ok(())) => reply::ok(),
+                        Ok(Err(e)) => storage_error("DEBUG RELOAD", e),
+                        Err(e) => reply::error(format!("ERR task error: {:?}", e)),
+                    }
+                }
+                "CONFIG" => config(&commands[1..], datastore),
+                // REPLICAOF host port | NO ONE. Only records the master for
+                // INFO, so tools probing replication carry on; nothing is
+                // replicated yet.
+                "REPLICAOF" | "SLAVEOF" => match &commands[1..] {
+                    [BytesFrame::BulkString(no), BytesFrame::BulkString(one)]
+                        if no.eq_ignore_ascii_case(b"NO") && one.eq_ignore_ascii_case(b"ONE") =>
+                    {
+                        datastore.set_replica_of(None);
+                        reply::ok()
+                    }
+                    [BytesFrame::BulkString(host), port] => {
+                        let Some(port) =
+                            parse_integer(port).and_then(|port| u16::try_from(port).ok())
+                        else {
+                            return reply::error("ERR Invalid master port");
+                        };
+                        let host = String::from_utf8_lossy(host).into_owned();
+                        datastore.set_replica_of(Some((host, port)));
+                        reply::ok()
+                    }
+                    _ => reply::error(format!("ERR Wrong number of arguments for {}", cmd)),
+                },
+                "PSYNC" | "SYNC" => reply::error("ERR replication is not supported"),
+                // WAIT numreplicas timeout. There are never replicas to
+                // acknowledge writes, so this replies 0 right away rather
+                // than waiting out the timeout.
+                "WAIT" => match &commands[1..] {
+                    [numreplicas, timeout] => {
+                        if parse_integer(numreplicas).is_none() {
+                            return reply::error("ERR value is not an integer or out of range");
+                        }
+                        match parse_integer(timeout) {
+                            Some(timeout) if timeout >= 0 => reply::integer(0),
+                            Some(_) => reply::error("ERR timeout is negative"),
+                            None => reply::error("ERR timeout is not an integer or out of range"),
+                        }
+                    }
+                    _ => reply::error("ERR Wrong number of arguments for WAIT"),
+                },
+                // FAILOVER [TO host port [FORCE]] [ABORT] [TIMEOUT ms]. Fails
+                // with Redis' own errors for a master without replicas, which
+                // HA tooling knows how to handle, rather than as unknown.
+                "FAILOVER" => {
+                    let abort = commands[1..].iter().any(|arg| {
+                        matches!(arg, BytesFrame::BulkString(arg) if arg.eq_ignore_ascii_case(b"ABORT"))
+                    });
+                    if abort {
+                        reply::error("ERR No failover in progress.")
+                    } else if datastore.replica_of().is_some() {
+                        reply::error("ERR FAILOVER is not valid when server is a replica.")
+                    } else {
+                        reply::error("ERR FAILOVER requires connected replicas.")
+                    }
+                }
+                "COMPACT" => {
+                    let name = match commands.get(1) {
+                        Some(BytesFrame::BulkString(bytes)) => String::from_utf8_lossy(bytes),
+                        Some(_) => return reply::error("ERR Invalid partition type"),
+                        None => partition.name().into(),
+                    };
+                    // The server only serves one partition
+                    if commands.len() > 2 || name != partition.name() {
+                        return reply::error(format!("ERR no such partition '{}'", name));
+                    }
+                    let partition = partition.clone();
+                    match datastore.run_blocking(move || partition.compact()).await {
+                        Ok(Ok(())) => reply::ok(),
+                        Ok(Err(e)) => reply::error(format!("ERR COMPACT error: {:?}", e)),
+                        Err(e) => reply::error(format!("ERR task error: {:?}", e)),
+                    }
+                }
+                // SAVE [partition]: syncs the partition's writes to disk
+                "SAVE" => {
+                    let name = match commands.get(1) {
+                        Some(BytesFrame::BulkString(bytes)) => String::from_utf8_lossy(bytes),
+                        Some(_) => return reply::error("ERR Invalid partition type"),
+                        None => partition.name().into(),
+                    };
+                    if commands.len() > 2 || name != partition.name() {
+                        return reply::error(format!("ERR no such partition '{}'", name));
+                    }
+                    let partition = partition.clone();
+                    match datastore.run_blocking(move || partition.flush()).await {
+                        Ok(Ok(())) => reply::ok(),
+                        Ok(Err(e)) => reply::error(format!("ERR SAVE error: {:?}", e)),
+                        Err(e) => reply::error(format!("ERR task error: {:?}", e)),
+                    }
+                }
+                // BACKUP path: writes a consistent snapshot of every partition
+                // to a new data store directory at `path`
+                "BACKUP" => {
+                    let path = match &commands[1..] {
+                        [BytesFrame::BulkString(path)] => {
+                            std::path::PathBuf::from(String::from_utf8_lossy(path).into_owned())
+                        }
+                        _ => return reply::error("ERR Wrong number of arguments for BACKUP"),
+                    };
+                    let store = datastore.clone();
+                    match datastore
+                        .run_blocking(move || store.snapshot()?.backup_to(&path))
+                        .await
+                    {
+                        Ok(Ok(_)) => reply::ok(),
+                        Ok(Err(e)) => reply::error(format!("ERR BACKUP error: {}", e)),
+                        Err(e) => reply::error(format!("ERR task error: {:?}", e)),
+                    }
+                }
+                "CLUSTER" => {
+                    if commands.len() < 2 {
+                        return reply::error("ERR Wrong number of arguments for CLUSTER");
+                    }
+                    let subcommand = match &commands[1] {
+                        BytesFrame::BulkString(bytes) => {
+                            String::from_utf8_lossy(bytes).to_ascii_uppercase()
+                        }
+                        _ => return reply::error("ERR invalid subcommand type"),
+                    };
+                    match subcommand.as_str() {
+                        "KEYSLOT" => {
+                            if commands.len() != 3 {
+                                return reply::error(
+                                    "ERR Wrong number of arguments for CLUSTER KEYSLOT",
+                                );
+                            }
+                            match &commands[2] {
+                                BytesFrame::BulkString(key) => {
+                                    reply::integer(DataStore::slot_for(key) as i64)
+                                }
+                                _ => reply::error("ERR Invalid key type"),
+                            }
+                        }
+                        _ => reply::error(format!(
+                            "ERR unknown subcommand '{}' for CLUSTER",
+                            subcommand
+                        )),
+                    }
+                }
+                _ => reply::error(format!("{}{}'", UNKNOWN_COMMAND, cmd)),
+            }
+        }
+        BytesFrame::Null => todo!(),
+    }
+}
+
+// The commands any `KvBackend` serves: PING and the plain key commands, or
+// None for the others
+async fn backend_command<B: KvBackend>(
+    cmd: &str,
+    commands: &[BytesFrame],
+    datastore: &DataStore,
+    backend: &B,
+) -> Option<BytesFrame> {
+    let reply = match cmd {
+        "PING" => reply::simple("PONG"),
+        "SET" => set_command(commands, datastore, backend).await,
+        "GET" | "DEL" | "EXISTS" | "MGET" | "MSET" => {
+            let (cmd, commands, backend) = (cmd.to_string(), commands.to_vec(), backend.clone());
+            match datastore
+                .run_blocking(move || kv_command(&cmd, &commands, &backend))
+                .await
+            {
+                Ok(reply) => reply,
+                Err(e) => reply::error(format!("ERR task error: {:?}", e)),
+            }
+        }
+        "TTL" | "PTTL" | "EXPIRETIME" | "PEXPIRETIME" => {
+            ttl_command(cmd, commands, datastore, backend).await
+        }
+        _ => return None,
+    };
+    Some(reply)
+}
+
+async fn set_command<B: KvBackend>(
+    commands: &[BytesFrame],
+    datastore: &DataStore,
+    backend: &B,
+) -> BytesFrame {
+    if commands.len() < 3 {
+        return reply::error("ERR Wrong number of arguments for SET");
+    }
+    let key = match &commands[1] {
+        BytesFrame::BulkString(bytes) => bytes.clone(),
+        _ => return reply::error("ERR Invalid key type"),
+    };
+    let value = match &commands[2] {
+        BytesFrame::BulkString(bytes) => bytes.clone(),
+        _ => return reply::error("ERR Invalid value type"),
+    };
+    // SET key value [EX seconds | PX milliseconds | REFRESH] [SYNC]; without EX
+    // or PX the partition's default TTL applies. REFRESH sets the refresh-ttl,
+    // so a key written with it on every write slides its expiry along. SYNC
+    // syncs the journal before replying, whatever the fsync policy.
+    let mut deadline = None;
+    let mut sync = false;
+    let mut options = commands[3..].iter();
+    while let Some(option) = options.next() {
+        let unit_ms = match option {
+            BytesFrame::BulkString(option) if option.eq_ignore_ascii_case(b"SYNC") && !sync => {
+                sync = true;
+                continue;
+            }
+            BytesFrame::BulkString(option)
+                if option.eq_ignore_ascii_case(b"REFRESH") && deadline.is_none() =>
+            {
+                let Some(ttl) = datastore.refresh_ttl() else {
+                    return reply::error("ERR SET REFRESH needs refresh-ttl to be set");
+                };
+                deadline = Some(now_millis().saturating_add(ttl.as_millis() as u64));
+                continue;
+            }
+            BytesFrame::BulkString(option)
+                if option.eq_ignore_ascii_case(b"EX") && deadline.is_none() =>
+            {
+                1000
+            }
+            BytesFrame::BulkString(option)
+                if option.eq_ignore_ascii_case(b"PX") && deadline.is_none() =>
+            {
+                1
+            }
+            _ => return reply::error("ERR syntax error"),
+        };
+        let Some(amount) = options.next() else {
+            return reply::error("ERR syntax error");
+        };
+        deadline = match parse_integer(amount) {
+            Some(amount) if amount > 0 => {
+                Some((now_millis() as i64).saturating_add(amount.saturating_mul(unit_ms)) as u64)
+            }
+            Some(_) => return reply::error("ERR invalid expire time in 'set' command"),
+            None => return reply::error("ERR value is not an integer or out of range"),
+        };
+    }
+    let deadline = match deadline.map(|deadline| limit_ttl(datastore, deadline)) {
+        Some(Some(deadline)) => Some(deadline),
+        Some(None) => return ttl_out_of_range("set"),
+        None => None,
+    };
+    let backend = backend.clone();
+    let store = datastore.clone();
+    match datastore
+        .run_blocking(move || {
+            match deadline {
+                Some(deadline) => backend.set_expiring(&key, &value, Some(deadline)),
+                None => backend.set(&key, &value),
+            }?;
+            if sync {
+                store.sync_data()?;
+            }
+            Ok::<_, DataStoreError>(())
+        })
+        .await
+    {
+        Ok(Ok(())) => reply::ok(),
+        Ok(Err(e)) => storage_error("SET", e),
+        Err(e) => reply::error(format!("ERR task error: {:?}", e)),
+    }
+}
+
+// TTL, PTTL, EXPIRETIME and PEXPIRETIME
+async fn ttl_command<B: KvBackend>(
+    cmd: &str,
+    commands: &[BytesFrame],
+    datastore: &DataStore,
+    backend: &B,
+) -> BytesFrame {
+    if commands.len() != 2 {
+        return reply::error(format!("ERR Wrong number of arguments for {}", cmd));
+    }
+    let key = match &commands[1] {
+        BytesFrame::BulkString(bytes) => bytes.clone(),
+        _ => return reply::error("ERR Invalid key type"),
+    };
+    let backend = backend.clone();
+    match datastore
+        .run_blocking(move || backend.expire_time(&key))
+        .await
+    {
+        Ok(Ok(Expiry::Missing)) => reply::integer(-2),
+        Ok(Ok(Expiry::Persistent)) => reply::integer(-1),
+        Ok(Ok(Expiry::At(deadline))) => {
+            let remaining = deadline.saturating_sub(now_millis());
+            let reply = match cmd {
+                // Rounded to the nearest second, as Redis does
+                "TTL" => (remaining + 500) / 1000,
+                "PTTL" => remaining,
+                "EXPIRETIME" => deadline / 1000,
+                _ => deadline,
+            };
+            reply::integer(reply as i64)
+        }
+        Ok(Err(e)) => reply::error(format!("ERR {} error: {:?}", cmd, e)),
+        Err(e) => reply::error(format!("ERR task error: {:?}", e)),
+    }
+}
+
+// The plain key commands, which only need string reads and writes and so are
+// served the same from any `KvBackend`. Blocks on storage, so runs on a
+// blocking thread.
+fn kv_command<B: KvBackend>(cmd: &str, commands: &[BytesFrame], backend: &B) -> BytesFrame {
+    let keys = || {
+        commands[1..]
+            .iter()
+            .filter_map(|arg| match arg {
+                BytesFrame::BulkString(bytes) => Some(bytes.clone()),
+                _ => None,
+            })
+            .collect::<Vec<_>>()
+    };
+    match cmd {
+        "GET" | "EXISTS" => {
+            if commands.len() != 2 {
+                return reply::error(format!("ERR Wrong number of arguments for {}", cmd));
+            }
+            let key = match &commands[1] {
+                BytesFrame::BulkString(bytes) => bytes,
+                _ => return reply::error("ERR Invalid key type"),
+            };
+            if cmd == "EXISTS" {
+                return match backend.exists(key) {
+                    Ok(exists) => reply::integer(exists),
+                    Err(e) => storage_error(cmd, e),
+                };
+            }
+            match backend.get(key) {
+                Ok(Some(value)) => reply::bulk(value),
+                Ok(None) => reply::nil(),
+                Err(e) => storage_error(cmd, e),
+            }
+        }
+        "DEL" => {
+            if commands.len() < 2 {
+                return reply::error("ERR Wrong number of arguments for DEL");
+            }
+            let mut deleted = 0;
+            for key in keys() {
+                match backend.delete(&key) {
+                    Ok(existed) => deleted += i64::from(existed),
+                    Err(e) => return storage_error(cmd, e),
+                }
+            }
+            // The number of keys that existed, as Redis replies
+            reply::integer(deleted)
+        }
+        "MGET" => {
+            if commands.len() < 2 {
+                return reply::error("ERR Wrong number of arguments for MGET");
+            }
+            let mut results = Vec::with_capacity(commands.len() - 1);
+            for key in keys() {
+                // Keys holding another type read as missing
+                match backend.get(&key) {
+                    Ok(Some(value)) => results.push(reply::bulk(value)),
+                    Ok(None) | Err(DataStoreError::WrongType) => results.push(reply::nil()),
+                    Err(e) => return storage_error(cmd, e),
+                }
+            }
+            reply::array(results)
+        }
+        "MSET" => {
+            if commands.len() < 3 || commands.len().is_multiple_of(2) {
+                return reply::error("ERR Wrong number of arguments for MSET");
+            }
+            let args = keys();
+            if args.len() != commands.len() - 1 {
+                return reply::error("ERR Invalid key type");
+            }
+            let ops: Vec<_> = args
+                .chunks(2)
+                .map(|pair| BatchOp::Set(pair[0].to_vec(), pair[1].to_vec()))
+                .collect();
+            match backend.batch(&ops) {
+                Ok(()) => reply::ok(),
+                Err(e) => storage_error(cmd, e),
+            }
+        }
+        _ => reply::error(format!("{}{}'", UNKNOWN_COMMAND, cmd)),
+    }
+}
+
+// CONFIG GET and CONFIG SET for the parameters the server knows about
+fn config(args: &[BytesFrame], datastore: &DataStore) -> BytesFrame {
+    let args: Option<Vec<String>> = args
+        .iter()
+        .map(|arg| match arg {
+            BytesFrame::BulkString(bytes) => Some(String::from_utf8_lossy(bytes).into_owned()),
+            _ => None,
+        })
+        .collect();
+    let Some(args) = args else {
+        return reply::error("ERR invalid argument type");
+    };
+    let Some(subcommand) = args.first().map(|s| s.to_ascii_uppercase()) else {
+        return reply::error("ERR Wrong number of arguments for CONFIG");
+    };
+    match (subcommand.as_str(), &args[1..]) {
+        ("GET", [pattern]) => reply::map_as_array(
+            CONFIG_PARAMETERS
+                .iter()
+                .filter(|parameter| pattern.eq_ignore_ascii_case(parameter) || pattern == "*")
+                .map(|parameter| {
+                    (
+                        reply::bulk(*parameter),
+                        reply::bulk(config_get(parameter, datastore)),
+                    )
+                }),
+        ),
+        ("SET", [parameter, value]) => {
+            let parameter = parameter.to_ascii_lowercase();
+            let result = match parameter.as_str() {
+                "appendfsync" => value
+                    .parse::<FsyncPolicy>()
+                    .map(|policy| datastore.set_fsync_policy(policy)),
+                "notify-keyspace-events" => value
+                    .parse::<KeyspaceEvents>()
+                    .map(|events| datastore.set_keyspace_events(events)),
+                // Same bounds as Redis
+                "maxmemory-samples" => match value.parse::<usize>() {
+                    Ok(samples @ 1..=64) => {
+                        datastore.set_maxmemory_samples(samples);
+                        Ok(())
+                    }
+                    _ => Err("argument must be between 1 and 64 inclusive".to_string()),
+                },
+                "lcs-max-cells" => value
+                    .parse::<usize>()
+                    .map(|cells| datastore.set_lcs_max_cells(cells))
+                    .map_err(|e| e.to_string()),
+                // Seconds, 0 to disable SET ... REFRESH
+                "refresh-ttl" => value
+                    .parse::<u64>()
+                    .map(|secs| datastore.set_refresh_ttl(Some(Duration::from_secs(secs))))
+                    .map_err(|e| e.to_string()),
+                // Microseconds, 0 to disable group commit
+                "group-commit-window-us" => value
+                    .parse::<u64>()
+                    .map(|micros| {
+                        datastore.set_group_commit(GroupCommit {
+                            window: Duration::from_micros(micros),
+                            ..datastore.group_commit()
+                        })
+                    })
+                    .map_err(|e| e.to_string()),
+                // 1 disables group commit
+                "group-commit-max-writes" => match value.parse::<usize>() {
+                    Ok(max_writes @ 1..) => {
+                        datastore.set_group_commit(GroupCommit {
+                            max_writes,
+                            ..datastore.group_commit()
+                        });
+                        Ok(())
+                    }
+                    _ => Err("argument must be a positive integer".to_string()),
+                },
+                _ => {
+                    return reply::error(format!(
+                        "ERR Unknown option or number of arguments for CONFIG SET - '{}'",
+                        parameter
+                    ))
+                }
+            };
+            match result {
+                Ok(()) => reply::ok(),
+                Err(e) => reply::error(format!(
+                    "ERR CONFIG SET failed (possibly related to argument '{}') - {}",
+                    parameter, e
+                )),
+            }
+        }
+        ("GET", _) | ("SET", _) => reply::error(format!(
+            "ERR Wrong number of arguments for CONFIG {}",
+            subcommand
+        )),
+        _ => reply::error(format!("ERR unknown subcommand '{}'", subcommand)),
+    }
+}
+
+// Parameters served by `config`, in CONFIG GET * order
+const CONFIG_PARAMETERS: &[&str] = &[
+    "appendfsync",
+    "notify-keyspace-events",
+    "maxmemory-samples",
+    "lcs-max-cells",
+    "refresh-ttl",
+    "group-commit-window-us",
+    "group-commit-max-writes",
+];
+
+fn config_get(parameter: &str, datastore: &DataStore) -> String {
+    match parameter {
+        "appendfsync" => datastore.fsync_policy().name().to_string(),
+        "notify-keyspace-events" => datastore.keyspace_events().to_string(),
+        "maxmemory-samples" => datastore.maxmemory_samples().to_string(),
+        "lcs-max-cells" => datastore.lcs_max_cells().to_string(),
+        "refresh-ttl" => datastore
+            .refresh_ttl()
+            .map_or(0, |ttl| ttl.as_secs())
+            .to_string(),
+        "group-commit-window-us" => datastore.group_commit().window.as_micros().to_string(),
+        "group-commit-max-writes" => datastore.group_commit().max_writes.to_string(),
+        _ => unreachable!("unknown parameter {}", parameter),
+    }
+}
+
+// Maps a storage error onto the error reply Redis would send for it
+// Scan totals, then the sizes of every type as field/value pairs under the
+// type's name
+fn big_keys_reply(report: BigKeysReport) -> BytesFrame {
+    let mut fields = vec![
+        (
+            reply::bulk("scanned"),
+            reply::integer(report.scanned as i64),
+        ),
+        (reply::bulk("truncated"), reply::integer(report.truncated)),
+    ];
+    for sizes in report.kinds {
+        fields.push((
+            reply::bulk(sizes.kind.name()),
+            reply::map_as_array([
+                (reply::bulk("keys"), reply::integer(sizes.keys as i64)),
+                (
+                    reply::bulk("total_bytes"),
+                    reply::integer(sizes.total_bytes as i64),
+                ),
+                (reply::bulk("biggest_key"), reply::bulk(sizes.biggest_key)),
+                (
+                    reply::bulk("biggest_bytes"),
+                    reply::integer(sizes.biggest_bytes as i64),
+                ),
+            ]),
+        ));
+    }
+    reply::map_as_array(fields)
+}
+
+// Brings the TTL of an expiry deadline (unix ms) within the data store's
+// `TtlLimits`; None if they reject it. Deadlines already passed delete the
+// key and are left alone.
+fn limit_ttl(datastore: &DataStore, deadline: u64) -> Option<u64> {
+    let now = now_millis();
+    if deadline <= now {
+        return Some(deadline);
+    }
+    datastore
+        .ttl_limits()
+        .apply(deadline - now)
+        .map(|ttl| now.saturating_add(ttl))
+}
+
+fn ttl_out_of_range(cmd: &str) -> BytesFrame {
+    reply::error(format!(
+        "ERR TTL outside the allowed range in '{}' command",
+        cmd
+    ))
+}
+
+fn storage_error(cmd: &str, e: DataStoreError) -> BytesFrame {
+    match e {
+        DataStoreError::WrongType | DataStoreError::BusyKey => reply::error(e.to_string()),
+        DataStoreError::NotAnInteger
+        | DataStoreError::Overflow
+        | DataStoreError::HashValueNotAnInteger
+        | DataStoreError::HashValueNotAFloat
+        | DataStoreError::NanOrInfinity
+        | DataStoreError::InvalidDump
+        | DataStoreError::KeyQuotaExceeded => reply::error(format!("ERR {}", e)),
+        _ => reply::error(format!("ERR {} error: {:?}", cmd, e)),
+    }
+}
+
+fn parse_integer(frame: &BytesFrame) -> Option<i64> {
+    match frame {
+        BytesFrame::BulkString(bytes) | BytesFrame::SimpleString(bytes) => {
+            std::str::from_utf8(bytes).ok()?.parse().ok()
+        }
+        BytesFrame::Integer(i) => Some(*i),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compress::decompress_value;
+    use crate::test_util::{Reply, TestClient};
+    use crate::{TtlLimits, TtlPolicy, ValueKind, RUN_ID_LEN};
+    use tempfile::TempDir;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    fn command(args: &[&[u8]]) -> BytesFrame {
+        BytesFrame::Array(
+            args.iter()
+                .map(|arg| BytesFrame::BulkString(Bytes::copy_from_slice(arg)))
+                .collect(),
+        )
+    }
+
+    fn test_store() -> (TempDir, DataStore, DataStorePartition) {
+        let temp_dir = TempDir::new().expect("Failed to create temp dir");
+        let datastore = DataStore::new(temp_dir.path().to_str().unwrap()).unwrap();
+        let partition = datastore.partition("test").unwrap();
+        (temp_dir, datastore, partition)
+    }
+
+    // Serves `test_store` on an ephemeral local port
+    async fn spawn_server() -> (TempDir, std::net::SocketAddr) {
+        spawn_server_with(|_| {}).await
+    }
+
+    // Like `spawn_server`, with `configure` adjusting the server settings
+    async fn spawn_server_with(
+        configure: impl FnOnce(&mut ServerContext),
+    ) -> (TempDir, std::net::SocketAddr) {
+        let (dir, datastore, _) = test_store();
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut context = ServerContext::new(
+            Databases::open(&datastore, 16).unwrap(),
+            ProtoLimits::new(1024, 16, 4096, DEFAULT_MAX_PIPELINE_DEPTH),
+        );
+        configure(&mut context);
+        tokio::spawn(async move {
+            while let Ok((socket, _)) = listener.accept().await {
+                let datastore = datastore.clone();
+                let context = context.clone();
+                tokio::spawn(async move {
+                    let _ = handle_client(socket, datastore, context).await;
+                });
+            }
+        });
+        (dir, addr)
+    }
+
+    fn blob(data: &'static [u8]) -> Resp3Frame {
+        Resp3Frame::BlobString {
+            data: Bytes::from_static(data),
+            attributes: None,
+        }
+    }
+
+    fn resp3_command(args: &[&'static [u8]]) -> Resp3Frame {
+        Resp3Frame::Array {
+            data: args.iter().map(|arg| blob(arg)).collect(),
+            attributes: None,
+        }
+    }
+
+    fn encode(frame: BytesFrame) -> BytesMut {
+        let mut buf = BytesMut::new();
+        redis_protocol::codec::Resp2
+            .encode(frame, &mut buf)
+            .unwrap();
+        buf
+    }
+
+    #[tokio::test]
+    async fn test_empty_value_is_not_null() {
+        let (_dir, datastore, partition) = test_store();
+
+        let reply = handle_command(command(&[b"SET", b"key", b""]), &datastore, &partition).await;
+        assert_eq!(reply, BytesFrame::SimpleString("OK".into()));
+
+        let reply = handle_command(command(&[b"GET", b"key"]), &datastore, &partition).await;
+        assert_eq!(reply, BytesFrame::BulkString(Bytes::new()));
+        assert_eq!(&encode(reply)[..], b"$0\r\n\r\n");
+
+        let reply = handle_command(command(&[b"GET", b"missing"]), &datastore, &partition).await;
+        assert_eq!(&encode(reply)[..], b"$-1\r\n");
+    }
+
+    #[tokio::test]
+    async fn test_binary_value_round_trip() {
+        let (_dir, datastore, partition) = test_store();
+        let value = b"\x00\r\n";
+
+        handle_command(command(&[b"SET", b"key", value]), &datastore, &partition).await;
+        let reply = handle_command(command(&[b"GET", b"key"]), &datastore, &partition).await;
+        assert_eq!(reply, BytesFrame::BulkString(Bytes::from_static(value)));
+        assert_eq!(&encode(reply)[..], b"$3\r\n\x00\r\n\r\n");
+
+        let reply = handle_command(command(&[b"MGET", b"key"]), &datastore, &partition).await;
+        assert_eq!(
+            reply,
+            BytesFrame::Array(vec![BytesFrame::BulkString(Bytes::from_static(value))])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_commands_in_memory() {
+        let (_dir, datastore, _) = test_store();
+        let backend = crate::MemBackend::new();
+        let run = |args: &'static [&'static [u8]]| {
+            let datastore = datastore.clone();
+            let backend = backend.clone();
+            async move { handle_command(command(args), &datastore, &backend).await }
+        };
+        assert_eq!(run(&[b"MSET", b"a", b"1", b"b", b"2"]).await, reply::ok());
+        assert_eq!(run(&[b"GET", b"a"]).await, reply::bulk("1"));
+        assert_eq!(run(&[b"EXISTS", b"b"]).await, reply::integer(1));
+        assert_eq!(
+            run(&[b"MGET", b"a", b"missing", b"b"]).await,
+            reply::array([reply::bulk("1"), reply::nil(), reply::bulk("2")])
+        );
+        assert_eq!(
+            run(&[b"DEL", b"a", b"b", b"missing"]).await,
+            reply::integer(2)
+        );
+        assert_eq!(run(&[b"GET", b"a"]).await, reply::nil());
+        assert!(matches!(run(&[b"MSET", b"a"]).await, BytesFrame::Error(_)));
+        assert!(matches!(
+            run(&[b"MSET", b"a", b"1", b"b"]).await,
+            BytesFrame::Error(_)
+        ));
+
+        // Expiry, as on a partition
+        assert_eq!(
+            run(&[b"SET", b"a", b"1", b"PX", b"60000"]).await,
+            reply::ok()
+        );
+        assert!(matches!(
+            run(&[b"PTTL", b"a"]).await,
+            BytesFrame::Integer(ttl) if ttl > 0 && ttl <= 60_000
+        ));
+        assert_eq!(run(&[b"TTL", b"b"]).await, reply::integer(-2));
+        assert_eq!(run(&[b"SET", b"b", b"2", b"PX", b"1"]).await, reply::ok());
+        std::thread::sleep(Duration::from_millis(5));
+        assert_eq!(run(&[b"GET", b"b"]).await, reply::nil());
+        assert_eq!(run(&[b"EXISTS", b"b"]).await, reply::integer(0));
+
+        // Keys of other kinds
+        backend.insert_kind(b"list", ValueKind::List);
+        assert_eq!(
+            run(&[b"GET", b"list"]).await,
+            reply::error("WRONGTYPE Operation against a key holding the wrong kind of value")
+        );
+        assert_eq!(
+            run(&[b"MGET", b"list", b"a"]).await,
+            reply::array([reply::nil(), reply::bulk("1")])
+        );
+        assert_eq!(run(&[b"TTL", b"list"]).await, reply::integer(-1));
+        assert_eq!(run(&[b"SET", b"list", b"now a string"]).await, reply::ok());
+        assert_eq!(run(&[b"GET", b"list"]).await, reply::bulk("now a string"));
+
+        // Commands beyond the backend's operations need a partition
+        assert_eq!(
+            run(&[b"RPUSH", b"list", b"item"]).await,
+            reply::error("ERR 'RPUSH' is not supported by this backend")
+        );
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn test_single_thread_set_get() {
+        let (_dir, datastore, partition) = test_store();
+        datastore.set_single_thread(true);
+        for i in 0..100 {
+            let key = format!("key:{}", i);
+            let reply = handle_command(
+                command(&[b"SET", key.as_bytes(), i.to_string().as_bytes()]),
+                &datastore,
+                &partition,
+            )
+            .await;
+            assert_eq!(reply, reply::ok());
+        }
+        for i in 0..100 {
+            let key = format!("key:{}", i);
+            let reply =
+                handle_command(command(&[b"GET", key.as_bytes()]), &datastore, &partition).await;
+            assert_eq!(reply, reply::bulk(i.to_string()));
+        }
+        let reply = handle_command(command(&[b"INCR", b"key:7"]), &datastore, &partition).await;
+        assert_eq!(reply, reply::integer(8));
+    }
+
+    #[tokio::test]
+    async fn test_mget_skips_containers() {
+        let (_dir, datastore, partition) = test_store();
+        handle_command(
+            command(&[b"SET", b"string", b"value"]),
+            &datastore,
+            &partition,
+        )
+        .await;
+        handle_command(
+            command(&[b"RPUSH", b"list", b"item"]),
+            &datastore,
+            &partition,
+        )
+        .await;
+        handle_command(
+            command(&[b"HSET", b"hash", b"f", b"v"]),
+            &datastore,
+            &partition,
+        )
+        .await;
+        let reply = handle_command(
+            command(&[b"MGET", b"string", b"list", b"hash", b"missing"]),
+            &datastore,
+            &partition,
+        )
+        .await;
+        assert_eq!(
+            reply,
+            BytesFrame::Array(vec![
+                BytesFrame::BulkString("value".into()),
+                BytesFrame::Null,
+                BytesFrame::Null,
+                BytesFrame::Null,
+            ])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_lmpop() {
+        let (_dir, datastore, partition) = test_store();
+        handle_command(
+            command(&[b"RPUSH", b"b", b"1", b"2", b"3"]),
+            &datastore,
+            &partition,
+        )
+        .await;
+        handle_command(command(&[b"RPUSH", b"c", b"x"]), &datastore, &partition).await;
+
+        let reply = handle_command(
+            command(&[b"LMPOP", b"3", b"a", b"b", b"c", b"RIGHT", b"COUNT", b"2"]),
+            &datastore,
+            &partition,
+        )
+        .await;
+        assert_eq!(
+            reply,
+            BytesFrame::Array(vec![
+                BytesFrame::BulkString("b".into()),
+                BytesFrame::Array(vec![
+                    BytesFrame::BulkString("3".into()),
+                    BytesFrame::BulkString("2".into()),
+                ]),
+            ])
+        );
+        let reply = handle_command(
+            command(&[b"LMPOP", b"2", b"a", b"c", b"LEFT"]),
+            &datastore,
+            &partition,
+        )
+        .await;
+        assert_eq!(
+            reply,
+            BytesFrame::Array(vec![
+                BytesFrame::BulkString("c".into()),
+                BytesFrame::Array(vec![BytesFrame::BulkString("x".into())]),
+            ])
+        );
+        let reply = handle_command(
+            command(&[b"LMPOP", b"2", b"a", b"c", b"LEFT"]),
+            &datastore,
+            &partition,
+        )
+        .await;
+        assert_eq!(reply, BytesFrame::Null);
+
+        for (args, error) in [
+            (
+                &[&b"LMPOP"[..], b"0", b"a", b"LEFT"][..],
+                "ERR numkeys should be greater than 0",
+            ),
+            (&[b"LMPOP", b"2", b"a", b"LEFT"], "ERR syntax error"),
+            (&[b"LMPOP", b"1", b"a", b"UP"], "ERR syntax error"),
+            (
+                &[b"LMPOP", b"1", b"a", b"LEFT", b"COUNT", b"0"],
+                "ERR count should be greater than 0",
+            ),
+        ] {
+            let reply = handle_command(command(args), &datastore, &partition).await;
+            assert_eq!(reply, BytesFrame::Error(error.into()));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_lpos_replies() {
+        let (_dir, datastore, partition) = test_store();
+        handle_command(
+            command(&[b"RPUSH", b"list", b"a", b"b", b"c", b"b"]),
+            &datastore,
+            &partition,
+        )
+        .await;
+
+        let reply =
+            handle_command(command(&[b"LPOS", b"list", b"b"]), &datastore, &partition).await;
+        assert_eq!(reply, BytesFrame::Integer(1));
+
+        let reply = handle_command(
+            command(&[b"LPOS", b"list", b"b", b"RANK", b"-1"]),
+            &datastore,
+            &partition,
+        )
+        .await;
+        assert_eq!(reply, BytesFrame::Integer(3));
+
+        let reply = handle_command(
+            command(&[b"LPOS", b"list", b"b", b"COUNT", b"0"]),
+            &datastore,
+            &partition,
+        )
+        .await;
+        assert_eq!(
+            reply,
+            BytesFrame::Array(vec![BytesFrame::Integer(1), BytesFrame::Integer(3)])
+        );
+
+        let reply =
+            handle_command(command(&[b"LPOS", b"list", b"z"]), &datastore, &partition).await;
+        assert_eq!(reply, BytesFrame::Null);
+
+        let reply = handle_command(command(&[b"GET", b"list"]), &datastore, &partition).await;
+        assert!(matches!(reply, BytesFrame::Error(e) if e.starts_with("WRONGTYPE")));
+    }
+
+    #[tokio::test]
+    async fn test_smismember_replies() {
+        let (_dir, datastore, partition) = test_store();
+        handle_command(
+            command(&[b"SADD", b"set", b"a", b"c"]),
+            &datastore,
+            &partition,
+        )
+        .await;
+
+        let reply = handle_command(
+            command(&[b"SMISMEMBER", b"set", b"a", b"b", b"c"]),
+            &datastore,
+            &partition,
+        )
+        .await;
+        assert_eq!(
+            reply,
+            BytesFrame::Array(vec![
+                BytesFrame::Integer(1),
+                BytesFrame::Integer(0),
+                BytesFrame::Integer(1)
+            ])
+        );
+
+        let reply = handle_command(
+            command(&[b"SMISMEMBER", b"missing", b"a", b"b"]),
+            &datastore,
+            &partition,
+        )
+        .await;
+        assert_eq!(
+            reply,
+            BytesFrame::Array(vec![BytesFrame::Integer(0), BytesFrame::Integer(0)])
+        );
+
+        handle_command(command(&[b"SET", b"string", b"v"]), &datastore, &partition).await;
+        let reply = handle_command(
+            command(&[b"SMISMEMBER", b"string", b"a"]),
+            &datastore,
+            &partition,
+        )
+        .await;
+        assert!(matches!(reply, BytesFrame::Error(e) if e.starts_with("WRONGTYPE")));
+    }
+
+    #[tokio::test]
+    async fn test_msetnx() {
+        let (_dir, datastore, partition) = test_store();
+        let run =
+            |args: &'static [&'static [u8]]| handle_command(command(args), &datastore, &partition);
+        assert_eq!(
+            run(&[b"MSETNX", b"a", b"1", b"b", b"2"]).await,
+            reply::integer(1)
+        );
+        assert_eq!(
+            run(&[b"MSETNX", b"c", b"3", b"a", b"new"]).await,
+            reply::integer(0)
+        );
+        assert_eq!(
+            run(&[b"MGET", b"a", b"b", b"c"]).await,
+            reply::array([reply::bulk("1"), reply::bulk("2"), reply::nil()])
+        );
+        assert!(matches!(
+            run(&[b"MSETNX", b"a", b"1", b"b"]).await,
+            BytesFrame::Error(_)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_set_algebra_replies() {
+        let (_dir, datastore, partition) = test_store();
+        let run =
+            |args: &'static [&'static [u8]]| handle_command(command(args), &datastore, &partition);
+        run(&[b"SADD", b"a", b"x", b"y", b"z"]).await;
+        run(&[b"SADD", b"b", b"y", b"z", b"w"]).await;
+        let bulks =
+            |members: &[&'static str]| reply::array(members.iter().map(|m| reply::bulk(*m)));
+
+        assert_eq!(
+            run(&[b"SUNION", b"a", b"b"]).await,
+            bulks(&["w", "x", "y", "z"])
+        );
+        assert_eq!(run(&[b"SINTER", b"a", b"b"]).await, bulks(&["y", "z"]));
+        assert_eq!(run(&[b"SDIFF", b"a", b"b"]).await, bulks(&["x"]));
+        assert_eq!(
+            run(&[b"SINTERSTORE", b"dest", b"a", b"b"]).await,
+            reply::integer(2)
+        );
+        assert_eq!(run(&[b"SMEMBERS", b"dest"]).await, bulks(&["y", "z"]));
+
+        assert_eq!(
+            run(&[b"SINTERCARD", b"2", b"a", b"b"]).await,
+            reply::integer(2)
+        );
+        assert_eq!(
+            run(&[b"SINTERCARD", b"2", b"a", b"b", b"LIMIT", b"1"]).await,
+            reply::integer(1)
+        );
+        assert_eq!(
+            run(&[b"SINTERCARD", b"2", b"a", b"b", b"limit", b"0"]).await,
+            reply::integer(2)
+        );
+        for args in [
+            &[&b"SINTERCARD"[..], b"0", b"a"][..],
+            &[b"SINTERCARD", b"3", b"a", b"b"],
+            &[b"SINTERCARD", b"1", b"a", b"LIMIT", b"-1"],
+            &[b"SINTERCARD", b"1", b"a", b"LIMIT"],
+            &[b"SINTERCARD", b"1", b"a", b"b"],
+            &[b"SUNIONSTORE", b"dest"],
+        ] {
+            assert!(matches!(
+                handle_command(command(args), &datastore, &partition).await,
+                BytesFrame::Error(_)
+            ));
+        }
+
+        run(&[b"SET", b"string", b"value"]).await;
+        assert_eq!(
+            run(&[b"SINTER", b"missing", b"string"]).await,
+            reply::error(DataStoreError::WrongType.to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_hmset_hmget_replies() {
+        let (_dir, datastore, partition) = test_store();
+        let reply = handle_command(
+            command(&[b"HMSET", b"hash", b"a", b"1", b"c", b"3"]),
+            &datastore,
+            &partition,
+        )
+        .await;
+        assert_eq!(reply, BytesFrame::SimpleString("OK".into()));
+
+        let reply = handle_command(
+            command(&[b"HMSET", b"hash", b"a", b"1", b"c"]),
+            &datastore,
+            &partition,
+        )
+        .await;
+        assert!(matches!(reply, BytesFrame::Error(_)));
+
+        let reply = handle_command(
+            command(&[b"HMGET", b"hash", b"a", b"b", b"c"]),
+            &datastore,
+            &partition,
+        )
+        .await;
+        assert_eq!(
+            reply,
+            BytesFrame::Array(vec![
+                BytesFrame::BulkString(Bytes::from_static(b"1")),
+                BytesFrame::Null,
+                BytesFrame::BulkString(Bytes::from_static(b"3"))
+            ])
+        );
+
+        let reply = handle_command(
+            command(&[b"HDEL", b"hash", b"a", b"b", b"c"]),
+            &datastore,
+            &partition,
+        )
+        .await;
+        assert_eq!(reply, BytesFrame::Integer(2));
+        let reply = handle_command(command(&[b"EXISTS", b"hash"]), &datastore, &partition).await;
+        assert_eq!(reply, BytesFrame::Integer(0));
+    }
+
+    #[test]
+    fn test_classify_accept_errors() {
+        for kind in [
+            io::ErrorKind::ConnectionAborted,
+            io::ErrorKind::ConnectionReset,
+            io::ErrorKind::Interrupted,
+        ] {
+            assert_eq!(
+                classify_accept_error(&io::Error::from(kind)),
+                AcceptErrorAction::Retry
+            );
+        }
+        #[cfg(unix)]
+        assert_eq!(
+            classify_accept_error(&io::Error::from_raw_os_error(24)),
+            AcceptErrorAction::Retry
+        );
+        assert_eq!(
+            classify_accept_error(&io::Error::from(io::ErrorKind::InvalidInput)),
+            AcceptErrorAction::Fatal
+        );
+    }
+
+    #[tokio::test]
+    async fn test_accept_survives_transient_errors() {
+        // Two transient failures, then a connection
+        let mut attempts = vec![
+            Ok(7),
+            Err(io::Error::from(io::ErrorKind::ConnectionAborted)),
+            Err(io::Error::from(io::ErrorKind::Interrupted)),
+        ];
+        let conn = accept_with_retry(
+            || std::future::ready(attempts.pop().unwrap()),
+            Duration::from_millis(1),
+        )
+        .await;
+        assert_eq!(conn.unwrap(), 7);
+        assert!(attempts.is_empty());
+
+        let mut attempts = vec![Ok(7), Err(io::Error::from(io::ErrorKind::InvalidInput))];
+        let result = accept_with_retry(
+            || std::future::ready(attempts.pop().unwrap()),
+            Duration::from_millis(1),
+        )
+        .await;
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[tokio::test]
+    async fn test_debug_reload_keeps_keys() {
+        let (_dir, datastore, partition) = test_store();
+        let entries: [(&[u8], &[u8]); 3] = [
+            (b"plain", b"value"),
+            (b"empty", b""),
+            (b"binary", b"\x00\r\n\xff"),
+        ];
+        for (key, value) in entries {
+            handle_command(command(&[b"SET", key, value]), &datastore, &partition).await;
+        }
+
+        let reply = handle_command(command(&[b"DEBUG", b"RELOAD"]), &datastore, &partition).await;
+        assert_eq!(reply, BytesFrame::SimpleString("OK".into()));
+
+        for (key, value) in entries {
+            let reply = handle_command(command(&[b"GET", key]), &datastore, &partition).await;
+            assert_eq!(reply, BytesFrame::BulkString(Bytes::copy_from_slice(value)));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_debug_error_and_no_ops() {
+        let (_dir, datastore, partition) = test_store();
+        let reply = handle_command(
+            command(&[b"DEBUG", b"ERROR", b"my error"]),
+            &datastore,
+            &partition,
+        )
+        .await;
+        assert_eq!(reply, BytesFrame::Error("my error".into()));
+
+        for args in [
+            &[&b"DEBUG"[..], b"JMAP"][..],
+            &[b"DEBUG", b"set-active-expire", b"0"],
+        ] {
+            let reply = handle_command(command(args), &datastore, &partition).await;
+            assert_eq!(reply, BytesFrame::SimpleString("OK".into()));
+        }
+        let reply = handle_command(command(&[b"DEBUG", b"ERROR"]), &datastore, &partition).await;
+        assert_eq!(
+            reply,
+            BytesFrame::Error("ERR Wrong number of arguments for DEBUG ERROR".into())
+        );
+        let reply = handle_command(command(&[b"DEBUG", b"SEGFAULT"]), &datastore, &partition).await;
+        assert_eq!(
+            reply,
+            BytesFrame::Error("ERR unknown subcommand 'SEGFAULT'".into())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_debug_change_repl_id() {
+        let (_dir, datastore, partition) = test_store();
+        let info = |section: &'static [u8]| {
+            let datastore = datastore.clone();
+            let partition = partition.clone();
+            async move {
+                match handle_command(command(&[b"INFO", section]), &datastore, &partition).await {
+                    BytesFrame::BulkString(bytes) => String::from_utf8(bytes.to_vec()).unwrap(),
+                    other => panic!("unexpected reply {:?}", other),
+                }
+            }
+        };
+        let pinned = "0123456789abcdef0123456789abcdef01234567";
+        datastore.set_run_id(pinned);
+        assert!(info(b"server")
+            .await
+            .contains(&format!("\r\nrun_id:{}\r\n", pinned)));
+        assert!(info(b"replication")
+            .await
+            .contains(&format!("\r\nmaster_replid:{}\r\n", pinned)));
+
+        let reply = handle_command(
+            command(&[b"DEBUG", b"CHANGE-REPL-ID"]),
+            &datastore,
+            &partition,
+        )
+        .await;
+        assert_eq!(reply, BytesFrame::SimpleString("OK".into()));
+        let run_id = datastore.run_id();
+        assert_ne!(run_id, pinned);
+        assert_eq!(run_id.len(), RUN_ID_LEN);
+        assert!(info(b"server")
+            .await
+            .contains(&format!("\r\nrun_id:{}\r\n", run_id)));
+    }
+
+    #[tokio::test]
+    async fn test_debug_verify() {
+        let (_dir, datastore, partition) = test_store();
+        handle_command(command(&[b"SET", b"key", b"value"]), &datastore, &partition).await;
+        let reply = handle_command(command(&[b"DEBUG", b"VERIFY"]), &datastore, &partition).await;
+        assert_eq!(
+            reply,
+            BytesFrame::Array(vec![
+                BytesFrame::BulkString("entries".into()),
+                BytesFrame::Integer(1),
+                BytesFrame::BulkString("bytes".into()),
+                BytesFrame::Integer(3 + 19 + 5),
+                BytesFrame::BulkString("corrupted".into()),
+                BytesFrame::Array(vec![]),
+            ])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_debug_object() {
+        let (_dir, datastore, partition) = test_store();
+        handle_command(command(&[b"SET", b"key", b"12345"]), &datastore, &partition).await;
+        let reply = handle_command(
+            command(&[b"DEBUG", b"OBJECT", b"key"]),
+            &datastore,
+            &partition,
+        )
+        .await;
+        let BytesFrame::SimpleString(line) = reply else {
+            panic!("unexpected reply {:?}", reply);
+        };
+        let line = String::from_utf8(line.to_vec()).unwrap();
+        assert!(line.contains(" encoding:int "));
+        let dump_len = partition.dump(b"key").unwrap().unwrap().len();
+        assert!(line.contains(&format!(" serializedlength:{} ", dump_len)));
+
+        let reply = handle_command(
+            command(&[b"DEBUG", b"OBJECT", b"missing"]),
+            &datastore,
+            &partition,
+        )
+        .await;
+        assert_eq!(reply, BytesFrame::Error("ERR no such key".into()));
+    }
+
+    #[tokio::test]
+    async fn test_replicaof_sets_info_role() {
+        let (_dir, datastore, partition) = test_store();
+        let role = || async {
+            let reply =
+                handle_command(command(&[b"INFO", b"replication"]), &datastore, &partition).await;
+            let BytesFrame::BulkString(info) = reply else {
+                panic!("expected a bulk string");
+            };
+            String::from_utf8(info.to_vec()).unwrap()
+        };
+        assert!(role().await.contains("role:master\r\n"));
+
+        let reply = handle_command(
+            command(&[b"REPLICAOF", b"10.0.0.1", b"6380"]),
+            &datastore,
+            &partition,
+        )
+        .await;
+        assert_eq!(reply, BytesFrame::SimpleString("OK".into()));
+        let info = role().await;
+        assert!(info.contains("role:slave\r\n"));
+        assert!(info.contains("master_host:10.0.0.1\r\nmaster_port:6380\r\n"));
+
+        handle_command(
+            command(&[b"SLAVEOF", b"no", b"one"]),
+            &datastore,
+            &partition,
+        )
+        .await;
+        assert!(role().await.contains("role:master\r\n"));
+
+        let reply = handle_command(
+            command(&[b"REPLICAOF", b"host", b"70000"]),
+            &datastore,
+            &partition,
+        )
+        .await;
+        assert_eq!(reply, BytesFrame::Error("ERR Invalid master port".into()));
+        let reply = handle_command(command(&[b"PSYNC", b"?", b"-1"]), &datastore, &partition).await;
+        assert_eq!(
+            reply,
+            BytesFrame::Error("ERR replication is not supported".into())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_lcs() {
+        let (_dir, datastore, partition) = test_store();
+        let run =
+            |args: &'static [&'static [u8]]| handle_command(command(args), &datastore, &partition);
+        run(&[b"SET", b"key1", b"ohmytext"]).await;
+        run(&[b"SET", b"key2", b"mynewtext"]).await;
+        assert_eq!(
+            run(&[b"LCS", b"key1", b"key2"]).await,
+            reply::bulk("mytext")
+        );
+        assert_eq!(
+            run(&[b"LCS", b"key1", b"key2", b"LEN"]).await,
+            reply::integer(6)
+        );
+        assert_eq!(
+            run(&[
+                b"LCS",
+                b"key1",
+                b"key2",
+                b"IDX",
+                b"MINMATCHLEN",
+                b"4",
+                b"WITHMATCHLEN"
+            ])
+            .await,
+            reply::map_as_array([
+                (
+                    reply::bulk("matches"),
+                    reply::array([reply::array([
+                        reply::array([reply::integer(4), reply::integer(7)]),
+                        reply::array([reply::integer(5), reply::integer(8)]),
+                        reply::integer(4),
+                    ])]),
+                ),
+                (reply::bulk("len"), reply::integer(6)),
+            ])
+        );
+        assert_eq!(run(&[b"LCS", b"key1", b"missing"]).await, reply::bulk(""));
+
+        run(&[b"RPUSH", b"list", b"item"]).await;
+        assert!(matches!(
+            run(&[b"LCS", b"key1", b"list"]).await,
+            BytesFrame::Error(e) if e.starts_with("WRONGTYPE")
+        ));
+        run(&[b"CONFIG", b"SET", b"lcs-max-cells", b"50"]).await;
+        assert!(matches!(
+            run(&[b"LCS", b"key1", b"key2"]).await,
+            BytesFrame::Error(e) if e.contains("lcs-max-cells")
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_failover_and_wait() {
+        let (_dir, datastore, partition) = test_store();
+        let reply = handle_command(command(&[b"FAILOVER"]), &datastore, &partition).await;
+        assert_eq!(
+            reply,
+            BytesFrame::Error("ERR FAILOVER requires connected replicas.".into())
+        );
+        let reply = handle_command(command(&[b"FAILOVER", b"abort"]), &datastore, &partition).await;
+        assert_eq!(
+            reply,
+            BytesFrame::Error("ERR No failover in progress.".into())
+        );
+        let reply = handle_command(command(&[b"WAIT", b"1", b"100"]), &datastore, &partition).await;
+        assert_eq!(reply, BytesFrame::Integer(0));
+
+        let reply = handle_command(command(&[b"INFO"]), &datastore, &partition).await;
+        let BytesFrame::BulkString(info) = reply else {
+            panic!("expected a bulk string");
+        };
+        let info = String::from_utf8(info.to_vec()).unwrap();
+        assert!(info.contains("role:master\r\n"));
+        assert!(info.contains("loading:0\r\n"));
+    }
+
+    #[tokio::test]
+    async fn test_debug_bigkeys() {
+        let (_dir, datastore, partition) = test_store();
+        handle_command(command(&[b"SET", b"a", b"1"]), &datastore, &partition).await;
+        handle_command(
+            command(&[b"SET", b"b", &[b'x'; 1000]]),
+            &datastore,
+            &partition,
+        )
+        .await;
+        let reply = handle_command(
+            command(&[b"DEBUG", b"BIGKEYS", b"COUNT", b"10"]),
+            &datastore,
+            &partition,
+        )
+        .await;
+        let BytesFrame::Array(fields) = reply else {
+            panic!("expected an array");
+        };
+        assert_eq!(fields[1], BytesFrame::Integer(2));
+        assert_eq!(fields[4], BytesFrame::BulkString("string".into()));
+        let BytesFrame::Array(sizes) = &fields[5] else {
+            panic!("expected the string sizes");
+        };
+        assert_eq!(sizes[5], BytesFrame::BulkString("b".into()));
+
+        let reply = handle_command(
+            command(&[b"DEBUG", b"BIGKEYS", b"COUNT"]),
+            &datastore,
+            &partition,
+        )
+        .await;
+        assert_eq!(reply, BytesFrame::Error("ERR syntax error".into()));
+    }
+
+    #[tokio::test]
+    async fn test_info_keyspace() {
+        let (_dir, datastore, partition) = test_store();
+        let info = |section: &'static [u8]| {
+            let datastore = datastore.clone();
+            let partition = partition.clone();
+            async move {
+                match handle_command(command(&[b"INFO", section]), &datastore, &partition).await {
+                    BytesFrame::BulkString(bytes) => String::from_utf8(bytes.to_vec()).unwrap(),
+                    other => panic!("unexpected reply {:?}", other),
+                }
+            }
+        };
+        assert_eq!(info(b"keyspace").await, "# Keyspace\r\n");
+
+        for key in [&b"a"[..], b"b", b"c"] {
+            handle_command(command(&[b"SET", key, b"value"]), &datastore, &partition).await;
+        }
+        handle_command(
+            command(&[b"SADD", b"set", b"member"]),
+            &datastore,
+            &partition,
+        )
+        .await;
+        handle_command(command(&[b"EXPIRE", b"a", b"100"]), &datastore, &partition).await;
+        handle_command(
+            command(&[b"EXPIRE", b"set", b"100"]),
+            &datastore,
+            &partition,
+        )
+        .await;
+
+        assert_eq!(
+            info(b"keyspace").await,
+            "# Keyspace\r\ndb0:keys=4,expires=2,avg_ttl=0\r\n"
+        );
+        assert!(!info(b"server").await.contains("# Keyspace"));
+    }
+
+    #[tokio::test]
+    async fn test_info_stats() {
+        let (_dir, datastore, partition) = test_store();
+        handle_command(command(&[b"SET", b"key", b"value"]), &datastore, &partition).await;
+        for key in [&b"key"[..], b"key", b"key", b"missing"] {
+            handle_command(command(&[b"GET", key]), &datastore, &partition).await;
+        }
+        let reply = handle_command(command(&[b"INFO", b"stats"]), &datastore, &partition).await;
+        let BytesFrame::BulkString(info) = reply else {
+            panic!("unexpected reply {:?}", reply);
+        };
+        let info = String::from_utf8(info.to_vec()).unwrap();
+        assert!(info.contains("keyspace_hits:3\r\n"));
+        assert!(info.contains("keyspace_misses:1\r\n"));
+        assert!(info.contains("keyspace_hit_rate:0.7500\r\n"));
+        assert!(info.contains("total_group_commits:0\r\n"));
+    }
+
+    #[tokio::test]
+    async fn test_keyspace_notifications() {
+        let (_dir, addr) = spawn_server().await;
+
+        let socket = TcpStream::connect(addr).await.unwrap();
+        let mut client = Framed::new(socket, Resp2);
+        client
+            .send(command(&[
+                b"CONFIG",
+                b"SET",
+                b"notify-keyspace-events",
+                b"KA",
+            ]))
+            .await
+            .unwrap();
+        client.next().await.unwrap().unwrap();
+        client
+            .send(command(&[b"CONFIG", b"GET", b"notify-keyspace-events"]))
+            .await
+            .unwrap();
+        assert_eq!(
+            client.next().await.unwrap().unwrap(),
+            BytesFrame::Array(vec![
+                BytesFrame::BulkString("notify-keyspace-events".into()),
+                BytesFrame::BulkString("AK".into()),
+            ])
+        );
+        client
+            .send(command(&[b"SUBSCRIBE", b"__keyspace@0__:key"]))
+            .await
+            .unwrap();
+        client.next().await.unwrap().unwrap();
+
+        let socket = TcpStream::connect(addr).await.unwrap();
+        let mut writer = Framed::new(socket, Resp2);
+        for args in [&[&b"SET"[..], b"key", b"value"][..], &[b"DEL", b"key"]] {
+            writer.send(command(args)).await.unwrap();
+            writer.next().await.unwrap().unwrap();
+        }
+        for event in ["set", "del"] {
+            assert_eq!(
+                client.next().await.unwrap().unwrap(),
+                BytesFrame::Array(vec![
+                    BytesFrame::BulkString("message".into()),
+                    BytesFrame::BulkString("__keyspace@0__:key".into()),
+                    BytesFrame::BulkString(event.into()),
+                ])
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_blpop_unblocks_on_push() {
+        let (_dir, addr) = spawn_server().await;
+
+        let socket = TcpStream::connect(addr).await.unwrap();
+        let mut blocked = Framed::new(socket, Resp2);
+        blocked
+            .send(command(&[b"BLPOP", b"empty", b"queue", b"0"]))
+            .await
+            .unwrap();
+        // Give the command time to start waiting
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        let socket = TcpStream::connect(addr).await.unwrap();
+        let mut pusher = Framed::new(socket, Resp2);
+        pusher
+            .send(command(&[b"LPUSH", b"queue", b"job"]))
+            .await
+            .unwrap();
+        assert_eq!(
+            pusher.next().await.unwrap().unwrap(),
+            BytesFrame::Integer(1)
+        );
+
+        let reply = tokio::time::timeout(Duration::from_secs(5), blocked.next())
+            .await
+            .expect("BLPOP did not unblock")
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            reply,
+            BytesFrame::Array(vec![
+                BytesFrame::BulkString("queue".into()),
+                BytesFrame::BulkString("job".into()),
+            ])
+        );
+        // The item was consumed
+        pusher.send(command(&[b"LLEN", b"queue"])).await.unwrap();
+        assert_eq!(
+            pusher.next().await.unwrap().unwrap(),
+            BytesFrame::Integer(0)
+        );
+
+        blocked
+            .send(command(&[b"BRPOP", b"queue", b"0.05"]))
+            .await
+            .unwrap();
+        assert_eq!(blocked.next().await.unwrap().unwrap(), BytesFrame::Null);
+    }
+
+    #[tokio::test]
+    async fn test_mget_after_expiry() {
+        let (_dir, datastore, partition) = test_store();
+        for key in [&b"a"[..], b"b"] {
+            handle_command(
+                command(&[b"SET", key, b"value", b"PX", b"50"]),
+                &datastore,
+                &partition,
+            )
+            .await;
+        }
+        handle_command(command(&[b"SET", b"c", b"value"]), &datastore, &partition).await;
+        assert_eq!(partition.verify().unwrap().entries, 3);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let reply = handle_command(
+            command(&[b"MGET", b"a", b"b", b"c"]),
+            &datastore,
+            &partition,
+        )
+        .await;
+        assert_eq!(
+            reply,
+            BytesFrame::Array(vec![
+                BytesFrame::Null,
+                BytesFrame::Null,
+                BytesFrame::BulkString("value".into()),
+            ])
+        );
+        // Reading the lapsed keys removed them, as GET does
+        assert_eq!(partition.verify().unwrap().entries, 1);
+    }
+
+    #[tokio::test]
+    async fn test_set_with_expiry() {
+        let (_dir, datastore, _) = test_store();
+        let partition = datastore
+            .partition_builder("cache")
+            .default_ttl(Duration::from_secs(3600))
+            .open()
+            .unwrap();
+        let reply = handle_command(
+            command(&[b"SET", b"key", b"value", b"PX", b"60000"]),
+            &datastore,
+            &partition,
+        )
+        .await;
+        assert_eq!(reply, BytesFrame::SimpleString("OK".into()));
+        let reply = handle_command(command(&[b"PTTL", b"key"]), &datastore, &partition).await;
+        assert!(matches!(reply, BytesFrame::Integer(ttl) if ttl > 0 && ttl <= 60_000));
+
+        // The default TTL applies without EX or PX
+        handle_command(command(&[b"SET", b"key", b"value"]), &datastore, &partition).await;
+        let reply = handle_command(command(&[b"TTL", b"key"]), &datastore, &partition).await;
+        assert!(matches!(reply, BytesFrame::Integer(ttl) if ttl > 60));
+
+        let reply = handle_command(
+            command(&[b"SET", b"key", b"value", b"EX", b"0"]),
+            &datastore,
+            &partition,
+        )
+        .await;
+        assert_eq!(
+            reply,
+            BytesFrame::Error("ERR invalid expire time in 'set' command".into())
+        );
+        let reply = handle_command(
+            command(&[b"SET", b"key", b"value", b"KEEP", b"1"]),
+            &datastore,
+            &partition,
+        )
+        .await;
+        assert_eq!(reply, BytesFrame::Error("ERR syntax error".into()));
+    }
+
+    #[tokio::test]
+    async fn test_set_sync() {
+        let (_dir, datastore, partition) = test_store();
+        datastore.set_fsync_policy(FsyncPolicy::No);
+        let reply = handle_command(
+            command(&[b"SET", b"key", b"value", b"SYNC", b"EX", b"60"]),
+            &datastore,
+            &partition,
+        )
+        .await;
+        assert_eq!(reply, BytesFrame::SimpleString("OK".into()));
+        assert_eq!(partition.get(b"key").unwrap(), Some(b"value".to_vec()));
+        assert!(matches!(
+            partition.expire_time(b"key").unwrap(),
+            Expiry::At(_)
+        ));
+
+        for options in [
+            &[&b"SYNC"[..], b"SYNC"][..],
+            &[b"EX"],
+            &[b"EX", b"1", b"PX", b"1"],
+        ] {
+            let mut args: Vec<&[u8]> = vec![b"SET", b"key", b"value"];
+            args.extend_from_slice(options);
+            let reply = handle_command(command(&args), &datastore, &partition).await;
+            assert_eq!(reply, BytesFrame::Error("ERR syntax error".into()));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_set_refresh() {
+        let (_dir, datastore, partition) = test_store();
+        let set = || {
+            handle_command(
+                command(&[b"SET", b"session", b"v", b"REFRESH"]),
+                &datastore,
+                &partition,
+            )
+        };
+        assert_eq!(
+            set().await,
+            BytesFrame::Error("ERR SET REFRESH needs refresh-ttl to be set".into())
+        );
+
+        datastore.set_refresh_ttl(Some(Duration::from_secs(1)));
+        // Each write pushes the deadline along, so the key outlives the
+        // first write's second for as long as it keeps being written
+        for _ in 0..3 {
+            assert_eq!(set().await, BytesFrame::SimpleString("OK".into()));
+            tokio::time::sleep(Duration::from_millis(600)).await;
+        }
+        assert_eq!(partition.get(b"session").unwrap(), Some(b"v".to_vec()));
+        tokio::time::sleep(Duration::from_millis(600)).await;
+        assert_eq!(partition.get(b"session").unwrap(), None);
+
+        for options in [
+            &[&b"REFRESH"[..], b"EX", b"1"][..],
+            &[b"PX", b"1", b"REFRESH"],
+        ] {
+            let mut args: Vec<&[u8]> = vec![b"SET", b"session", b"v"];
+            args.extend_from_slice(options);
+            let reply = handle_command(command(&args), &datastore, &partition).await;
+            assert_eq!(reply, BytesFrame::Error("ERR syntax error".into()));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_ttl_limits() {
+        let (_dir, datastore, partition) = test_store();
+        datastore.set_ttl_limits(TtlLimits {
+            min: Some(Duration::from_secs(10)),
+            max: Some(Duration::from_secs(60)),
+            policy: TtlPolicy::Clamp,
+        });
+        handle_command(
+            command(&[b"SET", b"a", b"1", b"EX", b"3600"]),
+            &datastore,
+            &partition,
+        )
+        .await;
+        handle_command(command(&[b"SET", b"b", b"1"]), &datastore, &partition).await;
+        handle_command(command(&[b"EXPIRE", b"b", b"1"]), &datastore, &partition).await;
+        let ttl = |key: &'static [u8]| {
+            let (datastore, partition) = (&datastore, &partition);
+            async move {
+                match handle_command(command(&[b"TTL", key]), datastore, partition).await {
+                    BytesFrame::Integer(ttl) => ttl,
+                    other => panic!("unexpected reply {:?}", other),
+                }
+            }
+        };
+        assert_eq!(ttl(b"a").await, 60);
+        assert_eq!(ttl(b"b").await, 10);
+
+        datastore.set_ttl_limits(TtlLimits {
+            policy: TtlPolicy::Reject,
+            ..datastore.ttl_limits()
+        });
+        let reply =
+            handle_command(command(&[b"PEXPIRE", b"a", b"500"]), &datastore, &partition).await;
+        assert_eq!(
+            reply,
+            BytesFrame::Error("ERR TTL outside the allowed range in 'pexpire' command".into())
+        );
+        assert_eq!(ttl(b"a").await, 60);
+        let reply = handle_command(
+            command(&[b"SET", b"c", b"1", b"EX", b"61"]),
+            &datastore,
+            &partition,
+        )
+        .await;
+        assert!(matches!(reply, BytesFrame::Error(_)));
+        assert!(!partition.exists(b"c").unwrap());
+
+        // Deleting through a past deadline is not a TTL
+        let reply =
+            handle_command(command(&[b"EXPIRE", b"a", b"-1"]), &datastore, &partition).await;
+        assert_eq!(reply, BytesFrame::Integer(1));
+        assert!(!partition.exists(b"a").unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_backup() {
+        let (_dir, datastore, partition) = test_store();
+        partition.set(b"key", b"value").unwrap();
+        let backup_dir = TempDir::new().unwrap();
+        let path = backup_dir.path().join("backup");
+        let backup = command(&[b"BACKUP", path.to_str().unwrap().as_bytes()]);
+        let reply = handle_command(backup.clone(), &datastore, &partition).await;
+        assert_eq!(reply, BytesFrame::SimpleString("OK".into()));
+        // An existing backup is never overwritten
+        let reply = handle_command(backup, &datastore, &partition).await;
+        assert!(matches!(reply, BytesFrame::Error(_)));
+
+        let restored = DataStore::new(path.to_str().unwrap()).unwrap();
+        let partition = restored.partition(partition.name()).unwrap();
+        assert_eq!(partition.get(b"key").unwrap(), Some(b"value".to_vec()));
+    }
+
+    #[tokio::test]
+    async fn test_del_replies_integer_count() {
+        let (_dir, datastore, partition) = test_store();
+        partition.set(b"a", b"1").unwrap();
+        partition.set(b"b", b"2").unwrap();
+        // Missing keys don't count
+        let reply = handle_command(
+            command(&[b"DEL", b"a", b"b", b"missing"]),
+            &datastore,
+            &partition,
+        )
+        .await;
+        assert_eq!(reply, BytesFrame::Integer(2));
+        let reply = handle_command(command(&[b"DEL", b"a"]), &datastore, &partition).await;
+        assert_eq!(reply, BytesFrame::Integer(0));
+    }
+
+    #[tokio::test]
+    async fn test_disconnect_mid_command() {
+        let (_dir, datastore, _) = test_store();
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (socket, _) = listener.accept().await.unwrap();
+        let context = ServerContext::new(
+            Databases::open(&datastore, 1).unwrap(),
+            ProtoLimits::default(),
+        );
+        let server = tokio::spawn(handle_client(socket, datastore, context));
+
+        // Hang up abruptly while BLPOP blocks, so its reply hits a reset
+        // connection
+        let mut client = Framed::new(client, Resp2);
+        client
+            .send(command(&[b"BLPOP", b"list", b"0.1"]))
+            .await
+            .unwrap();
+        let client = client.into_inner();
+        client.set_linger(Some(Duration::ZERO)).unwrap();
+        drop(client);
+
+        let result = server.await.unwrap();
+        assert!(result.is_ok(), "{:?}", result);
+    }
+
+    #[tokio::test]
+    async fn test_swap() {
+        let (_dir, datastore, partition) = test_store();
+        partition.set(b"live", b"blue").unwrap();
+        partition.set(b"staging", b"green").unwrap();
+        let reply = handle_command(
+            command(&[b"SWAP", b"live", b"staging"]),
+            &datastore,
+            &partition,
+        )
+        .await;
+        assert_eq!(reply, BytesFrame::SimpleString("OK".into()));
+        assert_eq!(partition.get(b"live").unwrap(), Some(b"green".to_vec()));
+        assert_eq!(partition.get(b"staging").unwrap(), Some(b"blue".to_vec()));
+
+        let reply = handle_command(command(&[b"SWAP", b"live"]), &datastore, &partition).await;
+        assert!(matches!(reply, BytesFrame::Error(_)));
+    }
+
+    #[tokio::test]
+    async fn test_dump_restore() {
+        let (_dir, datastore, partition) = test_store();
+        handle_command(
+            command(&[b"RPUSH", b"list", b"a", b"b"]),
+            &datastore,
+            &partition,
+        )
+        .await;
+        let dump = match handle_command(command(&[b"DUMP", b"list"]), &datastore, &partition).await
+        {
+            BytesFrame::BulkString(dump) => dump,
+            other => panic!("unexpected reply {:?}", other),
+        };
+        let reply = handle_command(command(&[b"DUMP", b"missing"]), &datastore, &partition).await;
+        assert_eq!(reply, BytesFrame::Null);
+
+        let reply = handle_command(
+            command(&[b"RESTORE", b"copy", b"60000", &dump]),
+            &datastore,
+            &partition,
+        )
+        .await;
+        assert_eq!(reply, BytesFrame::SimpleString("OK".into()));
+        let reply = handle_command(
+            command(&[b"LRANGE", b"copy", b"0", b"-1"]),
+            &datastore,
+            &partition,
+        )
+        .await;
+        assert_eq!(
+            reply,
+            BytesFrame::Array(vec![
+                BytesFrame::BulkString("a".into()),
+                BytesFrame::BulkString("b".into()),
+            ])
+        );
+        assert!(matches!(
+            partition.expire_time(b"copy").unwrap(),
+            Expiry::At(deadline) if deadline > now_millis()
+        ));
+
+        let reply = handle_command(
+            command(&[b"RESTORE", b"list", b"0", &dump]),
+            &datastore,
+            &partition,
+        )
+        .await;
+        assert_eq!(
+            reply,
+            BytesFrame::Error("BUSYKEY Target key name already exists.".into())
+        );
+        let reply = handle_command(
+            command(&[b"RESTORE", b"list", b"0", &dump, b"REPLACE"]),
+            &datastore,
+            &partition,
+        )
+        .await;
+        assert_eq!(reply, BytesFrame::SimpleString("OK".into()));
+        let reply = handle_command(
+            command(&[b"RESTORE", b"other", b"0", b"garbage"]),
+            &datastore,
+            &partition,
+        )
+        .await;
+        assert_eq!(
+            reply,
+            BytesFrame::Error("ERR DUMP payload version or checksum are wrong".into())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_dbsize() {
+        let (_dir, datastore, partition) = test_store();
+        for key in [&b"session:1"[..], b"session:2", b"user:1"] {
+            partition.set(key, b"value").unwrap();
+        }
+        let run =
+            |args: &'static [&'static [u8]]| handle_command(command(args), &datastore, &partition);
+        assert_eq!(run(&[b"DBSIZE"]).await, reply::integer(3));
+        assert_eq!(run(&[b"DBSIZE", b"session:"]).await, reply::integer(2));
+        assert_eq!(run(&[b"DBSIZE", b"cart:"]).await, reply::integer(0));
+        assert!(matches!(
+            run(&[b"DBSIZE", b"a", b"b"]).await,
+            BytesFrame::Error(_)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_keys_and_scan() {
+        let (_dir, datastore, partition) = test_store();
+        let run =
+            |args: &'static [&'static [u8]]| handle_command(command(args), &datastore, &partition);
+        for key in [&b"user:1"[..], b"user:2", b"user:3", b"session:1"] {
+            partition.set(key, b"value").unwrap();
+        }
+        assert_eq!(
+            run(&[b"KEYS", b"user:?"]).await,
+            reply::array([
+                reply::bulk("user:1"),
+                reply::bulk("user:2"),
+                reply::bulk("user:3")
+            ])
+        );
+
+        let mut keys = Vec::new();
+        let mut cursor = Bytes::from("0");
+        loop {
+            let BytesFrame::Array(page) = handle_command(
+                reply::array([
+                    reply::bulk("SCAN"),
+                    reply::bulk(cursor),
+                    reply::bulk("COUNT"),
+                    reply::bulk("3"),
+                ]),
+                &datastore,
+                &partition,
+            )
+            .await
+            else {
+                panic!("expected an array");
+            };
+            let [BytesFrame::BulkString(next), BytesFrame::Array(batch)] = &page[..] else {
+                panic!("unexpected SCAN reply {:?}", page);
+            };
+            keys.extend(batch.iter().cloned());
+            if next.as_ref() == b"0" {
+                break;
+            }
+            cursor = next.clone();
+        }
+        assert_eq!(keys.len(), 4);
+
+        assert_eq!(
+            run(&[b"SCAN", b"0", b"MATCH", b"session:*"]).await,
+            reply::array([reply::bulk("0"), reply::array([reply::bulk("session:1")])])
+        );
+        assert_eq!(
+            run(&[b"SCAN", b"12345"]).await,
+            reply::error("ERR invalid cursor")
+        );
+        assert_eq!(
+            run(&[b"SCAN", b"0", b"COUNT", b"0"]).await,
+            reply::error("ERR syntax error")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_hscan_pages_through_hash() {
+        let (_dir, datastore, partition) = test_store();
+        let fields: Vec<String> = (0..100).map(|i| format!("f{:03}", i)).collect();
+        let pairs: Vec<(&[u8], &[u8])> = fields
+            .iter()
+            .map(|field| (field.as_bytes(), &b"v"[..]))
+            .collect();
+        partition.hash_set(b"hash", &pairs).unwrap();
+
+        let mut seen = Vec::new();
+        let mut cursor = Bytes::from("0");
+        loop {
+            let BytesFrame::Array(page) = handle_command(
+                reply::array([
+                    reply::bulk("HSCAN"),
+                    reply::bulk("hash"),
+                    reply::bulk(cursor),
+                    reply::bulk("COUNT"),
+                    reply::bulk("7"),
+                ]),
+                &datastore,
+                &partition,
+            )
+            .await
+            else {
+                panic!("expected an array");
+            };
+            let [BytesFrame::BulkString(next), BytesFrame::Array(batch)] = &page[..] else {
+                panic!("unexpected HSCAN reply {:?}", page);
+            };
+            for pair in batch.chunks(2) {
+                assert_eq!(pair[1], reply::bulk("v"));
+                seen.push(pair[0].clone());
+            }
+            if next.as_ref() == b"0" {
+                break;
+            }
+            cursor = next.clone();
+        }
+        assert_eq!(
+            seen,
+            fields
+                .iter()
+                .map(|f| reply::bulk(f.clone()))
+                .collect::<Vec<_>>()
+        );
+
+        let run =
+            |args: &'static [&'static [u8]]| handle_command(command(args), &datastore, &partition);
+        assert_eq!(
+            run(&[
+                b"HSCAN",
+                b"hash",
+                b"0",
+                b"NOVALUES",
+                b"MATCH",
+                b"f09?",
+                b"COUNT",
+                b"1000"
+            ])
+            .await,
+            reply::array([
+                reply::bulk("0"),
+                reply::array((90..100).map(|i| reply::bulk(format!("f{:03}", i)))),
+            ])
+        );
+        assert_eq!(
+            run(&[b"HSCAN", b"missing", b"0"]).await,
+            reply::array([reply::bulk("0"), reply::array([])])
+        );
+        assert_eq!(
+            run(&[b"HSCAN", b"hash", b"12345"]).await,
+            reply::error("ERR invalid cursor")
+        );
+        assert_eq!(
+            run(&[b"HSCAN", b"hash", b"0", b"COUNT"]).await,
+            reply::error("ERR syntax error")
+        );
+        // NOVALUES is HSCAN's alone
+        assert_eq!(
+            run(&[b"SCAN", b"0", b"NOVALUES"]).await,
+            reply::error("ERR syntax error")
+        );
+        partition.set(b"string", b"value").unwrap();
+        assert!(matches!(
+            run(&[b"HSCAN", b"string", b"0"]).await,
+            BytesFrame::Error(e) if e.starts_with("WRONGTYPE")
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_sscan_pages_through_set() {
+        let (_dir, datastore, partition) = test_store();
+        let members: Vec<String> = (0..600).map(|i| format!("m{:03}", i)).collect();
+        let refs: Vec<&[u8]> = members.iter().map(|member| member.as_bytes()).collect();
+        partition.set_add(b"set", &refs).unwrap();
+
+        let mut seen = Vec::new();
+        let mut cursor = Bytes::from("0");
+        loop {
+            let BytesFrame::Array(page) = handle_command(
+                reply::array([
+                    reply::bulk("SSCAN"),
+                    reply::bulk("set"),
+                    reply::bulk(cursor),
+                    reply::bulk("COUNT"),
+                    reply::bulk("50"),
+                ]),
+                &datastore,
+                &partition,
+            )
+            .await
+            else {
+                panic!("expected an array");
+            };
+            let [BytesFrame::BulkString(next), BytesFrame::Array(batch)] = &page[..] else {
+                panic!("unexpected SSCAN reply {:?}", page);
+            };
+            seen.extend(batch.iter().cloned());
+            if next.as_ref() == b"0" {
+                break;
+            }
+            cursor = next.clone();
+        }
+        assert_eq!(
+            seen,
+            members
+                .iter()
+                .map(|m| reply::bulk(m.clone()))
+                .collect::<Vec<_>>()
+        );
+
+        let run =
+            |args: &'static [&'static [u8]]| handle_command(command(args), &datastore, &partition);
+        assert_eq!(
+            run(&[b"SSCAN", b"set", b"0", b"MATCH", b"m59?", b"COUNT", b"1000"]).await,
+            reply::array([
+                reply::bulk("0"),
+                reply::array((590..600).map(|i| reply::bulk(format!("m{:03}", i)))),
+            ])
+        );
+        assert_eq!(
+            run(&[b"SSCAN", b"set", b"0", b"NOVALUES"]).await,
+            reply::error("ERR syntax error")
+        );
+        assert_eq!(
+            run(&[b"SSCAN", b"set"]).await,
+            reply::error("ERR Wrong number of arguments for SSCAN")
+        );
+    }
+
+    // Commands replying with an array reply an empty one when there is
+    // nothing to return, never Null, which only stands for a missing value
+    #[tokio::test]
+    async fn test_empty_results_are_empty_arrays() {
+        let (_dir, datastore, partition) = test_store();
+        let run =
+            |args: &'static [&'static [u8]]| handle_command(command(args), &datastore, &partition);
+        let empty = reply::array([]);
+        assert_eq!(run(&[b"KEYS", b"*"]).await, empty);
+        assert_eq!(
+            run(&[b"SCAN", b"0"]).await,
+            reply::array([reply::bulk("0"), empty.clone()])
+        );
+        assert_eq!(run(&[b"LRANGE", b"missing", b"0", b"-1"]).await, empty);
+        assert_eq!(run(&[b"SMEMBERS", b"missing"]).await, empty);
+        assert_eq!(
+            run(&[b"LPOS", b"missing", b"x", b"COUNT", b"0"]).await,
+            empty
+        );
+        // One Null per missing key, inside an array
+        assert_eq!(
+            run(&[b"MGET", b"missing", b"other"]).await,
+            reply::array([reply::nil(), reply::nil()])
+        );
+        assert_eq!(run(&[b"GET", b"missing"]).await, reply::nil());
+
+        run(&[b"SET", b"key", b"value"]).await;
+        assert_eq!(run(&[b"KEYS", b"cart:*"]).await, empty);
+        assert_eq!(
+            run(&[b"SCAN", b"0", b"MATCH", b"cart:*"]).await,
+            reply::array([reply::bulk("0"), empty])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_compact_command() {
+        let (_dir, datastore, partition) = test_store();
+        handle_command(command(&[b"SET", b"key", b"value"]), &datastore, &partition).await;
+        for args in [&[&b"COMPACT"[..]][..], &[b"COMPACT", b"test"]] {
+            let reply = handle_command(command(args), &datastore, &partition).await;
+            assert_eq!(reply, BytesFrame::SimpleString("OK".into()));
+        }
+        let reply = handle_command(command(&[b"COMPACT", b"other"]), &datastore, &partition).await;
+        assert!(matches!(reply, BytesFrame::Error(_)));
+        let reply = handle_command(command(&[b"GET", b"key"]), &datastore, &partition).await;
+        assert_eq!(reply, BytesFrame::BulkString("value".into()));
+    }
+
+    #[tokio::test]
+    async fn test_save_command() {
+        let (_dir, datastore, partition) = test_store();
+        for args in [&[&b"SAVE"[..]][..], &[b"SAVE", b"test"]] {
+            let reply = handle_command(command(args), &datastore, &partition).await;
+            assert_eq!(reply, BytesFrame::SimpleString("OK".into()));
+        }
+        let reply = handle_command(command(&[b"SAVE", b"other"]), &datastore, &partition).await;
+        assert_eq!(
+            reply,
+            BytesFrame::Error("ERR no such partition 'other'".into())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_config_appendfsync() {
+        let (_dir, datastore, partition) = test_store();
+        let reply = handle_command(
+            command(&[b"CONFIG", b"SET", b"appendfsync", b"always"]),
+            &datastore,
+            &partition,
+        )
+        .await;
+        assert_eq!(reply, BytesFrame::SimpleString("OK".into()));
+        assert_eq!(datastore.fsync_policy(), FsyncPolicy::Always);
+
+        let reply = handle_command(
+            command(&[b"CONFIG", b"GET", b"appendfsync"]),
+            &datastore,
+            &partition,
+        )
+        .await;
+        assert_eq!(
+            reply,
+            BytesFrame::Array(vec![
+                BytesFrame::BulkString("appendfsync".into()),
+                BytesFrame::BulkString("always".into()),
+            ])
+        );
+
+        let reply = handle_command(
+            command(&[b"CONFIG", b"SET", b"appendfsync", b"sometimes"]),
+            &datastore,
+            &partition,
+        )
+        .await;
+        assert!(matches!(reply, BytesFrame::Error(_)));
+        assert_eq!(datastore.fsync_policy(), FsyncPolicy::Always);
+    }
+
+    #[tokio::test]
+    async fn test_memory_usage() {
+        let (_dir, datastore, partition) = test_store();
+        partition.set(b"small", b"x").unwrap();
+        partition.set(b"large", &[b'x'; 1000]).unwrap();
+        let mut usage = Vec::new();
+        for key in [&b"small"[..], b"large"] {
+            match handle_command(command(&[b"MEMORY", b"USAGE", key]), &datastore, &partition).await
+            {
+                BytesFrame::Integer(bytes) => usage.push(bytes),
+                other => panic!("unexpected reply {:?}", other),
+            }
+        }
+        assert!(usage[1] > usage[0]);
+
+        let reply = handle_command(
+            command(&[b"MEMORY", b"USAGE", b"missing", b"SAMPLES", b"0"]),
+            &datastore,
+            &partition,
+        )
+        .await;
+        assert_eq!(reply, BytesFrame::Null);
+        let reply = handle_command(
+            command(&[b"MEMORY", b"USAGE", b"small", b"SAMPLES", b"-1"]),
+            &datastore,
+            &partition,
+        )
+        .await;
+        assert!(matches!(reply, BytesFrame::Error(_)));
+    }
+
+    #[tokio::test]
+    async fn test_config_maxmemory_samples() {
+        let (_dir, datastore, partition) = test_store();
+        assert_eq!(datastore.maxmemory_samples(), 5);
+        let reply = handle_command(
+            command(&[b"CONFIG", b"SET", b"maxmemory-samples", b"10"]),
+            &datastore,
+            &partition,
+        )
+        .await;
+        assert_eq!(reply, BytesFrame::SimpleString("OK".into()));
+        assert_eq!(datastore.maxmemory_samples(), 10);
+
+        for invalid in [&b"0"[..], b"65", b"many"] {
+            let reply = handle_command(
+                command(&[b"CONFIG", b"SET", b"maxmemory-samples", invalid]),
+                &datastore,
+                &partition,
+            )
+            .await;
+            assert!(matches!(reply, BytesFrame::Error(_)));
+        }
+        assert_eq!(datastore.maxmemory_samples(), 10);
+    }
+
+    #[tokio::test]
+    async fn test_config_group_commit() {
+        let (_dir, datastore, partition) = test_store();
+        let run = |args: &'static [&'static [u8]]| {
+            let datastore = datastore.clone();
+            let partition = partition.clone();
+            async move { handle_command(command(args), &datastore, &partition).await }
+        };
+        assert!(!datastore.group_commit().enabled());
+        assert_eq!(
+            run(&[b"CONFIG", b"SET", b"group-commit-window-us", b"500"]).await,
+            BytesFrame::SimpleString("OK".into())
+        );
+        assert_eq!(
+            run(&[b"CONFIG", b"SET", b"group-commit-max-writes", b"4"]).await,
+            BytesFrame::SimpleString("OK".into())
+        );
+        assert!(matches!(
+            run(&[b"CONFIG", b"SET", b"group-commit-max-writes", b"0"]).await,
+            BytesFrame::Error(_)
+        ));
+        assert_eq!(
+            datastore.group_commit(),
+            GroupCommit {
+                window: Duration::from_micros(500),
+                max_writes: 4,
+            }
+        );
+        assert_eq!(
+            run(&[b"CONFIG", b"GET", b"group-commit-window-us"]).await,
+            BytesFrame::Array(vec![
+                BytesFrame::BulkString("group-commit-window-us".into()),
+                BytesFrame::BulkString("500".into()),
+            ])
+        );
+
+        // Grouped writes reply once committed, like any other
+        assert_eq!(
+            run(&[b"SET", b"key", b"value"]).await,
+            BytesFrame::SimpleString("OK".into())
+        );
+        assert_eq!(
+            run(&[b"GET", b"key"]).await,
+            BytesFrame::BulkString("value".into())
+        );
+        assert_eq!(run(&[b"DEL", b"key"]).await, BytesFrame::Integer(1));
+        assert_eq!(run(&[b"DEL", b"key"]).await, BytesFrame::Integer(0));
+        assert_eq!(partition.group_commits(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_key_quota_exceeded() {
+        let temp_dir = TempDir::new().unwrap();
+        let datastore = DataStore::builder()
+            .max_keys(1)
+            .open(temp_dir.path().to_str().unwrap())
+            .unwrap();
+        let partition = datastore.partition("test").unwrap();
+        let run = |args: &'static [&'static [u8]]| {
+            let datastore = datastore.clone();
+            let partition = partition.clone();
+            async move { handle_command(command(args), &datastore, &partition).await }
+        };
+        assert_eq!(
+            run(&[b"SET", b"a", b"1"]).await,
+            BytesFrame::SimpleString("OK".into())
+        );
+        assert_eq!(
+            run(&[b"SET", b"b", b"2"]).await,
+            BytesFrame::Error("ERR key quota exceeded".into())
+        );
+        assert_eq!(
+            run(&[b"SET", b"a", b"3"]).await,
+            BytesFrame::SimpleString("OK".into())
+        );
+        assert_eq!(run(&[b"DEL", b"a"]).await, BytesFrame::Integer(1));
+        assert_eq!(
+            run(&[b"SET", b"b", b"2"]).await,
+            BytesFrame::SimpleString("OK".into())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_resp3_publish_arrives_as_push() {
+        let (_dir, addr) = spawn_server().await;
+
+        let socket = TcpStream::connect(addr).await.unwrap();
+        let mut subscriber = Framed::new(socket, Resp3::default());
+        subscriber
+            .send(resp3_command(&[b"HELLO", b"3"]))
+            .await
+            .unwrap();
+        let hello = subscriber.next().await.unwrap().unwrap();
+        assert!(matches!(hello, Resp3Frame::Map { .. }));
+        subscriber
+            .send(resp3_command(&[b"SUBSCRIBE", b"news"]))
+            .await
+            .unwrap();
+        let confirmation = subscriber.next().await.unwrap().unwrap();
+        assert_eq!(
+            confirmation,
+            Resp3Frame::Push {
+                data: vec![
+                    blob(b"subscribe"),
+                    blob(b"news"),
+                    Resp3Frame::Number {
+                        data: 1,
+                        attributes: None
+                    }
+                ],
+                attributes: None,
+            }
+        );
+
+        let socket = TcpStream::connect(addr).await.unwrap();
+        let mut publisher = Framed::new(socket, Resp2);
+        publisher
+            .send(command(&[b"PUBLISH", b"news", b"hello"]))
+            .await
+            .unwrap();
+        let receivers = publisher.next().await.unwrap().unwrap();
+        assert_eq!(receivers, BytesFrame::Integer(1));
+
+        let message = subscriber.next().await.unwrap().unwrap();
+        assert_eq!(
+            message,
+            Resp3Frame::Push {
+                data: vec![blob(b"message"), blob(b"news"), blob(b"hello")],
+                attributes: None,
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_getwithttl() {
+        let (_dir, datastore, partition) = test_store();
+        let run = |args: &'static [&'static [u8]]| {
+            let datastore = datastore.clone();
+            let partition = partition.clone();
+            async move { handle_command(command(args), &datastore, &partition).await }
+        };
+        run(&[b"SET", b"session", b"data", b"EX", b"100"]).await;
+        run(&[b"SET", b"forever", b"data"]).await;
+        run(&[b"RPUSH", b"list", b"item"]).await;
+        assert_eq!(
+            run(&[b"GETWITHTTL", b"session"]).await,
+            BytesFrame::Array(vec![
+                BytesFrame::BulkString("data".into()),
+                BytesFrame::Integer(100),
+            ])
+        );
+        assert_eq!(
+            run(&[b"GETWITHTTL", b"forever"]).await,
+            BytesFrame::Array(vec![
+                BytesFrame::BulkString("data".into()),
+                BytesFrame::Integer(-1),
+            ])
+        );
+        assert_eq!(
+            run(&[b"GETWITHTTL", b"missing"]).await,
+            BytesFrame::Array(vec![BytesFrame::Null, BytesFrame::Integer(-2)])
+        );
+        assert!(matches!(
+            run(&[b"GETWITHTTL", b"list"]).await,
+            BytesFrame::Error(e) if e.starts_with("WRONGTYPE")
+        ));
+
+        // RESP3 clients get the TTL as an attribute of the value
+        let (_dir, addr) = spawn_server().await;
+        let socket = TcpStream::connect(addr).await.unwrap();
+        let mut client = Framed::new(socket, Resp3::default());
+        client.send(resp3_command(&[b"HELLO", b"3"])).await.unwrap();
+        client.next().await.unwrap().unwrap();
+        client
+            .send(resp3_command(&[b"SET", b"session", b"data", b"EX", b"100"]))
+            .await
+            .unwrap();
+        client.next().await.unwrap().unwrap();
+        client
+            .send(resp3_command(&[b"GETWITHTTL", b"session"]))
+            .await
+            .unwrap();
+        let ttl = [(
+            blob(b"ttl"),
+            Resp3Frame::Number {
+                data: 100,
+                attributes: None,
+            },
+        )];
+        assert_eq!(
+            client.next().await.unwrap().unwrap(),
+            Resp3Frame::BlobString {
+                data: "data".into(),
+                attributes: Some(ttl.into_iter().collect()),
+            }
+        );
+        client
+            .send(resp3_command(&[b"GETWITHTTL", b"missing"]))
+            .await
+            .unwrap();
+        assert_eq!(client.next().await.unwrap().unwrap(), Resp3Frame::Null);
+    }
+
+    #[tokio::test]
+    async fn test_resp3_verbatim_and_double_replies() {
+        let (_dir, addr) = spawn_server().await;
+        let socket = TcpStream::connect(addr).await.unwrap();
+        let mut client = Framed::new(socket, Resp3::default());
+        client.send(resp3_command(&[b"HELLO", b"3"])).await.unwrap();
+        client.next().await.unwrap().unwrap();
+
+        for args in [&[&b"INFO"[..], b"server"][..], &[b"CLIENT", b"INFO"]] {
+            client.send(resp3_command(args)).await.unwrap();
+            match client.next().await.unwrap().unwrap() {
+                Resp3Frame::VerbatimString { data, format, .. } => {
+                    assert_eq!(format, VerbatimStringFormat::Text);
+                    assert!(!data.is_empty());
+                }
+                other => panic!("expected a verbatim string, got {:?}", other),
+            }
+        }
+
+        client
+            .send(resp3_command(&[b"HINCRBYFLOAT", b"hash", b"field", b"2.5"]))
+            .await
+            .unwrap();
+        assert_eq!(
+            client.next().await.unwrap().unwrap(),
+            Resp3Frame::Double {
+                data: 2.5,
+                attributes: None,
+            }
+        );
+
+        // RESP2 clients still get bulk strings
+        let socket = TcpStream::connect(addr).await.unwrap();
+        let mut client = Framed::new(socket, Resp2);
+        client
+            .send(BytesFrame::Array(vec![BytesFrame::BulkString(
+                "INFO".into(),
+            )]))
+            .await
+            .unwrap();
+        assert!(matches!(
+            client.next().await.unwrap().unwrap(),
+            BytesFrame::BulkString(_)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_resp3_deprecation_warning() {
+        let (_dir, addr) = spawn_server().await;
+
+        let socket = TcpStream::connect(addr).await.unwrap();
+        let mut client = Framed::new(socket, Resp3::default());
+        client.send(resp3_command(&[b"HELLO", b"3"])).await.unwrap();
+        client.next().await.unwrap().unwrap();
+        client
+            .send(resp3_command(&[b"HMSET", b"hash", b"a", b"1"]))
+            .await
+            .unwrap();
+        assert_eq!(
+            client.next().await.unwrap().unwrap(),
+            Resp3Frame::SimpleString {
+                data: "OK".into(),
+                attributes: None
+            }
+        );
+        assert_eq!(
+            client.next().await.unwrap().unwrap(),
+            Resp3Frame::Push {
+                data: vec![
+                    blob(b"warning"),
+                    blob(b"HMSET is deprecated, use HSET instead")
+                ],
+                attributes: None,
+            }
+        );
+
+        // RESP2 clients only get the reply
+        client.send(resp3_command(&[b"HELLO", b"2"])).await.unwrap();
+        client.next().await.unwrap().unwrap();
+        client
+            .send(resp3_command(&[b"HMSET", b"hash", b"b", b"2"]))
+            .await
+            .unwrap();
+        client.send(resp3_command(&[b"PING"])).await.unwrap();
+        assert_eq!(
+            client.next().await.unwrap().unwrap(),
+            Resp3Frame::SimpleString {
+                data: "OK".into(),
+                attributes: None
+            }
+        );
+        assert_eq!(
+            client.next().await.unwrap().unwrap(),
+            Resp3Frame::SimpleString {
+                data: "PONG".into(),
+                attributes: None
+            }
+        );
+    }
+
+    #[test]
+    fn test_proto_limits() {
+        let limits = ProtoLimits::new(8, 2, 64, DEFAULT_MAX_PIPELINE_DEPTH);
+        assert!(limits.check(b"").is_ok());
+        assert!(limits.check(b"*2\r\n$3\r\nGET\r\n$1\r\nk\r\n").is_ok());
+        // Partial requests are checked as far as they go
+        assert!(limits.check(b"*2\r\n$3\r\nGE").is_ok());
+        assert!(limits.check(b"*1\r\n$1000000").is_ok());
+        assert_eq!(
+            limits.check(b"*3\r\n").unwrap_err(),
+            "invalid multibulk length"
+        );
+        assert_eq!(
+            limits.check(b"*1\r\n$9\r\n").unwrap_err(),
+            "invalid bulk length"
+        );
+        assert_eq!(
+            limits.check(b"*2\r\n*1\r\n$1\r\na\r\n").unwrap_err(),
+            "expected '$', got '*'"
+        );
+        assert_eq!(
+            limits.check(b"$3\r\nGET\r\n").unwrap_err(),
+            "expected '*', got '$'"
+        );
+        assert_eq!(
+            limits.check(&[b'*'; MAX_HEADER_LINE + 2]).unwrap_err(),
+            "too big header line"
+        );
+        assert_eq!(
+            ProtoLimits::new(64, 4, 32, DEFAULT_MAX_PIPELINE_DEPTH)
+                .check(b"*2\r\n$20\r\n")
+                .unwrap_err(),
+            "request too large"
+        );
+
+        limits.set_max_bulk_len(16);
+        assert!(limits.check(b"*1\r\n$9\r\n").is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_oversized_multibulk_closes_connection() {
+        let (_dir, addr) = spawn_server().await;
+
+        let mut socket = TcpStream::connect(addr).await.unwrap();
+        // Only the header: the server must not wait for the elements
+        socket.write_all(b"*1000000000\r\n").await.unwrap();
+        let mut reply = Vec::new();
+        socket.read_to_end(&mut reply).await.unwrap();
+        assert_eq!(reply, b"-ERR Protocol error: invalid multibulk length\r\n");
+    }
+
+    #[tokio::test]
+    async fn test_config_proto_max_bulk_len() {
+        let (_dir, addr) = spawn_server().await;
+
+        let socket = TcpStream::connect(addr).await.unwrap();
+        let mut client = Framed::new(socket, Resp2);
+        // Below Redis' minimum, which would lock out CONFIG itself
+        client
+            .send(command(&[b"CONFIG", b"SET", b"proto-max-bulk-len", b"4"]))
+            .await
+            .unwrap();
+        assert_eq!(
+            client.next().await.unwrap().unwrap(),
+            BytesFrame::Error(
+                "ERR CONFIG SET failed (possibly related to argument 'proto-max-bulk-len') - argument must be between 1048576 and 9223372036854775807 inclusive"
+                    .into()
+            )
+        );
+        client
+            .send(command(&[
+                b"CONFIG",
+                b"SET",
+                b"proto-max-bulk-len",
+                b"1048576",
+            ]))
+            .await
+            .unwrap();
+        assert_eq!(
+            client.next().await.unwrap().unwrap(),
+            BytesFrame::SimpleString("OK".into())
+        );
+        client
+            .send(command(&[b"CONFIG", b"GET", b"proto-max-bulk-len"]))
+            .await
+            .unwrap();
+        assert_eq!(
+            client.next().await.unwrap().unwrap(),
+            BytesFrame::Array(vec![
+                BytesFrame::BulkString("proto-max-bulk-len".into()),
+                BytesFrame::BulkString("1048576".into()),
+            ])
+        );
+        // Only the headers: the value is rejected before it is sent
+        client
+            .get_mut()
+            .write_all(b"*3\r\n$3\r\nSET\r\n$3\r\nkey\r\n$1048577\r\n")
+            .await
+            .unwrap();
+        assert_eq!(
+            client.next().await.unwrap().unwrap(),
+            BytesFrame::Error("ERR Protocol error: invalid bulk length".into())
+        );
+        assert!(client.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_pipeline_depth_backpressure() {
+        const PAIRS: usize = 100;
+        let mut partition = None;
+        let (_dir, addr) = spawn_server_with(|context| {
+            context.limits.set_max_pipeline_depth(4);
+            partition = context.databases.get(0);
+        })
+        .await;
+        let partition = partition.unwrap();
+        partition.set(b"big", &vec![b'x'; 1024 * 1024]).unwrap();
+
+        // Far more reply data than the socket buffers hold, sent without
+        // reading any of it
+        let socket = TcpStream::connect(addr).await.unwrap();
+        let mut client = Framed::new(socket, Resp2);
+        for _ in 0..PAIRS {
+            client.feed(command(&[b"INCR", b"counter"])).await.unwrap();
+            client.feed(command(&[b"GET", b"big"])).await.unwrap();
+        }
+        client.flush().await.unwrap();
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        let processed = partition.incr_by(b"counter", 0).unwrap();
+        assert!(processed < PAIRS as i64 / 2, "processed {}", processed);
+
+        for i in 0..PAIRS {
+            assert_eq!(
+                client.next().await.unwrap().unwrap(),
+                BytesFrame::Integer(i as i64 + 1)
+            );
+            assert!(matches!(
+                client.next().await.unwrap().unwrap(),
+                BytesFrame::BulkString(_)
+            ));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_serve_all_listeners() {
+        let (_dir, datastore, _) = test_store();
+        let mut listeners = Vec::new();
+        for _ in 0..2 {
+            listeners.push(tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap());
+        }
+        let addrs: Vec<_> = listeners
+            .iter()
+            .map(|listener| listener.local_addr().unwrap())
+            .collect();
+        let context = ServerContext::new(
+            Databases::open(&datastore, 1).unwrap(),
+            ProtoLimits::default(),
+        );
+        tokio::spawn(serve_all(listeners, context));
+
+        tokio::task::spawn_blocking(move || {
+            let mut first = TestClient::connect(addrs[0]).unwrap();
+            let mut second = TestClient::connect(addrs[1]).unwrap();
+            assert_eq!(
+                first.command(&[b"SET", b"key", b"value"]).unwrap(),
+                Reply::ok()
+            );
+            // Both listeners serve the same data
+            assert_eq!(
+                second.command(&[b"GET", b"key"]).unwrap(),
+                Reply::bulk(b"value")
+            );
+        })
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_serve_all_until_shutdown() {
+        let (_dir, datastore, _) = test_store();
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let context = ServerContext::new(
+            Databases::open(&datastore, 1).unwrap(),
+            ProtoLimits::default(),
+        );
+        let (trigger, shutdown) = tokio::sync::oneshot::channel::<()>();
+        let server = tokio::spawn(serve_all_until(vec![listener], context, async {
+            let _ = shutdown.await;
+        }));
+
+        let mut clients = Vec::new();
+        for _ in 0..2 {
+            let socket = TcpStream::connect(addr).await.unwrap();
+            let mut client = Framed::new(socket, Resp2);
+            client
+                .send(command(&[b"SET", b"key", b"value"]))
+                .await
+                .unwrap();
+            assert_eq!(
+                client.next().await.unwrap().unwrap(),
+                BytesFrame::SimpleString("OK".into())
+            );
+            clients.push(client);
+        }
+
+        trigger.send(()).unwrap();
+        let summary = server.await.unwrap().unwrap();
+        assert_eq!(summary.connections_drained, 2);
+        assert_eq!(summary.connections_abandoned, 0);
+        assert!(summary.persisted());
+        assert!(summary.disk_space > 0);
+        let line = summary.to_string();
+        for field in [
+            "connections_drained=2",
+            "connections_abandoned=0",
+            "flush_ms=",
+            "disk_space=",
+            "persisted=true",
+        ] {
+            assert!(line.contains(field), "{} missing from {}", field, line);
+        }
+
+        // Drained connections were closed, and nothing accepts new ones
+        for mut client in clients {
+            assert!(client.next().await.is_none());
+        }
+        assert!(TcpStream::connect(addr).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_rename_command() {
+        let (_dir, addr) = spawn_server_with(|context| {
+            context.rename_command("config", "obscure-config");
+            context.rename_command("DEBUG", "");
+            // Swapped names each run the other command
+            context.rename_command("GET", "EXISTS");
+            context.rename_command("EXISTS", "GET");
+        })
+        .await;
+        let socket = TcpStream::connect(addr).await.unwrap();
+        let mut client = Framed::new(socket, Resp2);
+        async fn run(client: &mut Framed<TcpStream, Resp2>, args: &[&[u8]]) -> BytesFrame {
+            client.send(command(args)).await.unwrap();
+            client.next().await.unwrap().unwrap()
+        }
+
+        assert_eq!(
+            run(&mut client, &[b"CONFIG", b"GET", b"lcs-max-cells"]).await,
+            BytesFrame::Error("ERR unknown command 'CONFIG'".into())
+        );
+        assert!(matches!(
+            run(&mut client, &[b"OBSCURE-CONFIG", b"GET", b"lcs-max-cells"]).await,
+            BytesFrame::Array(_)
+        ));
+        assert_eq!(
+            run(&mut client, &[b"debug", b"ERROR", b"boom"]).await,
+            BytesFrame::Error("ERR unknown command 'DEBUG'".into())
+        );
+
+        run(&mut client, &[b"SET", b"key", b"value"]).await;
+        assert_eq!(
+            run(&mut client, &[b"EXISTS", b"key"]).await,
+            reply::bulk("value")
+        );
+        assert_eq!(run(&mut client, &[b"GET", b"key"]).await, reply::integer(1));
+        // Other commands are untouched
+        assert_eq!(
+            run(&mut client, &[b"PING"]).await,
+            BytesFrame::SimpleString("PONG".into())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_hello_compression() {
+        let (_dir, addr) = spawn_server().await;
+        tokio::task::spawn_blocking(move || {
+            let mut client = TestClient::connect(addr).unwrap();
+            // Within spawn_server's bulk length limit
+            let large = b"a fairly repetitive value ".repeat(30);
+            client.command(&[b"SET", b"large", &large]).unwrap();
+            client.command(&[b"SET", b"small", b"value"]).unwrap();
+
+            // Values go out as they are until the client opts in
+            assert_eq!(
+                client.command(&[b"GET", b"large"]).unwrap(),
+                Reply::bulk(&large)
+            );
+            let Reply::Array(fields) = client
+                .command(&[b"HELLO", b"2", b"COMPRESSION", b"LZ4", b"256"])
+                .unwrap()
+            else {
+                panic!("expected an array reply");
+            };
+            assert_eq!(
+                fields[fields.len() - 2..],
+                [
+                    Reply::bulk(b"compression"),
+                    Reply::Array(vec![Reply::bulk(b"lz4"), Reply::Integer(256)]),
+                ]
+            );
+            let Reply::Bulk(compressed) = client.command(&[b"GET", b"large"]).unwrap() else {
+                panic!("expected a bulk reply");
+            };
+            assert!(compressed.len() < large.len() / 4);
+            assert_eq!(decompress_value(&compressed).unwrap(), large);
+
+            let Reply::Array(values) = client
+                .command(&[b"MGET", b"small", b"missing", b"large"])
+                .unwrap()
+            else {
+                panic!("expected an array reply");
+            };
+            let [Reply::Bulk(small), Reply::Nil, Reply::Bulk(compressed)] = &values[..] else {
+                panic!("unexpected reply {:?}", values);
+            };
+            assert_eq!(small, b"Rvalue");
+            assert_eq!(decompress_value(compressed).unwrap(), large);
+            // Other commands are left alone
+            assert_eq!(
+                client.command(&[b"LCS", b"small", b"small"]).unwrap(),
+                Reply::bulk(b"value")
+            );
+
+            // HELLO without options keeps the compression, a bad option
+            // changes nothing
+            client.command(&[b"HELLO", b"2"]).unwrap();
+            for args in [
+                &[&b"HELLO"[..], b"2", b"COMPRESSION", b"ZSTD"][..],
+                &[b"HELLO", b"2", b"COMPRESSION", b"LZ4", b"-1"],
+                &[b"HELLO", b"2", b"COMPRESSION", b"OFF", b"1"],
+            ] {
+                assert!(matches!(client.command(args).unwrap(), Reply::Error(_)));
+            }
+            assert_eq!(
+                client.command(&[b"GET", b"small"]).unwrap(),
+                Reply::bulk(b"Rvalue")
+            );
+            // A lower minimum size still leaves values that don't shrink
+            client
+                .command(&[b"HELLO", b"2", b"COMPRESSION", b"LZ4", b"0"])
+                .unwrap();
+            assert_eq!(
+                client.command(&[b"GET", b"small"]).unwrap(),
+                Reply::bulk(b"Rvalue")
+            );
+            let Reply::Array(fields) = client
+                .command(&[b"HELLO", b"2", b"COMPRESSION", b"OFF"])
+                .unwrap()
+            else {
+                panic!("expected an array reply");
+            };
+            assert!(!fields.contains(&Reply::bulk(b"compression")));
+            assert_eq!(
+                client.command(&[b"GET", b"small"]).unwrap(),
+                Reply::bulk(b"value")
+            );
+        })
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_unknown_command_policy() {
+        for (policy, expected) in [
+            (
+                UnknownCommandPolicy::Error,
+                Reply::Error("ERR unknown command 'NOSUCHCOMMAND'".into()),
+            ),
+            (UnknownCommandPolicy::Ignore, Reply::Nil),
+            (UnknownCommandPolicy::Ok, Reply::ok()),
+        ] {
+            assert_eq!(policy.name().parse::<UnknownCommandPolicy>(), Ok(policy));
+            let (_dir, addr) =
+                spawn_server_with(|context| context.set_unknown_command_policy(policy)).await;
+            tokio::task::spawn_blocking(move || {
+                let mut client = TestClient::connect(addr).unwrap();
+                assert_eq!(client.command(&[b"NOSUCHCOMMAND"]).unwrap(), expected);
+                // Other errors are untouched
+                assert_eq!(
+                    client.command(&[b"GET"]).unwrap(),
+                    Reply::Error("ERR Wrong number of arguments for GET".into())
+                );
+            })
+            .await
+            .unwrap();
+        }
+        assert!("lenient".parse::<UnknownCommandPolicy>().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_object_help() {
+        let (_dir, addr) = spawn_server().await;
+        tokio::task::spawn_blocking(move || {
+            let mut client = TestClient::connect(addr).unwrap();
+            let Reply::Array(lines) = client.command(&[b"OBJECT", b"help"]).unwrap() else {
+                panic!("expected an array");
+            };
+            assert!(!lines.is_empty());
+            assert!(lines.iter().all(|line| matches!(line, Reply::Bulk(_))));
+            assert!(matches!(
+                client.command(&[b"GET", b"HELP"]).unwrap(),
+                Reply::Nil
+            ));
+        })
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_socket_options() {
+        let (_dir, datastore, _) = test_store();
+        let mut context = ServerContext::new(
+            Databases::open(&datastore, 1).unwrap(),
+            ProtoLimits::default(),
+        );
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let _client = TcpStream::connect(addr).await.unwrap();
+        let (socket, _) = listener.accept().await.unwrap();
+        context.configure_socket(&socket).unwrap();
+        assert!(socket.nodelay().unwrap());
+        assert!(!SockRef::from(&socket).keepalive().unwrap());
+
+        context.set_tcp_nodelay(false);
+        context.set_tcp_keepalive(Some(Duration::from_secs(60)));
+        let _client = TcpStream::connect(addr).await.unwrap();
+        let (socket, _) = listener.accept().await.unwrap();
+        context.configure_socket(&socket).unwrap();
+        assert!(!socket.nodelay().unwrap());
+        assert!(SockRef::from(&socket).keepalive().unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_client_ping_set_get() {
+        let (_dir, addr) = spawn_server().await;
+        tokio::task::spawn_blocking(move || {
+            let mut client = TestClient::connect(addr).unwrap();
+            assert_eq!(
+                client.command(&[b"PING"]).unwrap(),
+                Reply::Status("PONG".into())
+            );
+            assert_eq!(
+                client.command(&[b"SET", b"key", b"\r\nvalue"]).unwrap(),
+                Reply::ok()
+            );
+            assert_eq!(
+                client.command(&[b"GET", b"key"]).unwrap(),
+                Reply::bulk(b"\r\nvalue")
+            );
+            assert_eq!(client.command(&[b"GET", b"missing"]).unwrap(), Reply::Nil);
+            assert!(matches!(
+                client.command(&[b"NOSUCHCOMMAND"]).unwrap(),
+                Reply::Error(_)
+            ));
+        })
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_auth_rejection_is_audited() {
+        let audit_dir = TempDir::new().unwrap();
+        let audit_path = audit_dir.path().join("audit.log");
+        let audit_log = AuditLog::open(&audit_path).unwrap();
+        let (_dir, addr) = spawn_server_with(|context| {
+            context.requirepass = Some(Arc::from("secret"));
+            context.audit_log = Some(audit_log);
+        })
+        .await;
+
+        tokio::task::spawn_blocking(move || {
+            let mut client = TestClient::connect(addr).unwrap();
+            assert_eq!(
+                client.command(&[b"GET", b"key"]).unwrap(),
+                Reply::Error("NOAUTH Authentication required.".into())
+            );
+            assert!(matches!(
+                client.command(&[b"AUTH", b"wrong"]).unwrap(),
+                Reply::Error(e) if e.starts_with("WRONGPASS")
+            ));
+            assert_eq!(client.command(&[b"AUTH", b"secret"]).unwrap(), Reply::ok());
+            assert_eq!(client.command(&[b"GET", b"key"]).unwrap(), Reply::Nil);
+        })
+        .await
+        .unwrap();
+
+        // Entries are written in the background
+        let mut lines = Vec::new();
+        for _ in 0..100 {
+            let contents = std::fs::read_to_string(&audit_path).unwrap();
+            lines = contents.lines().map(str::to_string).collect();
+            if lines.len() == 2 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].ends_with(" GET authentication required"));
+        assert!(lines[1].ends_with(" AUTH invalid password"));
+        // Passwords are never logged
+        assert!(!lines.iter().any(|line| line.contains("wrong")));
+        let fields: Vec<&str> = lines[0].split(' ').collect();
+        assert!(fields[0].parse::<u64>().is_ok());
+        assert!(fields[1].parse::<SocketAddr>().is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_request_id_in_audit_log() {
+        let audit_dir = TempDir::new().unwrap();
+        let audit_path = audit_dir.path().join("audit.log");
+        let audit_log = AuditLog::open(&audit_path).unwrap();
+        let (_dir, addr) = spawn_server_with(|context| {
+            context.requirepass = Some(Arc::from("secret"));
+            context.audit_log = Some(audit_log);
+        })
+        .await;
+
+        tokio::task::spawn_blocking(move || {
+            let mut client = TestClient::connect(addr).unwrap();
+            assert_eq!(client.command(&[b"AUTH", b"secret"]).unwrap(), Reply::ok());
+            assert_eq!(
+                client.command(&[b"CLIENT", b"GETREQUESTID"]).unwrap(),
+                Reply::Nil
+            );
+            assert_eq!(
+                client
+                    .command(&[b"CLIENT", b"SETREQUESTID", b"trace-7f3a"])
+                    .unwrap(),
+                Reply::ok()
+            );
+            assert_eq!(
+                client.command(&[b"CLIENT", b"GETREQUESTID"]).unwrap(),
+                Reply::Bulk(b"trace-7f3a".to_vec())
+            );
+            assert!(matches!(
+                client.command(&[b"CLIENT", b"INFO"]).unwrap(),
+                Reply::Bulk(info) if String::from_utf8_lossy(&info).contains(" reqid=trace-7f3a ")
+            ));
+            assert!(matches!(
+                client
+                    .command(&[b"CLIENT", b"SETREQUESTID", b"two words"])
+                    .unwrap(),
+                Reply::Error(_)
+            ));
+            assert!(matches!(
+                client.command(&[b"AUTH", b"wrong"]).unwrap(),
+                Reply::Error(e) if e.starts_with("WRONGPASS")
+            ));
+            assert_eq!(
+                client.command(&[b"CLIENT", b"SETREQUESTID", b""]).unwrap(),
+                Reply::ok()
+            );
+            assert!(matches!(
+                client.command(&[b"AUTH", b"wrong"]).unwrap(),
+                Reply::Error(e) if e.starts_with("WRONGPASS")
+            ));
+        })
+        .await
+        .unwrap();
+
+        let mut lines = Vec::new();
+        for _ in 0..100 {
+            let contents = std::fs::read_to_string(&audit_path).unwrap();
+            lines = contents.lines().map(str::to_string).collect();
+            if lines.len() == 2 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].ends_with(" trace-7f3a AUTH invalid password"));
+        assert!(lines[1].ends_with(" - AUTH invalid password"));
+    }
+
+    #[tokio::test]
+    async fn test_client_info_counts_commands() {
+        let (_dir, addr) = spawn_server().await;
+
+        tokio::task::spawn_blocking(move || {
+            let mut client = TestClient::connect(addr).unwrap();
+            let mut other = TestClient::connect(addr).unwrap();
+            client.command(&[b"SET", b"key", b"value"]).unwrap();
+            client.command(&[b"GET", b"key"]).unwrap();
+            client.command(&[b"PING"]).unwrap();
+            let Reply::Bulk(info) = client.command(&[b"CLIENT", b"INFO"]).unwrap() else {
+                panic!("expected a bulk string");
+            };
+            let info = String::from_utf8(info).unwrap();
+            assert!(info.contains(" tot-cmds=4 cmd=client\n"), "{}", info);
+
+            other.command(&[b"PING"]).unwrap();
+            let Reply::Bulk(list) = other.command(&[b"CLIENT", b"LIST"]).unwrap() else {
+                panic!("expected a bulk string");
+            };
+            let list = String::from_utf8(list).unwrap();
+            let lines: Vec<_> = list.lines().collect();
+            assert_eq!(lines.len(), 2);
+            assert!(lines[0].ends_with(" tot-cmds=4 cmd=client"));
+            assert!(lines[1].ends_with(" tot-cmds=2 cmd=client"));
+        })
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_read_only_user() {
+        let (_dir, addr) = spawn_server_with(|context| {
+            context.requirepass = Some(Arc::from("secret"));
+            context.add_user("reader:pw:+@read".parse().unwrap());
+        })
+        .await;
+
+        tokio::task::spawn_blocking(move || {
+            let mut client = TestClient::connect(addr).unwrap();
+            assert!(matches!(
+                client.command(&[b"AUTH", b"reader", b"secret"]).unwrap(),
+                Reply::Error(e) if e.starts_with("WRONGPASS")
+            ));
+            assert_eq!(
+                client.command(&[b"AUTH", b"reader", b"pw"]).unwrap(),
+                Reply::ok()
+            );
+            assert_eq!(
+                client.command(&[b"ACL", b"WHOAMI"]).unwrap(),
+                Reply::Bulk(b"reader".to_vec())
+            );
+            assert_eq!(
+                client.command(&[b"SET", b"key", b"value"]).unwrap(),
+                Reply::Error(
+                    "NOPERM User reader has no permissions to run the 'set' command".into()
+                )
+            );
+            assert_eq!(client.command(&[b"GET", b"key"]).unwrap(), Reply::Nil);
+            assert!(matches!(
+                client.command(&[b"CONFIG", b"GET", b"appendfsync"]).unwrap(),
+                Reply::Error(e) if e.starts_with("NOPERM")
+            ));
+
+            // The default user can do anything
+            assert_eq!(client.command(&[b"AUTH", b"secret"]).unwrap(), Reply::ok());
+            assert_eq!(
+                client.command(&[b"ACL", b"WHOAMI"]).unwrap(),
+                Reply::Bulk(b"default".to_vec())
+            );
+            assert_eq!(
+                client.command(&[b"SET", b"key", b"value"]).unwrap(),
+                Reply::ok()
+            );
+        })
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_select_by_name() {
+        let (_dir, addr) = spawn_server().await;
+        tokio::task::spawn_blocking(move || {
+            let mut client = TestClient::connect(addr).unwrap();
+            assert_eq!(
+                client.command(&[b"SELECT", b"sessions"]).unwrap(),
+                Reply::ok()
+            );
+            assert_eq!(
+                client.command(&[b"SET", b"key", b"named"]).unwrap(),
+                Reply::ok()
+            );
+            // Numbered databases keep working, and are separate
+            assert_eq!(client.command(&[b"SELECT", b"1"]).unwrap(), Reply::ok());
+            assert_eq!(client.command(&[b"GET", b"key"]).unwrap(), Reply::Nil);
+            assert_eq!(client.command(&[b"SELECT", b"0"]).unwrap(), Reply::ok());
+            assert_eq!(
+                client.command(&[b"SET", b"key", b"db0"]).unwrap(),
+                Reply::ok()
+            );
+            assert_eq!(
+                client.command(&[b"SELECT", b"16"]).unwrap(),
+                Reply::Error("ERR DB index is out of range".into())
+            );
+
+            let mut other = TestClient::connect(addr).unwrap();
+            assert_eq!(
+                other.command(&[b"SELECT", b"sessions"]).unwrap(),
+                Reply::ok()
+            );
+            assert_eq!(
+                other.command(&[b"GET", b"key"]).unwrap(),
+                Reply::bulk(b"named")
+            );
+            assert!(matches!(
+                other.command(&[b"SELECT", b"no/slashes"]).unwrap(),
+                Reply::Error(_)
+            ));
+            // db 0 is also reachable under its partition name
+            assert_eq!(
+                other.command(&[b"SELECT", b"default_partition"]).unwrap(),
+                Reply::ok()
+            );
+            assert_eq!(
+                other.command(&[b"GET", b"key"]).unwrap(),
+                Reply::bulk(b"db0")
+            );
+        })
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_swapdb() {
+        let (_dir, addr) = spawn_server().await;
+        tokio::task::spawn_blocking(move || {
+            let mut db0 = TestClient::connect(addr).unwrap();
+            let mut db1 = TestClient::connect(addr).unwrap();
+            assert_eq!(db1.command(&[b"SELECT", b"1"]).unwrap(), Reply::ok());
+            assert_eq!(
+                db0.command(&[b"SET", b"key", b"zero"]).unwrap(),
+                Reply::ok()
+            );
+            assert_eq!(db1.command(&[b"SET", b"key", b"one"]).unwrap(), Reply::ok());
+            assert_eq!(db1.command(&[b"SET", b"only1", b"x"]).unwrap(), Reply::ok());
+
+            assert_eq!(db0.command(&[b"SWAPDB", b"0", b"1"]).unwrap(), Reply::ok());
+            // Both connections see the swap without selecting again
+            assert_eq!(db0.command(&[b"GET", b"key"]).unwrap(), Reply::bulk(b"one"));
+            assert_eq!(db0.command(&[b"GET", b"only1"]).unwrap(), Reply::bulk(b"x"));
+            assert_eq!(
+                db1.command(&[b"GET", b"key"]).unwrap(),
+                Reply::bulk(b"zero")
+            );
+            assert_eq!(db1.command(&[b"GET", b"only1"]).unwrap(), Reply::Nil);
+
+            assert_eq!(
+                db0.command(&[b"SWAPDB", b"0", b"16"]).unwrap(),
+                Reply::Error("ERR DB index is out of range".into())
+            );
+        })
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_swapdb_by_another_connection() {
+        let (_dir, addr) = spawn_server().await;
+        tokio::task::spawn_blocking(move || {
+            let mut selected = TestClient::connect(addr).unwrap();
+            let mut admin = TestClient::connect(addr).unwrap();
+            assert_eq!(selected.command(&[b"SELECT", b"0"]).unwrap(), Reply::ok());
+            assert_eq!(
+                selected.command(&[b"SET", b"key", b"zero"]).unwrap(),
+                Reply::ok()
+            );
+            assert_eq!(admin.command(&[b"SELECT", b"1"]).unwrap(), Reply::ok());
+            assert_eq!(
+                admin.command(&[b"SET", b"key", b"one"]).unwrap(),
+                Reply::ok()
+            );
+
+            assert_eq!(
+                admin.command(&[b"SWAPDB", b"1", b"0"]).unwrap(),
+                Reply::ok()
+            );
+            // The next command of the other connection reads and writes what
+            // is now db 0
+            assert_eq!(
+                selected.command(&[b"GET", b"key"]).unwrap(),
+                Reply::bulk(b"one")
+            );
+            assert_eq!(
+                selected.command(&[b"SET", b"after", b"swap"]).unwrap(),
+                Reply::ok()
+            );
+            assert_eq!(
+                admin.command(&[b"GET", b"key"]).unwrap(),
+                Reply::bulk(b"zero")
+            );
+            assert_eq!(admin.command(&[b"SELECT", b"0"]).unwrap(), Reply::ok());
+            assert_eq!(
+                admin.command(&[b"GET", b"after"]).unwrap(),
+                Reply::bulk(b"swap")
+            );
+        })
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_quit_closes_connection() {
+        let (_dir, addr) = spawn_server().await;
+
+        let socket = TcpStream::connect(addr).await.unwrap();
+        let mut client = Framed::new(socket, Resp2);
+        client
+            .send(command(&[b"SUBSCRIBE", b"news"]))
+            .await
+            .unwrap();
+        client.next().await.unwrap().unwrap();
+        client.send(command(&[b"QUIT"])).await.unwrap();
+        assert_eq!(
+            client.next().await.unwrap().unwrap(),
+            BytesFrame::SimpleString("OK".into())
+        );
+        assert!(client.next().await.is_none());
+
+        // The closed connection no longer counts as a subscriber
+        let socket = TcpStream::connect(addr).await.unwrap();
+        let mut publisher = Framed::new(socket, Resp2);
+        publisher
+            .send(command(&[b"PUBLISH", b"news", b"hello"]))
+            .await
+            .unwrap();
+        assert_eq!(
+            publisher.next().await.unwrap().unwrap(),
+            BytesFrame::Integer(0)
+        );
+    }
+
+    #[test]
+    fn test_resp2_messages_are_arrays() {
+        let (_dir, datastore, _) = test_store();
+        let mut connection = Connection::new(
+            ServerContext::new(
+                Databases::open(&datastore, 1).unwrap(),
+                ProtoLimits::default(),
+            ),
+            None,
+        );
+        let replies = connection
+            .handle_command(&command(&[b"SUBSCRIBE", b"news"]))
+            .unwrap();
+        assert!(matches!(
+            &replies[..],
+            [Outgoing::Resp2(BytesFrame::Array(_))]
+        ));
+        let message = connection.message(Message {
+            channel: b"news".to_vec(),
+            payload: b"hello".to_vec(),
+        });
+        assert!(matches!(message, Outgoing::Resp2(BytesFrame::Array(items)) if items.len() == 3));
+    }
+}
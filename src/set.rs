@@ -0,0 +1,881 @@
+use crate::datastore::{member_key, member_prefix};
+use crate::scan::matches_pattern;
+use crate::value::{parse_canonical_integer, StoredValue, ValueKind};
+use crate::{DataStoreError, DataStorePartition};
+use std::collections::{BTreeSet, HashSet};
+
+// A set is a container header in the partition plus one empty-valued entry per
+// member in the members partition ("hashtable" encoding).
+//
+// Sets whose members are all integers are kept in the header instead, as a
+// sorted array of i64 BE after the member count ("intset" encoding), so a
+// numeric set costs a single entry. Adding a member that is not an integer,
+// or growing past `SET_MAX_INTSET_ENTRIES`, moves the set to the hashtable
+// encoding for good. The header payload tells the two apart: a hashtable
+// header is the bare count, an intset header also holds at least one integer.
+// Only members in canonical form count as integers, see
+// `parse_canonical_integer`.
+const SET_MAX_INTSET_ENTRIES: usize = 512;
+
+// One call's worth of SSCAN
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SetScanPage {
+    pub members: Vec<Vec<u8>>,
+    // The last member examined, to carry on after; None once the scan is done
+    pub next: Option<Vec<u8>>,
+}
+
+// Payload bytes of a hashtable header, the member count
+const COUNT_LEN: usize = 8;
+
+impl DataStorePartition {
+    // Adds the members that are not yet in the set at `key`, creating it if
+    // needed, and returns how many were added
+    pub fn set_add(&self, key: &[u8], members: &[&[u8]]) -> Result<usize, DataStoreError> {
+        let _guard = self.lock_key(key);
+        let current = self.read_for_update(key)?;
+        let (expires_at, card) = set_header(current.as_ref())?;
+
+        let intset = match current.as_ref() {
+            None => Some(BTreeSet::new()),
+            Some(stored) => intset_members(stored)?.map(BTreeSet::from_iter),
+        };
+        if let Some(mut intset) = intset {
+            let added = match members
+                .iter()
+                .map(|member| parse_canonical_integer(member))
+                .collect::<Option<Vec<_>>>()
+            {
+                Some(integers) => integers
+                    .into_iter()
+                    .filter(|&integer| intset.insert(integer))
+                    .count(),
+                None => return self.set_add_converting(key, current, intset, members),
+            };
+            if added == 0 {
+                return Ok(0);
+            }
+            if intset.len() > SET_MAX_INTSET_ENTRIES {
+                return self.set_add_converting(key, current, intset, &[]);
+            }
+            let updated = intset_value(expires_at, intset.iter().copied());
+            self.commit_update(self.batch(), key, current.as_ref(), Some(&updated))?;
+            return Ok(added);
+        }
+
+        let mut batch = self.batch();
+        let mut added = HashSet::new();
+        for &member in members {
+            let composite = member_key(key, member);
+            if added.contains(member) || self.members_handle().contains_key(&composite)? {
+                continue;
+            }
+            batch.insert(self.members_handle(), composite, []);
+            added.insert(member);
+        }
+        if added.is_empty() {
+            return Ok(0);
+        }
+        let updated = set_value(expires_at, card + added.len() as u64);
+        self.commit_update(batch, key, current.as_ref(), Some(&updated))?;
+        Ok(added.len())
+    }
+
+    // Moves an intset to the hashtable encoding, adding `members` on the way.
+    // Returns how many of `members` were not in it yet.
+    fn set_add_converting(
+        &self,
+        key: &[u8],
+        current: Option<StoredValue>,
+        intset: BTreeSet<i64>,
+        members: &[&[u8]],
+    ) -> Result<usize, DataStoreError> {
+        let expires_at = current.as_ref().and_then(|stored| stored.expires_at);
+        let mut all: HashSet<Vec<u8>> = intset
+            .iter()
+            .map(|integer| integer.to_string().into_bytes())
+            .collect();
+        let before = current
+            .as_ref()
+            .map(|stored| stored.container_len())
+            .transpose()?
+            .unwrap_or(0) as usize;
+        all.extend(members.iter().map(|member| member.to_vec()));
+        let mut batch = self.batch();
+        for member in &all {
+            batch.insert(self.members_handle(), member_key(key, member), []);
+        }
+        let updated = set_value(expires_at, all.len() as u64);
+        self.commit_update(batch, key, current.as_ref(), Some(&updated))?;
+        Ok(all.len() - before)
+    }
+
+    // Removes the given members and returns how many were present. The key is
+    // deleted once the set is empty.
+    pub fn set_remove(&self, key: &[u8], members: &[&[u8]]) -> Result<usize, DataStoreError> {
+        let _guard = self.lock_key(key);
+        let current = self.read_for_update(key)?;
+        let (expires_at, card) = set_header(current.as_ref())?;
+
+        if let Some(mut intset) = current.as_ref().map(intset_members).transpose()?.flatten() {
+            let before = intset.len();
+            for member in members {
+                if let Some(position) = parse_canonical_integer(member)
+                    .and_then(|integer| intset.binary_search(&integer).ok())
+                {
+                    intset.remove(position);
+                }
+            }
+            let removed = before - intset.len();
+            if removed > 0 {
+                let updated =
+                    (!intset.is_empty()).then(|| intset_value(expires_at, intset.iter().copied()));
+                self.commit_update(self.batch(), key, current.as_ref(), updated.as_ref())?;
+            }
+            return Ok(removed);
+        }
+
+        let mut batch = self.batch();
+        let mut removed = HashSet::new();
+        for &member in members {
+            let composite = member_key(key, member);
+            if removed.contains(member) || !self.members_handle().contains_key(&composite)? {
+                continue;
+            }
+            batch.remove(self.members_handle(), composite);
+            removed.insert(member);
+        }
+        if removed.is_empty() {
+            return Ok(0);
+        }
+        let remaining = card.saturating_sub(removed.len() as u64);
+        let updated = (remaining > 0).then(|| set_value(expires_at, remaining));
+        self.commit_update(batch, key, current.as_ref(), updated.as_ref())?;
+        Ok(removed.len())
+    }
+
+    pub fn set_is_member(&self, key: &[u8], member: &[u8]) -> Result<bool, DataStoreError> {
+        Ok(self.set_members_contain(key, &[member])?[0])
+    }
+
+    // Membership of each of `members`, in order. A missing key is an empty
+    // set, so every answer is false.
+    pub fn set_members_contain(
+        &self,
+        key: &[u8],
+        members: &[&[u8]],
+    ) -> Result<Vec<bool>, DataStoreError> {
+        let (contents, _) = set_contents(self.get_stored(key)?.as_ref())?;
+        members
+            .iter()
+            .map(|member| self.set_contains(key, &contents, member))
+            .collect()
+    }
+
+    // The members of the set, in byte order for the hashtable encoding and
+    // in numeric order for intsets
+    pub fn set_members(&self, key: &[u8]) -> Result<Vec<Vec<u8>>, DataStoreError> {
+        let (contents, _) = set_contents(self.get_stored(key)?.as_ref())?;
+        self.set_contents_members(key, &contents)
+    }
+
+    // Examines up to `count` members of the set in byte order, starting
+    // after the member `after`, and returns those matching `pattern`, with
+    // the same guarantees as `hash_scan`. Like Redis, an intset is small
+    // enough to come back whole in one page, in numeric order, whatever
+    // `count`. A missing key is an empty set.
+    pub fn set_scan(
+        &self,
+        key: &[u8],
+        after: Option<&[u8]>,
+        count: usize,
+        pattern: Option<&[u8]>,
+    ) -> Result<SetScanPage, DataStoreError> {
+        let (contents, _) = set_contents(self.get_stored(key)?.as_ref())?;
+        match contents {
+            SetContents::Empty => Ok(SetScanPage::default()),
+            SetContents::Intset(_) => Ok(SetScanPage {
+                members: self
+                    .set_contents_members(key, &contents)?
+                    .into_iter()
+                    .filter(|member| matches_pattern(pattern, member))
+                    .collect(),
+                next: None,
+            }),
+            SetContents::Hashtable => {
+                let (members, next) = self.scan_members(key, after, count, pattern)?;
+                Ok(SetScanPage {
+                    members: members.into_iter().map(|(member, _)| member).collect(),
+                    next,
+                })
+            }
+        }
+    }
+
+    // The members in any of the sets at `keys`, in byte order, as SUNION. A
+    // missing key is an empty set.
+    pub fn set_union(&self, keys: &[&[u8]]) -> Result<Vec<Vec<u8>>, DataStoreError> {
+        self.set_algebra(SetOp::Union, self.read_sets(keys)?)
+    }
+
+    // The members in every one of the sets at `keys`, as SINTER
+    pub fn set_inter(&self, keys: &[&[u8]]) -> Result<Vec<Vec<u8>>, DataStoreError> {
+        self.set_algebra(SetOp::Inter { limit: None }, self.read_sets(keys)?)
+    }
+
+    // Size of the intersection of the sets at `keys`, as SINTERCARD. With a
+    // `limit` the intersection stops being computed once that many members
+    // are found.
+    pub fn set_inter_card(
+        &self,
+        keys: &[&[u8]],
+        limit: Option<usize>,
+    ) -> Result<usize, DataStoreError> {
+        Ok(self
+            .set_algebra(SetOp::Inter { limit }, self.read_sets(keys)?)?
+            .len())
+    }
+
+    // The members of the first set at `keys` that are in none of the others,
+    // as SDIFF
+    pub fn set_diff(&self, keys: &[&[u8]]) -> Result<Vec<Vec<u8>>, DataStoreError> {
+        self.set_algebra(SetOp::Diff, self.read_sets(keys)?)
+    }
+
+    // SUNIONSTORE, SINTERSTORE and SDIFFSTORE: replace whatever is at
+    // `destination` with the result, or delete it if the result is empty.
+    // Return the size of the result.
+    pub fn set_union_store(
+        &self,
+        destination: &[u8],
+        keys: &[&[u8]],
+    ) -> Result<usize, DataStoreError> {
+        self.set_store(destination, keys, SetOp::Union)
+    }
+
+    pub fn set_inter_store(
+        &self,
+        destination: &[u8],
+        keys: &[&[u8]],
+    ) -> Result<usize, DataStoreError> {
+        self.set_store(destination, keys, SetOp::Inter { limit: None })
+    }
+
+    pub fn set_diff_store(
+        &self,
+        destination: &[u8],
+        keys: &[&[u8]],
+    ) -> Result<usize, DataStoreError> {
+        self.set_store(destination, keys, SetOp::Diff)
+    }
+
+    fn read_sets<'k>(&self, keys: &[&'k [u8]]) -> Result<Vec<ReadSet<'k>>, DataStoreError> {
+        keys.iter()
+            .map(|&key| Ok((key, self.get_stored(key)?)))
+            .collect()
+    }
+
+    // Computes `op` over the sets at `keys` and writes the result to
+    // `destination`, all while holding every key's lock, so the result
+    // reflects the sets at a single point in time
+    fn set_store(
+        &self,
+        destination: &[u8],
+        keys: &[&[u8]],
+        op: SetOp,
+    ) -> Result<usize, DataStoreError> {
+        let _guards = self.lock_keys(keys.iter().copied().chain([destination]));
+        // `get_stored` would take the key lock again, to record the access
+        let sets = keys
+            .iter()
+            .map(|&key| Ok((key, self.read_for_update(key)?)))
+            .collect::<Result<Vec<_>, DataStoreError>>()?;
+        let members = self.set_algebra(op, sets)?;
+        let current = self.read_for_update(destination)?;
+
+        let mut batch = self.batch();
+        let intset = (members.len() <= SET_MAX_INTSET_ENTRIES)
+            .then(|| {
+                members
+                    .iter()
+                    .map(|member| parse_canonical_integer(member))
+                    .collect::<Option<BTreeSet<_>>>()
+            })
+            .flatten();
+        let mut kept = HashSet::new();
+        let updated = match intset {
+            _ if members.is_empty() => None,
+            Some(intset) => Some(intset_value(None, intset.into_iter())),
+            None => {
+                for member in &members {
+                    batch.insert(self.members_handle(), member_key(destination, member), []);
+                    kept.insert(member.as_slice());
+                }
+                Some(set_value(None, members.len() as u64))
+            }
+        };
+        // `commit_update` leaves the member entries of a set at `destination`
+        // alone, since the new value is a set too, so drop the ones the result
+        // doesn't have
+        if updated.is_some() {
+            for (member, _) in self.members_of(destination, current.as_ref())? {
+                if !kept.contains(member.as_slice()) {
+                    batch.remove(self.members_handle(), member_key(destination, &member));
+                }
+            }
+        }
+        self.commit_update(batch, destination, current.as_ref(), updated.as_ref())?;
+        Ok(members.len())
+    }
+
+    // Every key is type checked before any result, so a non-set fails the
+    // operation even where an empty set would have decided it
+    fn set_algebra(
+        &self,
+        op: SetOp,
+        sets: Vec<ReadSet<'_>>,
+    ) -> Result<Vec<Vec<u8>>, DataStoreError> {
+        let mut sets = sets
+            .into_iter()
+            .map(|(key, stored)| Ok((key, set_contents(stored.as_ref())?)))
+            .collect::<Result<Vec<_>, DataStoreError>>()?;
+        match op {
+            SetOp::Union => {
+                let mut union = BTreeSet::new();
+                for (key, (contents, _)) in &sets {
+                    union.extend(self.set_contents_members(key, contents)?);
+                }
+                Ok(union.into_iter().collect())
+            }
+            SetOp::Inter { limit } => {
+                // Walk the smallest set, and probe the others smallest first,
+                // where a member is likeliest to be missing
+                sets.sort_by_key(|(_, (_, card))| *card);
+                let Some(((key, (contents, _)), others)) = sets.split_first() else {
+                    return Ok(Vec::new());
+                };
+                let mut found = Vec::new();
+                for member in self.set_contents_members(key, contents)? {
+                    if limit.is_some_and(|limit| found.len() >= limit) {
+                        break;
+                    }
+                    if self.in_every_set(others, &member)? {
+                        found.push(member);
+                    }
+                }
+                Ok(found)
+            }
+            SetOp::Diff => {
+                let Some(((key, (contents, _)), others)) = sets.split_first() else {
+                    return Ok(Vec::new());
+                };
+                let mut remaining = Vec::new();
+                for member in self.set_contents_members(key, contents)? {
+                    let mut elsewhere = false;
+                    for (other, (contents, _)) in others {
+                        if self.set_contains(other, contents, &member)? {
+                            elsewhere = true;
+                            break;
+                        }
+                    }
+                    if !elsewhere {
+                        remaining.push(member);
+                    }
+                }
+                Ok(remaining)
+            }
+        }
+    }
+
+    fn in_every_set(
+        &self,
+        sets: &[(&[u8], (SetContents, u64))],
+        member: &[u8],
+    ) -> Result<bool, DataStoreError> {
+        for (key, (contents, _)) in sets {
+            if !self.set_contains(key, contents, member)? {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    fn set_contains(
+        &self,
+        key: &[u8],
+        contents: &SetContents,
+        member: &[u8],
+    ) -> Result<bool, DataStoreError> {
+        match contents {
+            SetContents::Empty => Ok(false),
+            SetContents::Intset(intset) => Ok(parse_canonical_integer(member)
+                .is_some_and(|integer| intset.binary_search(&integer).is_ok())),
+            SetContents::Hashtable => Ok(self
+                .members_handle()
+                .contains_key(member_key(key, member))?),
+        }
+    }
+
+    fn set_contents_members(
+        &self,
+        key: &[u8],
+        contents: &SetContents,
+    ) -> Result<Vec<Vec<u8>>, DataStoreError> {
+        match contents {
+            SetContents::Empty => Ok(Vec::new()),
+            SetContents::Intset(intset) => Ok(intset
+                .iter()
+                .map(|integer| integer.to_string().into_bytes())
+                .collect()),
+            SetContents::Hashtable => {
+                let prefix = member_prefix(key);
+                self.members_handle()
+                    .prefix(&prefix)
+                    .map(|entry| {
+                        entry
+                            .map(|(member, _)| member[prefix.len()..].to_vec())
+                            .map_err(DataStoreError::from)
+                    })
+                    .collect()
+            }
+        }
+    }
+
+    pub fn set_card(&self, key: &[u8]) -> Result<u64, DataStoreError> {
+        let current = self.get_stored(key)?;
+        set_header(current.as_ref()).map(|(_, card)| card)
+    }
+}
+
+// A key of a set operation with its value, as read for it
+type ReadSet<'k> = (&'k [u8], Option<StoredValue>);
+
+#[derive(Clone, Copy)]
+enum SetOp {
+    Union,
+    Inter { limit: Option<usize> },
+    Diff,
+}
+
+// A set read once, for testing many members against it
+enum SetContents {
+    Empty,
+    Intset(Vec<i64>),
+    // Members are looked up in the members partition
+    Hashtable,
+}
+
+// What a stored set holds and its member count, treating a missing key as an
+// empty set
+fn set_contents(stored: Option<&StoredValue>) -> Result<(SetContents, u64), DataStoreError> {
+    let (_, card) = set_header(stored)?;
+    let contents = match stored {
+        None => SetContents::Empty,
+        Some(stored) => match intset_members(stored)? {
+            Some(intset) => SetContents::Intset(intset),
+            None => SetContents::Hashtable,
+        },
+    };
+    Ok((contents, card))
+}
+
+// Splits a stored set header into its expiry and member count, treating a
+// missing key as an empty set
+fn set_header(stored: Option<&StoredValue>) -> Result<(Option<u64>, u64), DataStoreError> {
+    match stored {
+        None => Ok((None, 0)),
+        Some(stored) if stored.kind != ValueKind::Set => Err(DataStoreError::WrongType),
+        Some(stored) => Ok((stored.expires_at, stored.container_len()?)),
+    }
+}
+
+fn set_value(expires_at: Option<u64>, card: u64) -> StoredValue {
+    StoredValue::container(ValueKind::Set, expires_at, card)
+}
+
+fn intset_value(
+    expires_at: Option<u64>,
+    intset: impl ExactSizeIterator<Item = i64>,
+) -> StoredValue {
+    let mut stored = set_value(expires_at, intset.len() as u64);
+    for integer in intset {
+        stored.payload.extend_from_slice(&integer.to_be_bytes());
+    }
+    stored
+}
+
+// The members of an intset, sorted, or None for a set in the hashtable
+// encoding
+pub(crate) fn intset_members(stored: &StoredValue) -> Result<Option<Vec<i64>>, DataStoreError> {
+    if stored.kind != ValueKind::Set || stored.payload.len() <= COUNT_LEN {
+        return Ok(None);
+    }
+    let packed = &stored.payload[COUNT_LEN..];
+    if !packed.len().is_multiple_of(8) || (packed.len() / 8) as u64 != stored.container_len()? {
+        return Err(DataStoreError::DataError("invalid intset".to_string()));
+    }
+    Ok(Some(
+        packed
+            .chunks_exact(8)
+            .map(|chunk| i64::from_be_bytes(chunk.try_into().unwrap()))
+            .collect(),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::DataStore;
+    use tempfile::TempDir;
+
+    fn create_test_store() -> (TempDir, DataStorePartition) {
+        let temp_dir = TempDir::new().expect("Failed to create temp dir");
+        let data_store = DataStore::new(temp_dir.path().to_str().unwrap()).unwrap();
+        let partition = data_store.partition("sets").unwrap();
+        (temp_dir, partition)
+    }
+
+    #[test]
+    fn test_add_remove_members() {
+        let (_dir, partition) = create_test_store();
+        assert_eq!(partition.set_add(b"set", &[b"a", b"b", b"a"]).unwrap(), 2);
+        assert_eq!(partition.set_add(b"set", &[b"b", b"c"]).unwrap(), 1);
+        assert_eq!(partition.set_card(b"set").unwrap(), 3);
+        assert_eq!(partition.key_type(b"set").unwrap(), Some(ValueKind::Set));
+        assert_eq!(
+            partition.set_members(b"set").unwrap(),
+            vec![b"a".to_vec(), b"b".to_vec(), b"c".to_vec()]
+        );
+
+        assert_eq!(partition.set_remove(b"set", &[b"a", b"z"]).unwrap(), 1);
+        assert!(!partition.set_is_member(b"set", b"a").unwrap());
+        assert_eq!(partition.set_remove(b"set", &[b"b", b"c"]).unwrap(), 2);
+        // Removing the last member removes the key
+        assert!(!partition.exists(b"set").unwrap());
+        assert!(partition.set_members(b"set").unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_members_contain() {
+        let (_dir, partition) = create_test_store();
+        partition.set_add(b"set", &[b"a", b"c"]).unwrap();
+        assert_eq!(
+            partition
+                .set_members_contain(b"set", &[b"a", b"b", b"c", b"d"])
+                .unwrap(),
+            vec![true, false, true, false]
+        );
+        assert_eq!(
+            partition
+                .set_members_contain(b"missing", &[b"a", b"b"])
+                .unwrap(),
+            vec![false, false]
+        );
+
+        partition.set(b"string", b"value").unwrap();
+        assert!(matches!(
+            partition.set_members_contain(b"string", &[b"a"]),
+            Err(DataStoreError::WrongType)
+        ));
+    }
+
+    #[test]
+    fn test_intset_encoding() {
+        let (_dir, partition) = create_test_store();
+        assert_eq!(partition.set_add(b"set", &[b"3", b"-1", b"2"]).unwrap(), 3);
+        assert_eq!(partition.set_add(b"set", &[b"2", b"02"]).unwrap(), 1);
+        assert_eq!(partition.encoding(b"set").unwrap(), Some("hashtable"));
+
+        assert_eq!(partition.set_add(b"ints", &[b"3", b"-1", b"2"]).unwrap(), 3);
+        assert_eq!(partition.set_add(b"ints", &[b"2"]).unwrap(), 0);
+        assert_eq!(partition.encoding(b"ints").unwrap(), Some("intset"));
+        assert_eq!(
+            partition.set_members(b"ints").unwrap(),
+            vec![b"-1".to_vec(), b"2".to_vec(), b"3".to_vec()]
+        );
+        assert_eq!(
+            partition
+                .set_members_contain(b"ints", &[b"2", b"02", b"4"])
+                .unwrap(),
+            vec![true, false, false]
+        );
+        // No member entries are written
+        assert!(partition
+            .members_handle()
+            .prefix(member_prefix(b"ints"))
+            .next()
+            .is_none());
+
+        // A member that isn't an integer converts the set
+        assert_eq!(partition.set_add(b"ints", &[b"a", b"3"]).unwrap(), 1);
+        assert_eq!(partition.encoding(b"ints").unwrap(), Some("hashtable"));
+        assert_eq!(partition.set_card(b"ints").unwrap(), 4);
+        assert_eq!(
+            partition.set_members(b"ints").unwrap(),
+            vec![b"-1".to_vec(), b"2".to_vec(), b"3".to_vec(), b"a".to_vec()]
+        );
+        assert_eq!(partition.set_remove(b"ints", &[b"a"]).unwrap(), 1);
+        assert_eq!(partition.encoding(b"ints").unwrap(), Some("hashtable"));
+
+        // So does outgrowing the intset
+        let many: Vec<Vec<u8>> = (0..=SET_MAX_INTSET_ENTRIES)
+            .map(|i| i.to_string().into_bytes())
+            .collect();
+        let many: Vec<&[u8]> = many.iter().map(|m| m.as_slice()).collect();
+        partition
+            .set_add(b"big", &many[..SET_MAX_INTSET_ENTRIES])
+            .unwrap();
+        assert_eq!(partition.encoding(b"big").unwrap(), Some("intset"));
+        assert_eq!(partition.set_add(b"big", &many).unwrap(), 1);
+        assert_eq!(partition.encoding(b"big").unwrap(), Some("hashtable"));
+        assert_eq!(
+            partition.set_card(b"big").unwrap(),
+            SET_MAX_INTSET_ENTRIES as u64 + 1
+        );
+
+        partition.set_add(b"small", &[b"1", b"2"]).unwrap();
+        let dump = partition.dump(b"small").unwrap().unwrap();
+        partition.restore(b"copy", &dump, None, false).unwrap();
+        assert_eq!(partition.encoding(b"copy").unwrap(), Some("intset"));
+        assert_eq!(
+            partition.set_remove(b"small", &[b"1", b"2", b"3"]).unwrap(),
+            2
+        );
+        assert!(!partition.exists(b"small").unwrap());
+    }
+
+    fn members(values: &[&str]) -> Vec<Vec<u8>> {
+        values
+            .iter()
+            .map(|value| value.as_bytes().to_vec())
+            .collect()
+    }
+
+    #[test]
+    fn test_set_algebra() {
+        let (_dir, partition) = create_test_store();
+        partition.set_add(b"a", &[b"x", b"y", b"z", b"1"]).unwrap();
+        partition.set_add(b"b", &[b"y", b"z", b"w"]).unwrap();
+        partition.set_add(b"ints", &[b"1", b"2"]).unwrap();
+
+        assert_eq!(
+            partition.set_union(&[b"a", b"b", b"ints"]).unwrap(),
+            members(&["1", "2", "w", "x", "y", "z"])
+        );
+        assert_eq!(
+            partition.set_inter(&[b"a", b"b"]).unwrap(),
+            members(&["y", "z"])
+        );
+        // Intsets and hashtables mix
+        assert_eq!(
+            partition.set_inter(&[b"a", b"ints"]).unwrap(),
+            members(&["1"])
+        );
+        assert_eq!(
+            partition.set_diff(&[b"a", b"b", b"ints"]).unwrap(),
+            members(&["x"])
+        );
+        assert_eq!(
+            partition.set_diff(&[b"ints", b"a"]).unwrap(),
+            members(&["2"])
+        );
+
+        // A missing key is an empty set
+        assert!(partition.set_inter(&[b"a", b"missing"]).unwrap().is_empty());
+        assert_eq!(
+            partition.set_union(&[b"missing", b"b"]).unwrap(),
+            members(&["w", "y", "z"])
+        );
+        assert!(partition.set_diff(&[b"missing", b"a"]).unwrap().is_empty());
+
+        // Even when an empty set settles the result, other types fail it
+        partition.set(b"string", b"value").unwrap();
+        for result in [
+            partition.set_inter(&[b"missing", b"string"]),
+            partition.set_union(&[b"a", b"string"]),
+            partition.set_diff(&[b"missing", b"string"]),
+        ] {
+            assert!(matches!(result, Err(DataStoreError::WrongType)));
+        }
+    }
+
+    #[test]
+    fn test_inter_card_limit() {
+        let (_dir, partition) = create_test_store();
+        let many: Vec<Vec<u8>> = (0..100).map(|i| format!("m{}", i).into_bytes()).collect();
+        let many: Vec<&[u8]> = many.iter().map(|m| m.as_slice()).collect();
+        partition.set_add(b"a", &many).unwrap();
+        partition.set_add(b"b", &many[50..]).unwrap();
+
+        assert_eq!(partition.set_inter_card(&[b"a", b"b"], None).unwrap(), 50);
+        assert_eq!(
+            partition.set_inter_card(&[b"a", b"b"], Some(10)).unwrap(),
+            10
+        );
+        assert_eq!(
+            partition.set_inter_card(&[b"a", b"b"], Some(1000)).unwrap(),
+            50
+        );
+        assert_eq!(
+            partition
+                .set_inter_card(&[b"a", b"missing"], Some(10))
+                .unwrap(),
+            0
+        );
+    }
+
+    #[test]
+    fn test_scan_pages_through_members() {
+        let (_dir, partition) = create_test_store();
+        let members: Vec<String> = (0..1000).map(|i| format!("member:{:04}", i)).collect();
+        let refs: Vec<&[u8]> = members.iter().map(|member| member.as_bytes()).collect();
+        partition.set_add(b"set", &refs).unwrap();
+        partition.set_add(b"other", &[b"member:x"]).unwrap();
+
+        let mut seen = Vec::new();
+        let mut after = None;
+        let mut pages = 0;
+        loop {
+            let page = partition
+                .set_scan(b"set", after.as_deref(), 64, None)
+                .unwrap();
+            seen.extend(page.members);
+            pages += 1;
+            if pages == 2 {
+                // Members written mid-scan don't disturb it
+                partition
+                    .set_add(b"set", &[b"member:0000a", b"zzz"])
+                    .unwrap();
+                partition.set_remove(b"set", &[b"member:0001"]).unwrap();
+            }
+            match page.next {
+                Some(next) => after = Some(next),
+                None => break,
+            }
+        }
+        assert_eq!(pages, 16);
+        let mut expected: Vec<Vec<u8>> = members.iter().map(|m| m.clone().into_bytes()).collect();
+        expected.push(b"zzz".to_vec());
+        assert_eq!(seen, expected);
+
+        let page = partition
+            .set_scan(b"set", None, 10_000, Some(b"member:099?"))
+            .unwrap();
+        assert_eq!(page.members.len(), 10);
+        assert_eq!(page.next, None);
+
+        // An intset comes back whole, whatever the count
+        partition.set_add(b"ints", &[b"30", b"-2", b"100"]).unwrap();
+        assert_eq!(
+            partition.set_scan(b"ints", None, 1, None).unwrap(),
+            SetScanPage {
+                members: vec![b"-2".to_vec(), b"30".to_vec(), b"100".to_vec()],
+                next: None,
+            }
+        );
+        assert_eq!(
+            partition
+                .set_scan(b"ints", None, 1, Some(b"1*"))
+                .unwrap()
+                .members,
+            vec![b"100".to_vec()]
+        );
+        assert_eq!(
+            partition.set_scan(b"missing", None, 10, None).unwrap(),
+            SetScanPage::default()
+        );
+        partition.set(b"string", b"value").unwrap();
+        assert!(matches!(
+            partition.set_scan(b"string", None, 10, None),
+            Err(DataStoreError::WrongType)
+        ));
+    }
+
+    #[test]
+    fn test_store_variants() {
+        let (_dir, partition) = create_test_store();
+        partition.set_add(b"a", &[b"x", b"y", b"1"]).unwrap();
+        partition.set_add(b"b", &[b"y", b"1", b"2"]).unwrap();
+
+        // An existing set's members not in the result are dropped
+        partition.set_add(b"dest", &[b"old", b"y"]).unwrap();
+        assert_eq!(
+            partition.set_inter_store(b"dest", &[b"a", b"b"]).unwrap(),
+            2
+        );
+        assert_eq!(
+            partition.set_members(b"dest").unwrap(),
+            members(&["1", "y"])
+        );
+        assert_eq!(partition.set_card(b"dest").unwrap(), 2);
+        assert!(!partition.set_is_member(b"dest", b"old").unwrap());
+
+        // An all-integer result is stored as an intset, which leaves no
+        // member entries behind
+        assert_eq!(partition.set_diff_store(b"dest", &[b"b", b"a"]).unwrap(), 1);
+        assert_eq!(partition.encoding(b"dest").unwrap(), Some("intset"));
+        assert_eq!(partition.set_members(b"dest").unwrap(), members(&["2"]));
+        assert!(partition
+            .members_handle()
+            .prefix(member_prefix(b"dest"))
+            .next()
+            .is_none());
+
+        // A destination of another type is replaced, and may be a source
+        partition.set(b"string", b"value").unwrap();
+        assert_eq!(
+            partition.set_union_store(b"string", &[b"a", b"b"]).unwrap(),
+            4
+        );
+        assert_eq!(partition.key_type(b"string").unwrap(), Some(ValueKind::Set));
+        assert_eq!(partition.set_diff_store(b"a", &[b"a", b"b"]).unwrap(), 1);
+        assert_eq!(partition.set_members(b"a").unwrap(), members(&["x"]));
+
+        // An empty result deletes the destination
+        assert_eq!(
+            partition
+                .set_inter_store(b"dest", &[b"a", b"missing"])
+                .unwrap(),
+            0
+        );
+        assert!(!partition.exists(b"dest").unwrap());
+
+        assert!(matches!(
+            partition.set_union_store(b"dest", &[b"a", b"plain"]),
+            Ok(1)
+        ));
+        partition.set(b"plain", b"value").unwrap();
+        assert!(matches!(
+            partition.set_union_store(b"dest", &[b"a", b"plain"]),
+            Err(DataStoreError::WrongType)
+        ));
+        assert_eq!(partition.set_members(b"dest").unwrap(), members(&["x"]));
+    }
+
+    #[test]
+    fn test_overwrite_drops_members() {
+        let (_dir, partition) = create_test_store();
+        partition.set_add(b"key", &[b"a"]).unwrap();
+        partition.set(b"key", b"value").unwrap();
+        partition.delete(b"key").unwrap();
+        // A new set under the same key does not inherit the old members
+        partition.set_add(b"key", &[b"b"]).unwrap();
+        assert_eq!(partition.set_members(b"key").unwrap(), vec![b"b".to_vec()]);
+
+        // Nor once a string written over the set expires
+        partition
+            .set_expiring(b"key", b"value", Some(crate::now_millis() - 1))
+            .unwrap();
+        partition.set_add(b"key", &[b"c"]).unwrap();
+        assert_eq!(partition.set_members(b"key").unwrap(), vec![b"c".to_vec()]);
+
+        partition.hash_set(b"hash", &[(b"old", b"1")]).unwrap();
+        partition.set(b"hash", b"value").unwrap();
+        partition.delete(b"hash").unwrap();
+        partition.hash_set(b"hash", &[(b"new", b"2")]).unwrap();
+        assert_eq!(partition.hash_len(b"hash").unwrap(), 1);
+        assert_eq!(partition.hash_get(b"hash", b"old").unwrap(), None);
+        // Two headers and their one member each, nothing left over
+        assert_eq!(partition.verify().unwrap().entries, 4);
+    }
+}
@@ -0,0 +1,108 @@
+use crate::{DataStorePartition, KeySlot, KeyValue};
+
+// Spreads keys over several partitions so that writes to different keys land
+// in different LSM-trees (and memtables), letting flushes and compactions of
+// busy keyspaces proceed in parallel. All shards still share the keyspace
+// journal. Keys are routed by cluster slot, so keys sharing a `{hash tag}`
+// always live in the same shard.
+#[derive(Clone)]
+pub struct ShardedPartition {
+    shards: Vec<DataStorePartition>,
+}
+
+impl ShardedPartition {
+    pub fn new(shards: Vec<DataStorePartition>) -> Self {
+        assert!(
+            !shards.is_empty(),
+            "ShardedPartition needs at least one shard"
+        );
+        ShardedPartition { shards }
+    }
+
+    pub fn shard_count(&self) -> usize {
+        self.shards.len()
+    }
+
+    pub fn shard_index(&self, key: &[u8]) -> usize {
+        KeySlot::for_key(key).value() as usize % self.shards.len()
+    }
+
+    pub fn shard(&self, index: usize) -> &DataStorePartition {
+        &self.shards[index]
+    }
+
+    fn shard_for(&self, key: &[u8]) -> &DataStorePartition {
+        &self.shards[self.shard_index(key)]
+    }
+
+    pub fn set(&self, key: &[u8], value: &[u8]) -> Result<(), fjall::Error> {
+        self.shard_for(key).set(key, value)
+    }
+
+    pub fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, fjall::Error> {
+        self.shard_for(key).get(key)
+    }
+
+    pub fn delete(&self, key: &[u8]) -> Result<bool, fjall::Error> {
+        self.shard_for(key).delete(key)
+    }
+
+    pub fn exists(&self, key: &[u8]) -> Result<bool, fjall::Error> {
+        self.shard_for(key).exists(key)
+    }
+
+    // Scans every shard and merges the results into a single key-ordered list.
+    // Shards hold disjoint keys, so no deduplication is needed.
+    pub fn scan_prefix(&self, prefix: &[u8]) -> Result<Vec<KeyValue>, fjall::Error> {
+        let mut entries = Vec::new();
+        for shard in &self.shards {
+            entries.extend(shard.scan_prefix(prefix)?);
+        }
+        entries.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+        Ok(entries)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::DataStore;
+    use std::collections::HashSet;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_keys_spread_across_shards() {
+        let temp_dir = TempDir::new().expect("Failed to create temp dir");
+        let data_store = DataStore::new(temp_dir.path().to_str().unwrap()).unwrap();
+        let sharded = data_store.sharded_partition("sharded", 4).unwrap();
+
+        let keys: Vec<Vec<u8>> = (0..100)
+            .map(|i| format!("key:{}", i).into_bytes())
+            .collect();
+        for key in &keys {
+            sharded.set(key, key).unwrap();
+        }
+
+        let mut used_shards = HashSet::new();
+        for key in &keys {
+            assert_eq!(sharded.get(key).unwrap().as_deref(), Some(key.as_slice()));
+            let index = sharded.shard_index(key);
+            used_shards.insert(index);
+            // The key only lives in the shard it was routed to
+            for other in (0..sharded.shard_count()).filter(|&i| i != index) {
+                assert!(!sharded.shard(other).exists(key).unwrap());
+            }
+        }
+        assert_eq!(used_shards.len(), 4);
+
+        // Scans come back merged in key order
+        let scanned: Vec<Vec<u8>> = sharded
+            .scan_prefix(b"key:")
+            .unwrap()
+            .into_iter()
+            .map(|(key, _)| key)
+            .collect();
+        let mut sorted = keys.clone();
+        sorted.sort();
+        assert_eq!(scanned, sorted);
+    }
+}
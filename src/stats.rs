@@ -0,0 +1,61 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+// Snapshot of a partition's operation counters, see
+// `DataStorePartition::stats`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PartitionStats {
+    pub gets: u64,
+    pub sets: u64,
+    pub deletes: u64,
+    // Gets that found the key, and those that did not
+    pub hits: u64,
+    pub misses: u64,
+}
+
+impl PartitionStats {
+    // Fraction of gets that found their key, None before the first get
+    pub fn hit_rate(&self) -> Option<f64> {
+        let lookups = self.hits + self.misses;
+        (lookups > 0).then(|| self.hits as f64 / lookups as f64)
+    }
+}
+
+// Live counters behind `PartitionStats`. Relaxed ordering is enough: the
+// counters are independent and only ever read as a rough snapshot.
+#[derive(Default)]
+pub(crate) struct StatsCounters {
+    gets: AtomicU64,
+    sets: AtomicU64,
+    deletes: AtomicU64,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl StatsCounters {
+    pub(crate) fn record_get(&self, hit: bool) {
+        self.gets.fetch_add(1, Ordering::Relaxed);
+        if hit {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.misses.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub(crate) fn record_set(&self) {
+        self.sets.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_delete(&self) {
+        self.deletes.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn snapshot(&self) -> PartitionStats {
+        PartitionStats {
+            gets: self.gets.load(Ordering::Relaxed),
+            sets: self.sets.load(Ordering::Relaxed),
+            deletes: self.deletes.load(Ordering::Relaxed),
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+        }
+    }
+}
@@ -0,0 +1,125 @@
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+
+// A RESP2 reply as decoded by `TestClient`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Reply {
+    Status(String),
+    Error(String),
+    Integer(i64),
+    Bulk(Vec<u8>),
+    Array(Vec<Reply>),
+    // Null bulk string or null array
+    Nil,
+}
+
+impl Reply {
+    pub fn bulk(data: &[u8]) -> Self {
+        Reply::Bulk(data.to_vec())
+    }
+
+    pub fn ok() -> Self {
+        Reply::Status("OK".to_string())
+    }
+}
+
+// Minimal blocking client for talking to a running server from tests and
+// embedders, without pulling in a full Redis client. Call it from a blocking
+// context (`spawn_blocking` or a thread) when the server runs on the same
+// tokio runtime.
+pub struct TestClient {
+    reader: BufReader<Box<dyn Read + Send>>,
+    writer: Box<dyn Write + Send>,
+}
+
+impl TestClient {
+    pub fn connect<A: ToSocketAddrs>(addr: A) -> io::Result<Self> {
+        let stream = TcpStream::connect(addr)?;
+        let writer = stream.try_clone()?;
+        Ok(TestClient::new(Box::new(stream), Box::new(writer)))
+    }
+
+    #[cfg(unix)]
+    pub fn connect_unix<P: AsRef<std::path::Path>>(path: P) -> io::Result<Self> {
+        let stream = std::os::unix::net::UnixStream::connect(path)?;
+        let writer = stream.try_clone()?;
+        Ok(TestClient::new(Box::new(stream), Box::new(writer)))
+    }
+
+    fn new(reader: Box<dyn Read + Send>, writer: Box<dyn Write + Send>) -> Self {
+        TestClient {
+            reader: BufReader::new(reader),
+            writer,
+        }
+    }
+
+    // Sends a command and waits for its reply
+    pub fn command(&mut self, args: &[&[u8]]) -> io::Result<Reply> {
+        self.send(args)?;
+        self.read_reply()
+    }
+
+    // Sends a command without waiting, for pipelining or pub/sub
+    pub fn send(&mut self, args: &[&[u8]]) -> io::Result<()> {
+        let mut buf = format!("*{}\r\n", args.len()).into_bytes();
+        for arg in args {
+            buf.extend_from_slice(format!("${}\r\n", arg.len()).as_bytes());
+            buf.extend_from_slice(arg);
+            buf.extend_from_slice(b"\r\n");
+        }
+        self.writer.write_all(&buf)?;
+        self.writer.flush()
+    }
+
+    // Reads the next reply. Fails with `UnexpectedEof` once the server
+    // closed the connection.
+    pub fn read_reply(&mut self) -> io::Result<Reply> {
+        let line = self.read_line()?;
+        let (marker, rest) = line.split_at(1);
+        match marker {
+            "+" => Ok(Reply::Status(rest.to_string())),
+            "-" => Ok(Reply::Error(rest.to_string())),
+            ":" => parse_int(rest).map(Reply::Integer),
+            "$" => {
+                let Ok(len) = usize::try_from(parse_int(rest)?) else {
+                    return Ok(Reply::Nil);
+                };
+                let mut data = vec![0; len + 2];
+                self.reader.read_exact(&mut data)?;
+                data.truncate(len);
+                Ok(Reply::Bulk(data))
+            }
+            "*" => {
+                let Ok(len) = usize::try_from(parse_int(rest)?) else {
+                    return Ok(Reply::Nil);
+                };
+                (0..len)
+                    .map(|_| self.read_reply())
+                    .collect::<io::Result<_>>()
+                    .map(Reply::Array)
+            }
+            _ => Err(invalid(format!("unexpected reply line '{}'", line))),
+        }
+    }
+
+    // Reads a CRLF terminated line, without the terminator
+    fn read_line(&mut self) -> io::Result<String> {
+        let mut line = String::new();
+        if self.reader.read_line(&mut line)? == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        match line.strip_suffix("\r\n") {
+            Some(stripped) if !stripped.is_empty() => Ok(stripped.to_string()),
+            _ => Err(invalid(format!("malformed reply line '{}'", line))),
+        }
+    }
+}
+
+fn parse_int(s: &str) -> io::Result<i64> {
+    s.parse()
+        .map_err(|_| invalid(format!("invalid integer '{}'", s)))
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
@@ -0,0 +1,98 @@
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+
+// What happens to a client-requested TTL outside of the `TtlLimits`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TtlPolicy {
+    // Raised to the minimum or cut down to the maximum
+    #[default]
+    Clamp,
+    // Fails the command, leaving the key untouched
+    Reject,
+}
+
+impl TtlPolicy {
+    // Name used by `--ttl-policy`
+    pub fn name(self) -> &'static str {
+        match self {
+            TtlPolicy::Clamp => "clamp",
+            TtlPolicy::Reject => "reject",
+        }
+    }
+}
+
+impl FromStr for TtlPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "clamp" => Ok(TtlPolicy::Clamp),
+            "reject" => Ok(TtlPolicy::Reject),
+            _ => Err(format!("invalid TTL policy '{}'", s)),
+        }
+    }
+}
+
+impl fmt::Display for TtlPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+// Bounds on the TTLs clients set with EXPIRE and friends or SET EX/PX, so
+// that a shared cache can't be filled with keys that never expire. Keys set
+// without a TTL are not affected, nor are TTLs that delete the key outright.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct TtlLimits {
+    pub min: Option<Duration>,
+    pub max: Option<Duration>,
+    pub policy: TtlPolicy,
+}
+
+impl TtlLimits {
+    // Brings a positive TTL in milliseconds within the bounds. None if it is
+    // out of them and the policy rejects it.
+    pub fn apply(&self, ttl_ms: u64) -> Option<u64> {
+        let min = self.min.map_or(0, duration_ms);
+        let max = self.max.map_or(u64::MAX, duration_ms);
+        if (min..=max).contains(&ttl_ms) {
+            return Some(ttl_ms);
+        }
+        match self.policy {
+            // A minimum above the maximum wins
+            TtlPolicy::Clamp => Some(ttl_ms.min(max).max(min)),
+            TtlPolicy::Reject => None,
+        }
+    }
+}
+
+fn duration_ms(duration: Duration) -> u64 {
+    duration.as_millis().try_into().unwrap_or(u64::MAX)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_apply() {
+        let mut limits = TtlLimits {
+            min: Some(Duration::from_secs(1)),
+            max: Some(Duration::from_secs(60)),
+            policy: TtlPolicy::Clamp,
+        };
+        assert_eq!(limits.apply(30_000), Some(30_000));
+        assert_eq!(limits.apply(3_600_000), Some(60_000));
+        assert_eq!(limits.apply(10), Some(1_000));
+
+        limits.policy = TtlPolicy::Reject;
+        assert_eq!(limits.apply(60_000), Some(60_000));
+        assert_eq!(limits.apply(60_001), None);
+        assert_eq!(limits.apply(999), None);
+
+        assert_eq!(TtlLimits::default().apply(u64::MAX), Some(u64::MAX));
+        assert_eq!("REJECT".parse::<TtlPolicy>(), Ok(TtlPolicy::Reject));
+        assert!("ignore".parse::<TtlPolicy>().is_err());
+    }
+}
@@ -0,0 +1,540 @@
+use crate::lfu::LFU_INIT_VAL;
+use crate::{DataStoreError, KeyValue};
+use std::collections::VecDeque;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// On-disk layout of every value in a partition:
+//
+//   [format: u8][crc32c: u32 BE][kind: u8][expires_at: u64 BE, 0 = no expiry]
+//   [accessed_at: u32 BE][freq: u8][payload...]
+//
+// The format byte is `FORMAT_MARKER | version`; the checksum covers
+// everything after it, so a damaged value fails to decode instead of being
+// misread. Format bytes are 0x80..=0xBF, which never start valid UTF-8 text:
+// values starting with any other byte were written before values had a header
+// and are read as plain strings without expiry. Any change to the fields must
+// bump the version, keeping older versions decodable.
+//
+// The header is fixed-width and the payload runs to the end of the value, so
+// no separator byte is involved: empty payloads and payloads containing NUL,
+// CR or LF round-trip unchanged. Any future composite encoding must keep this
+// property by length-prefixing user bytes rather than delimiting them.
+const FORMAT_MARKER: u8 = 0x80;
+// Bits of the format byte set to the marker, the others being the version
+const FORMAT_MARKER_MASK: u8 = 0xc0;
+const FORMAT_VERSION: u8 = 1;
+// Format byte and checksum
+const PREFIX_LEN: usize = 1 + 4;
+// Kind, expiry, access time and frequency
+const FIELDS_LEN: usize = 1 + 8 + 4 + 1;
+const HEADER_LEN: usize = PREFIX_LEN + FIELDS_LEN;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ValueKind {
+    String,
+    List,
+    Set,
+    Hash,
+}
+
+impl ValueKind {
+    // Name reported by the TYPE command
+    pub fn name(self) -> &'static str {
+        match self {
+            ValueKind::String => "string",
+            ValueKind::List => "list",
+            ValueKind::Set => "set",
+            ValueKind::Hash => "hash",
+        }
+    }
+
+    // Whether values of this kind keep their members as separate entries
+    pub fn has_members(self) -> bool {
+        matches!(self, ValueKind::Set | ValueKind::Hash)
+    }
+
+    fn to_byte(self) -> u8 {
+        match self {
+            ValueKind::String => 0,
+            ValueKind::List => 1,
+            ValueKind::Set => 2,
+            ValueKind::Hash => 3,
+        }
+    }
+
+    fn from_byte(byte: u8) -> Result<Self, DataStoreError> {
+        match byte {
+            0 => Ok(ValueKind::String),
+            1 => Ok(ValueKind::List),
+            2 => Ok(ValueKind::Set),
+            3 => Ok(ValueKind::Hash),
+            other => Err(DataStoreError::DataError(format!(
+                "unknown value kind {}",
+                other
+            ))),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StoredValue {
+    pub kind: ValueKind,
+    // Absolute unix time in milliseconds
+    pub expires_at: Option<u64>,
+    // Unix time in seconds of the last write or, with
+    // `PartitionBuilder::track_reads`, sampled read
+    pub accessed_at: u32,
+    // Logarithmic access frequency counter, see `Lfu`
+    pub freq: u8,
+    pub payload: Vec<u8>,
+}
+
+impl StoredValue {
+    pub fn string(payload: &[u8]) -> Self {
+        StoredValue {
+            kind: ValueKind::String,
+            expires_at: None,
+            accessed_at: 0,
+            freq: LFU_INIT_VAL,
+            payload: payload.to_vec(),
+        }
+    }
+
+    pub fn list(items: &VecDeque<Vec<u8>>) -> Self {
+        StoredValue {
+            kind: ValueKind::List,
+            expires_at: None,
+            accessed_at: 0,
+            freq: LFU_INIT_VAL,
+            payload: encode_items(items),
+        }
+    }
+
+    // Header of a container whose members live in the members partition. The
+    // payload is the member count as a u64 BE.
+    pub fn container(kind: ValueKind, expires_at: Option<u64>, len: u64) -> Self {
+        StoredValue {
+            kind,
+            expires_at,
+            accessed_at: 0,
+            freq: LFU_INIT_VAL,
+            payload: len.to_be_bytes().to_vec(),
+        }
+    }
+
+    // Read from the start of the payload, which intsets extend
+    pub fn container_len(&self) -> Result<u64, DataStoreError> {
+        self.payload
+            .get(..8)
+            .and_then(|count| count.try_into().ok())
+            .map(u64::from_be_bytes)
+            .ok_or_else(|| DataStoreError::DataError("invalid container header".to_string()))
+    }
+
+    pub fn is_expired(&self, now_ms: u64) -> bool {
+        matches!(self.expires_at, Some(deadline) if deadline <= now_ms)
+    }
+
+    // Whole seconds since the last access
+    pub fn idle_secs(&self, now_secs: u32) -> u64 {
+        now_secs.saturating_sub(self.accessed_at) as u64
+    }
+
+    // Length of `encode`'s output
+    pub fn encoded_len(&self) -> usize {
+        HEADER_LEN + self.payload.len()
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(self.encoded_len());
+        bytes.push(FORMAT_MARKER | FORMAT_VERSION);
+        bytes.extend_from_slice(&[0; 4]);
+        bytes.push(self.kind.to_byte());
+        bytes.extend_from_slice(&self.expires_at.unwrap_or(0).to_be_bytes());
+        bytes.extend_from_slice(&self.accessed_at.to_be_bytes());
+        bytes.push(self.freq);
+        bytes.extend_from_slice(&self.payload);
+        let checksum = crc32c(&bytes[PREFIX_LEN..]);
+        bytes[1..PREFIX_LEN].copy_from_slice(&checksum.to_be_bytes());
+        bytes
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Self, DataStoreError> {
+        let Some((checksum, fields)) = split_format(bytes)? else {
+            return Ok(StoredValue::string(bytes));
+        };
+        if crc32c(fields) != checksum {
+            return Err(DataStoreError::DataError(
+                "stored value checksum mismatch".to_string(),
+            ));
+        }
+        let kind = ValueKind::from_byte(fields[0])?;
+        let mut accessed_at = [0u8; 4];
+        accessed_at.copy_from_slice(&fields[9..13]);
+        Ok(StoredValue {
+            kind,
+            expires_at: deadline(fields),
+            accessed_at: u32::from_be_bytes(accessed_at),
+            freq: fields[13],
+            payload: fields[FIELDS_LEN..].to_vec(),
+        })
+    }
+
+    // Reads only the expiry of an encoded value, without verifying the
+    // checksum or copying the payload, for scans that just count keys
+    pub fn peek_expires_at(bytes: &[u8]) -> Result<Option<u64>, DataStoreError> {
+        Ok(split_format(bytes)?.and_then(|(_, fields)| deadline(fields)))
+    }
+}
+
+// Splits an encoded value into its stored checksum and the fields the
+// checksum covers, or None for a value written before values had a header
+fn split_format(bytes: &[u8]) -> Result<Option<(u32, &[u8])>, DataStoreError> {
+    match bytes.first() {
+        Some(&format) if format & FORMAT_MARKER_MASK == FORMAT_MARKER => {
+            if format != FORMAT_MARKER | FORMAT_VERSION {
+                return Err(DataStoreError::DataError(format!(
+                    "unknown value format version {}",
+                    format & !FORMAT_MARKER_MASK
+                )));
+            }
+            if bytes.len() < HEADER_LEN {
+                return Err(too_short(bytes));
+            }
+            let mut checksum = [0u8; 4];
+            checksum.copy_from_slice(&bytes[1..PREFIX_LEN]);
+            Ok(Some((u32::from_be_bytes(checksum), &bytes[PREFIX_LEN..])))
+        }
+        _ => Ok(None),
+    }
+}
+
+fn deadline(fields: &[u8]) -> Option<u64> {
+    let mut deadline = [0u8; 8];
+    deadline.copy_from_slice(&fields[1..9]);
+    match u64::from_be_bytes(deadline) {
+        0 => None,
+        deadline => Some(deadline),
+    }
+}
+
+fn too_short(bytes: &[u8]) -> DataStoreError {
+    DataStoreError::DataError(format!("stored value too short ({} bytes)", bytes.len()))
+}
+
+// CRC-32C (Castagnoli), table-driven
+fn crc32c(bytes: &[u8]) -> u32 {
+    const TABLE: [u32; 256] = {
+        let mut table = [0u32; 256];
+        let mut i = 0;
+        while i < 256 {
+            let mut crc = i as u32;
+            let mut bit = 0;
+            while bit < 8 {
+                crc = if crc & 1 != 0 {
+                    (crc >> 1) ^ 0x82f6_3b78
+                } else {
+                    crc >> 1
+                };
+                bit += 1;
+            }
+            table[i] = crc;
+            i += 1;
+        }
+        table
+    };
+    !bytes.iter().fold(!0u32, |crc, &byte| {
+        TABLE[((crc ^ byte as u32) & 0xff) as usize] ^ (crc >> 8)
+    })
+}
+
+// Serialized form of a key's value used by DUMP and RESTORE:
+//
+//   [DUMP_VERSION: u8][kind: u8][items][checksum: u64 BE]
+//
+// where items are encoded as in `encode_items`: the payload followed by the
+// member and value of every container member. The expiry and access stats
+// are not included; RESTORE takes the TTL as an argument.
+const DUMP_VERSION: u8 = 1;
+
+impl StoredValue {
+    pub fn dump(&self, members: &[KeyValue]) -> Vec<u8> {
+        let mut items = VecDeque::with_capacity(1 + 2 * members.len());
+        items.push_back(self.payload.clone());
+        for (member, value) in members {
+            items.push_back(member.clone());
+            items.push_back(value.clone());
+        }
+        let mut bytes = vec![DUMP_VERSION, self.kind.to_byte()];
+        bytes.extend_from_slice(&encode_items(&items));
+        let checksum = fnv1a(&bytes);
+        bytes.extend_from_slice(&checksum.to_be_bytes());
+        bytes
+    }
+
+    // Inverse of `dump`, returning a value without expiry along with its
+    // container members
+    pub fn from_dump(bytes: &[u8]) -> Result<(Self, Vec<KeyValue>), DataStoreError> {
+        let Some(split) = bytes.len().checked_sub(8).filter(|&split| split >= 2) else {
+            return Err(DataStoreError::InvalidDump);
+        };
+        let (body, checksum) = bytes.split_at(split);
+        if body[0] != DUMP_VERSION || fnv1a(body).to_be_bytes() != checksum {
+            return Err(DataStoreError::InvalidDump);
+        }
+        let kind = ValueKind::from_byte(body[1]).map_err(|_| DataStoreError::InvalidDump)?;
+        let mut items = decode_items(&body[2..]).map_err(|_| DataStoreError::InvalidDump)?;
+        let payload = items.pop_front().ok_or(DataStoreError::InvalidDump)?;
+        if items.len() % 2 != 0 || (!kind.has_members() && !items.is_empty()) {
+            return Err(DataStoreError::InvalidDump);
+        }
+        let mut members = Vec::with_capacity(items.len() / 2);
+        while let (Some(member), Some(value)) = (items.pop_front(), items.pop_front()) {
+            members.push((member, value));
+        }
+        let stored = StoredValue {
+            kind,
+            expires_at: None,
+            accessed_at: 0,
+            freq: LFU_INIT_VAL,
+            payload,
+        };
+        Ok((stored, members))
+    }
+}
+
+// 64-bit FNV-1a, guarding dumps against corruption in transit
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &byte| {
+        (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+// Lists are stored as a sequence of length-prefixed items:
+//
+//   [len: u32 BE][item bytes]...
+pub fn encode_items(items: &VecDeque<Vec<u8>>) -> Vec<u8> {
+    let total = items.iter().map(|item| 4 + item.len()).sum();
+    let mut bytes = Vec::with_capacity(total);
+    for item in items {
+        bytes.extend_from_slice(&(item.len() as u32).to_be_bytes());
+        bytes.extend_from_slice(item);
+    }
+    bytes
+}
+
+pub fn decode_items(mut bytes: &[u8]) -> Result<VecDeque<Vec<u8>>, DataStoreError> {
+    let mut items = VecDeque::new();
+    while !bytes.is_empty() {
+        if bytes.len() < 4 {
+            return Err(DataStoreError::DataError(
+                "truncated item length".to_string(),
+            ));
+        }
+        let len = u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as usize;
+        if bytes.len() < 4 + len {
+            return Err(DataStoreError::DataError("truncated item".to_string()));
+        }
+        items.push_back(bytes[4..4 + len].to_vec());
+        bytes = &bytes[4 + len..];
+    }
+    Ok(items)
+}
+
+// How counters (INCR and friends) are stored. Replies to numeric commands are
+// RESP integers either way; only the stored bytes, and therefore what a plain
+// GET returns, differ. A BigEndianI64 partition returns 8 raw bytes on GET
+// rather than the decimal text Redis clients expect.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum NumericEncoding {
+    // Base-10 ASCII, as Redis stores counters
+    #[default]
+    AsciiDecimal,
+    // Fixed-width 8-byte big-endian two's complement
+    BigEndianI64,
+}
+
+impl NumericEncoding {
+    pub fn encode(self, value: i64) -> Vec<u8> {
+        match self {
+            NumericEncoding::AsciiDecimal => value.to_string().into_bytes(),
+            NumericEncoding::BigEndianI64 => value.to_be_bytes().to_vec(),
+        }
+    }
+
+    pub fn decode(self, bytes: &[u8]) -> Result<i64, DataStoreError> {
+        match self {
+            NumericEncoding::AsciiDecimal => std::str::from_utf8(bytes)
+                .ok()
+                .and_then(|s| s.parse().ok())
+                .ok_or(DataStoreError::NotAnInteger),
+            NumericEncoding::BigEndianI64 => bytes
+                .try_into()
+                .map(i64::from_be_bytes)
+                .map_err(|_| DataStoreError::NotAnInteger),
+        }
+    }
+}
+
+// Parses a float the way Redis accepts them: finite values only
+// The integer `bytes` spell in canonical form: "12" is one, "012" and "+12"
+// are not, as they would not round-trip
+pub(crate) fn parse_canonical_integer(bytes: &[u8]) -> Option<i64> {
+    let integer = std::str::from_utf8(bytes).ok()?.parse::<i64>().ok()?;
+    (integer.to_string().as_bytes() == bytes).then_some(integer)
+}
+
+pub fn parse_float(bytes: &[u8]) -> Option<f64> {
+    std::str::from_utf8(bytes)
+        .ok()?
+        .parse::<f64>()
+        .ok()
+        .filter(|value| value.is_finite())
+}
+
+// Shortest decimal representation that parses back to the same value, without
+// exponent or trailing zeros ("3" rather than "3.0")
+pub fn format_float(value: f64) -> String {
+    format!("{}", value)
+}
+
+pub fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+pub fn now_secs() -> u32 {
+    (now_millis() / 1000) as u32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode_decode_round_trip() {
+        let mut value = StoredValue::string(b"hello");
+        assert_eq!(StoredValue::decode(&value.encode()).unwrap(), value);
+
+        value.expires_at = Some(1_700_000_000_000);
+        assert_eq!(StoredValue::decode(&value.encode()).unwrap(), value);
+
+        value.accessed_at = 1_700_000_000;
+        value.freq = 200;
+        assert_eq!(StoredValue::decode(&value.encode()).unwrap(), value);
+
+        assert!(StoredValue::decode(b"\x81\x00").is_err());
+    }
+
+    #[test]
+    fn test_format_versions() {
+        let mut value = StoredValue::string(b"hello");
+        value.expires_at = Some(1_700_000_000_000);
+        let encoded = value.encode();
+        assert_eq!(encoded[0], FORMAT_MARKER | FORMAT_VERSION);
+
+        // Values written before values had a header are plain strings
+        for legacy in [&b"hello"[..], b"", b"\x00\r\n", "h\u{e9}llo".as_bytes()] {
+            assert_eq!(
+                StoredValue::decode(legacy).unwrap(),
+                StoredValue::string(legacy)
+            );
+            assert_eq!(StoredValue::peek_expires_at(legacy).unwrap(), None);
+        }
+
+        let mut corrupted = encoded.clone();
+        *corrupted.last_mut().unwrap() ^= 1;
+        assert!(matches!(
+            StoredValue::decode(&corrupted),
+            Err(DataStoreError::DataError(e)) if e.contains("checksum")
+        ));
+
+        let mut future = encoded;
+        future[0] = FORMAT_MARKER | (FORMAT_VERSION + 1);
+        assert!(matches!(
+            StoredValue::decode(&future),
+            Err(DataStoreError::DataError(e)) if e.contains("version")
+        ));
+
+        // The standard CRC-32C check value
+        assert_eq!(crc32c(b"123456789"), 0xe306_9283);
+    }
+
+    #[test]
+    fn test_empty_and_binary_payloads() {
+        for payload in [&b""[..], b"\x00", b"\x00\r\n", b"\r\n\r\n\x00\xff"] {
+            let value = StoredValue::string(payload);
+            assert_eq!(
+                StoredValue::decode(&value.encode()).unwrap().payload,
+                payload
+            );
+        }
+    }
+
+    #[test]
+    fn test_item_encoding() {
+        let items: VecDeque<Vec<u8>> = vec![b"a".to_vec(), Vec::new(), b"\x00\r\n".to_vec()].into();
+        assert_eq!(decode_items(&encode_items(&items)).unwrap(), items);
+        assert!(decode_items(&encode_items(&VecDeque::new()))
+            .unwrap()
+            .is_empty());
+        assert!(decode_items(b"\x00\x00\x00\x05ab").is_err());
+    }
+
+    #[test]
+    fn test_numeric_encodings() {
+        let ascii = NumericEncoding::AsciiDecimal;
+        assert_eq!(ascii.encode(-42), b"-42");
+        assert_eq!(ascii.decode(b"-42").unwrap(), -42);
+        assert!(ascii.decode(b"4.2").is_err());
+        assert!(ascii.decode(b" 42").is_err());
+
+        let binary = NumericEncoding::BigEndianI64;
+        assert_eq!(binary.encode(1), [0, 0, 0, 0, 0, 0, 0, 1]);
+        assert_eq!(binary.decode(&binary.encode(i64::MIN)).unwrap(), i64::MIN);
+        assert!(binary.decode(b"1234").is_err());
+    }
+
+    #[test]
+    fn test_float_parsing_and_formatting() {
+        assert_eq!(parse_float(b"10.5"), Some(10.5));
+        assert_eq!(parse_float(b"-3e2"), Some(-300.0));
+        assert_eq!(parse_float(b"inf"), None);
+        assert_eq!(parse_float(b"nan"), None);
+        assert_eq!(parse_float(b"abc"), None);
+
+        assert_eq!(format_float(10.5 + 0.1), "10.6");
+        assert_eq!(format_float(3.0), "3");
+        assert_eq!(format_float(5.0e3 + 2.0e2), "5200");
+        assert_eq!(format_float(-0.25), "-0.25");
+    }
+
+    #[test]
+    fn test_dump_round_trip() {
+        let mut value = StoredValue::container(ValueKind::Hash, Some(42), 2);
+        value.freq = 100;
+        let members = vec![
+            (b"field".to_vec(), b"value".to_vec()),
+            (b"\r\n".to_vec(), Vec::new()),
+        ];
+        let dump = value.dump(&members);
+        let (restored, restored_members) = StoredValue::from_dump(&dump).unwrap();
+        assert_eq!(restored.kind, ValueKind::Hash);
+        assert_eq!(restored.payload, value.payload);
+        assert_eq!(restored.expires_at, None);
+        assert_eq!(restored_members, members);
+
+        let mut corrupted = dump.clone();
+        corrupted[3] ^= 1;
+        assert!(matches!(
+            StoredValue::from_dump(&corrupted),
+            Err(DataStoreError::InvalidDump)
+        ));
+        assert!(matches!(
+            StoredValue::from_dump(&dump[..9]),
+            Err(DataStoreError::InvalidDump)
+        ));
+    }
+}
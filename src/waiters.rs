@@ -0,0 +1,74 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, MutexGuard};
+use tokio::sync::Notify;
+
+// Connections blocked on keys (BLPOP and friends), woken when a key they
+// wait on gets written. Each waiter has its own `Notify`, registered under
+// every key it waits on; waking stores a permit, so a write that lands
+// between a waiter's check and its wait is not missed.
+#[derive(Default)]
+pub(crate) struct KeyWaiters {
+    waiters: Mutex<HashMap<Vec<u8>, Vec<Arc<Notify>>>>,
+}
+
+impl KeyWaiters {
+    pub(crate) fn register(self: &Arc<Self>, keys: &[Vec<u8>]) -> Waiter {
+        let notify = Arc::new(Notify::new());
+        let mut waiters = self.lock();
+        for key in keys {
+            waiters.entry(key.clone()).or_default().push(notify.clone());
+        }
+        Waiter {
+            waiters: self.clone(),
+            keys: keys.to_vec(),
+            notify,
+        }
+    }
+
+    // Wakes every waiter on `key`. They all retry, and those that lose the
+    // race go back to waiting.
+    pub(crate) fn wake(&self, key: &[u8]) {
+        if let Some(notifies) = self.lock().get(key) {
+            for notify in notifies {
+                notify.notify_one();
+            }
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, HashMap<Vec<u8>, Vec<Arc<Notify>>>> {
+        // Registrations are updated in single steps, so a poisoned lock is
+        // still usable
+        self.waiters
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+// A registration on some keys, removed when dropped
+pub(crate) struct Waiter {
+    waiters: Arc<KeyWaiters>,
+    keys: Vec<Vec<u8>>,
+    notify: Arc<Notify>,
+}
+
+impl Waiter {
+    // Resolves once one of the keys was written since the last call, or
+    // since registering
+    pub(crate) async fn woken(&self) {
+        self.notify.notified().await
+    }
+}
+
+impl Drop for Waiter {
+    fn drop(&mut self) {
+        let mut waiters = self.waiters.lock();
+        for key in &self.keys {
+            if let Some(notifies) = waiters.get_mut(key) {
+                notifies.retain(|notify| !Arc::ptr_eq(notify, &self.notify));
+                if notifies.is_empty() {
+                    waiters.remove(key);
+                }
+            }
+        }
+    }
+}